@@ -0,0 +1,247 @@
+// Account Data
+// Per-user client data, global or scoped to a room, and room tags
+// Focus: Validating account data writes and editing m.tag
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::auth::AuthenticatedUser;
+use crate::state::{StateError, StateStore};
+
+/// Room account data type holding the room's tags
+pub const TAG_ACCOUNT_DATA_TYPE: &str = "m.tag";
+
+/// Types the server maintains through their own APIs
+const SERVER_MANAGED_TYPES: [&str; 2] = ["m.fully_read", "m.push_rules"];
+
+/// Longest tag name accepted, in bytes
+const MAX_TAG_LENGTH: usize = 255;
+
+#[derive(Error, Debug)]
+pub enum AccountDataError {
+    #[error("Account data not found: {0}")]
+    NotFound(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("{0} is managed by the server and cannot be set directly")]
+    ServerManaged(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
+
+    #[error("Bad JSON: {0}")]
+    BadJson(String),
+
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+}
+
+impl AccountDataError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            AccountDataError::NotFound(_) => 404,
+            AccountDataError::Forbidden(_) => 403,
+            AccountDataError::ServerManaged(_) => 405,
+            AccountDataError::InvalidParam(_) => 400,
+            AccountDataError::BadJson(_) => 400,
+            AccountDataError::State(_) => 500,
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            AccountDataError::NotFound(_) => "M_NOT_FOUND",
+            AccountDataError::Forbidden(_) => "M_FORBIDDEN",
+            AccountDataError::ServerManaged(_) => "M_BAD_JSON",
+            AccountDataError::InvalidParam(_) => "M_INVALID_PARAM",
+            AccountDataError::BadJson(_) => "M_BAD_JSON",
+            AccountDataError::State(_) => "M_UNKNOWN",
+        }
+    }
+}
+
+/// A tag on a room; `order` positions it among rooms with the same tag
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TagInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<f64>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// `m.tag` content
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TagsContent {
+    #[serde(default)]
+    pub tags: BTreeMap<String, TagInfo>,
+}
+
+/// Store `data_type` for `user_id`, globally or for `room_id`
+pub async fn set_account_data(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    user_id: &str,
+    room_id: Option<&str>,
+    data_type: &str,
+    content: serde_json::Value,
+) -> Result<(), AccountDataError> {
+    check_access(user, user_id, room_id)?;
+    if SERVER_MANAGED_TYPES.contains(&data_type) {
+        return Err(AccountDataError::ServerManaged(data_type.to_string()));
+    }
+    if !content.is_object() {
+        return Err(AccountDataError::BadJson("Account data content must be an object".to_string()));
+    }
+    if data_type == TAG_ACCOUNT_DATA_TYPE {
+        serde_json::from_value::<TagsContent>(content.clone())
+            .map_err(|e| AccountDataError::BadJson(format!("Invalid m.tag content: {}", e)))?;
+    }
+
+    store.set_account_data(user_id, room_id, data_type, content).await?;
+    Ok(())
+}
+
+pub async fn get_account_data(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    user_id: &str,
+    room_id: Option<&str>,
+    data_type: &str,
+) -> Result<serde_json::Value, AccountDataError> {
+    check_access(user, user_id, room_id)?;
+    store
+        .get_account_data(user_id, room_id, data_type)
+        .await?
+        .ok_or_else(|| AccountDataError::NotFound(data_type.to_string()))
+}
+
+/// Tags `user_id` has put on `room_id`; none if never tagged
+pub async fn get_tags(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    user_id: &str,
+    room_id: &str,
+) -> Result<TagsContent, AccountDataError> {
+    check_access(user, user_id, Some(room_id))?;
+    load_tags(store, user_id, room_id).await
+}
+
+/// Add or replace `tag` on `room_id`
+pub async fn set_tag(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    user_id: &str,
+    room_id: &str,
+    tag: &str,
+    info: TagInfo,
+) -> Result<(), AccountDataError> {
+    check_access(user, user_id, Some(room_id))?;
+    if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+        return Err(AccountDataError::InvalidParam(format!("Tag names must be 1 to {} bytes", MAX_TAG_LENGTH)));
+    }
+
+    let mut content = load_tags(store, user_id, room_id).await?;
+    content.tags.insert(tag.to_string(), info);
+    store_tags(store, user_id, room_id, &content).await
+}
+
+/// Remove `tag` from `room_id`; removing an absent tag is not an error
+pub async fn delete_tag(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    user_id: &str,
+    room_id: &str,
+    tag: &str,
+) -> Result<(), AccountDataError> {
+    check_access(user, user_id, Some(room_id))?;
+
+    let mut content = load_tags(store, user_id, room_id).await?;
+    if content.tags.remove(tag).is_none() {
+        return Ok(());
+    }
+    store_tags(store, user_id, room_id, &content).await
+}
+
+/// Users may only touch their own account data, under a well-formed room ID
+fn check_access(user: &AuthenticatedUser, user_id: &str, room_id: Option<&str>) -> Result<(), AccountDataError> {
+    if user.user_id != user_id {
+        return Err(AccountDataError::Forbidden("Cannot access account data of another user".to_string()));
+    }
+    if let Some(room_id) = room_id {
+        if !room_id.starts_with('!') {
+            return Err(AccountDataError::InvalidParam(format!("{} is not a room ID", room_id)));
+        }
+    }
+    Ok(())
+}
+
+async fn load_tags(store: &dyn StateStore, user_id: &str, room_id: &str) -> Result<TagsContent, AccountDataError> {
+    Ok(store
+        .get_account_data(user_id, Some(room_id), TAG_ACCOUNT_DATA_TYPE)
+        .await?
+        .and_then(|content| serde_json::from_value(content).ok())
+        .unwrap_or_default())
+}
+
+async fn store_tags(
+    store: &dyn StateStore,
+    user_id: &str,
+    room_id: &str,
+    content: &TagsContent,
+) -> Result<(), AccountDataError> {
+    let content = serde_json::to_value(content).map_err(|e| AccountDataError::BadJson(e.to_string()))?;
+    store.set_account_data(user_id, Some(room_id), TAG_ACCOUNT_DATA_TYPE, content).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStateStore;
+    use crate::tests::test_user;
+
+    const ROOM_ID: &str = "!room:localhost";
+
+    #[tokio::test]
+    async fn test_tags_are_added_and_removed() {
+        let store = InMemoryStateStore::new();
+        let alice = test_user("@alice:localhost");
+
+        let favourite = TagInfo { order: Some(0.25), ..Default::default() };
+        set_tag(&store, &alice, &alice.user_id, ROOM_ID, "m.favourite", favourite).await.unwrap();
+        set_tag(&store, &alice, &alice.user_id, ROOM_ID, "u.support.open", TagInfo::default()).await.unwrap();
+
+        let tags = get_tags(&store, &alice, &alice.user_id, ROOM_ID).await.unwrap();
+        assert_eq!(tags.tags.len(), 2);
+        assert_eq!(tags.tags["m.favourite"].order, Some(0.25));
+
+        delete_tag(&store, &alice, &alice.user_id, ROOM_ID, "m.favourite").await.unwrap();
+        delete_tag(&store, &alice, &alice.user_id, ROOM_ID, "m.favourite").await.unwrap();
+        let stored = store.get_account_data(&alice.user_id, Some(ROOM_ID), TAG_ACCOUNT_DATA_TYPE).await.unwrap().unwrap();
+        assert_eq!(stored, serde_json::json!({ "tags": { "u.support.open": {} } }));
+    }
+
+    #[tokio::test]
+    async fn test_account_data_access_rules() {
+        let store = InMemoryStateStore::new();
+        let alice = test_user("@alice:localhost");
+        let content = serde_json::json!({ "theme": "dark" });
+
+        let result = set_account_data(&store, &alice, "@bob:localhost", None, "org.example.prefs", content.clone()).await;
+        assert!(matches!(result, Err(AccountDataError::Forbidden(_))));
+        let result = set_account_data(&store, &alice, &alice.user_id, Some(ROOM_ID), "m.fully_read", content.clone()).await;
+        assert!(matches!(result, Err(AccountDataError::ServerManaged(_))));
+        let result = set_account_data(&store, &alice, &alice.user_id, None, "org.example.prefs", serde_json::json!([1])).await;
+        assert!(matches!(result, Err(AccountDataError::BadJson(_))));
+        let result = get_account_data(&store, &alice, &alice.user_id, None, "org.example.prefs").await;
+        assert!(matches!(result, Err(AccountDataError::NotFound(_))));
+
+        set_account_data(&store, &alice, &alice.user_id, None, "org.example.prefs", content.clone()).await.unwrap();
+        assert_eq!(get_account_data(&store, &alice, &alice.user_id, None, "org.example.prefs").await.unwrap(), content);
+        let result = get_account_data(&store, &alice, &alice.user_id, Some(ROOM_ID), "org.example.prefs").await;
+        assert!(matches!(result, Err(AccountDataError::NotFound(_))));
+    }
+}
@@ -1,9 +1,9 @@
 // Authentication and OIDC Integration
 // Simplified version for Matrix chat system
 
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use std::collections::HashMap;
 
 /// OIDC configuration for Zitadel integration
 #[derive(Debug, Clone)]
@@ -14,6 +14,9 @@ pub struct OIDCConfig {
     pub redirect_url: String,
     pub scopes: Vec<String>,
     pub server_name: String,
+    /// Project roles by user ID, standing in for the project roles claim
+    /// until tokens are decoded
+    pub user_roles: HashMap<String, Vec<String>>,
 }
 
 /// Authenticated user information
@@ -24,6 +27,9 @@ pub struct AuthenticatedUser {
     pub device_id: String,
     pub subscription_active: bool,
     pub scopes: Vec<String>,
+    /// Project roles granted by the identity provider
+    #[serde(default)]
+    pub roles: Vec<String>,
 }
 
 /// OIDC handler for authentication
@@ -37,6 +43,11 @@ impl OIDCHandler {
         Ok(Self { config })
     }
 
+    /// Get OIDC configuration
+    pub fn config(&self) -> &OIDCConfig {
+        &self.config
+    }
+
     /// Validate access token and return user info
     pub async fn validate_token(&self, access_token: &str) -> Result<AuthenticatedUser, AuthError> {
         // In production, this would validate the JWT token with Zitadel
@@ -49,12 +60,16 @@ impl OIDCHandler {
             return Err(AuthError::InvalidToken("Invalid token format".to_string()));
         };
 
+        // In production, read from the token's project roles claim
+        let roles = self.config.user_roles.get(&user_id).cloned().unwrap_or_default();
+
         Ok(AuthenticatedUser {
             user_id,
             access_token: access_token.to_string(),
             device_id: "device_123".to_string(),
             subscription_active: true, // In production, check with billing system
             scopes: vec!["matrix:write".to_string(), "matrix:read".to_string()],
+            roles,
         })
     }
 
@@ -215,7 +230,7 @@ pub async fn auth_middleware(
 pub async fn extract_user(
     // TODO: Implement proper auth header extraction
     // auth_header: axum::extract::TypedHeader<axum::headers::Authorization<axum::headers::Bearer>>,
-    state: axum::extract::State<crate::MatrixServer>,
+    _state: axum::extract::State<crate::MatrixServer>,
 ) -> Result<AuthenticatedUser, AuthError> {
     // TODO: Extract token from authorization header when TypedHeader is available
     // For now, return a mock user for testing
@@ -225,6 +240,7 @@ pub async fn extract_user(
         device_id: "mock_device".to_string(),
         subscription_active: true,
         scopes: vec!["openid".to_string(), "profile".to_string()],
+        roles: vec![],
     })
 }
 
@@ -232,31 +248,45 @@ pub async fn extract_user(
 mod tests {
     use super::*;
     use crate::MatrixServer;
-    use std::sync::Arc;
     use axum::extract::State;
 
     // Mock MatrixServer for testing
     async fn create_mock_server() -> MatrixServer {
-        MatrixServer {
-            auth_handler: Arc::new(OIDCHandler::new(OIDCConfig {
+        MatrixServer::new(crate::ServerConfig {
+            server_name: "test.local".to_string(),
+            oidc_config: OIDCConfig {
                 issuer_url: "https://test-issuer.com".to_string(),
                 client_id: "test-client".to_string(),
                 client_secret: "test-secret".to_string(),
                 redirect_url: "http://localhost:8000/callback".to_string(),
                 scopes: vec!["openid".to_string(), "profile".to_string()],
                 server_name: "test.local".to_string(),
-            }).await.unwrap()),
-            room_handler: Arc::new(crate::RoomHandler::new(Arc::new(crate::state::InMemoryStateStore::new()))),
-            federation_client: Arc::new(crate::FederationClient::new(crate::federation::FederationConfig {
+                user_roles: HashMap::new(),
+            },
+            federation_config: crate::federation::FederationConfig {
                 server_name: "test.local".to_string(),
                 signing_key: "test-key".to_string(),
                 verify_signatures: false,
                 federation_whitelist: None,
                 federation_blacklist: None,
-            }).await.unwrap()),
-            state_store: Arc::new(crate::state::InMemoryStateStore::new()),
-            server_name: "test.local".to_string(),
-        }
+                server_addresses: std::collections::HashMap::new(),
+                ip_range_blacklist: crate::url_preview::default_ip_range_blacklist(),
+                ip_range_whitelist: Vec::new(),
+            },
+            redaction_retention: None,
+            directory_publish_role: None,
+            presence_enabled: true,
+            media_config: crate::media::MediaConfig {
+                media_path: std::env::temp_dir().join(format!("matrix-media-{}", uuid::Uuid::new_v4())),
+                max_upload_size: 1024 * 1024,
+                user_quota: None,
+                max_image_pixels: crate::media::DEFAULT_MAX_IMAGE_PIXELS,
+                remote: crate::media::RemoteMediaConfig::default(),
+            },
+            url_preview_config: crate::url_preview::UrlPreviewConfig::default(),
+            pusher_config: crate::pushers::PusherConfig::default(),
+            admin_role: None,
+        }).await.unwrap()
     }
 
     #[tokio::test]
@@ -268,6 +298,7 @@ mod tests {
             redirect_url: "http://localhost:8000/callback".to_string(),
             scopes: vec!["openid".to_string(), "profile".to_string()],
             server_name: "test.local".to_string(),
+            user_roles: HashMap::new(),
         };
 
         let handler = OIDCHandler::new(config).await;
@@ -283,6 +314,7 @@ mod tests {
             redirect_url: "http://localhost:8000/callback".to_string(),
             scopes: vec!["openid".to_string(), "profile".to_string()],
             server_name: "test.local".to_string(),
+            user_roles: HashMap::new(),
         };
 
         let handler = OIDCHandler::new(config).await.unwrap();
@@ -1,9 +1,42 @@
 // Client-Server API Handler
 // Simplified version for Matrix chat system
 
+use std::time::Duration;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use std::collections::HashMap;
+
+use crate::account_data::{self, TagInfo, TagsContent};
+use crate::auth::AuthenticatedUser;
+use crate::direct::DirectRoomResponse;
+use crate::federation::Edu;
+use crate::directory::{PublicRoomsFilter, PublicRoomsRequest, PublicRoomsResponse, RoomVisibilityBody};
+use crate::events::{parse_room_alias, EventType, MatrixEvent, MembershipState, ReactionContent, RoomMessageContent};
+use crate::media::{
+    CreateMediaResponse, DownloadQuery, MediaContent, MediaError, PurgeMediaCacheQuery, ThumbnailQuery, UploadQuery, UploadResponse,
+};
+use crate::notifications::{self, GetNotificationsRequest, NotificationsResponse};
+use crate::presence::{self, PresenceState, PresenceStatus, SetPresenceRequest};
+use crate::push_rules::{self, PushRule, PushRuleBody, PushRuleError, PushRulePosition, PushRulesResponse, RuleKind};
+use crate::pushers::{self, PushersResponse, SetPusherRequest};
+use crate::receipts::{self, receipt_edu_content, ReadMarkersRequest, ReceiptType, RECEIPT_EDU_TYPE};
+use crate::relations::{GetRelationsRequest, GetThreadsRequest, RelationsResponse, ThreadsInclude, ThreadsResponse};
+use crate::room::{
+    CreateRoomResponse, GetContextRequest, GetContextResponse, GetMembersRequest, GetMessagesRequest,
+    GetMessagesResponse, JoinRoomRequest, JoinRoomResponse, JoinedMembersResponse, KnockRoomRequest,
+    KnockRoomResponse, LeaveRoomRequest, MembersResponse, MembershipChangeRequest, RedactEventRequest,
+    ResolveAliasResponse, RoomConfig, RoomError, RoomEventFilter, SendMessageRequest, SendReactionRequest,
+    SendStateEventRequest, SendStateEventResponse,
+};
+use crate::search::{self, SearchQuery, SearchRequest, SearchResponse};
+use crate::spaces::{HierarchyRequest, HierarchyResponse};
+use crate::state::{Direction, Receipt};
+use crate::sync::{SyncRequest, SyncResponse};
+use crate::typing::{TypingEdu, DEFAULT_TYPING_TIMEOUT, MAX_TYPING_TIMEOUT, TYPING_EDU_TYPE};
+use crate::url_preview::PreviewUrlQuery;
+use crate::{MatrixServer, MatrixServerError};
 
 /// Client-server API configuration
 #[derive(Debug, Clone)]
@@ -25,7 +58,7 @@ impl ClientServerAPI {
     }
 
     /// Handle user registration
-    pub async fn register_user(&self, username: &str, password: &str) -> Result<RegisterResponse, ClientError> {
+    pub async fn register_user(&self, username: &str, _password: &str) -> Result<RegisterResponse, ClientError> {
         // In production, this would:
         // 1. Validate username/password
         // 2. Check if user already exists
@@ -46,7 +79,7 @@ impl ClientServerAPI {
     }
 
     /// Handle user login
-    pub async fn login_user(&self, username: &str, password: &str) -> Result<LoginResponse, ClientError> {
+    pub async fn login_user(&self, username: &str, _password: &str) -> Result<LoginResponse, ClientError> {
         // In production, this would:
         // 1. Validate credentials
         // 2. Check if account is active
@@ -66,7 +99,7 @@ impl ClientServerAPI {
     }
 
     /// Get user profile
-    pub async fn get_profile(&self, user_id: &str) -> Result<UserProfile, ClientError> {
+    pub async fn get_profile(&self, _user_id: &str) -> Result<UserProfile, ClientError> {
         // In production, this would fetch from database
         Ok(UserProfile {
             displayname: Some("Test User".to_string()),
@@ -75,7 +108,7 @@ impl ClientServerAPI {
     }
 
     /// Update user profile
-    pub async fn update_profile(&self, user_id: &str, profile: UserProfile) -> Result<(), ClientError> {
+    pub async fn update_profile(&self, user_id: &str, _profile: UserProfile) -> Result<(), ClientError> {
         // In production, this would update database
         tracing::info!("Updated profile for user {}", user_id);
         Ok(())
@@ -213,79 +246,391 @@ pub async fn get_events() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn get_room_state() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "chunk": []
-    }))
+pub async fn get_room_state(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<MatrixEvent>>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    Ok(Json(server.room_handler.get_state(&user, &room_id).await?))
 }
 
-pub async fn get_room_state_by_type() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "type": "m.room.name",
-        "state_key": "",
-        "content": {
-            "name": "Test Room"
-        }
-    }))
+/// Path of /state/{eventType}/{stateKey}; the state key may be omitted when empty
+#[derive(Debug, Deserialize)]
+pub struct StateEventPath {
+    pub room_id: String,
+    pub event_type: String,
+    #[serde(default)]
+    pub state_key: String,
 }
 
-pub async fn get_room_members() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "chunk": []
-    }))
+/// How a single state event is returned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateEventFormat {
+    #[default]
+    Content,
+    Event,
 }
 
-pub async fn get_room_members_by_id() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "type": "m.room.member",
-        "state_key": "@test:localhost",
-        "content": {
-            "membership": "join"
-        }
-    }))
+#[derive(Debug, Deserialize)]
+pub struct StateEventQuery {
+    #[serde(default)]
+    pub format: StateEventFormat,
 }
 
-pub async fn get_room_id_by_alias() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "room_id": "!test:localhost",
-        "servers": ["localhost"]
-    }))
+pub async fn get_room_state_event(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<StateEventPath>,
+    Query(query): Query<StateEventQuery>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let event = server.room_handler
+        .get_state_event(&user, &path.room_id, &path.event_type, &path.state_key)
+        .await?;
+    let body = match query.format {
+        StateEventFormat::Content => serde_json::to_value(&event.content),
+        StateEventFormat::Event => serde_json::to_value(&event),
+    }.map_err(|e| MatrixServerError::Internal(e.to_string()))?;
+
+    Ok(Json(body))
 }
 
-pub async fn get_room_aliases() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "aliases": []
-    }))
+pub async fn put_room_state_event(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<StateEventPath>,
+    Json(content): Json<serde_json::Value>,
+) -> Result<Json<SendStateEventResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let response = server.room_handler.send_state_event(&user, SendStateEventRequest {
+        room_id: path.room_id,
+        event_type: path.event_type,
+        state_key: path.state_key,
+        content,
+    }).await?;
+
+    if let Some(event) = server.state_store.get_event(&response.event_id).await? {
+        server.federate_event(&event).await?;
+    }
+
+    Ok(Json(response))
 }
 
-pub async fn get_public_rooms() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "chunk": [],
-        "next_batch": null,
-        "total_room_count_estimate": 0
-    }))
+/// Query parameters of /members
+#[derive(Debug, Deserialize)]
+pub struct MembersQuery {
+    pub at: Option<String>,
+    pub membership: Option<MembershipState>,
+    pub not_membership: Option<MembershipState>,
 }
 
-pub async fn get_room_visibility() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "visibility": "private"
-    }))
+pub async fn get_room_members(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Query(query): Query<MembersQuery>,
+) -> Result<Json<MembersResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let response = server.room_handler.get_members(&user, GetMembersRequest {
+        room_id,
+        at: query.at,
+        membership: query.membership,
+        not_membership: query.not_membership,
+    }).await?;
+
+    Ok(Json(response))
 }
 
-pub async fn get_room_state_event() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "type": "m.room.name",
-        "state_key": "",
-        "content": {
-            "name": "Test Room"
-        }
-    }))
+pub async fn get_joined_members(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+) -> Result<Json<JoinedMembersResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    Ok(Json(server.room_handler.get_joined_members(&user, &room_id).await?))
 }
 
-pub async fn put_room_state_event() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "event_id": "$1234567890"
-    }))
+#[derive(Debug, Deserialize)]
+pub struct TypingBody {
+    pub typing: bool,
+    /// How long to show the user as typing, in milliseconds
+    pub timeout: Option<u64>,
+}
+
+/// Start or stop showing the caller as typing, and tell the room's other servers
+pub async fn set_typing(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, user_id)): Path<(String, String)>,
+    Json(body): Json<TypingBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    if user_id != user.user_id {
+        return Err(RoomError::InsufficientPermissions("Cannot set typing state of another user".to_string()).into());
+    }
+    let room_state = server.state_store
+        .get_room(&room_id)
+        .await?
+        .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+    if !room_state.is_member(&user.user_id) {
+        return Err(RoomError::UserNotInRoom(user.user_id).into());
+    }
+
+    let timeout = body.timeout
+        .map_or(DEFAULT_TYPING_TIMEOUT, Duration::from_millis)
+        .min(MAX_TYPING_TIMEOUT);
+    server.typing_handler.set_typing(&room_id, &user.user_id, body.typing, timeout).await;
+
+    let edu = Edu {
+        edu_type: TYPING_EDU_TYPE.to_string(),
+        content: serde_json::json!(TypingEdu { room_id: room_id.clone(), user_id: user.user_id, typing: body.typing }),
+    };
+    server.federate_edu(&room_id, &edu).await?;
+
+    Ok(Json(serde_json::json!({})))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReceiptBody {
+    #[serde(default)]
+    pub thread_id: Option<String>,
+}
+
+pub async fn post_receipt(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, receipt_type, event_id)): Path<(String, String, String)>,
+    Json(body): Json<ReceiptBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let receipt_type = ReceiptType::parse(&receipt_type)?;
+    let receipt = receipts::send_receipt(&*server.state_store, &user, &room_id, receipt_type, &event_id, body.thread_id).await?;
+    if let Some(receipt) = receipt.filter(|_| receipt_type == ReceiptType::Read) {
+        federate_receipts(&server, &[receipt]).await?;
+    }
+
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn set_read_markers(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(request): Json<ReadMarkersRequest>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let public = receipts::set_read_markers(&*server.state_store, &user, &room_id, request).await?;
+    federate_receipts(&server, &public).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+/// Announce public receipts to the other servers in their rooms
+async fn federate_receipts(server: &MatrixServer, receipts: &[Receipt]) -> Result<(), MatrixServerError> {
+    for receipt in receipts {
+        let edu = Edu {
+            edu_type: RECEIPT_EDU_TYPE.to_string(),
+            content: serde_json::json!(receipt_edu_content(receipt)),
+        };
+        server.federate_edu(&receipt.room_id, &edu).await?;
+    }
+    Ok(())
+}
+
+pub async fn get_presence(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<PresenceStatus>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    if !presence::can_see_presence(&*server.state_store, &user.user_id, &user_id).await? {
+        return Err(RoomError::InsufficientPermissions("You do not share a room with this user".to_string()).into());
+    }
+    Ok(Json(server.presence_handler.get_presence(&user_id).await))
+}
+
+pub async fn set_presence(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(request): Json<SetPresenceRequest>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    if user_id != user.user_id {
+        return Err(RoomError::InsufficientPermissions("Cannot set presence of another user".to_string()).into());
+    }
+
+    if server.presence_handler.set_presence(&user.user_id, request).await {
+        server.federate_presence(&user.user_id).await?;
+    }
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn get_room_id_by_alias(
+    State(server): State<MatrixServer>,
+    Path(room_alias): Path<String>,
+) -> Result<Json<ResolveAliasResponse>, MatrixServerError> {
+    Ok(Json(resolve_alias(&server, &room_alias).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAliasBody {
+    pub room_id: String,
+}
+
+pub async fn create_room_alias(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_alias): Path<String>,
+    Json(body): Json<CreateAliasBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    server.room_handler.create_alias(&user, &room_alias, &body.room_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn delete_room_alias(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_alias): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    server.room_handler.delete_alias(&user, &room_alias).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn get_room_aliases(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let aliases = server.room_handler.get_aliases(&user, &room_id).await?;
+    Ok(Json(serde_json::json!({ "aliases": aliases })))
+}
+
+/// Resolve an alias locally or, for other servers' aliases, over federation
+async fn resolve_alias(server: &MatrixServer, room_alias: &str) -> Result<ResolveAliasResponse, MatrixServerError> {
+    let (_, alias_server) = parse_room_alias(room_alias)
+        .ok_or_else(|| RoomError::InvalidAlias(room_alias.to_string()))?;
+    if alias_server == server.server_name {
+        return Ok(server.room_handler.resolve_alias(room_alias).await?);
+    }
+
+    server.federation_client
+        .query_directory(alias_server, room_alias)
+        .await?
+        .ok_or_else(|| RoomError::AliasNotFound(room_alias.to_string()).into())
+}
+
+/// Accept either a room ID or an alias wherever the spec allows `roomIdOrAlias`
+async fn resolve_room_id(server: &MatrixServer, room_id_or_alias: String) -> Result<String, MatrixServerError> {
+    if room_id_or_alias.starts_with('#') {
+        Ok(resolve_alias(server, &room_id_or_alias).await?.room_id)
+    } else {
+        Ok(room_id_or_alias)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicRoomsQuery {
+    pub limit: Option<u32>,
+    pub since: Option<String>,
+    pub server: Option<String>,
+}
+
+pub async fn get_public_rooms(
+    State(server): State<MatrixServer>,
+    Query(query): Query<PublicRoomsQuery>,
+) -> Result<Json<PublicRoomsResponse>, MatrixServerError> {
+    let request = PublicRoomsRequest {
+        limit: query.limit,
+        since: query.since,
+        filter: PublicRoomsFilter::default(),
+    };
+    Ok(Json(public_rooms(&server, query.server.as_deref(), request).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicRoomsServerQuery {
+    pub server: Option<String>,
+}
+
+pub async fn search_public_rooms(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<PublicRoomsServerQuery>,
+    Json(request): Json<PublicRoomsRequest>,
+) -> Result<Json<PublicRoomsResponse>, MatrixServerError> {
+    authenticate(&server, &headers).await?;
+    Ok(Json(public_rooms(&server, query.server.as_deref(), request).await?))
+}
+
+/// List our own directory, or another server's over federation
+async fn public_rooms(
+    server: &MatrixServer,
+    target_server: Option<&str>,
+    request: PublicRoomsRequest,
+) -> Result<PublicRoomsResponse, MatrixServerError> {
+    match target_server.filter(|target| *target != server.server_name) {
+        Some(target) => Ok(server.federation_client.get_public_rooms(target, &request).await?),
+        None => Ok(server.room_handler.get_public_rooms(request, false).await?),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HierarchyQuery {
+    pub from: Option<String>,
+    pub limit: Option<u32>,
+    pub max_depth: Option<u32>,
+    #[serde(default)]
+    pub suggested_only: bool,
+}
+
+pub async fn get_hierarchy(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Query(query): Query<HierarchyQuery>,
+) -> Result<Json<HierarchyResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let response = crate::spaces::get_hierarchy(
+        server.state_store.as_ref(),
+        &server.federation_client,
+        &user.user_id,
+        HierarchyRequest {
+            room_id,
+            from: query.from,
+            limit: query.limit,
+            max_depth: query.max_depth,
+            suggested_only: query.suggested_only,
+        },
+    ).await?;
+    Ok(Json(response))
+}
+
+pub async fn get_room_visibility(
+    State(server): State<MatrixServer>,
+    Path(room_id): Path<String>,
+) -> Result<Json<RoomVisibilityBody>, MatrixServerError> {
+    let visibility = server.room_handler.get_room_visibility(&room_id).await?;
+    Ok(Json(RoomVisibilityBody { visibility }))
+}
+
+pub async fn set_room_visibility(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<RoomVisibilityBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    server.room_handler.set_room_visibility(&user, &room_id, body.visibility).await?;
+    Ok(Json(serde_json::json!({})))
 }
 
 pub async fn get_room_messages() -> axum::Json<serde_json::Value> {
@@ -296,16 +641,83 @@ pub async fn get_room_messages() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn send_message() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "event_id": "$1234567890"
-    }))
+pub async fn send_message(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
+    Json(content): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    // Retried transactions return the event created by the first attempt
+    let endpoint = format!("send/{}/{}", room_id, event_type);
+    let event_id = server.room_handler.transaction(&user, &endpoint, &txn_id, async {
+        let invalid_content = |e: serde_json::Error| RoomError::InvalidRoomConfig(format!("Invalid content: {}", e));
+        let response = match EventType::from(event_type.as_str()) {
+            EventType::RoomMessage => {
+                let content: RoomMessageContent = serde_json::from_value(content).map_err(invalid_content)?;
+                server.room_handler.send_message(&user, SendMessageRequest {
+                    room_id,
+                    msgtype: content.msgtype,
+                    body: content.body,
+                    formatted_body: content.formatted_body,
+                    format: content.format,
+                    relates_to: content.relates_to.map(serde_json::to_value).transpose()?,
+                    new_content: content.new_content,
+                    mentions: content.mentions,
+                    url: content.url,
+                    info: content.info,
+                    filename: content.filename,
+                }).await?
+            }
+            EventType::Reaction => {
+                let content: ReactionContent = serde_json::from_value(content).map_err(invalid_content)?;
+                server.room_handler.send_reaction(&user, SendReactionRequest {
+                    room_id,
+                    relates_to: content.relates_to,
+                }).await?
+            }
+            _ => return Err(RoomError::InvalidRoomConfig(format!("Unsupported event type: {}", event_type)).into()),
+        };
+
+        if let Some(event) = server.state_store.get_event(&response.event_id).await? {
+            server.federate_event(&event).await?;
+        }
+        Ok::<_, MatrixServerError>(response.event_id)
+    }).await?;
+
+    Ok(Json(serde_json::json!({ "event_id": event_id })))
 }
 
-pub async fn redact_event() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "event_id": "$1234567890"
-    }))
+/// Body of a redaction request
+#[derive(Debug, Default, Deserialize)]
+pub struct RedactEventBody {
+    pub reason: Option<String>,
+}
+
+pub async fn redact_event(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, event_id, txn_id)): Path<(String, String, String)>,
+    Json(body): Json<RedactEventBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let endpoint = format!("redact/{}/{}", room_id, event_id);
+    let redaction_id = server.room_handler.transaction(&user, &endpoint, &txn_id, async {
+        let response = server.room_handler.redact_event(&user, RedactEventRequest {
+            room_id,
+            event_id,
+            reason: body.reason,
+        }).await?;
+
+        if let Some(redaction) = server.state_store.get_event(&response.event_id).await? {
+            server.federate_event(&redaction).await?;
+        }
+        Ok::<_, MatrixServerError>(response.event_id)
+    }).await?;
+
+    Ok(Json(serde_json::json!({ "event_id": redaction_id })))
 }
 
 pub async fn get_room_event() -> axum::Json<serde_json::Value> {
@@ -328,28 +740,105 @@ pub async fn get_room_event_by_id() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn get_room_event_context() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "start": "s1234567890",
-        "events_before": [],
-        "event": {
-            "type": "m.room.message",
-            "content": {
-                "msgtype": "m.text",
-                "body": "Hello, World!"
-            }
-        },
-        "events_after": [],
-        "end": "s1234567890"
-    }))
+/// Query parameters of /context
+#[derive(Debug, Deserialize)]
+pub struct ContextQuery {
+    pub limit: Option<u32>,
+    /// JSON-encoded `RoomEventFilter`
+    pub filter: Option<String>,
 }
 
-pub async fn get_room_event_relations() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "chunk": [],
-        "next_batch": null,
-        "prev_batch": null
-    }))
+pub async fn get_room_event_context(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, event_id)): Path<(String, String)>,
+    Query(query): Query<ContextQuery>,
+) -> Result<Json<GetContextResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let filter: RoomEventFilter = query.filter
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| RoomError::InvalidRoomConfig(format!("Invalid filter: {}", e)))?
+        .unwrap_or_default();
+
+    let response = server.room_handler.get_context(&user, GetContextRequest {
+        room_id,
+        event_id,
+        limit: query.limit,
+        filter,
+    }).await?;
+
+    Ok(Json(response))
+}
+
+/// Path of /relations, optionally narrowed by relation and event type
+#[derive(Debug, Deserialize)]
+pub struct RelationsPath {
+    pub room_id: String,
+    pub event_id: String,
+    pub rel_type: Option<String>,
+    pub event_type: Option<String>,
+}
+
+/// Query parameters of /relations
+#[derive(Debug, Deserialize)]
+pub struct RelationsQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    #[serde(default)]
+    pub dir: Direction,
+    pub limit: Option<u32>,
+}
+
+pub async fn get_room_event_relations(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<RelationsPath>,
+    Query(query): Query<RelationsQuery>,
+) -> Result<Json<RelationsResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let response = server.room_handler.get_relations(&user, GetRelationsRequest {
+        room_id: path.room_id,
+        event_id: path.event_id,
+        rel_type: path.rel_type,
+        event_type: path.event_type,
+        from: query.from,
+        to: query.to,
+        limit: query.limit,
+        dir: query.dir,
+    }).await?;
+
+    Ok(Json(response))
+}
+
+/// Query parameters of /threads
+#[derive(Debug, Deserialize)]
+pub struct ThreadsQuery {
+    #[serde(default)]
+    pub include: ThreadsInclude,
+    pub from: Option<String>,
+    pub limit: Option<u32>,
+}
+
+pub async fn get_threads(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Query(query): Query<ThreadsQuery>,
+) -> Result<Json<ThreadsResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let response = server.room_handler.get_threads(&user, GetThreadsRequest {
+        room_id,
+        include: query.include,
+        from: query.from,
+        limit: query.limit,
+    }).await?;
+
+    Ok(Json(response))
 }
 
 // Missing functions that are referenced in lib.rs
@@ -367,35 +856,601 @@ pub async fn logout() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn get_messages() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "chunk": [],
-        "start": "s1234567890",
-        "end": "s1234567890"
-    }))
+/// Query parameters of /messages
+#[derive(Debug, Deserialize)]
+pub struct MessagesQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    #[serde(default)]
+    pub dir: Direction,
+    pub limit: Option<u32>,
 }
 
-pub async fn join_room() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "room_id": "!test:localhost"
-    }))
+pub async fn get_messages(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<GetMessagesResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let response = server.room_handler.get_messages(&user, GetMessagesRequest {
+        room_id,
+        from: query.from,
+        to: query.to,
+        limit: query.limit,
+        dir: query.dir,
+    }).await?;
+
+    Ok(Json(response))
 }
 
-pub async fn leave_room() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "success": true
-    }))
+pub async fn create_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Json(config): Json<RoomConfig>,
+) -> Result<Json<CreateRoomResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    let invite = config.invite.clone();
+    let response = server.room_handler.create_room(&user, config).await?;
+    for invitee in &invite {
+        federate_membership(&server, &response.room_id, invitee).await?;
+    }
+
+    Ok(Json(response))
 }
 
-pub async fn sync() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "next_batch": "s1234567890",
-        "rooms": {
-            "join": {},
-            "invite": {},
-            "leave": {}
+/// Find the caller's DM room with another user, creating it if needed
+pub async fn get_or_create_direct_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((user_id, other_user_id)): Path<(String, String)>,
+) -> Result<Json<DirectRoomResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    if user_id != user.user_id {
+        return Err(RoomError::InsufficientPermissions("Cannot manage direct chats of another user".to_string()).into());
+    }
+
+    let response = server.room_handler.get_or_create_direct_room(&user, &other_user_id).await?;
+    if response.created {
+        federate_membership(&server, &response.room_id, &other_user_id).await?;
+    }
+
+    Ok(Json(response))
+}
+
+/// Body of membership requests that only carry a reason
+#[derive(Debug, Default, Deserialize)]
+pub struct MembershipBody {
+    pub reason: Option<String>,
+}
+
+pub async fn join_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id_or_alias): Path<String>,
+    Json(body): Json<MembershipBody>,
+) -> Result<Json<JoinRoomResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let room_id = resolve_room_id(&server, room_id_or_alias).await?;
+
+    let response = server.room_handler.join_room(&user, JoinRoomRequest {
+        room_id,
+        reason: body.reason,
+    }).await?;
+    federate_membership(&server, &response.room_id, &user.user_id).await?;
+
+    Ok(Json(response))
+}
+
+pub async fn knock_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id_or_alias): Path<String>,
+    Json(body): Json<MembershipBody>,
+) -> Result<Json<KnockRoomResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let room_id = resolve_room_id(&server, room_id_or_alias).await?;
+
+    let response = server.room_handler.knock_room(&user, KnockRoomRequest {
+        room_id,
+        reason: body.reason,
+    }).await?;
+    federate_membership(&server, &response.room_id, &user.user_id).await?;
+
+    Ok(Json(response))
+}
+
+pub async fn leave_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<MembershipBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+
+    server.room_handler.leave_room(&user, LeaveRoomRequest {
+        room_id: room_id.clone(),
+        reason: body.reason,
+    }).await?;
+    federate_membership(&server, &room_id, &user.user_id).await?;
+
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn forget_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    server.room_handler.forget_room(&user, &room_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+/// Body of /invite, /kick, /ban and /unban
+#[derive(Debug, Deserialize)]
+pub struct TargetUserBody {
+    pub user_id: String,
+    pub reason: Option<String>,
+}
+
+pub async fn invite_user(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<TargetUserBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let request = membership_change(room_id, body);
+    server.room_handler.invite_user(&user, request.clone()).await?;
+    federate_membership(&server, &request.room_id, &request.user_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn kick_user(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<TargetUserBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let request = membership_change(room_id, body);
+    server.room_handler.kick_user(&user, request.clone()).await?;
+    federate_membership(&server, &request.room_id, &request.user_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn ban_user(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<TargetUserBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let request = membership_change(room_id, body);
+    server.room_handler.ban_user(&user, request.clone()).await?;
+    federate_membership(&server, &request.room_id, &request.user_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn unban_user(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(body): Json<TargetUserBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let request = membership_change(room_id, body);
+    server.room_handler.unban_user(&user, request.clone()).await?;
+    federate_membership(&server, &request.room_id, &request.user_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+fn membership_change(room_id: String, body: TargetUserBody) -> MembershipChangeRequest {
+    MembershipChangeRequest {
+        room_id,
+        user_id: body.user_id,
+        reason: body.reason,
+    }
+}
+
+/// Send the current `m.room.member` event of `user_id` to the other servers in the room
+async fn federate_membership(server: &MatrixServer, room_id: &str, user_id: &str) -> Result<(), MatrixServerError> {
+    let member_event = server.state_store
+        .get_room(room_id)
+        .await?
+        .and_then(|room_state| room_state.get_state_event(&EventType::RoomMember, user_id).cloned());
+    if let Some(event) = member_event {
+        server.federate_event(&event).await?;
+    }
+    Ok(())
+}
+
+pub async fn sync(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(request): Query<SyncRequest>,
+) -> Result<Json<SyncResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let set_presence = request.set_presence.unwrap_or(PresenceState::Online);
+    if server.presence_handler.sync_activity(&user.user_id, set_presence).await {
+        server.federate_presence(&user.user_id).await?;
+    }
+    Ok(Json(server.sync_handler.sync(&user, request).await?))
+}
+
+/// Path of global and per-room account data
+#[derive(Debug, Deserialize)]
+pub struct AccountDataPath {
+    pub user_id: String,
+    pub room_id: Option<String>,
+    #[serde(rename = "type")]
+    pub data_type: String,
+}
+
+pub async fn get_account_data(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<AccountDataPath>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let content = account_data::get_account_data(
+        server.state_store.as_ref(),
+        &user,
+        &path.user_id,
+        path.room_id.as_deref(),
+        &path.data_type,
+    ).await?;
+    Ok(Json(content))
+}
+
+pub async fn set_account_data(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<AccountDataPath>,
+    Json(content): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    account_data::set_account_data(
+        server.state_store.as_ref(),
+        &user,
+        &path.user_id,
+        path.room_id.as_deref(),
+        &path.data_type,
+        content,
+    ).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn get_room_tags(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((user_id, room_id)): Path<(String, String)>,
+) -> Result<Json<TagsContent>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    Ok(Json(account_data::get_tags(server.state_store.as_ref(), &user, &user_id, &room_id).await?))
+}
+
+pub async fn put_room_tag(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((user_id, room_id, tag)): Path<(String, String, String)>,
+    Json(info): Json<TagInfo>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    account_data::set_tag(server.state_store.as_ref(), &user, &user_id, &room_id, &tag, info).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn delete_room_tag(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((user_id, room_id, tag)): Path<(String, String, String)>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    account_data::delete_tag(server.state_store.as_ref(), &user, &user_id, &room_id, &tag).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+/// Path of a single push rule
+#[derive(Debug, Deserialize)]
+pub struct PushRulePath {
+    pub scope: String,
+    pub kind: String,
+    pub rule_id: String,
+}
+
+impl PushRulePath {
+    fn kind(&self) -> Result<RuleKind, PushRuleError> {
+        if self.scope != push_rules::GLOBAL_SCOPE {
+            return Err(PushRuleError::InvalidParam(format!("Unknown push rule scope {}", self.scope)));
         }
-    }))
+        RuleKind::parse(&self.kind)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushRuleEnabledBody {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushRuleActionsBody {
+    pub actions: Vec<serde_json::Value>,
+}
+
+pub async fn get_push_rules(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+) -> Result<Json<PushRulesResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let global = push_rules::get_push_rules(server.state_store.as_ref(), &user.user_id).await?;
+    Ok(Json(PushRulesResponse { global }))
+}
+
+pub async fn get_push_rule(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+) -> Result<Json<PushRule>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let rule = push_rules::get_push_rule(server.state_store.as_ref(), &user.user_id, path.kind()?, &path.rule_id).await?;
+    Ok(Json(rule))
+}
+
+pub async fn set_push_rule(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+    Query(position): Query<PushRulePosition>,
+    Json(body): Json<PushRuleBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    push_rules::set_push_rule(server.state_store.as_ref(), &user.user_id, path.kind()?, &path.rule_id, body, position).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn delete_push_rule(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    push_rules::delete_push_rule(server.state_store.as_ref(), &user.user_id, path.kind()?, &path.rule_id).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn get_push_rule_enabled(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+) -> Result<Json<PushRuleEnabledBody>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let rule = push_rules::get_push_rule(server.state_store.as_ref(), &user.user_id, path.kind()?, &path.rule_id).await?;
+    Ok(Json(PushRuleEnabledBody { enabled: rule.enabled }))
+}
+
+pub async fn set_push_rule_enabled(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+    Json(body): Json<PushRuleEnabledBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    push_rules::set_push_rule_enabled(
+        server.state_store.as_ref(),
+        &user.user_id,
+        path.kind()?,
+        &path.rule_id,
+        body.enabled,
+    ).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn get_push_rule_actions(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+) -> Result<Json<PushRuleActionsBody>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let rule = push_rules::get_push_rule(server.state_store.as_ref(), &user.user_id, path.kind()?, &path.rule_id).await?;
+    Ok(Json(PushRuleActionsBody { actions: rule.actions }))
+}
+
+pub async fn set_push_rule_actions(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<PushRulePath>,
+    Json(body): Json<PushRuleActionsBody>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    push_rules::set_push_rule_actions(
+        server.state_store.as_ref(),
+        &user.user_id,
+        path.kind()?,
+        &path.rule_id,
+        body.actions,
+    ).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn search(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<SearchQuery>,
+    Json(request): Json<SearchRequest>,
+) -> Result<Json<SearchResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    Ok(Json(search::search(server.state_store.as_ref(), &user.user_id, request, query).await?))
+}
+
+pub async fn get_notifications(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(request): Query<GetNotificationsRequest>,
+) -> Result<Json<NotificationsResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let response = notifications::get_notifications(server.state_store.as_ref(), &user.user_id, request).await?;
+    Ok(Json(response))
+}
+
+pub async fn get_pushers(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+) -> Result<Json<PushersResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let pushers = server.state_store.get_pushers(Some(&user.user_id)).await?;
+    Ok(Json(PushersResponse { pushers }))
+}
+
+pub async fn set_pusher(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Json(request): Json<SetPusherRequest>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    pushers::set_pusher(server.state_store.as_ref(), server.push_sender.config(), &user.user_id, request).await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn get_media_config(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    authenticate(&server, &headers).await?;
+    Ok(Json(serde_json::json!({ "m.upload.size": server.media_repository.max_upload_size() })))
+}
+
+pub async fn upload_media(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<UploadQuery>,
+    body: axum::body::Body,
+) -> Result<Json<UploadResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let content = read_upload(&server, body).await?;
+    let response = server.media_repository
+        .upload(&user.user_id, upload_content_type(&headers), query.filename, &content)
+        .await?;
+    Ok(Json(response))
+}
+
+pub async fn create_media(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+) -> Result<Json<CreateMediaResponse>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    Ok(Json(server.media_repository.create(&user.user_id).await?))
+}
+
+pub async fn upload_media_to(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((server_name, media_id)): Path<(String, String)>,
+    Query(query): Query<UploadQuery>,
+    body: axum::body::Body,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    let content = read_upload(&server, body).await?;
+    server.media_repository
+        .upload_to(&user.user_id, &server_name, &media_id, upload_content_type(&headers), query.filename, &content)
+        .await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+pub async fn download_media(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(path): Path<Vec<String>>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<axum::response::Response, MatrixServerError> {
+    authenticate(&server, &headers).await?;
+    let [server_name, media_id, file_name @ ..] = path.as_slice() else {
+        return Err(MediaError::InvalidParam("Expected a server name and media ID".to_string()).into());
+    };
+    let mut content = server.media_repository
+        .download(server_name, media_id, query.timeout_ms.map(Duration::from_millis))
+        .await?;
+    if let Some(file_name) = file_name.first() {
+        content.filename = Some(file_name.clone());
+    }
+    Ok(media_response(content))
+}
+
+pub async fn get_media_thumbnail(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((server_name, media_id)): Path<(String, String)>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<axum::response::Response, MatrixServerError> {
+    authenticate(&server, &headers).await?;
+    let content = server.media_repository.thumbnail(&server_name, &media_id, &query).await?;
+    Ok(media_response(content))
+}
+
+pub async fn preview_url(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<PreviewUrlQuery>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    Ok(Json(server.url_previewer.preview(&user.user_id, &query.url, query.ts).await?))
+}
+
+pub async fn purge_media_cache(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<PurgeMediaCacheQuery>,
+) -> Result<Json<serde_json::Value>, MatrixServerError> {
+    let user = authenticate(&server, &headers).await?;
+    match &server.admin_role {
+        Some(role) if user.roles.contains(role) => {}
+        _ => return Err(MediaError::Forbidden("Purging the media cache requires the admin role".to_string()).into()),
+    }
+    let deleted = server.media_repository.purge_remote_cache(query.before_ts).await?;
+    Ok(Json(serde_json::json!({ "deleted": deleted })))
+}
+
+/// Read an upload body, refusing anything over the configured size limit
+async fn read_upload(server: &MatrixServer, body: axum::body::Body) -> Result<Vec<u8>, MediaError> {
+    let limit = server.media_repository.max_upload_size();
+    axum::body::to_bytes(body, limit)
+        .await
+        .map(|bytes| bytes.to_vec())
+        .map_err(|_| MediaError::TooLarge(limit))
+}
+
+fn upload_content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Serve media with headers that stop browsers running it as part of the
+/// homeserver's origin
+fn media_response(content: MediaContent) -> axum::response::Response {
+    use axum::http::header;
+    use axum::response::IntoResponse;
+
+    let disposition = content.content_disposition();
+    (
+        [
+            (header::CONTENT_TYPE, content.content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                "sandbox; default-src 'none'; script-src 'none'; plugin-types application/pdf; \
+                 style-src 'unsafe-inline'; media-src 'self'; object-src 'self';"
+                    .to_string(),
+            ),
+            (header::HeaderName::from_static("cross-origin-resource-policy"), "cross-origin".to_string()),
+        ],
+        content.content,
+    )
+        .into_response()
 }
 
 pub async fn whoami() -> axum::Json<serde_json::Value> {
@@ -418,6 +1473,12 @@ pub async fn create_support_request() -> axum::Json<serde_json::Value> {
     }))
 }
 
+/// Authenticate a request from its `Authorization: Bearer` header
+pub async fn authenticate(server: &MatrixServer, headers: &HeaderMap) -> Result<AuthenticatedUser, MatrixServerError> {
+    let auth_header = headers.get(axum::http::header::AUTHORIZATION).cloned();
+    Ok(crate::auth::auth_middleware(auth_header, State(server.clone())).await?)
+}
+
 /// Helper function to create client error response
 pub fn client_error_response(error: ClientError) -> axum::Json<serde_json::Value> {
     let status_code = error.status_code();
@@ -433,7 +1494,6 @@ pub fn client_error_response(error: ClientError) -> axum::Json<serde_json::Value
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio_test;
 
     fn create_test_config() -> ClientServerConfig {
         ClientServerConfig {
@@ -73,6 +73,11 @@ impl ConduitServer {
         &self.config
     }
 
+    /// Get the state store backing this server
+    pub fn state_store(&self) -> &Arc<dyn StateStore + Send + Sync> {
+        &self.state_store
+    }
+
     /// Check if OIDC is enabled
     pub fn oidc_enabled(&self) -> bool {
         self.config.oidc_enabled
@@ -0,0 +1,83 @@
+// Direct Messages
+// One-to-one rooms tracked in m.direct account data
+// Focus: Keeping m.direct in sync and finding a user's DM room with someone
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{StateError, StateStore};
+
+/// Account data type mapping other users to the DM rooms shared with them
+pub const DIRECT_ACCOUNT_DATA_TYPE: &str = "m.direct";
+
+/// `m.direct` content: user ID -> room IDs
+pub type DirectContent = BTreeMap<String, Vec<String>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectRoomResponse {
+    pub room_id: String,
+    /// Whether the room was created by this request rather than reused
+    pub created: bool,
+}
+
+/// `user_id`'s `m.direct` content; malformed content reads as empty
+pub async fn direct_content(store: &dyn StateStore, user_id: &str) -> Result<DirectContent, StateError> {
+    Ok(store
+        .get_account_data(user_id, None, DIRECT_ACCOUNT_DATA_TYPE)
+        .await?
+        .and_then(|content| serde_json::from_value(content).ok())
+        .unwrap_or_default())
+}
+
+/// Rooms `user_id` has marked as DMs with `other_user_id`, oldest first
+pub async fn direct_rooms_with(
+    store: &dyn StateStore,
+    user_id: &str,
+    other_user_id: &str,
+) -> Result<Vec<String>, StateError> {
+    Ok(direct_content(store, user_id)
+        .await?
+        .remove(other_user_id)
+        .unwrap_or_default())
+}
+
+/// Record `room_id` as a DM between `user_id` and `other_user_id` in `user_id`'s `m.direct`
+pub async fn add_direct_room(
+    store: &dyn StateStore,
+    user_id: &str,
+    other_user_id: &str,
+    room_id: &str,
+) -> Result<(), StateError> {
+    let mut content = direct_content(store, user_id).await?;
+    let rooms = content.entry(other_user_id.to_string()).or_default();
+    if rooms.iter().any(|existing| existing == room_id) {
+        return Ok(());
+    }
+    rooms.push(room_id.to_string());
+
+    let content = serde_json::to_value(content).map_err(|e| StateError::InvalidEvent(e.to_string()))?;
+    store.set_account_data(user_id, None, DIRECT_ACCOUNT_DATA_TYPE, content).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStateStore;
+
+    #[tokio::test]
+    async fn test_add_direct_room_is_idempotent() {
+        let store = InMemoryStateStore::new();
+        add_direct_room(&store, "@alice:localhost", "@bob:localhost", "!one:localhost").await.unwrap();
+        add_direct_room(&store, "@alice:localhost", "@bob:localhost", "!one:localhost").await.unwrap();
+        add_direct_room(&store, "@alice:localhost", "@bob:localhost", "!two:localhost").await.unwrap();
+        add_direct_room(&store, "@alice:localhost", "@carol:localhost", "!three:localhost").await.unwrap();
+
+        let rooms = direct_rooms_with(&store, "@alice:localhost", "@bob:localhost").await.unwrap();
+        assert_eq!(rooms, vec!["!one:localhost", "!two:localhost"]);
+        assert!(direct_rooms_with(&store, "@bob:localhost", "@alice:localhost").await.unwrap().is_empty());
+
+        let stored = store.get_account_data("@alice:localhost", None, DIRECT_ACCOUNT_DATA_TYPE).await.unwrap().unwrap();
+        assert_eq!(stored["@carol:localhost"], serde_json::json!(["!three:localhost"]));
+    }
+}
@@ -0,0 +1,176 @@
+// Room Directory
+// Rooms published to the public room list
+// Focus: Building, filtering and paginating /publicRooms entries
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::RoomState;
+
+/// Whether a room is listed in the public room directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomVisibility {
+    #[serde(rename = "public")]
+    Public,
+    #[default]
+    #[serde(rename = "private")]
+    Private,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomVisibilityBody {
+    #[serde(default)]
+    pub visibility: RoomVisibility,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PublicRoomsFilter {
+    pub generic_search_term: Option<String>,
+    /// Room types to include; `null` stands for rooms without a type
+    pub room_types: Option<Vec<Option<String>>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PublicRoomsRequest {
+    pub limit: Option<u32>,
+    pub since: Option<String>,
+    #[serde(default)]
+    pub filter: PublicRoomsFilter,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicRoomsChunk {
+    pub room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_type: Option<String>,
+    pub num_joined_members: usize,
+    pub world_readable: bool,
+    pub guest_can_join: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PublicRoomsResponse {
+    pub chunk: Vec<PublicRoomsChunk>,
+    pub next_batch: Option<String>,
+    pub prev_batch: Option<String>,
+    pub total_room_count_estimate: usize,
+}
+
+impl PublicRoomsChunk {
+    pub fn from_room_state(room_state: &RoomState) -> Self {
+        let summary = room_state.get_summary();
+        Self {
+            room_id: summary.room_id,
+            name: summary.name,
+            topic: summary.topic,
+            canonical_alias: room_state.canonical_alias_content().and_then(|content| content.alias),
+            avatar_url: room_state.avatar_url.clone(),
+            join_rule: summary.join_rules,
+            room_type: room_state.room_type(),
+            num_joined_members: summary.member_count,
+            world_readable: summary.history_visibility.as_deref() == Some("world_readable"),
+            guest_can_join: room_state.guest_can_join(),
+        }
+    }
+}
+
+impl PublicRoomsFilter {
+    /// Whether a directory entry passes the search term and room type filters
+    pub fn matches(&self, entry: &PublicRoomsChunk) -> bool {
+        if let Some(room_types) = &self.room_types {
+            if !room_types.contains(&entry.room_type) {
+                return false;
+            }
+        }
+
+        let Some(term) = self.generic_search_term.as_deref().map(str::to_lowercase).filter(|term| !term.is_empty()) else {
+            return true;
+        };
+        [&entry.name, &entry.topic, &entry.canonical_alias]
+            .into_iter()
+            .flatten()
+            .any(|field| field.to_lowercase().contains(&term))
+    }
+}
+
+/// Order entries the way the directory lists them: busiest rooms first
+pub fn sort_public_rooms(entries: &mut [PublicRoomsChunk]) {
+    entries.sort_by(|a, b| {
+        b.num_joined_members
+            .cmp(&a.num_joined_members)
+            .then_with(|| a.room_id.cmp(&b.room_id))
+    });
+}
+
+/// Format an offset into the sorted directory as a pagination token
+pub fn format_directory_token(offset: usize) -> String {
+    format!("p{}", offset)
+}
+
+/// Parse a directory pagination token back into an offset
+pub fn parse_directory_token(token: &str) -> Option<usize> {
+    token.strip_prefix('p')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(room_id: &str, name: Option<&str>, members: usize, room_type: Option<&str>) -> PublicRoomsChunk {
+        PublicRoomsChunk {
+            room_id: room_id.to_string(),
+            name: name.map(str::to_string),
+            topic: None,
+            canonical_alias: None,
+            avatar_url: None,
+            join_rule: Some("public".to_string()),
+            room_type: room_type.map(str::to_string),
+            num_joined_members: members,
+            world_readable: false,
+            guest_can_join: false,
+        }
+    }
+
+    #[test]
+    fn test_filter_matches_search_term_and_room_type() {
+        let rust = entry("!a:localhost", Some("Rustaceans"), 3, None);
+        let space = entry("!b:localhost", Some("Rust Space"), 1, Some("m.space"));
+
+        let search = PublicRoomsFilter { generic_search_term: Some("rust".to_string()), room_types: None };
+        assert!(search.matches(&rust) && search.matches(&space));
+
+        let rooms_only = PublicRoomsFilter { generic_search_term: None, room_types: Some(vec![None]) };
+        assert!(rooms_only.matches(&rust));
+        assert!(!rooms_only.matches(&space));
+
+        let no_match = PublicRoomsFilter { generic_search_term: Some("python".to_string()), room_types: None };
+        assert!(!no_match.matches(&rust));
+    }
+
+    #[test]
+    fn test_sort_busiest_first() {
+        let mut entries = vec![
+            entry("!quiet:localhost", None, 1, None),
+            entry("!busy:localhost", None, 10, None),
+            entry("!also-quiet:localhost", None, 1, None),
+        ];
+        sort_public_rooms(&mut entries);
+        let order: Vec<&str> = entries.iter().map(|entry| entry.room_id.as_str()).collect();
+        assert_eq!(order, vec!["!busy:localhost", "!also-quiet:localhost", "!quiet:localhost"]);
+    }
+
+    #[test]
+    fn test_directory_token_round_trip() {
+        assert_eq!(parse_directory_token(&format_directory_token(20)), Some(20));
+        assert_eq!(parse_directory_token("s20"), None);
+    }
+}
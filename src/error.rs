@@ -22,6 +22,21 @@ pub enum MatrixServerError {
     #[error("Client error: {0}")]
     Client(#[from] crate::client_server::ClientError),
     
+    #[error("Account data error: {0}")]
+    AccountData(#[from] crate::account_data::AccountDataError),
+    
+    #[error("Push rule error: {0}")]
+    PushRule(#[from] crate::push_rules::PushRuleError),
+    
+    #[error("Pusher error: {0}")]
+    Pusher(#[from] crate::pushers::PusherError),
+    
+    #[error("Media error: {0}")]
+    Media(#[from] crate::media::MediaError),
+    
+    #[error("URL preview error: {0}")]
+    UrlPreview(#[from] crate::url_preview::UrlPreviewError),
+    
     #[error("Network error: {0}")]
     NetworkError(String),
     
@@ -49,6 +64,11 @@ impl MatrixServerError {
             MatrixServerError::Room(room_err) => room_err.status_code(),
             MatrixServerError::Federation(_) => 500, // Internal server error for federation issues
             MatrixServerError::Client(client_err) => client_err.status_code(),
+            MatrixServerError::AccountData(account_data_err) => account_data_err.status_code(),
+            MatrixServerError::PushRule(push_rule_err) => push_rule_err.status_code(),
+            MatrixServerError::Pusher(pusher_err) => pusher_err.status_code(),
+            MatrixServerError::Media(media_err) => media_err.status_code(),
+            MatrixServerError::UrlPreview(url_preview_err) => url_preview_err.status_code(),
             MatrixServerError::NetworkError(_) => 503, // Service unavailable
             MatrixServerError::ConfigError(_) => 500, // Internal server error
             MatrixServerError::DatabaseError(_) => 500, // Internal server error
@@ -65,6 +85,11 @@ impl MatrixServerError {
             MatrixServerError::Room(room_err) => room_err.error_code(),
             MatrixServerError::Federation(_) => "M_FEDERATION_ERROR",
             MatrixServerError::Client(client_err) => client_err.error_code(),
+            MatrixServerError::AccountData(account_data_err) => account_data_err.error_code(),
+            MatrixServerError::PushRule(push_rule_err) => push_rule_err.error_code(),
+            MatrixServerError::Pusher(pusher_err) => pusher_err.error_code(),
+            MatrixServerError::Media(media_err) => media_err.error_code(),
+            MatrixServerError::UrlPreview(url_preview_err) => url_preview_err.error_code(),
             MatrixServerError::NetworkError(_) => "M_UNKNOWN",
             MatrixServerError::ConfigError(_) => "M_UNKNOWN",
             MatrixServerError::DatabaseError(_) => "M_UNKNOWN",
@@ -76,6 +101,25 @@ impl MatrixServerError {
     }
 }
 
+impl axum::response::IntoResponse for MatrixServerError {
+    fn into_response(self) -> axum::response::Response {
+        error_response(self.status_code(), self.error_code(), self.to_string())
+    }
+}
+
+/// Build a Matrix standard error response (`errcode` + `error`)
+pub fn error_response(status_code: u16, error_code: &str, message: String) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let status = axum::http::StatusCode::from_u16(status_code)
+        .unwrap_or(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+    let body = axum::Json(serde_json::json!({
+        "errcode": error_code,
+        "error": message,
+    }));
+    (status, body).into_response()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,7 +159,7 @@ mod tests {
         let matrix_error: MatrixServerError = auth_error.into();
         
         match matrix_error {
-            MatrixServerError::Auth(_) => assert!(true),
+            MatrixServerError::Auth(_) => {}
             _ => panic!("Expected Auth variant"),
         }
     }
@@ -126,7 +170,7 @@ mod tests {
         let matrix_error: MatrixServerError = room_error.into();
         
         match matrix_error {
-            MatrixServerError::Room(_) => assert!(true),
+            MatrixServerError::Room(_) => {}
             _ => panic!("Expected Room variant"),
         }
     }
@@ -137,7 +181,7 @@ mod tests {
         let matrix_error: MatrixServerError = federation_error.into();
         
         match matrix_error {
-            MatrixServerError::Federation(_) => assert!(true),
+            MatrixServerError::Federation(_) => {}
             _ => panic!("Expected Federation variant"),
         }
     }
@@ -148,7 +192,7 @@ mod tests {
         let matrix_error: MatrixServerError = client_error.into();
         
         match matrix_error {
-            MatrixServerError::Client(_) => assert!(true),
+            MatrixServerError::Client(_) => {}
             _ => panic!("Expected Client variant"),
         }
     }
@@ -159,7 +203,7 @@ mod tests {
         let matrix_error: MatrixServerError = state_error.into();
         
         match matrix_error {
-            MatrixServerError::State(_) => assert!(true),
+            MatrixServerError::State(_) => {}
             _ => panic!("Expected State variant"),
         }
     }
@@ -186,7 +230,7 @@ mod tests {
         let matrix_error: MatrixServerError = json_error.into();
         
         match matrix_error {
-            MatrixServerError::SerializationError(_) => assert!(true),
+            MatrixServerError::SerializationError(_) => {}
             _ => panic!("Expected SerializationError variant"),
         }
     }
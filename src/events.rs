@@ -6,9 +6,13 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Matrix event wrapper - simplified version for trading platform
+///
+/// Content is parsed according to the event type when deserializing.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "WireEvent")]
 pub struct MatrixEvent {
     pub event_id: String,
+    #[serde(rename = "type")]
     pub event_type: EventType,
     pub content: EventContent,
     pub sender: String,
@@ -16,6 +20,47 @@ pub struct MatrixEvent {
     pub origin_server_ts: u64,
     pub unsigned: Option<serde_json::Value>,
     pub state_key: Option<String>, // Present for state events
+    /// Event a redaction redacts, in room versions before 11
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redacts: Option<String>,
+    /// server name -> key ID -> signature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signatures: Option<HashMap<String, HashMap<String, String>>>,
+}
+
+/// A `MatrixEvent` as received, before its content is parsed by type
+#[derive(Deserialize)]
+struct WireEvent {
+    event_id: String,
+    #[serde(rename = "type")]
+    event_type: EventType,
+    content: serde_json::Value,
+    sender: String,
+    room_id: String,
+    origin_server_ts: u64,
+    unsigned: Option<serde_json::Value>,
+    state_key: Option<String>,
+    #[serde(default)]
+    redacts: Option<String>,
+    #[serde(default)]
+    signatures: Option<HashMap<String, HashMap<String, String>>>,
+}
+
+impl From<WireEvent> for MatrixEvent {
+    fn from(event: WireEvent) -> Self {
+        Self {
+            content: EventContent::parse(&event.event_type, event.content),
+            event_id: event.event_id,
+            event_type: event.event_type,
+            sender: event.sender,
+            room_id: event.room_id,
+            origin_server_ts: event.origin_server_ts,
+            unsigned: event.unsigned,
+            state_key: event.state_key,
+            redacts: event.redacts,
+            signatures: event.signatures,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -27,6 +72,8 @@ pub enum EventType {
     RoomEncrypted,
     #[serde(rename = "m.reaction")]
     Reaction,
+    #[serde(rename = "m.room.redaction")]
+    RoomRedaction,
     
     // State events
     #[serde(rename = "m.room.create")]
@@ -45,6 +92,22 @@ pub enum EventType {
     RoomTopic,
     #[serde(rename = "m.room.avatar")]
     RoomAvatar,
+    #[serde(rename = "m.room.encryption")]
+    RoomEncryption,
+    #[serde(rename = "m.room.guest_access")]
+    RoomGuestAccess,
+    #[serde(rename = "m.room.server_acl")]
+    RoomServerAcl,
+    #[serde(rename = "m.room.tombstone")]
+    RoomTombstone,
+    #[serde(rename = "m.room.pinned_events")]
+    RoomPinnedEvents,
+    #[serde(rename = "m.room.canonical_alias")]
+    RoomCanonicalAlias,
+    #[serde(rename = "m.space.child")]
+    SpaceChild,
+    #[serde(rename = "m.space.parent")]
+    SpaceParent,
     
     // Custom events for general use
     #[serde(rename = "custom.support.request")]
@@ -56,17 +119,35 @@ pub enum EventType {
     Custom(String),
 }
 
+/// Event content, typed by event type via `EventContent::parse`
+///
+/// Deserializing content on its own cannot see the event type and takes the
+/// first variant whose shape matches, so prefer `parse` wherever the type is known.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum EventContent {
     RoomMessage(RoomMessageContent),
+    Reaction(ReactionContent),
     RoomMember(RoomMemberContent),
     RoomCreate(RoomCreateContent),
-    RoomPowerLevels(RoomPowerLevelsContent),
+    RoomHistoryVisibility(RoomHistoryVisibilityContent),
     RoomJoinRules(RoomJoinRulesContent),
+    RoomPowerLevels(RoomPowerLevelsContent),
+    // All-optional like RoomPowerLevels, so only `parse` ever produces it
+    RoomRedaction(RoomRedactionContent),
     RoomName(RoomNameContent),
     RoomTopic(RoomTopicContent),
+    RoomAvatar(RoomAvatarContent),
+    RoomCanonicalAlias(RoomCanonicalAliasContent),
+    RoomEncryption(RoomEncryptionContent),
+    RoomGuestAccess(RoomGuestAccessContent),
+    RoomServerAcl(RoomServerAclContent),
+    RoomTombstone(RoomTombstoneContent),
+    RoomPinnedEvents(RoomPinnedEventsContent),
+    SpaceChild(SpaceChildContent),
+    SpaceParent(SpaceParentContent),
     CustomSupport(CustomSupportContent),
+    /// Content of unknown event types, or known content that failed to parse
     Raw(serde_json::Value),
 }
 
@@ -76,8 +157,57 @@ pub struct RoomMessageContent {
     pub body: String,
     pub formatted_body: Option<String>,
     pub format: Option<String>,
+    /// mxc:// URI of the content of image, file, video and audio messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<Box<MediaInfo>>,
+    /// Original filename, when `body` is a caption rather than the filename
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
     #[serde(rename = "m.relates_to")]
     pub relates_to: Option<RelatesTo>,
+    /// Replacement content carried by `m.replace` edits
+    #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+    pub new_content: Option<serde_json::Value>,
+    #[serde(rename = "m.mentions", default, skip_serializing_if = "Option::is_none")]
+    pub mentions: Option<Mentions>,
+}
+
+/// Metadata of the content behind a media message's `url`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub h: Option<u32>,
+    /// Length of video and audio, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_info: Option<serde_json::Value>,
+}
+
+/// Users and rooms a message intentionally mentions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Mentions {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_ids: Vec<String>,
+    /// Whether the whole room is mentioned, as with `@room`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub room: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionContent {
+    #[serde(rename = "m.relates_to")]
+    pub relates_to: RelatesTo,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -108,8 +238,17 @@ pub struct RelatesTo {
     pub rel_type: String,
     #[serde(rename = "event_id")]
     pub event_id: String,
+    /// Annotation key, e.g. the emoji of an `m.annotation` reaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
 }
 
+// Relation types with server-side aggregation
+pub const REL_TYPE_REPLACE: &str = "m.replace";
+pub const REL_TYPE_THREAD: &str = "m.thread";
+pub const REL_TYPE_ANNOTATION: &str = "m.annotation";
+pub const REL_TYPE_REFERENCE: &str = "m.reference";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InReplyTo {
     pub event_id: String,
@@ -123,6 +262,9 @@ pub struct RoomMemberContent {
     pub reason: Option<String>,
     pub is_direct: Option<bool>,
     pub third_party_invite: Option<ThirdPartyInvite>,
+    /// Member of the resident server who vouched for a restricted join
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_authorised_via_users_server: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -153,8 +295,14 @@ pub struct RoomCreateContent {
     pub room_version: Option<String>,
     #[serde(rename = "predecessor")]
     pub predecessor: Option<RoomPredecessor>,
+    /// Room type, e.g. `m.space`; absent for ordinary rooms
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub room_type: Option<String>,
 }
 
+/// Room type of spaces
+pub const ROOM_TYPE_SPACE: &str = "m.space";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomPredecessor {
     pub room_id: String,
@@ -172,14 +320,39 @@ pub struct RoomPowerLevelsContent {
     pub kick: Option<i32>,
     pub redact: Option<i32>,
     pub invite: Option<i32>,
+    /// Notification key, such as `room`, -> level required to trigger it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notifications: Option<HashMap<String, i32>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomRedactionContent {
+    /// Event being redacted, in room version 11 and later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redacts: Option<String>,
+    pub reason: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomJoinRulesContent {
     #[serde(rename = "join_rule")]
     pub join_rule: JoinRule,
+    /// Conditions under which `restricted` and `knock_restricted` rooms may be joined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<AllowCondition>>,
+}
+
+/// Allow condition of a restricted join rule
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AllowCondition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    pub room_id: Option<String>,
 }
 
+/// Allow condition satisfied by membership of `room_id`
+pub const ALLOW_ROOM_MEMBERSHIP: &str = "m.room_membership";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum JoinRule {
     #[serde(rename = "public")]
@@ -196,6 +369,49 @@ pub enum JoinRule {
     Restricted,
 }
 
+impl JoinRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JoinRule::Public => "public",
+            JoinRule::Invite => "invite",
+            JoinRule::Private => "private",
+            JoinRule::Knock => "knock",
+            JoinRule::KnockRestricted => "knock_restricted",
+            JoinRule::Restricted => "restricted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomHistoryVisibilityContent {
+    pub history_visibility: HistoryVisibility,
+}
+
+/// Who may read room history, from most to least permissive
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HistoryVisibility {
+    #[serde(rename = "world_readable")]
+    WorldReadable,
+    #[default]
+    #[serde(rename = "shared")]
+    Shared,
+    #[serde(rename = "invited")]
+    Invited,
+    #[serde(rename = "joined")]
+    Joined,
+}
+
+impl HistoryVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HistoryVisibility::WorldReadable => "world_readable",
+            HistoryVisibility::Shared => "shared",
+            HistoryVisibility::Invited => "invited",
+            HistoryVisibility::Joined => "joined",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomNameContent {
     pub name: String,
@@ -206,6 +422,150 @@ pub struct RoomTopicContent {
     pub topic: String,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomCanonicalAliasContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt_aliases: Option<Vec<String>>,
+}
+
+impl RoomCanonicalAliasContent {
+    /// The canonical alias followed by the alternative aliases
+    pub fn aliases(&self) -> impl Iterator<Item = &String> {
+        self.alias.iter().chain(self.alt_aliases.iter().flatten())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomAvatarContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomEncryptionContent {
+    pub algorithm: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_period_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_period_msgs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomGuestAccessContent {
+    pub guest_access: GuestAccess,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GuestAccess {
+    #[serde(rename = "can_join")]
+    CanJoin,
+    #[serde(rename = "forbidden")]
+    Forbidden,
+}
+
+/// `m.room.server_acl`: glob patterns of servers allowed or denied in the room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomServerAclContent {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default = "default_allow_ip_literals")]
+    pub allow_ip_literals: bool,
+}
+
+fn default_allow_ip_literals() -> bool {
+    true
+}
+
+impl RoomServerAclContent {
+    /// Whether `server_name` (without port) may take part in the room
+    pub fn allows(&self, server_name: &str) -> bool {
+        let host = server_name.rsplit_once(':').map_or(server_name, |(host, _)| host);
+        let is_ip_literal = host.starts_with('[') || host.parse::<std::net::Ipv4Addr>().is_ok();
+        if is_ip_literal && !self.allow_ip_literals {
+            return false;
+        }
+        if self.deny.iter().any(|pattern| glob_matches(pattern, host)) {
+            return false;
+        }
+        self.allow.iter().any(|pattern| glob_matches(pattern, host))
+    }
+}
+
+/// Match `value` against a glob where `*` is any run of characters and `?` one character
+pub(crate) fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// `m.room.tombstone`: the room has been replaced by `replacement_room`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomTombstoneContent {
+    pub body: String,
+    pub replacement_room: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomPinnedEventsContent {
+    pub pinned: Vec<String>,
+}
+
+/// `m.space.child`, keyed by the child room ID; only valid while `via` is non-empty
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpaceChildContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggested: Option<bool>,
+}
+
+impl SpaceChildContent {
+    pub fn is_valid(&self) -> bool {
+        self.via.as_ref().is_some_and(|via| !via.is_empty())
+    }
+
+    /// `order` if it is usable for sorting: at most 50 printable ASCII characters
+    pub fn valid_order(&self) -> Option<&str> {
+        self.order
+            .as_deref()
+            .filter(|order| order.len() <= 50 && order.chars().all(|c| ('\x20'..='\x7e').contains(&c)))
+    }
+}
+
+/// `m.space.parent`, keyed by the parent space ID
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpaceParentContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonical: Option<bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CustomSupportContent {
     pub request_type: String,
@@ -235,6 +595,35 @@ impl MatrixEvent {
                 .as_millis() as u64,
             unsigned: None,
             state_key: None,
+            redacts: None,
+            signatures: None,
+        }
+    }
+
+    /// A redaction of `redacts`, which goes at the top level before room
+    /// version 11 and in content from then on
+    pub fn redaction(redacts: &str, reason: Option<String>, sender: String, room_id: String, room_version: &str) -> Self {
+        let in_content = RedactionRules::for_room_version(room_version).keep_redaction_redacts;
+        let content = RoomRedactionContent {
+            redacts: in_content.then(|| redacts.to_string()),
+            reason,
+        };
+        let mut redaction = Self::new(EventType::RoomRedaction, EventContent::RoomRedaction(content), sender, room_id);
+        if !in_content {
+            redaction.redacts = Some(redacts.to_string());
+        }
+        redaction
+    }
+
+    /// The event this redaction redacts, read from where `room_version` puts it
+    pub fn redacted_event_id(&self, room_version: &str) -> Option<&str> {
+        if RedactionRules::for_room_version(room_version).keep_redaction_redacts {
+            match &self.content {
+                EventContent::RoomRedaction(content) => content.redacts.as_deref(),
+                _ => None,
+            }
+        } else {
+            self.redacts.as_deref()
         }
     }
 
@@ -250,6 +639,15 @@ impl MatrixEvent {
     pub fn is_message_event(&self) -> bool {
         matches!(self.event_type, EventType::RoomMessage)
     }
+
+    /// Relation this event declares in its `m.relates_to`, if any
+    pub fn relates_to(&self) -> Option<&RelatesTo> {
+        match &self.content {
+            EventContent::RoomMessage(content) => content.relates_to.as_ref(),
+            EventContent::Reaction(content) => Some(&content.relates_to),
+            _ => None,
+        }
+    }
 }
 
 // Helper functions for content creation
@@ -261,6 +659,11 @@ impl EventContent {
             formatted_body: None,
             format: None,
             relates_to: None,
+            new_content: None,
+            mentions: None,
+            url: None,
+            info: None,
+            filename: None,
         })
     }
 
@@ -272,6 +675,7 @@ impl EventContent {
             reason: None,
             is_direct: None,
             third_party_invite: None,
+            join_authorised_via_users_server: None,
         })
     }
 
@@ -281,6 +685,7 @@ impl EventContent {
             m_federate: Some(true),
             room_version: Some("9".to_string()),
             predecessor: None,
+            room_type: None,
         })
     }
 
@@ -295,8 +700,203 @@ impl EventContent {
             kick: Some(50),
             redact: Some(50),
             invite: Some(50),
+            notifications: None,
+        })
+    }
+}
+
+impl EventType {
+    /// Matrix type string of this event, e.g. `m.room.message`
+    pub fn as_str(&self) -> &str {
+        match self {
+            EventType::RoomMessage => "m.room.message",
+            EventType::RoomEncrypted => "m.room.encrypted",
+            EventType::Reaction => "m.reaction",
+            EventType::RoomRedaction => "m.room.redaction",
+            EventType::RoomCreate => "m.room.create",
+            EventType::RoomMember => "m.room.member",
+            EventType::RoomPowerLevels => "m.room.power_levels",
+            EventType::RoomJoinRules => "m.room.join_rules",
+            EventType::RoomHistoryVisibility => "m.room.history_visibility",
+            EventType::RoomName => "m.room.name",
+            EventType::RoomTopic => "m.room.topic",
+            EventType::RoomAvatar => "m.room.avatar",
+            EventType::RoomEncryption => "m.room.encryption",
+            EventType::RoomGuestAccess => "m.room.guest_access",
+            EventType::RoomServerAcl => "m.room.server_acl",
+            EventType::RoomTombstone => "m.room.tombstone",
+            EventType::RoomPinnedEvents => "m.room.pinned_events",
+            EventType::RoomCanonicalAlias => "m.room.canonical_alias",
+            EventType::SpaceChild => "m.space.child",
+            EventType::SpaceParent => "m.space.parent",
+            EventType::CustomSupportRequest => "custom.support.request",
+            EventType::CustomAlert => "custom.alert",
+            EventType::Custom(event_type) => event_type,
+        }
+    }
+}
+
+impl From<&str> for EventType {
+    fn from(event_type: &str) -> Self {
+        serde_json::from_value(serde_json::Value::String(event_type.to_string()))
+            .unwrap_or_else(|_| EventType::Custom(event_type.to_string()))
+    }
+}
+
+// Redaction algorithm
+impl MatrixEvent {
+    /// Return a copy of this event stripped by the redaction algorithm of
+    /// `room_version`, with the redaction recorded as `redacted_because`
+    pub fn redact(&self, redaction: &MatrixEvent, room_version: &str) -> MatrixEvent {
+        let rules = RedactionRules::for_room_version(room_version);
+        let content = serde_json::to_value(&self.content).unwrap_or_default();
+        let content = rules.redact_content(&self.event_type, content);
+
+        // Keep whatever else the event carried in `unsigned`, e.g. `prev_content` or `age`
+        let mut unsigned = match &self.unsigned {
+            Some(serde_json::Value::Object(unsigned)) => unsigned.clone(),
+            _ => serde_json::Map::new(),
+        };
+        unsigned.insert(
+            "redacted_because".to_string(),
+            serde_json::to_value(redaction).unwrap_or_default(),
+        );
+
+        MatrixEvent {
+            content: EventContent::parse(&self.event_type, content),
+            unsigned: Some(serde_json::Value::Object(unsigned)),
+            // Not among the top-level keys redaction preserves
+            redacts: None,
+            ..self.clone()
+        }
+    }
+
+    /// Check if this event has been stripped by a redaction
+    pub fn is_redacted(&self) -> bool {
+        self.unsigned
+            .as_ref()
+            .and_then(|unsigned| unsigned.get("redacted_because"))
+            .is_some()
+    }
+}
+
+/// Room-version dependent parts of the redaction algorithm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RedactionRules {
+    /// `m.room.aliases` keeps `aliases` (v1-v5)
+    pub keep_aliases: bool,
+    /// `m.room.join_rules` keeps `allow` (v8+)
+    pub keep_join_rules_allow: bool,
+    /// `m.room.member` keeps `join_authorised_via_users_server` (v9+)
+    pub keep_join_authorised_via_users_server: bool,
+    /// `m.room.create` keeps its whole content (v11+)
+    pub keep_create_content: bool,
+    /// `m.room.power_levels` keeps `invite` (v11+)
+    pub keep_power_levels_invite: bool,
+    /// `m.room.redaction` keeps `redacts` (v11+)
+    pub keep_redaction_redacts: bool,
+    /// `m.room.member` keeps `third_party_invite.signed` (v11+)
+    pub keep_third_party_invite_signed: bool,
+}
+
+impl RedactionRules {
+    /// Rules for a room version; unknown versions follow the latest stable one
+    pub fn for_room_version(room_version: &str) -> Self {
+        let version = room_version.parse::<u32>().unwrap_or(11);
+        Self {
+            keep_aliases: version <= 5,
+            keep_join_rules_allow: version >= 8,
+            keep_join_authorised_via_users_server: version >= 9,
+            keep_create_content: version >= 11,
+            keep_power_levels_invite: version >= 11,
+            keep_redaction_redacts: version >= 11,
+            keep_third_party_invite_signed: version >= 11,
+        }
+    }
+
+    /// Strip event content down to the keys preserved for `event_type`
+    pub fn redact_content(&self, event_type: &EventType, content: serde_json::Value) -> serde_json::Value {
+        const POWER_LEVELS_KEYS: &[&str] = &[
+            "ban", "events", "events_default", "kick", "redact", "state_default", "users", "users_default",
+        ];
+
+        let mut keep: Vec<&str> = match event_type.as_str() {
+            "m.room.member" => vec!["membership"],
+            "m.room.create" if self.keep_create_content => return content,
+            "m.room.create" => vec!["creator"],
+            "m.room.join_rules" => vec!["join_rule"],
+            "m.room.power_levels" => POWER_LEVELS_KEYS.to_vec(),
+            "m.room.history_visibility" => vec!["history_visibility"],
+            "m.room.aliases" if self.keep_aliases => vec!["aliases"],
+            "m.room.redaction" if self.keep_redaction_redacts => vec!["redacts"],
+            _ => vec![],
+        };
+        match event_type {
+            EventType::RoomMember if self.keep_join_authorised_via_users_server => {
+                keep.push("join_authorised_via_users_server");
+            }
+            EventType::RoomJoinRules if self.keep_join_rules_allow => keep.push("allow"),
+            EventType::RoomPowerLevels if self.keep_power_levels_invite => keep.push("invite"),
+            _ => {}
+        }
+
+        let mut redacted = serde_json::Map::new();
+        if let serde_json::Value::Object(mut object) = content {
+            for key in keep {
+                if let Some(value) = object.remove(key).filter(|value| !value.is_null()) {
+                    redacted.insert(key.to_string(), value);
+                }
+            }
+
+            if *event_type == EventType::RoomMember && self.keep_third_party_invite_signed {
+                if let Some(signed) = object.get("third_party_invite").and_then(|invite| invite.get("signed")) {
+                    redacted.insert(
+                        "third_party_invite".to_string(),
+                        serde_json::json!({ "signed": signed }),
+                    );
+                }
+            }
+        }
+        serde_json::Value::Object(redacted)
+    }
+}
+
+impl EventContent {
+    /// Parse content into the typed variant for `event_type`, failing if it
+    /// does not match that type's schema; unknown types are kept raw
+    pub fn from_type(event_type: &EventType, content: serde_json::Value) -> Result<Self, serde_json::Error> {
+        use serde_json::from_value;
+
+        Ok(match event_type {
+            EventType::RoomMessage => EventContent::RoomMessage(from_value(content)?),
+            EventType::Reaction => EventContent::Reaction(from_value(content)?),
+            EventType::RoomRedaction => EventContent::RoomRedaction(from_value(content)?),
+            EventType::RoomCreate => EventContent::RoomCreate(from_value(content)?),
+            EventType::RoomMember => EventContent::RoomMember(from_value(content)?),
+            EventType::RoomPowerLevels => EventContent::RoomPowerLevels(from_value(content)?),
+            EventType::RoomJoinRules => EventContent::RoomJoinRules(from_value(content)?),
+            EventType::RoomHistoryVisibility => EventContent::RoomHistoryVisibility(from_value(content)?),
+            EventType::RoomName => EventContent::RoomName(from_value(content)?),
+            EventType::RoomTopic => EventContent::RoomTopic(from_value(content)?),
+            EventType::RoomAvatar => EventContent::RoomAvatar(from_value(content)?),
+            EventType::RoomCanonicalAlias => EventContent::RoomCanonicalAlias(from_value(content)?),
+            EventType::RoomEncryption => EventContent::RoomEncryption(from_value(content)?),
+            EventType::RoomGuestAccess => EventContent::RoomGuestAccess(from_value(content)?),
+            EventType::RoomServerAcl => EventContent::RoomServerAcl(from_value(content)?),
+            EventType::RoomTombstone => EventContent::RoomTombstone(from_value(content)?),
+            EventType::RoomPinnedEvents => EventContent::RoomPinnedEvents(from_value(content)?),
+            EventType::SpaceChild => EventContent::SpaceChild(from_value(content)?),
+            EventType::SpaceParent => EventContent::SpaceParent(from_value(content)?),
+            EventType::CustomSupportRequest => EventContent::CustomSupport(from_value(content)?),
+            EventType::RoomEncrypted | EventType::CustomAlert | EventType::Custom(_) => EventContent::Raw(content),
         })
     }
+
+    /// Like `from_type`, but keep content that does not match its type's
+    /// schema (e.g. once redacted) as raw JSON so nothing is lost
+    pub fn parse(event_type: &EventType, content: serde_json::Value) -> Self {
+        Self::from_type(event_type, content.clone()).unwrap_or(EventContent::Raw(content))
+    }
 }
 
 // Validation functions
@@ -325,6 +925,18 @@ pub enum EventValidationError {
     EmptyEventId,
 }
 
+/// Split a `#localpart:server_name` room alias into its localpart and server name
+pub fn parse_room_alias(alias: &str) -> Option<(&str, &str)> {
+    let (localpart, server_name) = alias.strip_prefix('#')?.split_once(':')?;
+    if localpart.is_empty() || server_name.is_empty() || alias.len() > 255 {
+        return None;
+    }
+    if localpart.chars().any(|c| c.is_whitespace() || c == ':' || c == '#') {
+        return None;
+    }
+    Some((localpart, server_name))
+}
+
 // Type aliases for better readability
 pub type EventId = String;
 pub type RoomId = String;
@@ -537,6 +1149,7 @@ mod tests {
             }),
             rel_type: "m.reference".to_string(),
             event_id: "$event_id".to_string(),
+            key: None,
         };
 
         let json = serde_json::to_string(&relates_to).unwrap();
@@ -561,4 +1174,250 @@ mod tests {
         
         assert_eq!(deserialized.event_id, in_reply_to.event_id);
     }
+
+    fn create_redaction(redacts: &str) -> MatrixEvent {
+        MatrixEvent::redaction(
+            redacts,
+            Some("spam".to_string()),
+            "@admin:localhost".to_string(),
+            "!test:localhost".to_string(),
+            "9",
+        )
+    }
+
+    #[test]
+    fn test_redacts_key_depends_on_room_version() {
+        let wire = serde_json::json!({
+            "event_id": "$redaction",
+            "type": "m.room.redaction",
+            "content": { "reason": "spam" },
+            "redacts": "$target",
+            "sender": "@admin:remote.example",
+            "room_id": "!test:localhost",
+            "origin_server_ts": 1,
+        });
+        let redaction: MatrixEvent = serde_json::from_value(wire).unwrap();
+        assert!(matches!(redaction.content, EventContent::RoomRedaction(_)));
+        assert_eq!(redaction.redacted_event_id("9"), Some("$target"));
+        assert_eq!(redaction.redacted_event_id("11"), None);
+
+        let v11 = MatrixEvent::redaction("$target", None, "@admin:localhost".to_string(), "!test:localhost".to_string(), "11");
+        assert_eq!(v11.redacts, None);
+        assert_eq!(v11.redacted_event_id("11"), Some("$target"));
+        let v9 = create_redaction("$target");
+        assert_eq!(serde_json::to_value(&v9).unwrap()["redacts"], "$target");
+        assert_eq!(v9.redacted_event_id("9"), Some("$target"));
+    }
+
+    #[test]
+    fn test_redact_message_strips_content() {
+        let mut event = MatrixEvent::new(
+            EventType::RoomMessage,
+            EventContent::room_message(MessageType::Text, "Secret".to_string()),
+            "@user:localhost".to_string(),
+            "!test:localhost".to_string(),
+        );
+        event.unsigned = Some(serde_json::json!({ "transaction_id": "txn1" }));
+        let redaction = create_redaction(&event.event_id);
+
+        let redacted = event.redact(&redaction, "9");
+
+        assert_eq!(redacted.event_id, event.event_id);
+        let unsigned = redacted.unsigned.as_ref().unwrap();
+        assert_eq!(unsigned["transaction_id"], "txn1");
+        assert_eq!(unsigned["redacted_because"]["event_id"], redaction.event_id);
+        assert_eq!(redacted.sender, event.sender);
+        assert!(redacted.is_redacted());
+        assert!(!event.is_redacted());
+        assert!(matches!(redacted.content, EventContent::Raw(ref content) if content == &serde_json::json!({})));
+    }
+
+    #[test]
+    fn test_redact_member_keeps_membership() {
+        let event = MatrixEvent::new(
+            EventType::RoomMember,
+            EventContent::room_member(MembershipState::Join, Some("Display Name".to_string())),
+            "@user:localhost".to_string(),
+            "!test:localhost".to_string(),
+        ).with_state_key("@user:localhost".to_string());
+
+        let redacted = event.redact(&create_redaction(&event.event_id), "9");
+
+        if let EventContent::RoomMember(content) = redacted.content {
+            assert_eq!(content.membership, MembershipState::Join);
+            assert!(content.displayname.is_none());
+        } else {
+            panic!("Expected RoomMember content");
+        }
+        assert_eq!(redacted.state_key, Some("@user:localhost".to_string()));
+    }
+
+    #[test]
+    fn test_redaction_rules_by_room_version() {
+        let create = serde_json::json!({ "creator": "@user:localhost", "m.federate": true });
+        let v9 = RedactionRules::for_room_version("9");
+        let v11 = RedactionRules::for_room_version("11");
+
+        assert_eq!(
+            v9.redact_content(&EventType::RoomCreate, create.clone()),
+            serde_json::json!({ "creator": "@user:localhost" })
+        );
+        assert_eq!(v11.redact_content(&EventType::RoomCreate, create.clone()), create);
+
+        let join_rules = serde_json::json!({ "join_rule": "restricted", "allow": [] });
+        assert_eq!(
+            RedactionRules::for_room_version("7").redact_content(&EventType::RoomJoinRules, join_rules.clone()),
+            serde_json::json!({ "join_rule": "restricted" })
+        );
+        assert_eq!(v9.redact_content(&EventType::RoomJoinRules, join_rules.clone()), join_rules);
+
+        let redaction = serde_json::json!({ "redacts": "$event", "reason": "spam" });
+        assert_eq!(v9.redact_content(&EventType::RoomRedaction, redaction.clone()), serde_json::json!({}));
+        assert_eq!(
+            v11.redact_content(&EventType::RoomRedaction, redaction),
+            serde_json::json!({ "redacts": "$event" })
+        );
+
+        let aliases = serde_json::json!({ "aliases": ["#room:localhost"] });
+        let aliases_type = EventType::Custom("m.room.aliases".to_string());
+        assert_eq!(RedactionRules::for_room_version("5").redact_content(&aliases_type, aliases.clone()), aliases);
+        assert_eq!(v9.redact_content(&aliases_type, aliases), serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_redact_power_levels_invite_by_room_version() {
+        let power_levels = serde_json::to_value(EventContent::room_power_levels()).unwrap();
+
+        let v9 = RedactionRules::for_room_version("9").redact_content(&EventType::RoomPowerLevels, power_levels.clone());
+        assert!(v9.get("invite").is_none());
+        assert_eq!(v9["ban"], 50);
+
+        let v11 = RedactionRules::for_room_version("11").redact_content(&EventType::RoomPowerLevels, power_levels);
+        assert_eq!(v11["invite"], 50);
+    }
+
+    #[test]
+    fn test_event_type_string_round_trip() {
+        assert_eq!(EventType::from("m.room.redaction"), EventType::RoomRedaction);
+        assert_eq!(EventType::from("m.room.message").as_str(), "m.room.message");
+        assert_eq!(EventType::from("org.example.custom"), EventType::Custom("org.example.custom".to_string()));
+    }
+
+    #[test]
+    fn test_event_serializes_type_field() {
+        let event = MatrixEvent::new(
+            EventType::RoomMessage,
+            EventContent::room_message(MessageType::Text, "Hello".to_string()),
+            "@user:localhost".to_string(),
+            "!test:localhost".to_string(),
+        );
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "m.room.message");
+    }
+
+    #[test]
+    fn test_history_visibility_content_deserialization() {
+        let content: EventContent = serde_json::from_value(serde_json::json!({
+            "history_visibility": "joined"
+        })).unwrap();
+
+        match content {
+            EventContent::RoomHistoryVisibility(content) => {
+                assert_eq!(content.history_visibility, HistoryVisibility::Joined);
+            }
+            other => panic!("Expected history visibility content, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_room_alias() {
+        assert_eq!(parse_room_alias("#general:example.org"), Some(("general", "example.org")));
+        assert_eq!(parse_room_alias("#general:example.org:8448"), Some(("general", "example.org:8448")));
+        assert_eq!(parse_room_alias("!general:example.org"), None);
+        assert_eq!(parse_room_alias("#:example.org"), None);
+        assert_eq!(parse_room_alias("#general"), None);
+        assert_eq!(parse_room_alias("#gen eral:example.org"), None);
+    }
+
+    fn wire_event(event_type: &str, content: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "event_id": "$event",
+            "type": event_type,
+            "content": content,
+            "sender": "@alice:localhost",
+            "room_id": "!room:localhost",
+            "origin_server_ts": 1,
+            "state_key": "",
+        })
+    }
+
+    #[test]
+    fn test_event_content_parsed_by_event_type() {
+        // Shapes that used to be claimed by the all-optional power levels variant
+        let cases = [
+            ("m.room.name", serde_json::json!({ "name": "Lobby" })),
+            ("m.room.avatar", serde_json::json!({ "url": "mxc://localhost/abc" })),
+            ("m.room.canonical_alias", serde_json::json!({ "alias": "#lobby:localhost" })),
+            ("m.room.encryption", serde_json::json!({ "algorithm": "m.megolm.v1.aes-sha2" })),
+            ("m.room.guest_access", serde_json::json!({ "guest_access": "can_join" })),
+            ("m.room.server_acl", serde_json::json!({ "allow": ["*"], "deny": ["evil.example"] })),
+            ("m.room.tombstone", serde_json::json!({ "body": "moved", "replacement_room": "!new:localhost" })),
+            ("m.room.pinned_events", serde_json::json!({ "pinned": ["$event"] })),
+        ];
+        for (event_type, content) in cases {
+            let event: MatrixEvent = serde_json::from_value(wire_event(event_type, content)).unwrap();
+            let matches_type = matches!(
+                (&event.event_type, &event.content),
+                (EventType::RoomName, EventContent::RoomName(_))
+                    | (EventType::RoomAvatar, EventContent::RoomAvatar(_))
+                    | (EventType::RoomCanonicalAlias, EventContent::RoomCanonicalAlias(_))
+                    | (EventType::RoomEncryption, EventContent::RoomEncryption(_))
+                    | (EventType::RoomGuestAccess, EventContent::RoomGuestAccess(_))
+                    | (EventType::RoomServerAcl, EventContent::RoomServerAcl(_))
+                    | (EventType::RoomTombstone, EventContent::RoomTombstone(_))
+                    | (EventType::RoomPinnedEvents, EventContent::RoomPinnedEvents(_))
+            );
+            assert!(matches_type, "{} parsed as {:?}", event_type, event.content);
+        }
+    }
+
+    #[test]
+    fn test_server_acl_defaults() {
+        let content = EventContent::from_type(&EventType::RoomServerAcl, serde_json::json!({})).unwrap();
+        match content {
+            EventContent::RoomServerAcl(acl) => {
+                assert!(acl.allow.is_empty() && acl.deny.is_empty());
+                assert!(acl.allow_ip_literals);
+            }
+            other => panic!("Expected server ACL content, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_server_acl_glob_matching() {
+        let acl = RoomServerAclContent {
+            allow: vec!["*.example.org".to_string(), "matrix.local".to_string()],
+            deny: vec!["evil?.example.org".to_string()],
+            allow_ip_literals: false,
+        };
+        assert!(acl.allows("chat.example.org"));
+        assert!(acl.allows("matrix.local:8448"));
+        assert!(!acl.allows("evil1.example.org"));
+        assert!(!acl.allows("example.com"));
+        assert!(!acl.allows("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_unknown_and_malformed_content_round_trips() {
+        let custom = serde_json::json!({ "name": 42, "nested": { "keep": [1, 2, 3] } });
+        for event_type in ["org.example.custom", "m.room.name"] {
+            let json = wire_event(event_type, custom.clone());
+            let event: MatrixEvent = serde_json::from_value(json).unwrap();
+            assert!(matches!(event.content, EventContent::Raw(_)));
+            assert_eq!(serde_json::to_value(&event).unwrap()["content"], custom);
+        }
+
+        assert!(EventContent::from_type(&EventType::RoomName, custom).is_err());
+    }
 }
@@ -1,9 +1,32 @@
 // Federation Handler
 // Simplified version for Matrix chat system
 
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use ipnet::IpNet;
 use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::directory::{PublicRoomsFilter, PublicRoomsRequest, PublicRoomsResponse};
+use crate::events::{EventContent, EventType, MatrixEvent};
+use crate::media::{self, MediaContent, MediaError, ThumbnailQuery};
+use crate::presence::{shares_room_with_server, PresenceEdu, PRESENCE_EDU_TYPE};
+use crate::receipts::{receive_receipt, ReceiptEduContent, ReceiptType, RECEIPT_EDU_TYPE};
+use crate::room::{ResolveAliasResponse, RoomError};
+use crate::spaces::FederationHierarchyResponse;
+use crate::state::{Receipt, StateError};
+use crate::typing::{TypingEdu, DEFAULT_TYPING_TIMEOUT, TYPING_EDU_TYPE};
+use crate::url_preview::{self, UrlPreviewError};
+use crate::MatrixServer;
+
+/// Default number of events returned by /backfill and /get_missing_events
+const DEFAULT_FEDERATION_LIMIT: usize = 10;
+
+/// How long an outgoing federation request may take
+const FEDERATION_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Federation configuration
 #[derive(Debug, Clone)]
@@ -13,17 +36,38 @@ pub struct FederationConfig {
     pub verify_signatures: bool,
     pub federation_whitelist: Option<Vec<String>>,
     pub federation_blacklist: Option<Vec<String>>,
+    /// Base URLs of servers not reachable at `https://{server_name}`
+    pub server_addresses: HashMap<String, String>,
+    /// Addresses outgoing requests may not connect to, unless the server is
+    /// listed in `server_addresses`
+    pub ip_range_blacklist: Vec<IpNet>,
+    /// Exceptions to the blacklist
+    pub ip_range_whitelist: Vec<IpNet>,
+}
+
+impl FederationConfig {
+    /// Whether this server federates with `server_name` at all
+    pub fn is_server_allowed(&self, server_name: &str) -> bool {
+        self.federation_whitelist.as_ref().is_none_or(|whitelist| whitelist.iter().any(|s| s == server_name))
+            && !self.federation_blacklist.as_ref().is_some_and(|blacklist| blacklist.iter().any(|s| s == server_name))
+    }
 }
 
 /// Federation client for server-to-server communication
 pub struct FederationClient {
     config: FederationConfig,
-    // In production, you'd have proper HTTP client and signing here
+    http: reqwest::Client,
+    // In production, you'd have proper signing here
 }
 
 impl FederationClient {
     pub async fn new(config: FederationConfig) -> Result<Self, FederationError> {
-        Ok(Self { config })
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(FEDERATION_REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| FederationError::ConfigError(e.to_string()))?;
+        Ok(Self { config, http })
     }
 
     /// Send event to another server
@@ -37,8 +81,176 @@ impl FederationClient {
         Ok(())
     }
 
+    /// Send an ephemeral event to another server in a single-EDU transaction
+    pub async fn send_edu(&self, target_server: &str, edu: &Edu) -> Result<(), FederationError> {
+        // In production, this would PUT /_matrix/federation/v1/send/{txnId}
+        // on the target server with the EDU in the transaction's `edus`
+
+        tracing::info!("Sending {} EDU to server {}", edu.edu_type, target_server);
+        Ok(())
+    }
+
+    /// Ask `target_server` which room one of its aliases points to
+    pub async fn query_directory(
+        &self,
+        target_server: &str,
+        room_alias: &str,
+    ) -> Result<Option<ResolveAliasResponse>, FederationError> {
+        // In production, this would GET /_matrix/federation/v1/query/directory
+        // on the target server and treat M_NOT_FOUND as an unknown alias
+        tracing::info!("Querying {} for room alias {}", target_server, room_alias);
+        Ok(None)
+    }
+
+    /// Browse the public room directory of `target_server`
+    pub async fn get_public_rooms(
+        &self,
+        target_server: &str,
+        request: &PublicRoomsRequest,
+    ) -> Result<PublicRoomsResponse, FederationError> {
+        // In production, this would POST the request to
+        // /_matrix/federation/v1/publicRooms on the target server
+        tracing::info!("Requesting public rooms from {} (limit {:?})", target_server, request.limit);
+        Ok(PublicRoomsResponse::default())
+    }
+
+    /// Ask `target_server` to summarise a space it is part of
+    pub async fn get_hierarchy(
+        &self,
+        target_server: &str,
+        room_id: &str,
+        suggested_only: bool,
+    ) -> Result<Option<FederationHierarchyResponse>, FederationError> {
+        // In production, this would GET /_matrix/federation/v1/hierarchy/{roomId}
+        // on the target server and treat M_NOT_FOUND as an unknown room
+        tracing::info!("Requesting hierarchy of {} from {} (suggested only: {})", room_id, target_server, suggested_only);
+        Ok(None)
+    }
+
+    /// Fetch media stored on `origin`, refusing content over `max_size` bytes
+    pub async fn download_media(
+        &self,
+        origin: &str,
+        media_id: &str,
+        max_size: usize,
+    ) -> Result<MediaContent, FederationError> {
+        let path = format!("/_matrix/federation/v1/media/download/{}", media_id);
+        self.fetch_media(origin, &path, max_size).await
+    }
+
+    /// Fetch a thumbnail of media stored on `origin`
+    pub async fn get_media_thumbnail(
+        &self,
+        origin: &str,
+        media_id: &str,
+        query: &ThumbnailQuery,
+        max_size: usize,
+    ) -> Result<MediaContent, FederationError> {
+        let path = format!(
+            "/_matrix/federation/v1/media/thumbnail/{}?width={}&height={}&method={}",
+            media_id,
+            query.width,
+            query.height,
+            query.method.as_str(),
+        );
+        self.fetch_media(origin, &path, max_size).await
+    }
+
+    /// GET a multipart media response, following the redirect when the
+    /// origin serves the content from elsewhere
+    async fn fetch_media(&self, origin: &str, path: &str, max_size: usize) -> Result<MediaContent, FederationError> {
+        if !self.config.is_server_allowed(origin) {
+            return Err(FederationError::Forbidden(format!("Federation with {} is not allowed", origin)));
+        }
+        let url = format!("{}{}", self.server_url(origin), path);
+        let request = match self.config.server_addresses.contains_key(origin) {
+            true => self.http.get(&url),
+            false => self.pinned_get(&url).await?,
+        };
+        let response = request
+            .header(reqwest::header::AUTHORIZATION, self.authorization(origin))
+            .send()
+            .await
+            .map_err(|e| FederationError::NetworkError(e.to_string()))?;
+        let content_type = header_value(&response, reqwest::header::CONTENT_TYPE);
+        let body = read_limited(response, origin, path, max_size).await?;
+
+        let part = media::parse_multipart(&content_type, &body)
+            .ok_or_else(|| FederationError::NetworkError(format!("Malformed media response from {}", origin)))?;
+        let Some(location) = part.location else {
+            return Ok(part.content);
+        };
+
+        // The location is chosen by the origin, so it gets the same checks
+        // as any other untrusted URL
+        let response = self.pinned_get(&location)
+            .await?
+            .send()
+            .await
+            .map_err(|e| FederationError::NetworkError(e.to_string()))?;
+        let content_type = header_value(&response, reqwest::header::CONTENT_TYPE);
+        let content = read_limited(response, origin, path, max_size).await?;
+        Ok(MediaContent {
+            content,
+            content_type: if content_type.is_empty() { part.content.content_type } else { content_type },
+            filename: part.content.filename,
+        })
+    }
+
+    /// A GET of `url` from a client pinned to an address outside the IP
+    /// range blacklist, which does not follow redirects
+    async fn pinned_get(&self, url: &str) -> Result<reqwest::RequestBuilder, FederationError> {
+        let url = reqwest::Url::parse(url).map_err(|e| FederationError::NetworkError(e.to_string()))?;
+        let address = url_preview::resolve_allowed(&url, &self.config.ip_range_blacklist, &self.config.ip_range_whitelist)
+            .await
+            .map_err(|e| match e {
+                UrlPreviewError::Forbidden(reason) => FederationError::Forbidden(reason),
+                other => FederationError::NetworkError(other.to_string()),
+            })?;
+        let client = url_preview::pinned_client(&url, address)
+            .timeout(FEDERATION_REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| FederationError::NetworkError(e.to_string()))?;
+        Ok(client.get(url))
+    }
+
+    fn server_url(&self, server_name: &str) -> String {
+        match self.config.server_addresses.get(server_name) {
+            Some(address) => address.trim_end_matches('/').to_string(),
+            None => format!("https://{}", server_name),
+        }
+    }
+
+    /// `X-Matrix` authorization of a request to `destination`
+    fn authorization(&self, destination: &str) -> String {
+        // In production, `sig` would sign the request's canonical JSON
+        format!(
+            "X-Matrix origin=\"{}\",destination=\"{}\",key=\"{}\",sig=\"unverified\"",
+            self.config.server_name,
+            destination,
+            self.config.signing_key,
+        )
+    }
+
+    /// Add this server's signature to an event
+    pub fn sign_event(&self, event: &mut crate::events::MatrixEvent) {
+        // In production, this would sign the redacted canonical JSON of the
+        // event with our Ed25519 key; for now record a placeholder under our key ID
+        let key_id = if self.config.signing_key.contains(':') {
+            self.config.signing_key.clone()
+        } else {
+            format!("ed25519:{}", self.config.signing_key)
+        };
+
+        event.signatures
+            .get_or_insert_with(HashMap::new)
+            .entry(self.config.server_name.clone())
+            .or_default()
+            .insert(key_id, format!("unverified:{}", event.event_id));
+    }
+
     /// Verify event signature from another server
-    pub async fn verify_event_signature(&self, event: &crate::events::MatrixEvent, signature: &str) -> Result<bool, FederationError> {
+    pub async fn verify_event_signature(&self, _event: &crate::events::MatrixEvent, _signature: &str) -> Result<bool, FederationError> {
         if !self.config.verify_signatures {
             return Ok(true); // Skip verification if disabled
         }
@@ -69,6 +281,18 @@ pub enum FederationError {
     
     #[error("Configuration error: {0}")]
     ConfigError(String),
+    
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    
+    #[error("Media not found: {0}")]
+    MediaNotFound(String),
+    
+    #[error("Media exceeds the maximum size of {0} bytes")]
+    MediaTooLarge(usize),
+    
+    #[error("State error: {0}")]
+    StateError(#[from] StateError),
 }
 
 impl FederationError {
@@ -80,6 +304,10 @@ impl FederationError {
             FederationError::InvalidSignature => 401,
             FederationError::NetworkError(_) => 502,
             FederationError::ConfigError(_) => 500,
+            FederationError::Forbidden(_) => 403,
+            FederationError::MediaNotFound(_) => 404,
+            FederationError::MediaTooLarge(_) => 413,
+            FederationError::StateError(_) => 500,
         }
     }
 
@@ -91,10 +319,42 @@ impl FederationError {
             FederationError::InvalidSignature => "M_UNAUTHORIZED",
             FederationError::NetworkError(_) => "M_UNKNOWN",
             FederationError::ConfigError(_) => "M_UNKNOWN",
+            FederationError::Forbidden(_) => "M_FORBIDDEN",
+            FederationError::MediaNotFound(_) => "M_NOT_FOUND",
+            FederationError::MediaTooLarge(_) => "M_TOO_LARGE",
+            FederationError::StateError(_) => "M_UNKNOWN",
         }
     }
 }
 
+impl From<RoomError> for FederationError {
+    fn from(err: RoomError) -> Self {
+        match err {
+            RoomError::RoomNotFound(room_id) => FederationError::RoomNotFound(room_id),
+            RoomError::EventNotFound(event_id) => FederationError::EventNotFound(event_id),
+            RoomError::StateError(state_err) => FederationError::StateError(state_err),
+            other => FederationError::Forbidden(other.to_string()),
+        }
+    }
+}
+
+impl From<MediaError> for FederationError {
+    fn from(err: MediaError) -> Self {
+        match err {
+            MediaError::NotFound(mxc) | MediaError::NotYetUploaded(mxc) => FederationError::MediaNotFound(mxc),
+            MediaError::TooLarge(max_size) => FederationError::MediaTooLarge(max_size),
+            MediaError::State(state_err) => FederationError::StateError(state_err),
+            other => FederationError::Forbidden(other.to_string()),
+        }
+    }
+}
+
+impl axum::response::IntoResponse for FederationError {
+    fn into_response(self) -> axum::response::Response {
+        crate::error::error_response(self.status_code(), self.error_code(), self.to_string())
+    }
+}
+
 /// Processing result for federation events
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ProcessingResult {
@@ -108,6 +368,24 @@ pub struct TransactionResponse {
     pub pdus: HashMap<String, ProcessingResult>,
 }
 
+/// Ephemeral data unit, such as a typing notification
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Edu {
+    pub edu_type: String,
+    pub content: serde_json::Value,
+}
+
+/// Body of PUT /send/{txnId}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub origin: String,
+    pub origin_server_ts: u64,
+    #[serde(default)]
+    pub pdus: Vec<MatrixEvent>,
+    #[serde(default)]
+    pub edus: Vec<Edu>,
+}
+
 /// Federation API endpoints
 pub async fn get_version() -> axum::Json<serde_json::Value> {
     axum::Json(serde_json::json!({
@@ -118,19 +396,95 @@ pub async fn get_version() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn query_directory() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "room_id": "!test:matrix.local",
-        "servers": ["matrix.local"]
-    }))
+#[derive(Debug, Deserialize)]
+pub struct DirectoryQuery {
+    pub room_alias: String,
 }
 
-pub async fn get_event() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "origin": "matrix.local",
-        "origin_server_ts": 1234567890,
-        "pdus": []
-    }))
+pub async fn query_directory(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+) -> Result<Json<ResolveAliasResponse>, FederationError> {
+    request_origin(&headers)?;
+
+    // Only aliases on this server are answered; anything else is unknown here
+    let response = server.room_handler
+        .resolve_alias(&query.room_alias)
+        .await
+        .map_err(|e| match e {
+            RoomError::AliasNotFound(alias) | RoomError::InvalidAlias(alias) => FederationError::RoomNotFound(alias),
+            other => other.into(),
+        })?;
+    Ok(Json(response))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicRoomsQuery {
+    pub limit: Option<u32>,
+    pub since: Option<String>,
+}
+
+pub async fn get_public_rooms(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Query(query): Query<PublicRoomsQuery>,
+) -> Result<Json<PublicRoomsResponse>, FederationError> {
+    request_origin(&headers)?;
+
+    let request = PublicRoomsRequest {
+        limit: query.limit,
+        since: query.since,
+        filter: PublicRoomsFilter::default(),
+    };
+    Ok(Json(server.room_handler.get_public_rooms(request, true).await?))
+}
+
+pub async fn search_public_rooms(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Json(request): Json<PublicRoomsRequest>,
+) -> Result<Json<PublicRoomsResponse>, FederationError> {
+    request_origin(&headers)?;
+    Ok(Json(server.room_handler.get_public_rooms(request, true).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HierarchyQuery {
+    #[serde(default)]
+    pub suggested_only: bool,
+}
+
+pub async fn get_hierarchy(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Query(query): Query<HierarchyQuery>,
+) -> Result<Json<FederationHierarchyResponse>, FederationError> {
+    let origin = request_origin(&headers)?;
+    let response = crate::spaces::get_federation_hierarchy(
+        server.state_store.as_ref(),
+        &origin,
+        &room_id,
+        query.suggested_only,
+    ).await?;
+    Ok(Json(response))
+}
+
+pub async fn get_event(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(event_id): Path<String>,
+) -> Result<Json<serde_json::Value>, FederationError> {
+    let origin = request_origin(&headers)?;
+    // Redacted events are stored stripped, so they are served that way too
+    let event = server.room_handler.event_for_server(&origin, &event_id).await?;
+
+    Ok(Json(serde_json::json!({
+        "origin": server.server_name,
+        "origin_server_ts": now_millis(),
+        "pdus": [event]
+    })))
 }
 
 pub async fn get_room_state() -> axum::Json<serde_json::Value> {
@@ -147,16 +501,63 @@ pub async fn get_room_state_ids() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn backfill_room() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "pdus": []
-    }))
+/// Query parameters of /backfill
+#[derive(Debug, Deserialize)]
+pub struct BackfillQuery {
+    /// Event to backfill from
+    pub v: String,
+    pub limit: Option<usize>,
 }
 
-pub async fn get_missing_events() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "events": []
-    }))
+pub async fn backfill_room(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Query(query): Query<BackfillQuery>,
+) -> Result<Json<serde_json::Value>, FederationError> {
+    let origin = request_origin(&headers)?;
+
+    let pdus = server.room_handler
+        .backfill(&origin, &room_id, &query.v, query.limit.unwrap_or(DEFAULT_FEDERATION_LIMIT))
+        .await?;
+
+    Ok(Json(serde_json::json!({
+        "origin": server.server_name,
+        "origin_server_ts": now_millis(),
+        "pdus": pdus
+    })))
+}
+
+/// Body of /get_missing_events
+#[derive(Debug, Deserialize)]
+pub struct MissingEventsRequest {
+    #[serde(default)]
+    pub earliest_events: Vec<String>,
+    pub latest_events: Vec<String>,
+    pub limit: Option<usize>,
+}
+
+pub async fn get_missing_events(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(room_id): Path<String>,
+    Json(request): Json<MissingEventsRequest>,
+) -> Result<Json<serde_json::Value>, FederationError> {
+    let origin = request_origin(&headers)?;
+
+    let events = server.room_handler
+        .missing_events(
+            &origin,
+            &room_id,
+            &request.earliest_events,
+            &request.latest_events,
+            request.limit.unwrap_or(DEFAULT_FEDERATION_LIMIT),
+        )
+        .await?;
+
+    Ok(Json(serde_json::json!({
+        "events": events
+    })))
 }
 
 pub async fn get_event_auth() -> axum::Json<serde_json::Value> {
@@ -172,23 +573,58 @@ pub async fn query_profile() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn make_join() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "event": {
-            "type": "m.room.member",
-            "content": {
-                "membership": "join"
-            }
-        }
-    }))
+pub async fn make_join(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, user_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, FederationError> {
+    let origin = request_origin(&headers)?;
+    if user_id.split_once(':').map(|(_, server_name)| server_name) != Some(origin.as_str()) {
+        return Err(FederationError::Forbidden(format!("{} does not belong to {}", user_id, origin)));
+    }
+
+    let event = server.room_handler.make_join(&room_id, &user_id, &server.server_name).await?;
+    let room_version = server.state_store
+        .get_room(&room_id)
+        .await?
+        .map(|room_state| room_state.room_version);
+
+    Ok(Json(serde_json::json!({
+        "event": event,
+        "room_version": room_version
+    })))
 }
 
-pub async fn send_join() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "origin": "matrix.local",
+pub async fn send_join(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, event_id)): Path<(String, String)>,
+    Json(mut event): Json<MatrixEvent>,
+) -> Result<Json<serde_json::Value>, FederationError> {
+    let origin = request_origin(&headers)?;
+    if event.room_id != room_id || event.event_id != event_id {
+        return Err(FederationError::Forbidden("Event does not match request path".to_string()));
+    }
+    if event.sender.split_once(':').map(|(_, server_name)| server_name) != Some(origin.as_str()) {
+        return Err(FederationError::Forbidden(format!("{} does not belong to {}", event.sender, origin)));
+    }
+
+    // Vouched restricted joins carry our signature alongside the joining server's
+    if let EventContent::RoomMember(ref content) = event.content {
+        if content.join_authorised_via_users_server.is_some() {
+            server.federation_client.sign_event(&mut event);
+        }
+    }
+
+    let state = server.room_handler.send_join(event.clone(), &server.server_name).await?;
+    server.federate_event(&event).await.map_err(|e| FederationError::NetworkError(e.to_string()))?;
+
+    Ok(Json(serde_json::json!({
+        "origin": server.server_name,
         "auth_chain": [],
-        "state": []
-    }))
+        "state": state,
+        "event": event
+    })))
 }
 
 pub async fn invite() -> axum::Json<serde_json::Value> {
@@ -197,10 +633,163 @@ pub async fn invite() -> axum::Json<serde_json::Value> {
     }))
 }
 
-pub async fn send_event() -> axum::Json<serde_json::Value> {
-    axum::Json(serde_json::json!({
-        "origin": "matrix.local"
-    }))
+pub async fn send_event(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path((room_id, event_id)): Path<(String, String)>,
+    Json(event): Json<MatrixEvent>,
+) -> Result<Json<serde_json::Value>, FederationError> {
+    let origin = request_origin(&headers)?;
+    if event.room_id != room_id || event.event_id != event_id {
+        return Err(FederationError::Forbidden("Event does not match request path".to_string()));
+    }
+    if !belongs_to(&event.sender, &origin) {
+        return Err(FederationError::Forbidden(format!("{} cannot send events for {}", origin, event.sender)));
+    }
+
+    if event.event_type == EventType::RoomRedaction {
+        server.room_handler.receive_redaction(event).await?;
+    }
+
+    Ok(Json(serde_json::json!({
+        "origin": server.server_name
+    })))
+}
+
+/// Receive a transaction of PDUs and EDUs from another server
+///
+/// A failing PDU is reported in the response rather than failing the
+/// transaction; EDUs are best effort and never reported back.
+pub async fn send_transaction(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(_txn_id): Path<String>,
+    Json(transaction): Json<Transaction>,
+) -> Result<Json<TransactionResponse>, FederationError> {
+    let origin = request_origin(&headers)?;
+    if transaction.origin != origin {
+        return Err(FederationError::Forbidden("Transaction origin does not match request origin".to_string()));
+    }
+
+    let mut pdus = HashMap::new();
+    for event in transaction.pdus {
+        let event_id = event.event_id.clone();
+        let result = if !belongs_to(&event.sender, &origin) {
+            Err(RoomError::InsufficientPermissions(format!("{} cannot send events for {}", origin, event.sender)))
+        } else if event.event_type == EventType::RoomRedaction {
+            server.room_handler.receive_redaction(event).await
+        } else {
+            Ok(())
+        };
+        let result = match result {
+            Ok(()) => ProcessingResult::Success(serde_json::json!({})),
+            Err(e) => ProcessingResult::Error(e.to_string()),
+        };
+        pdus.insert(event_id, result);
+    }
+
+    for edu in transaction.edus {
+        if let Err(e) = receive_edu(&server, &origin, edu).await {
+            tracing::warn!("Dropped EDU from {}: {}", origin, e);
+        }
+    }
+
+    Ok(Json(TransactionResponse { pdus }))
+}
+
+async fn receive_edu(server: &MatrixServer, origin: &str, edu: Edu) -> Result<(), FederationError> {
+    match edu.edu_type.as_str() {
+        TYPING_EDU_TYPE => {
+            let typing: TypingEdu = serde_json::from_value(edu.content)
+                .map_err(|e| FederationError::Forbidden(format!("Invalid m.typing EDU: {}", e)))?;
+            if !belongs_to(&typing.user_id, origin) {
+                return Err(FederationError::Forbidden(format!("{} does not belong to {}", typing.user_id, origin)));
+            }
+            let room_state = server.state_store
+                .get_room(&typing.room_id)
+                .await?
+                .ok_or_else(|| FederationError::RoomNotFound(typing.room_id.clone()))?;
+            if !room_state.is_member(&typing.user_id) {
+                return Err(FederationError::Forbidden(format!("{} is not in {}", typing.user_id, typing.room_id)));
+            }
+            server.typing_handler
+                .set_typing(&typing.room_id, &typing.user_id, typing.typing, DEFAULT_TYPING_TIMEOUT)
+                .await;
+            Ok(())
+        }
+        RECEIPT_EDU_TYPE => {
+            let content: ReceiptEduContent = serde_json::from_value(edu.content)
+                .map_err(|e| FederationError::Forbidden(format!("Invalid m.receipt EDU: {}", e)))?;
+            for (room_id, receipt_types) in content {
+                // Private receipts never leave their server, so only m.read arrives here
+                let Some(users) = receipt_types.get(ReceiptType::Read.as_str()) else {
+                    continue;
+                };
+                for (user_id, user_receipt) in users {
+                    if !belongs_to(user_id, origin) {
+                        tracing::warn!("Dropped receipt of {} sent by {}", user_id, origin);
+                        continue;
+                    }
+                    let Some(event_id) = user_receipt.event_ids.last() else {
+                        continue;
+                    };
+                    receive_receipt(&*server.state_store, Receipt {
+                        room_id: room_id.clone(),
+                        receipt_type: ReceiptType::Read.as_str().to_string(),
+                        user_id: user_id.clone(),
+                        event_id: event_id.clone(),
+                        thread_id: user_receipt.data.thread_id.clone(),
+                        ts: user_receipt.data.ts,
+                    }).await?;
+                }
+            }
+            Ok(())
+        }
+        PRESENCE_EDU_TYPE => {
+            let content: PresenceEdu = serde_json::from_value(edu.content)
+                .map_err(|e| FederationError::Forbidden(format!("Invalid m.presence EDU: {}", e)))?;
+            for update in content.push {
+                if !belongs_to(&update.user_id, origin) {
+                    tracing::warn!("Dropped presence of {} sent by {}", update.user_id, origin);
+                    continue;
+                }
+                if !shares_room_with_server(&*server.state_store, &update.user_id, &server.server_name).await? {
+                    tracing::debug!("Dropped presence of {} who shares no room with us", update.user_id);
+                    continue;
+                }
+                server.presence_handler.receive_presence(update).await;
+            }
+            Ok(())
+        }
+        other => {
+            tracing::debug!("Ignoring unsupported {} EDU from {}", other, origin);
+            Ok(())
+        }
+    }
+}
+
+/// Serve local media to another server as `multipart/mixed`
+pub async fn download_media(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(media_id): Path<String>,
+    Query(query): Query<media::DownloadQuery>,
+) -> Result<axum::response::Response, FederationError> {
+    request_origin(&headers)?;
+    let timeout = query.timeout_ms.map(Duration::from_millis);
+    let content = server.media_repository.download(&server.server_name, &media_id, timeout).await?;
+    Ok(media::multipart_response(content))
+}
+
+pub async fn get_media_thumbnail(
+    State(server): State<MatrixServer>,
+    headers: HeaderMap,
+    Path(media_id): Path<String>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<axum::response::Response, FederationError> {
+    request_origin(&headers)?;
+    let content = server.media_repository.thumbnail(&server.server_name, &media_id, &query).await?;
+    Ok(media::multipart_response(content))
 }
 
 pub async fn query_keys() -> axum::Json<serde_json::Value> {
@@ -233,6 +822,68 @@ pub async fn claim_one_time_key() -> axum::Json<serde_json::Value> {
     }))
 }
 
+/// Whether `user_id` is a user of `server_name`
+fn belongs_to(user_id: &str, server_name: &str) -> bool {
+    user_id.split_once(':').is_some_and(|(_, name)| name == server_name)
+}
+
+/// Origin server named in an `Authorization: X-Matrix origin=...` header
+fn request_origin(headers: &HeaderMap) -> Result<String, FederationError> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("X-Matrix "))
+        .and_then(|params| {
+            params.split(',').find_map(|param| {
+                let (key, value) = param.trim().split_once('=')?;
+                (key == "origin").then(|| value.trim_matches('"').to_string())
+            })
+        })
+        .ok_or(FederationError::InvalidSignature)
+}
+
+fn header_value(response: &reqwest::Response, name: reqwest::header::HeaderName) -> String {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Read a media response body, giving up as soon as it exceeds `max_size`
+async fn read_limited(
+    mut response: reqwest::Response,
+    origin: &str,
+    path: &str,
+    max_size: usize,
+) -> Result<Vec<u8>, FederationError> {
+    match response.status().as_u16() {
+        200..=299 => {}
+        404 => return Err(FederationError::MediaNotFound(format!("{}{}", origin, path))),
+        413 => return Err(FederationError::MediaTooLarge(max_size)),
+        status => return Err(FederationError::NetworkError(format!("{} answered {} with {}", origin, path, status))),
+    }
+    if response.content_length().is_some_and(|length| length > max_size as u64) {
+        return Err(FederationError::MediaTooLarge(max_size));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| FederationError::NetworkError(e.to_string()))? {
+        if body.len() + chunk.len() > max_size {
+            return Err(FederationError::MediaTooLarge(max_size));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 /// Helper function to create federation error response
 pub fn federation_error_response(error: FederationError) -> axum::Json<serde_json::Value> {
     let status_code = error.status_code();
@@ -257,6 +908,9 @@ mod tests {
             verify_signatures: true,
             federation_whitelist: Some(vec!["trusted.server.com".to_string()]),
             federation_blacklist: Some(vec!["blocked.server.com".to_string()]),
+            server_addresses: HashMap::new(),
+            ip_range_blacklist: url_preview::default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
         }
     }
 
@@ -269,6 +923,11 @@ mod tests {
                 relates_to: None,
                 format: None,
                 formatted_body: None,
+                new_content: None,
+                mentions: None,
+                url: None,
+                info: None,
+                filename: None,
             }),
             "!testroom:test.server.com".to_string(),
             "@testuser:test.server.com".to_string(),
@@ -343,12 +1002,15 @@ mod tests {
             verify_signatures: false,
             federation_whitelist: None,
             federation_blacklist: None,
+            server_addresses: HashMap::new(),
+            ip_range_blacklist: url_preview::default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
         };
         
         // Should be valid even with empty strings
         assert_eq!(config.server_name, "");
         assert_eq!(config.signing_key, "");
-        assert_eq!(config.verify_signatures, false);
+        assert!(!config.verify_signatures);
     }
 
     #[test]
@@ -362,11 +1024,14 @@ mod tests {
                 "trusted2.server.com".to_string(),
             ]),
             federation_blacklist: None,
+            server_addresses: HashMap::new(),
+            ip_range_blacklist: url_preview::default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
         };
         
         assert_eq!(config.server_name, "test.server.com");
         assert_eq!(config.signing_key, "ed25519:test_key");
-        assert_eq!(config.verify_signatures, true);
+        assert!(config.verify_signatures);
         assert_eq!(config.federation_whitelist.as_ref().unwrap().len(), 2);
         assert!(config.federation_blacklist.is_none());
     }
@@ -382,11 +1047,14 @@ mod tests {
                 "blocked1.server.com".to_string(),
                 "blocked2.server.com".to_string(),
             ]),
+            server_addresses: HashMap::new(),
+            ip_range_blacklist: url_preview::default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
         };
         
         assert_eq!(config.server_name, "test.server.com");
         assert_eq!(config.signing_key, "ed25519:test_key");
-        assert_eq!(config.verify_signatures, true);
+        assert!(config.verify_signatures);
         assert!(config.federation_whitelist.is_none());
         assert_eq!(config.federation_blacklist.as_ref().unwrap().len(), 2);
     }
@@ -425,7 +1093,7 @@ mod tests {
         let deserialized: ProcessingResult = serde_json::from_str(&serialized).unwrap();
         
         match deserialized {
-            ProcessingResult::Success(_) => assert!(true),
+            ProcessingResult::Success(_) => {}
             _ => panic!("Expected Success variant"),
         }
     }
@@ -457,4 +1125,71 @@ mod tests {
         assert!(debug.contains("NetworkError"));
         assert!(debug.contains("connection failed"));
     }
+
+    #[test]
+    fn test_request_origin_from_x_matrix_header() {
+        let mut headers = HeaderMap::new();
+        assert!(matches!(request_origin(&headers), Err(FederationError::InvalidSignature)));
+
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            r#"X-Matrix origin="remote.example",destination="matrix.local",key="ed25519:1",sig="abc""#.parse().unwrap(),
+        );
+        assert_eq!(request_origin(&headers).unwrap(), "remote.example");
+    }
+
+    #[tokio::test]
+    async fn test_sign_event_adds_server_signature() {
+        let client = FederationClient::new(create_test_config()).await.unwrap();
+        let mut event = create_test_event();
+
+        client.sign_event(&mut event);
+
+        let signatures = event.signatures.unwrap();
+        assert!(signatures["test.server.com"].contains_key("ed25519:test_key"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_media_refuses_internal_locations() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // The origin answers with a redirect part pointing at a loopback service
+        let secret_hits = Arc::new(AtomicUsize::new(0));
+        let hits = secret_hits.clone();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stub = axum::Router::new()
+            .route("/_matrix/federation/v1/media/download/:media_id", axum::routing::get(move || async move {
+                let body = format!(
+                    "--b\r\nContent-Type: application/json\r\n\r\n{{}}\r\n--b\r\nLocation: http://{}/secret\r\n\r\n\r\n--b--\r\n",
+                    address,
+                );
+                ([(axum::http::header::CONTENT_TYPE, "multipart/mixed; boundary=b")], body)
+            }))
+            .route("/secret", axum::routing::get(move || async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                "secret"
+            }));
+        tokio::spawn(async move { axum::serve(listener, stub).await });
+
+        let mut config = create_test_config();
+        config.federation_whitelist = None;
+        config.server_addresses.insert("origin.example".to_string(), format!("http://{}", address));
+        let client = FederationClient::new(config.clone()).await.unwrap();
+        let result = client.download_media("origin.example", "abc", 1024).await;
+        assert!(matches!(result, Err(FederationError::Forbidden(_))));
+        assert_eq!(secret_hits.load(Ordering::SeqCst), 0);
+
+        // Server names are not a way to address internal services either
+        let result = client.download_media("127.0.0.1:6379", "abc", 1024).await;
+        assert!(matches!(result, Err(FederationError::Forbidden(_))));
+        let result = client.download_media("blocked.server.com", "abc", 1024).await;
+        assert!(matches!(result, Err(FederationError::Forbidden(_))));
+
+        config.federation_whitelist = Some(vec!["trusted.server.com".to_string()]);
+        let client = FederationClient::new(config).await.unwrap();
+        let result = client.download_media("origin.example", "abc", 1024).await;
+        assert!(matches!(result, Err(FederationError::Forbidden(_))));
+    }
 }
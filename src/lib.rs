@@ -2,11 +2,27 @@
 // Simplified working version that demonstrates compilation
 
 pub mod auth;
+pub mod account_data;
 pub mod room;
 pub mod federation;
 pub mod client_server;
 pub mod events;
+pub mod media;
+pub mod notifications;
+pub mod presence;
+pub mod push_rules;
+pub mod pushers;
+pub mod relations;
+pub mod receipts;
+pub mod search;
+pub mod directory;
+pub mod direct;
+pub mod spaces;
+pub mod visibility;
 pub mod state;
+pub mod sync;
+pub mod typing;
+pub mod url_preview;
 pub mod error;
 pub mod conduit;
 
@@ -17,21 +33,48 @@ pub use federation::{FederationClient, FederationError};
 pub use client_server::{ClientServerAPI, ClientError};
 pub use events::{MatrixEvent, EventType, EventContent};
 pub use state::{RoomState, StateStore, StateError};
+pub use sync::SyncHandler;
+pub use typing::TypingHandler;
+pub use presence::PresenceHandler;
+pub use pushers::PushSender;
+pub use media::{MediaRepository, MediaConfig, MediaError};
+pub use url_preview::UrlPreviewer;
 pub use error::{MatrixServerError, Result};
 pub use conduit::{ConduitServer, ConduitConfig, ConduitError};
 
 use std::sync::Arc;
+use std::time::Duration;
 use axum::{routing::*, Router};
 
+/// How often retained originals of redacted events are checked for purging
+const REDACTION_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often presence idle and offline timeouts are applied
+const PRESENCE_TIMEOUT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often pending notifications are handed to push gateways
+const PUSH_SEND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the remote media cache's eviction policy is applied
+const MEDIA_CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(300);
+
 /// Main Matrix server instance
 /// Coordinates all components like Synapse's main application
 #[derive(Clone)]
 pub struct MatrixServer {
     pub auth_handler: Arc<OIDCHandler>,
     pub room_handler: Arc<RoomHandler>,
+    pub sync_handler: Arc<SyncHandler>,
+    pub typing_handler: Arc<TypingHandler>,
+    pub presence_handler: Arc<PresenceHandler>,
+    pub push_sender: Arc<PushSender>,
+    pub media_repository: Arc<MediaRepository>,
+    pub url_previewer: Arc<UrlPreviewer>,
     pub federation_client: Arc<FederationClient>,
     pub state_store: Arc<dyn StateStore + Send + Sync>,
     pub server_name: String,
+    /// OIDC role required to use the admin API; without one it is disabled
+    pub admin_role: Option<String>,
 }
 
 impl MatrixServer {
@@ -45,20 +88,49 @@ impl MatrixServer {
             OIDCHandler::new(config.oidc_config).await?
         );
         
-        let room_handler = Arc::new(
-            RoomHandler::new(state_store.clone())
+        let mut room_handler = RoomHandler::new(state_store.clone())
+            .with_server_name(config.server_name.clone());
+        if let Some(role) = config.directory_publish_role {
+            room_handler = room_handler.with_directory_publish_role(role);
+        }
+        if let Some(retention) = config.redaction_retention {
+            room_handler = room_handler.with_redaction_retention(retention);
+        }
+        let room_handler = Arc::new(room_handler);
+
+        let typing_handler = Arc::new(TypingHandler::new());
+        let presence_handler = Arc::new(PresenceHandler::new(config.presence_enabled));
+
+        let sync_handler = Arc::new(
+            SyncHandler::new(state_store.clone())
+                .with_typing_handler(typing_handler.clone())
+                .with_presence_handler(presence_handler.clone())
         );
         
+        let push_sender = Arc::new(PushSender::new(state_store.clone(), config.pusher_config));
         let federation_client = Arc::new(
             FederationClient::new(config.federation_config).await?
         );
 
+        let media_repository = Arc::new(
+            MediaRepository::new(state_store.clone(), config.media_config, config.server_name.clone())
+                .with_federation_client(federation_client.clone())
+        );
+        let url_previewer = Arc::new(UrlPreviewer::new(config.url_preview_config, media_repository.clone()));
+
         Ok(MatrixServer {
             auth_handler,
             room_handler,
+            sync_handler,
+            typing_handler,
+            presence_handler,
+            push_sender,
+            media_repository,
+            url_previewer,
             federation_client,
             state_store,
             server_name: config.server_name,
+            admin_role: config.admin_role,
         })
     }
 
@@ -67,6 +139,17 @@ impl MatrixServer {
         let app = self.create_router().await?;
         
         tracing::info!("Starting Matrix server on {}", bind_addr);
+
+        if let Some(retention) = self.room_handler.redaction_retention() {
+            self.spawn_redaction_purge(retention);
+        }
+        if self.presence_handler.is_enabled() {
+            self.spawn_presence_timeouts();
+        }
+        self.spawn_push_sender();
+        if self.media_repository.has_cache_eviction() {
+            self.spawn_media_cache_eviction();
+        }
         
         let listener = tokio::net::TcpListener::bind(bind_addr).await
             .map_err(|e| MatrixServerError::NetworkError(e.to_string()))?;
@@ -77,12 +160,149 @@ impl MatrixServer {
         Ok(())
     }
 
+    /// Periodically purge original content of redacted events past retention
+    fn spawn_redaction_purge(&self, retention: Duration) {
+        let room_handler = self.room_handler.clone();
+        let period = REDACTION_PURGE_INTERVAL.min(retention).max(Duration::from_secs(1));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match room_handler.purge_redacted_content().await {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!("Purged {} redacted event originals", purged),
+                    Err(e) => tracing::warn!("Failed to purge redacted event originals: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Periodically move idle users to unavailable and absent users offline
+    fn spawn_presence_timeouts(&self) {
+        let server = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRESENCE_TIMEOUT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let changed = server.presence_handler.apply_timeouts(presence::now_millis()).await;
+                for user_id in changed {
+                    if let Err(e) = server.federate_presence(&user_id).await {
+                        tracing::warn!("Failed to send presence of {}: {}", user_id, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Continuously deliver new notifications to users' push gateways
+    fn spawn_push_sender(&self) {
+        let push_sender = self.push_sender.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PUSH_SEND_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = push_sender.process_pending().await {
+                    tracing::warn!("Failed to send pending push notifications: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Periodically evict remote media past the cache's age or size limits
+    fn spawn_media_cache_eviction(&self) {
+        let media_repository = self.media_repository.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MEDIA_CACHE_EVICTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                match media_repository.evict_remote_cache().await {
+                    Ok(0) => {}
+                    Ok(evicted) => tracing::info!("Evicted {} cached remote media", evicted),
+                    Err(e) => tracing::warn!("Failed to evict cached remote media: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Send a locally created event to every other server in its room
+    pub async fn federate_event(&self, event: &MatrixEvent) -> Result<()> {
+        let Some(room_state) = self.state_store.get_room(&event.room_id).await?.filter(|room_state| room_state.federates()) else {
+            return Ok(());
+        };
+
+        for server in room_state.servers() {
+            if server == self.server_name {
+                continue;
+            }
+            if let Err(e) = self.federation_client.send_event(&server, event).await {
+                tracing::warn!("Failed to send event {} to {}: {}", event.event_id, server, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send an EDU about `room_id` to every other server in the room
+    pub async fn federate_edu(&self, room_id: &str, edu: &federation::Edu) -> Result<()> {
+        let Some(room_state) = self.state_store.get_room(room_id).await?.filter(|room_state| room_state.federates()) else {
+            return Ok(());
+        };
+
+        for server in room_state.servers() {
+            if server == self.server_name {
+                continue;
+            }
+            if let Err(e) = self.federation_client.send_edu(&server, edu).await {
+                tracing::warn!("Failed to send {} EDU to {}: {}", edu.edu_type, server, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a local user's presence to every other server sharing a room with them
+    pub async fn federate_presence(&self, user_id: &str) -> Result<()> {
+        let update = presence::UserPresenceUpdate {
+            user_id: user_id.to_string(),
+            status: self.presence_handler.get_presence(user_id).await,
+        };
+        let edu = federation::Edu {
+            edu_type: presence::PRESENCE_EDU_TYPE.to_string(),
+            content: serde_json::json!(presence::PresenceEdu { push: vec![update] }),
+        };
+
+        let mut servers = std::collections::BTreeSet::new();
+        for room_id in self.state_store.get_rooms_for_user(user_id).await? {
+            let Some(room_state) = self.state_store.get_room(&room_id).await? else {
+                continue;
+            };
+            if room_state.federates() && room_state.is_member(user_id) {
+                servers.extend(room_state.servers());
+            }
+        }
+        servers.remove(&self.server_name);
+
+        for server in servers {
+            if let Err(e) = self.federation_client.send_edu(&server, &edu).await {
+                tracing::warn!("Failed to send {} EDU to {}: {}", edu.edu_type, server, e);
+            }
+        }
+        Ok(())
+    }
+
     async fn create_router(&self) -> Result<axum::Router> {
         Ok(Router::new()
             // Client-Server API (/_matrix/client/*)
             .nest("/_matrix/client", self.client_server_routes())
             // Server-Server API (/_matrix/federation/*)
             .nest("/_matrix/federation", self.federation_routes())
+            // Media repository (/_matrix/media/*), superseded by the
+            // authenticated /_matrix/client/v1/media endpoints
+            .nest("/_matrix/media", self.media_routes())
+            // Admin API (/_synapse/admin/*)
+            .nest("/_synapse/admin", self.admin_routes())
             // Health check
             .route("/health", get(|| async { "OK" }))
             .with_state(self.clone()))
@@ -94,18 +314,120 @@ impl MatrixServer {
             .route("/v3/logout", post(client_server::logout))
             .route("/v3/rooms/:room_id/send/:event_type/:txn_id", put(client_server::send_message))
             .route("/v3/rooms/:room_id/messages", get(client_server::get_messages))
+            .route("/v3/rooms/:room_id/context/:event_id", get(client_server::get_room_event_context))
+            .route("/v3/rooms/:room_id/redact/:event_id/:txn_id", put(client_server::redact_event))
+            .route("/v3/rooms/:room_id/typing/:user_id", put(client_server::set_typing))
+            .route(
+                "/v3/presence/:user_id/status",
+                get(client_server::get_presence).put(client_server::set_presence),
+            )
+            .route("/v3/rooms/:room_id/receipt/:receipt_type/:event_id", post(client_server::post_receipt))
+            .route("/v3/rooms/:room_id/read_markers", post(client_server::set_read_markers))
+            .route("/v3/rooms/:room_id/state", get(client_server::get_room_state))
+            .route("/v3/rooms/:room_id/members", get(client_server::get_room_members))
+            .route("/v3/rooms/:room_id/joined_members", get(client_server::get_joined_members))
+            .route(
+                "/v3/rooms/:room_id/state/:event_type",
+                get(client_server::get_room_state_event).put(client_server::put_room_state_event),
+            )
+            .route(
+                "/v3/rooms/:room_id/state/:event_type/:state_key",
+                get(client_server::get_room_state_event).put(client_server::put_room_state_event),
+            )
+            .route("/v1/rooms/:room_id/relations/:event_id", get(client_server::get_room_event_relations))
+            .route("/v1/rooms/:room_id/relations/:event_id/:rel_type", get(client_server::get_room_event_relations))
+            .route("/v1/rooms/:room_id/relations/:event_id/:rel_type/:event_type", get(client_server::get_room_event_relations))
+            .route("/v1/rooms/:room_id/threads", get(client_server::get_threads))
+            .route("/v1/rooms/:room_id/hierarchy", get(client_server::get_hierarchy))
+            .route("/v3/createRoom", post(client_server::create_room))
+            .route("/v3/user/:user_id/dm/:other_user_id", post(client_server::get_or_create_direct_room))
             .route("/v3/rooms/:room_id/join", post(client_server::join_room))
             .route("/v3/rooms/:room_id/leave", post(client_server::leave_room))
+            .route("/v3/join/:room_id_or_alias", post(client_server::join_room))
+            .route("/v3/knock/:room_id_or_alias", post(client_server::knock_room))
+            .route("/v3/rooms/:room_id/forget", post(client_server::forget_room))
+            .route("/v3/rooms/:room_id/invite", post(client_server::invite_user))
+            .route("/v3/rooms/:room_id/kick", post(client_server::kick_user))
+            .route("/v3/rooms/:room_id/ban", post(client_server::ban_user))
+            .route("/v3/rooms/:room_id/unban", post(client_server::unban_user))
+            .route("/v3/rooms/:room_id/aliases", get(client_server::get_room_aliases))
+            .route(
+                "/v3/directory/room/:room_alias",
+                get(client_server::get_room_id_by_alias)
+                    .put(client_server::create_room_alias)
+                    .delete(client_server::delete_room_alias),
+            )
+            .route("/v3/publicRooms", get(client_server::get_public_rooms).post(client_server::search_public_rooms))
+            .route(
+                "/v3/directory/list/room/:room_id",
+                get(client_server::get_room_visibility).put(client_server::set_room_visibility),
+            )
+            .route(
+                "/v3/user/:user_id/account_data/:type",
+                get(client_server::get_account_data).put(client_server::set_account_data),
+            )
+            .route(
+                "/v3/user/:user_id/rooms/:room_id/account_data/:type",
+                get(client_server::get_account_data).put(client_server::set_account_data),
+            )
+            .route("/v3/user/:user_id/rooms/:room_id/tags", get(client_server::get_room_tags))
+            .route(
+                "/v3/user/:user_id/rooms/:room_id/tags/:tag",
+                put(client_server::put_room_tag).delete(client_server::delete_room_tag),
+            )
+            .route("/v3/pushrules/", get(client_server::get_push_rules))
+            .route("/v3/pushrules", get(client_server::get_push_rules))
+            .route(
+                "/v3/pushrules/:scope/:kind/:rule_id",
+                get(client_server::get_push_rule)
+                    .put(client_server::set_push_rule)
+                    .delete(client_server::delete_push_rule),
+            )
+            .route(
+                "/v3/pushrules/:scope/:kind/:rule_id/enabled",
+                get(client_server::get_push_rule_enabled).put(client_server::set_push_rule_enabled),
+            )
+            .route(
+                "/v3/pushrules/:scope/:kind/:rule_id/actions",
+                get(client_server::get_push_rule_actions).put(client_server::set_push_rule_actions),
+            )
+            .route("/v3/notifications", get(client_server::get_notifications))
+            .route("/v3/search", post(client_server::search))
+            .route("/v3/pushers", get(client_server::get_pushers))
+            .route("/v3/pushers/set", post(client_server::set_pusher))
+            .route("/v1/media/config", get(client_server::get_media_config))
+            .route("/v1/media/upload", post(client_server::upload_media))
+            .route("/v1/media/create", post(client_server::create_media))
+            .route("/v1/media/download/:server_name/:media_id", get(client_server::download_media))
+            .route("/v1/media/download/:server_name/:media_id/:file_name", get(client_server::download_media))
+            .route("/v1/media/thumbnail/:server_name/:media_id", get(client_server::get_media_thumbnail))
+            .route("/v1/media/preview_url", get(client_server::preview_url))
             .route("/v3/sync", get(client_server::sync))
             .route("/v3/account/whoami", get(client_server::whoami))
             .route("/v3/rooms", get(client_server::list_rooms))
             .route("/v3/support/request", post(client_server::create_support_request))
     }
 
+    fn media_routes(&self) -> Router<MatrixServer> {
+        Router::new()
+            .route("/v3/config", get(client_server::get_media_config))
+            .route("/v3/upload", post(client_server::upload_media))
+            .route("/v3/upload/:server_name/:media_id", put(client_server::upload_media_to))
+            .route("/v3/preview_url", get(client_server::preview_url))
+            .route("/v1/create", post(client_server::create_media))
+    }
+
+    fn admin_routes(&self) -> Router<MatrixServer> {
+        Router::new()
+            .route("/v1/purge_media_cache", post(client_server::purge_media_cache))
+    }
+
     fn federation_routes(&self) -> Router<MatrixServer> {
         Router::new()
             .route("/v1/version", get(federation::get_version))
             .route("/v1/query/directory", get(federation::query_directory))
+            .route("/v1/hierarchy/:room_id", get(federation::get_hierarchy))
+            .route("/v1/publicRooms", get(federation::get_public_rooms).post(federation::search_public_rooms))
             .route("/v1/event/:event_id", get(federation::get_event))
             .route("/v1/state/:room_id", get(federation::get_room_state))
             .route("/v1/state_ids/:room_id", get(federation::get_room_state_ids))
@@ -113,17 +435,18 @@ impl MatrixServer {
             .route("/v1/get_missing_events/:room_id", post(federation::get_missing_events))
             .route("/v1/event_auth/:room_id/:event_id", get(federation::get_event_auth))
             .route("/v1/query/profile", get(federation::query_profile))
-            .route("/v1/make_join/:room_id/:user_id", post(federation::make_join))
+            .route("/v1/make_join/:room_id/:user_id", get(federation::make_join))
             .route("/v1/send_join/:room_id/:event_id", put(federation::send_join))
             .route("/v1/invite/:room_id/:event_id", put(federation::invite))
             .route("/v1/event/:room_id/:event_id", put(federation::send_event))
-            .route("/v1/query/directory", get(federation::query_directory))
-            .route("/v1/query/profile", get(federation::query_profile))
+            .route("/v1/send/:txn_id", put(federation::send_transaction))
             .route("/v1/query/keys", post(federation::query_keys))
             .route("/v1/query/client_keys", post(federation::query_client_keys))
             .route("/v1/user/keys/query", post(federation::query_user_keys))
             .route("/v1/user/devices/:user_id", get(federation::get_user_devices))
             .route("/v1/claim/e2e_one_time_key", post(federation::claim_one_time_key))
+            .route("/v1/media/download/:media_id", get(federation::download_media))
+            .route("/v1/media/thumbnail/:media_id", get(federation::get_media_thumbnail))
     }
 }
 
@@ -133,6 +456,20 @@ pub struct ServerConfig {
     pub server_name: String,
     pub oidc_config: auth::OIDCConfig,
     pub federation_config: federation::FederationConfig,
+    /// Keep original content of redacted events this long before purging
+    pub redaction_retention: Option<Duration>,
+    /// OIDC role required to publish rooms to the public room directory
+    pub directory_publish_role: Option<String>,
+    /// Track and share user presence; off for privacy-sensitive deployments
+    pub presence_enabled: bool,
+    /// Media storage location, upload size limit and per-user quota
+    pub media_config: media::MediaConfig,
+    /// Limits on fetching pages and images for URL previews
+    pub url_preview_config: url_preview::UrlPreviewConfig,
+    /// Addresses push gateways may be reached at
+    pub pusher_config: pushers::PusherConfig,
+    /// OIDC role required to use the admin API; without one it is disabled
+    pub admin_role: Option<String>,
 }
 
 /// Well-known endpoints for Matrix discovery
@@ -152,3 +489,1091 @@ pub async fn well_known_client() -> axum::Json<serde_json::Value> {
         }
     }))
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) async fn create_test_server() -> MatrixServer {
+        MatrixServer::new(test_config()).await.unwrap()
+    }
+
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            server_name: "test.local".to_string(),
+            oidc_config: auth::OIDCConfig {
+                issuer_url: "https://test-issuer.com".to_string(),
+                client_id: "test-client".to_string(),
+                client_secret: "test-secret".to_string(),
+                redirect_url: "http://localhost:8000/callback".to_string(),
+                scopes: vec!["openid".to_string()],
+                server_name: "test.local".to_string(),
+                user_roles: std::collections::HashMap::new(),
+            },
+            federation_config: federation::FederationConfig {
+                server_name: "test.local".to_string(),
+                signing_key: "test-key".to_string(),
+                verify_signatures: false,
+                federation_whitelist: None,
+                federation_blacklist: None,
+                server_addresses: std::collections::HashMap::new(),
+                ip_range_blacklist: crate::url_preview::default_ip_range_blacklist(),
+                ip_range_whitelist: Vec::new(),
+            },
+            redaction_retention: None,
+            directory_publish_role: None,
+            presence_enabled: true,
+            media_config: media::MediaConfig {
+                media_path: std::env::temp_dir().join(format!("matrix-media-{}", uuid::Uuid::new_v4())),
+                max_upload_size: 1024 * 1024,
+                user_quota: None,
+                max_image_pixels: media::DEFAULT_MAX_IMAGE_PIXELS,
+                remote: media::RemoteMediaConfig::default(),
+            },
+            url_preview_config: url_preview::UrlPreviewConfig::default(),
+            pusher_config: pushers::PusherConfig::default(),
+            admin_role: None,
+        }
+    }
+
+    /// Send a request through the full router as the user behind `token`
+    pub(crate) async fn request(
+        server: &MatrixServer,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> (u16, serde_json::Value) {
+        use tower::ServiceExt;
+
+        let mut builder = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+
+        let router = server.create_router().await.unwrap();
+        let response = router.oneshot(builder.body(axum::body::Body::from(body)).unwrap()).await.unwrap();
+        let status = response.status().as_u16();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null))
+    }
+
+    /// Create a room as `creator`, returning its ID
+    async fn create_room(server: &MatrixServer, creator: &str, body: serde_json::Value) -> String {
+        let (status, created) = request(server, "POST", "/_matrix/client/v3/createRoom", Some(creator), Some(body)).await;
+        assert_eq!(status, 200);
+        created["room_id"].as_str().unwrap().to_string()
+    }
+
+    /// Join `room_id` as `user`, returning the response status
+    async fn join_room(server: &MatrixServer, user: &str, room_id: &str) -> u16 {
+        let uri = format!("/_matrix/client/v3/rooms/{}/join", room_id);
+        request(server, "POST", &uri, Some(user), Some(serde_json::json!({}))).await.0
+    }
+
+    /// Send an `m.text` message as `sender`, returning its event ID
+    async fn send_text(server: &MatrixServer, sender: &str, room_id: &str, txn_id: &str, body: &str) -> String {
+        let uri = format!("/_matrix/client/v3/rooms/{}/send/m.room.message/{}", room_id, txn_id);
+        let message = serde_json::json!({ "msgtype": "m.text", "body": body });
+        let (status, sent) = request(server, "PUT", &uri, Some(sender), Some(message)).await;
+        assert_eq!(status, 200);
+        sent["event_id"].as_str().unwrap().to_string()
+    }
+
+    /// Record `user_id` of another server as joined to `room_id`
+    async fn add_remote_member(server: &MatrixServer, room_id: &str, user_id: &str) {
+        let mut room_state = server.state_store.get_room(room_id).await.unwrap().unwrap();
+        room_state.members.insert(user_id.to_string(), events::MembershipState::Join);
+        server.state_store.update_room(room_state).await.unwrap();
+    }
+
+    /// Authorization headers of a federation request from `origin`
+    fn origin_headers(origin: &str) -> axum::http::HeaderMap {
+        let mut headers = axum::http::HeaderMap::new();
+        let authorization = format!(r#"X-Matrix origin="{}""#, origin);
+        headers.insert(axum::http::header::AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    /// Deliver a federation transaction from `origin`
+    async fn send_transaction(
+        server: &MatrixServer,
+        origin: &str,
+        pdus: Vec<events::MatrixEvent>,
+        edus: Vec<federation::Edu>,
+    ) -> federation::TransactionResponse {
+        let transaction = federation::Transaction {
+            origin: origin.to_string(),
+            origin_server_ts: 0,
+            pdus,
+            edus,
+        };
+        let axum::Json(response) = federation::send_transaction(
+            axum::extract::State(server.clone()),
+            origin_headers(origin),
+            axum::extract::Path("txn1".to_string()),
+            axum::Json(transaction),
+        ).await.unwrap();
+        response
+    }
+
+    pub(crate) fn test_user(user_id: &str) -> AuthenticatedUser {
+        AuthenticatedUser {
+            user_id: user_id.to_string(),
+            access_token: user_id.to_string(),
+            device_id: "device_123".to_string(),
+            subscription_active: true,
+            scopes: vec![],
+            roles: vec![],
+        }
+    }
+
+    pub(crate) fn text_message(room_id: &str, body: &str) -> room::SendMessageRequest {
+        room::SendMessageRequest {
+            room_id: room_id.to_string(),
+            msgtype: events::MessageType::Text,
+            body: body.to_string(),
+            formatted_body: None,
+            format: None,
+            relates_to: None,
+            new_content: None,
+            mentions: None,
+            url: None,
+            info: None,
+            filename: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router_builds() {
+        let server = create_test_server().await;
+        assert!(server.create_router().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_and_redact_over_http() {
+        let server = create_test_server().await;
+        let alice = test_user("user_alice");
+        let room_id = server.room_handler
+            .create_room(&alice, room::RoomConfig::default())
+            .await
+            .unwrap()
+            .room_id;
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/send/m.room.message/txn1", room_id);
+        let body = serde_json::json!({ "msgtype": "m.text", "body": "hello" });
+        let (status, sent) = request(&server, "PUT", &uri, Some("user_alice"), Some(body.clone())).await;
+        assert_eq!(status, 200);
+
+        // Retrying the transaction returns the same event
+        let (_, retried) = request(&server, "PUT", &uri, Some("user_alice"), Some(body)).await;
+        assert_eq!(sent["event_id"], retried["event_id"]);
+
+        // Transaction IDs are scoped to the endpoint, so reusing one for a redaction still redacts
+        let event_id = sent["event_id"].as_str().unwrap();
+        let uri = format!("/_matrix/client/v3/rooms/{}/redact/{}/txn1", room_id, event_id);
+        let (status, _) = request(&server, "PUT", &uri, Some("user_bob"), Some(serde_json::json!({}))).await;
+        assert_eq!(status, 403);
+
+        let (status, redacted) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "reason": "typo" }))).await;
+        assert_eq!(status, 200);
+        assert_ne!(redacted["event_id"], sent["event_id"]);
+        let (_, retried) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "reason": "typo" }))).await;
+        assert_eq!(retried["event_id"], redacted["event_id"]);
+        assert!(server.state_store.get_event(event_id).await.unwrap().unwrap().is_redacted());
+
+        let uri = format!("/_matrix/federation/v1/event/{}", event_id);
+        let (status, _) = request(&server, "GET", &uri, None, None).await;
+        assert_eq!(status, 401);
+
+        // Only servers with members in the room can fetch its events
+        add_remote_member(&server, &room_id, "@bob:remote.example").await;
+        let get_event = |origin: &str| federation::get_event(
+            axum::extract::State(server.clone()),
+            origin_headers(origin),
+            axum::extract::Path(event_id.to_string()),
+        );
+        let result = get_event("elsewhere.example").await;
+        assert!(matches!(result, Err(federation::FederationError::EventNotFound(_))));
+
+        let axum::Json(body) = get_event("remote.example").await.unwrap();
+        assert_eq!(body["pdus"][0]["content"], serde_json::json!({}));
+        assert_eq!(body["pdus"][0]["unsigned"]["redacted_because"]["content"]["reason"], "typo");
+    }
+
+    #[tokio::test]
+    async fn test_federated_redaction_requires_origin() {
+        let server = create_test_server().await;
+        let room_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+        let event_id = send_text(&server, "user_alice", &room_id, "txn1", "hello").await;
+
+        add_remote_member(&server, &room_id, "@mallory:evil.example").await;
+        let room_state_version = server.state_store.get_room(&room_id).await.unwrap().unwrap().room_version;
+
+        let redaction = |sender: &str| events::MatrixEvent::redaction(
+            &event_id,
+            None,
+            sender.to_string(),
+            room_id.clone(),
+            &room_state_version,
+        );
+        let send = |headers: axum::http::HeaderMap, event: events::MatrixEvent| federation::send_event(
+            axum::extract::State(server.clone()),
+            headers,
+            axum::extract::Path((event.room_id.clone(), event.event_id.clone())),
+            axum::Json(event),
+        );
+
+        let result = send(axum::http::HeaderMap::new(), redaction("@mallory:evil.example")).await;
+        assert!(matches!(result, Err(federation::FederationError::InvalidSignature)));
+
+        let result = send(origin_headers("evil.example"), redaction("user_alice")).await;
+        assert!(matches!(result, Err(federation::FederationError::Forbidden(_))));
+        assert!(send(origin_headers("evil.example"), redaction("@mallory:evil.example")).await.is_err());
+
+        // Transactions report PDUs sent on behalf of other servers' users
+        let forged = redaction("user_alice");
+        let response = send_transaction(&server, "evil.example", vec![forged.clone()], vec![]).await;
+        assert!(matches!(response.pdus[&forged.event_id], federation::ProcessingResult::Error(_)));
+
+        let event = server.state_store.get_event(&event_id).await.unwrap().unwrap();
+        assert!(!event.is_redacted());
+    }
+
+    #[tokio::test]
+    async fn test_invite_and_join_over_http() {
+        let server = create_test_server().await;
+        let alice = test_user("user_alice");
+        let room_id = server.room_handler
+            .create_room(&alice, room::RoomConfig {
+                preset: Some(room::RoomPreset::PrivateChat),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .room_id;
+
+        assert_eq!(join_room(&server, "user_bob", &room_id).await, 403);
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/invite", room_id);
+        let body = serde_json::json!({ "user_id": "user_bob", "reason": "welcome" });
+        let (status, _) = request(&server, "POST", &uri, Some("user_alice"), Some(body)).await;
+        assert_eq!(status, 200);
+
+        let join_uri = format!("/_matrix/client/v3/rooms/{}/join", room_id);
+        let (status, joined) = request(&server, "POST", &join_uri, Some("user_bob"), Some(serde_json::json!({}))).await;
+        assert_eq!(status, 200);
+        assert_eq!(joined["room_id"], room_id);
+    }
+
+    #[tokio::test]
+    async fn test_alias_directory_over_http() {
+        let server = create_test_server().await;
+        let alice = test_user("user_alice");
+        let room_id = server.room_handler
+            .create_room(&alice, room::RoomConfig {
+                room_alias_name: Some("lobby".to_string()),
+                preset: Some(room::RoomPreset::PublicChat),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .room_id;
+        assert!(room_id.starts_with('!') && room_id.ends_with(":test.local"));
+
+        let (status, resolved) = request(&server, "GET", "/_matrix/client/v3/directory/room/%23lobby:test.local", None, None).await;
+        assert_eq!(status, 200);
+        assert_eq!(resolved["room_id"], room_id);
+        assert_eq!(resolved["servers"][0], "test.local");
+
+        let uri = "/_matrix/client/v3/directory/room/%23hangout:test.local";
+        let body = serde_json::json!({ "room_id": room_id });
+        let (status, _) = request(&server, "PUT", uri, Some("user_bob"), Some(body.clone())).await;
+        assert_eq!(status, 403);
+        let (status, _) = request(&server, "PUT", uri, Some("user_alice"), Some(body.clone())).await;
+        assert_eq!(status, 200);
+        let (status, conflict) = request(&server, "PUT", uri, Some("user_alice"), Some(body)).await;
+        assert_eq!(status, 409);
+        assert_eq!(conflict["errcode"], "M_ROOM_IN_USE");
+
+        let (status, joined) = request(&server, "POST", "/_matrix/client/v3/join/%23hangout:test.local", Some("user_bob"), Some(serde_json::json!({}))).await;
+        assert_eq!(status, 200);
+        assert_eq!(joined["room_id"], room_id);
+
+        let (status, aliases) = request(&server, "GET", &format!("/_matrix/client/v3/rooms/{}/aliases", room_id), Some("user_bob"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(aliases["aliases"], serde_json::json!(["#hangout:test.local", "#lobby:test.local"]));
+
+        let (status, _) = request(&server, "DELETE", uri, Some("user_carol"), None).await;
+        assert_eq!(status, 403);
+        let (status, _) = request(&server, "DELETE", uri, Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        let (status, missing) = request(&server, "GET", uri, None, None).await;
+        assert_eq!(status, 404);
+        assert_eq!(missing["errcode"], "M_NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn test_restricted_join_over_http() {
+        let server = create_test_server().await;
+        let space_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+        let body = serde_json::json!({
+            "preset": "private_chat",
+            "initial_state": [{
+                "type": "m.room.join_rules",
+                "state_key": "",
+                "content": { "join_rule": "restricted", "allow": [{ "type": "m.room_membership", "room_id": space_id }] },
+            }],
+        });
+        let room_id = create_room(&server, "user_alice", body).await;
+
+        assert_eq!(join_room(&server, "user_bob", &room_id).await, 403);
+        join_room(&server, "user_bob", &space_id).await;
+        assert_eq!(join_room(&server, "user_bob", &room_id).await, 200);
+        let uri = format!("/_matrix/client/v3/rooms/{}/state/m.room.member/user_bob", room_id);
+        let (_, member) = request(&server, "GET", &uri, Some("user_bob"), None).await;
+        assert_eq!(member["membership"], "join");
+        assert_eq!(member["join_authorised_via_users_server"], "user_alice");
+    }
+
+    #[tokio::test]
+    async fn test_public_room_directory_over_http() {
+        let server = create_test_server().await;
+        let alice = test_user("user_alice");
+        let mut room_ids = Vec::new();
+        for (name, topic) in [("Rust Help", "Ask anything"), ("Off Topic", "Cats and rust-free bikes")] {
+            let room_id = server.room_handler
+                .create_room(&alice, room::RoomConfig {
+                    name: Some(name.to_string()),
+                    topic: Some(topic.to_string()),
+                    preset: Some(room::RoomPreset::PublicChat),
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .room_id;
+            room_ids.push(room_id);
+        }
+
+        let uri = format!("/_matrix/client/v3/directory/list/room/{}", room_ids[0]);
+        let (status, _) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "visibility": "public" }))).await;
+        assert_eq!(status, 200);
+        let (_, visibility) = request(&server, "GET", &uri, None, None).await;
+        assert_eq!(visibility["visibility"], "public");
+
+        let (status, listed) = request(&server, "GET", "/_matrix/client/v3/publicRooms", None, None).await;
+        assert_eq!(status, 200);
+        assert_eq!(listed["total_room_count_estimate"], 1);
+        assert_eq!(listed["chunk"][0]["name"], "Rust Help");
+        assert_eq!(listed["chunk"][0]["num_joined_members"], 1);
+
+        let uri = format!("/_matrix/client/v3/directory/list/room/{}", room_ids[1]);
+        request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "visibility": "public" }))).await;
+        let body = serde_json::json!({ "filter": { "generic_search_term": "CATS" } });
+        let (status, searched) = request(&server, "POST", "/_matrix/client/v3/publicRooms", Some("user_bob"), Some(body)).await;
+        assert_eq!(status, 200);
+        assert_eq!(searched["total_room_count_estimate"], 1);
+        assert_eq!(searched["chunk"][0]["room_id"], room_ids[1]);
+    }
+
+    #[tokio::test]
+    async fn test_oidc_roles_gate_over_http() {
+        let mut config = test_config();
+        config.directory_publish_role = Some("publisher".to_string());
+        config.admin_role = Some("admin".to_string());
+        config.oidc_config.user_roles.insert("user_alice".to_string(), vec!["publisher".to_string(), "admin".to_string()]);
+        let server = MatrixServer::new(config).await.unwrap();
+
+        for (user, expected) in [("user_alice", 200), ("user_bob", 403)] {
+            let room_id = create_room(&server, user, serde_json::json!({ "preset": "public_chat" })).await;
+            let uri = format!("/_matrix/client/v3/directory/list/room/{}", room_id);
+            let (status, _) = request(&server, "PUT", &uri, Some(user), Some(serde_json::json!({ "visibility": "public" }))).await;
+            assert_eq!(status, expected, "{} publishing", user);
+
+            let uri = format!("/_synapse/admin/v1/purge_media_cache?before_ts={}", presence::now_millis());
+            let (status, _) = request(&server, "POST", &uri, Some(user), None).await;
+            assert_eq!(status, expected, "{} purging", user);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_room_state_over_http() {
+        let server = create_test_server().await;
+        let alice = test_user("user_alice");
+        let room_id = server.room_handler
+            .create_room(&alice, room::RoomConfig {
+                name: Some("Ops".to_string()),
+                preset: Some(room::RoomPreset::PrivateChat),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .room_id;
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/state/m.room.topic", room_id);
+        let (status, sent) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "topic": "deploys" }))).await;
+        assert_eq!(status, 200);
+        assert!(sent["event_id"].as_str().is_some_and(|id| id.starts_with('$')));
+
+        let (status, content) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(content, serde_json::json!({ "topic": "deploys" }));
+        let (_, event) = request(&server, "GET", &format!("{}?format=event", uri), Some("user_alice"), None).await;
+        assert_eq!(event["event_id"], sent["event_id"]);
+        assert_eq!(event["state_key"], "");
+
+        let (status, state) = request(&server, "GET", &format!("/_matrix/client/v3/rooms/{}/state", room_id), Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        let types: Vec<&str> = state.as_array().unwrap().iter().filter_map(|event| event["type"].as_str()).collect();
+        assert!(types.contains(&"m.room.create") && types.contains(&"m.room.name") && types.contains(&"m.room.topic"));
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/state/m.room.name", room_id);
+        let (status, error) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "name": 1 }))).await;
+        assert_eq!(status, 400);
+        assert!(error["errcode"].is_string());
+        let (status, _) = request(&server, "GET", &uri, Some("user_mallory"), None).await;
+        assert_eq!(status, 403);
+    }
+
+    #[tokio::test]
+    async fn test_room_members_over_http() {
+        let server = create_test_server().await;
+        let alice = test_user("user_alice");
+        let room_id = server.room_handler
+            .create_room(&alice, room::RoomConfig {
+                invite: vec!["user_bob".to_string()],
+                preset: Some(room::RoomPreset::PrivateChat),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .room_id;
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/members?not_membership=join", room_id);
+        let (status, members) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(members["chunk"].as_array().unwrap().len(), 1);
+        assert_eq!(members["chunk"][0]["state_key"], "user_bob");
+        assert_eq!(members["chunk"][0]["content"]["membership"], "invite");
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/joined_members", room_id);
+        let (status, joined) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(joined["joined"].as_object().unwrap().len(), 1);
+        assert!(joined["joined"]["user_alice"].is_object());
+        let (status, _) = request(&server, "GET", &uri, Some("user_bob"), None).await;
+        assert_eq!(status, 403);
+    }
+
+    #[tokio::test]
+    async fn test_create_room_over_http() {
+        let server = create_test_server().await;
+
+        let body = serde_json::json!({
+            "visibility": "public",
+            "name": "Support",
+            "room_alias_name": "support",
+            "power_level_content_override": { "events_default": 10 },
+        });
+        let (status, created) = request(&server, "POST", "/_matrix/client/v3/createRoom", Some("user_alice"), Some(body)).await;
+        assert_eq!(status, 200);
+        let room_id = created["room_id"].as_str().unwrap().to_string();
+
+        let (_, listed) = request(&server, "GET", "/_matrix/client/v3/publicRooms", None, None).await;
+        assert_eq!(listed["chunk"][0]["room_id"], room_id);
+        assert_eq!(listed["chunk"][0]["join_rule"], "public");
+        assert_eq!(listed["chunk"][0]["canonical_alias"], "#support:test.local");
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/state/m.room.power_levels", room_id);
+        let (_, power_levels) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(power_levels["events_default"], 10);
+        assert_eq!(power_levels["users"]["user_alice"], 100);
+    }
+
+    #[tokio::test]
+    async fn test_direct_room_helper_over_http() {
+        let server = create_test_server().await;
+
+        let (status, created) = request(&server, "POST", "/_matrix/client/v3/user/user_alice/dm/user_bob", Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(created["created"], true);
+        let (_, reused) = request(&server, "POST", "/_matrix/client/v3/user/user_alice/dm/user_bob", Some("user_alice"), None).await;
+        assert_eq!(reused["room_id"], created["room_id"]);
+        assert_eq!(reused["created"], false);
+
+        let (status, _) = request(&server, "POST", "/_matrix/client/v3/user/user_alice/dm/user_bob", Some("user_mallory"), None).await;
+        assert_eq!(status, 403);
+    }
+
+    #[tokio::test]
+    async fn test_account_data_and_tags_over_http() {
+        let server = create_test_server().await;
+        let room_id = "!ticket:test.local";
+
+        let uri = "/_matrix/client/v3/user/user_alice/account_data/org.example.prefs";
+        let (status, _) = request(&server, "GET", uri, Some("user_alice"), None).await;
+        assert_eq!(status, 404);
+        let (status, _) = request(&server, "PUT", uri, Some("user_alice"), Some(serde_json::json!({ "theme": "dark" }))).await;
+        assert_eq!(status, 200);
+        let (_, prefs) = request(&server, "GET", uri, Some("user_alice"), None).await;
+        assert_eq!(prefs["theme"], "dark");
+        let (status, _) = request(&server, "GET", uri, Some("user_bob"), None).await;
+        assert_eq!(status, 403);
+
+        let uri = format!("/_matrix/client/v3/user/user_alice/rooms/{}/tags/u.support.open", room_id);
+        let (status, _) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "order": 0.5 }))).await;
+        assert_eq!(status, 200);
+        let tags_uri = format!("/_matrix/client/v3/user/user_alice/rooms/{}/tags", room_id);
+        let (_, tags) = request(&server, "GET", &tags_uri, Some("user_alice"), None).await;
+        assert_eq!(tags, serde_json::json!({ "tags": { "u.support.open": { "order": 0.5 } } }));
+
+        let (status, _) = request(&server, "DELETE", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        let (_, tags) = request(&server, "GET", &tags_uri, Some("user_alice"), None).await;
+        assert_eq!(tags, serde_json::json!({ "tags": {} }));
+
+        let uri = format!("/_matrix/client/v3/user/user_alice/rooms/{}/account_data/m.fully_read", room_id);
+        let (status, error) = request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({ "event_id": "$x" }))).await;
+        assert_eq!(status, 405);
+        assert_eq!(error["errcode"], "M_BAD_JSON");
+    }
+
+    #[tokio::test]
+    async fn test_typing_over_http_and_federation() {
+        let server = create_test_server().await;
+        let room_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/typing/user_alice", room_id);
+        let typing = serde_json::json!({ "typing": true, "timeout": 30000 });
+        let (status, _) = request(&server, "PUT", &uri, Some("user_bob"), Some(typing.clone())).await;
+        assert_eq!(status, 403);
+        let (status, _) = request(&server, "PUT", &uri, Some("user_alice"), Some(typing)).await;
+        assert_eq!(status, 200);
+
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_alice"), None).await;
+        let ephemeral = &sync["rooms"]["join"][&room_id]["ephemeral"]["events"];
+        assert_eq!(ephemeral[0]["content"]["user_ids"], serde_json::json!(["user_alice"]));
+
+        // A remote user in the room starts typing through a transaction
+        add_remote_member(&server, &room_id, "@bob:remote.example").await;
+        let edu = |user_id: &str| federation::Edu {
+            edu_type: typing::TYPING_EDU_TYPE.to_string(),
+            content: serde_json::json!({ "room_id": room_id, "user_id": user_id, "typing": true }),
+        };
+        let edus = vec![edu("@bob:remote.example"), edu("@mallory:elsewhere.example")];
+        let response = send_transaction(&server, "remote.example", vec![], edus).await;
+        assert!(response.pdus.is_empty());
+
+        let typing_users = server.typing_handler.typing_users(&room_id).await;
+        assert_eq!(typing_users, vec!["@bob:remote.example", "user_alice"]);
+    }
+
+    #[tokio::test]
+    async fn test_receipts_and_read_markers_over_http() {
+        let server = create_test_server().await;
+        let room_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+        join_room(&server, "user_bob", &room_id).await;
+
+        let event_id = send_text(&server, "user_alice", &room_id, "txn1", "hello").await;
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/receipt/m.unread/{}", room_id, event_id);
+        let (status, error) = request(&server, "POST", &uri, Some("user_bob"), Some(serde_json::json!({}))).await;
+        assert_eq!(status, 400);
+        assert_eq!(error["errcode"], "M_INVALID_PARAM");
+        let uri = format!("/_matrix/client/v3/rooms/{}/receipt/m.read/{}", room_id, event_id);
+        let (status, _) = request(&server, "POST", &uri, Some("user_bob"), Some(serde_json::json!({ "thread_id": "main" }))).await;
+        assert_eq!(status, 200);
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/read_markers", room_id);
+        let markers = serde_json::json!({ "m.fully_read": event_id, "m.read.private": event_id });
+        let (status, _) = request(&server, "POST", &uri, Some("user_alice"), Some(markers)).await;
+        assert_eq!(status, 200);
+
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_bob"), None).await;
+        let room = &sync["rooms"]["join"][&room_id];
+        let receipts = room["ephemeral"]["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event["type"] == "m.receipt")
+            .unwrap();
+        assert_eq!(receipts["content"][&event_id]["m.read"]["user_bob"]["thread_id"], "main");
+        assert!(receipts["content"][&event_id].get("m.read.private").is_none());
+
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_alice"), None).await;
+        let room = &sync["rooms"]["join"][&room_id];
+        let fully_read = room["account_data"]["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event["type"] == "m.fully_read")
+            .unwrap();
+        assert_eq!(fully_read["content"]["event_id"], event_id);
+        let receipts = room["ephemeral"]["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event["type"] == "m.receipt")
+            .unwrap();
+        assert!(receipts["content"][&event_id]["m.read.private"].get("user_alice").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_push_rules_and_unread_counts_over_http() {
+        let server = create_test_server().await;
+        let room_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+        join_room(&server, "user_bob", &room_id).await;
+
+        send_text(&server, "user_alice", &room_id, "txn1", "hello").await;
+        let uri = format!("/_matrix/client/v3/rooms/{}/send/m.room.message/txn2", room_id);
+        let mention = serde_json::json!({ "msgtype": "m.text", "body": "bob?", "m.mentions": { "user_ids": ["user_bob"] } });
+        let (_, sent) = request(&server, "PUT", &uri, Some("user_alice"), Some(mention)).await;
+        let event_id = sent["event_id"].as_str().unwrap().to_string();
+
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_bob"), None).await;
+        let counts = &sync["rooms"]["join"][&room_id]["unread_notifications"];
+        assert_eq!(counts["notification_count"], 2);
+        assert_eq!(counts["highlight_count"], 1);
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_alice"), None).await;
+        assert_eq!(sync["rooms"]["join"][&room_id]["unread_notifications"]["notification_count"], 0);
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/receipt/m.read/{}", room_id, event_id);
+        request(&server, "POST", &uri, Some("user_bob"), Some(serde_json::json!({}))).await;
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_bob"), None).await;
+        assert_eq!(sync["rooms"]["join"][&room_id]["unread_notifications"]["notification_count"], 0);
+
+        // Muting the room with a room rule stops further notifications
+        let uri = format!("/_matrix/client/v3/pushrules/global/room/{}", room_id);
+        let (status, _) = request(&server, "PUT", &uri, Some("user_bob"), Some(serde_json::json!({ "actions": [] }))).await;
+        assert_eq!(status, 200);
+        let (_, rules) = request(&server, "GET", "/_matrix/client/v3/pushrules/", Some("user_bob"), None).await;
+        assert_eq!(rules["global"]["room"][0]["rule_id"], room_id);
+        assert_eq!(rules["global"]["override"][0]["rule_id"], ".m.rule.master");
+
+        send_text(&server, "user_alice", &room_id, "txn3", "hi").await;
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync", Some("user_bob"), None).await;
+        assert_eq!(sync["rooms"]["join"][&room_id]["unread_notifications"]["notification_count"], 0);
+
+        let uri = "/_matrix/client/v3/pushrules/global/override/.m.rule.suppress_notices/enabled";
+        let (status, _) = request(&server, "PUT", uri, Some("user_bob"), Some(serde_json::json!({ "enabled": false }))).await;
+        assert_eq!(status, 200);
+        let (_, enabled) = request(&server, "GET", uri, Some("user_bob"), None).await;
+        assert_eq!(enabled["enabled"], false);
+
+        let uri = "/_matrix/client/v3/pushrules/global/override/.m.rule.master";
+        let (status, error) = request(&server, "DELETE", uri, Some("user_bob"), None).await;
+        assert_eq!(status, 400);
+        assert_eq!(error["errcode"], "M_INVALID_PARAM");
+        let uri = "/_matrix/client/v3/pushrules/global/sender/missing";
+        let (status, _) = request(&server, "GET", uri, Some("user_bob"), None).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_notifications_over_http() {
+        let server = create_test_server().await;
+        let room_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+        join_room(&server, "user_bob", &room_id).await;
+
+        let mut event_ids = Vec::new();
+        for (i, body) in ["one", "ping user_bob", "three"].iter().enumerate() {
+            event_ids.push(send_text(&server, "user_alice", &room_id, &format!("txn{}", i), body).await);
+        }
+        let uri = format!("/_matrix/client/v3/rooms/{}/receipt/m.read/{}", room_id, event_ids[1]);
+        request(&server, "POST", &uri, Some("user_bob"), Some(serde_json::json!({}))).await;
+
+        let (_, page) = request(&server, "GET", "/_matrix/client/v3/notifications?limit=2", Some("user_bob"), None).await;
+        let notifications = page["notifications"].as_array().unwrap();
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0]["event"]["event_id"], event_ids[2]);
+        assert_eq!(notifications[0]["read"], false);
+        assert_eq!(notifications[1]["read"], true);
+        assert_eq!(notifications[1]["room_id"], room_id);
+        let uri = format!("/_matrix/client/v3/notifications?from={}", page["next_token"].as_str().unwrap());
+        let (_, page) = request(&server, "GET", &uri, Some("user_bob"), None).await;
+        assert_eq!(page["notifications"][0]["event"]["event_id"], event_ids[0]);
+        assert!(page.get("next_token").is_none());
+
+        let (_, page) = request(&server, "GET", "/_matrix/client/v3/notifications?only=highlight", Some("user_bob"), None).await;
+        let notifications = page["notifications"].as_array().unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["event"]["event_id"], event_ids[1]);
+        assert!(notifications[0]["actions"].as_array().unwrap().iter().any(|action| action["set_tweak"] == "highlight"));
+    }
+
+    #[tokio::test]
+    async fn test_search_over_http() {
+        let server = create_test_server().await;
+        let mut room_ids = Vec::new();
+        for _ in 0..2 {
+            room_ids.push(create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await);
+        }
+        join_room(&server, "user_bob", &room_ids[0]).await;
+
+        let mut event_ids = Vec::new();
+        let messages = [(0, "Printer jammed again"), (0, "printer fixed, printer works"), (0, "lunch?"), (1, "printer on floor 3")];
+        for (i, (room, body)) in messages.iter().enumerate() {
+            event_ids.push(send_text(&server, "user_alice", &room_ids[*room], &format!("txn{}", i), body).await);
+        }
+
+        // Bob never joined the second room, so only the first room's messages match
+        let body = serde_json::json!({ "search_categories": { "room_events": {
+            "search_term": "PRINTER",
+            "event_context": { "before_limit": 1, "after_limit": 1, "include_profile": true },
+            "groupings": { "group_by": [{ "key": "room_id" }] },
+            "include_state": true,
+        } } });
+        let (status, response) = request(&server, "POST", "/_matrix/client/v3/search", Some("user_bob"), Some(body)).await;
+        assert_eq!(status, 200);
+        let room_events = &response["search_categories"]["room_events"];
+        assert_eq!(room_events["count"], 2);
+        assert_eq!(room_events["highlights"], serde_json::json!(["printer"]));
+        let results = room_events["results"].as_array().unwrap();
+        assert_eq!(results[0]["result"]["event_id"], event_ids[1]);
+        assert_eq!(results[0]["context"]["events_after"][0]["event_id"], event_ids[2]);
+        assert!(results[0]["context"]["profile_info"].get("user_alice").is_some());
+        assert_eq!(room_events["groups"]["room_id"][&room_ids[0]]["results"].as_array().unwrap().len(), 2);
+        assert!(room_events["state"][&room_ids[0]].as_array().is_some_and(|state| !state.is_empty()));
+
+        let uri = format!("/_matrix/client/v3/rooms/{}/redact/{}/txn9", room_ids[0], event_ids[1]);
+        request(&server, "PUT", &uri, Some("user_alice"), Some(serde_json::json!({}))).await;
+        let body = serde_json::json!({ "search_categories": { "room_events": {
+            "search_term": "printer",
+            "order_by": "recent",
+            "filter": { "limit": 1 },
+        } } });
+        let (_, response) = request(&server, "POST", "/_matrix/client/v3/search", Some("user_alice"), Some(body.clone())).await;
+        let room_events = &response["search_categories"]["room_events"];
+        assert_eq!(room_events["count"], 2);
+        assert_eq!(room_events["results"][0]["result"]["event_id"], event_ids[3]);
+        let uri = format!("/_matrix/client/v3/search?next_batch={}", room_events["next_batch"].as_str().unwrap());
+        let (_, response) = request(&server, "POST", &uri, Some("user_alice"), Some(body.clone())).await;
+        let room_events = &response["search_categories"]["room_events"];
+        assert_eq!(room_events["results"][0]["result"]["event_id"], event_ids[0]);
+        assert!(room_events.get("next_batch").is_none());
+        let uri = format!("/_matrix/client/v3/search?next_batch={}", usize::MAX);
+        let (status, response) = request(&server, "POST", &uri, Some("user_alice"), Some(body)).await;
+        assert_eq!(status, 200);
+        assert!(response["search_categories"]["room_events"]["results"].as_array().unwrap().is_empty());
+
+        // Once Bob leaves, what he saw stays searchable but later messages and the room's state do not
+        let leave_uri = format!("/_matrix/client/v3/rooms/{}/leave", room_ids[0]);
+        let (status, _) = request(&server, "POST", &leave_uri, Some("user_bob"), Some(serde_json::json!({}))).await;
+        assert_eq!(status, 200);
+        send_text(&server, "user_alice", &room_ids[0], "txn10", "printer gone").await;
+        let body = serde_json::json!({ "search_categories": { "room_events": {
+            "search_term": "printer",
+            "include_state": true,
+        } } });
+        let (_, response) = request(&server, "POST", "/_matrix/client/v3/search", Some("user_bob"), Some(body)).await;
+        let room_events = &response["search_categories"]["room_events"];
+        assert_eq!(room_events["count"], 1);
+        assert_eq!(room_events["results"][0]["result"]["event_id"], event_ids[0]);
+        assert!(room_events["state"].get(&room_ids[0]).is_none());
+
+        let body = serde_json::json!({ "search_categories": { "room_events": { "search_term": "x", "keys": ["content.msgtype"] } } });
+        let (status, _) = request(&server, "POST", "/_matrix/client/v3/search", Some("user_alice"), Some(body)).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn test_pushers_over_http() {
+        let server = create_test_server().await;
+        let mut pusher = serde_json::json!({
+            "pushkey": "key",
+            "kind": "http",
+            "app_id": "com.example.app",
+            "app_display_name": "Example",
+            "device_display_name": "Phone",
+            "lang": "en",
+            "data": { "url": "https://push.example.com/_matrix/push/v1/notify", "format": "event_id_only" },
+        });
+        let (status, _) = request(&server, "POST", "/_matrix/client/v3/pushers/set", Some("user_bob"), Some(pusher.clone())).await;
+        assert_eq!(status, 200);
+
+        let (_, pushers) = request(&server, "GET", "/_matrix/client/v3/pushers", Some("user_bob"), None).await;
+        assert_eq!(pushers["pushers"][0]["pushkey"], "key");
+        assert_eq!(pushers["pushers"][0]["data"]["format"], "event_id_only");
+        assert!(pushers["pushers"][0].get("user_id").is_none());
+
+        for url in ["not a url", "http://169.254.169.254/_matrix/push/v1/notify"] {
+            pusher["data"]["url"] = serde_json::json!(url);
+            let (status, error) = request(&server, "POST", "/_matrix/client/v3/pushers/set", Some("user_bob"), Some(pusher.clone())).await;
+            assert_eq!(status, 400);
+            assert_eq!(error["errcode"], "M_INVALID_PARAM");
+        }
+
+        pusher["kind"] = serde_json::Value::Null;
+        request(&server, "POST", "/_matrix/client/v3/pushers/set", Some("user_bob"), Some(pusher)).await;
+        let (_, pushers) = request(&server, "GET", "/_matrix/client/v3/pushers", Some("user_bob"), None).await;
+        assert_eq!(pushers["pushers"], serde_json::json!([]));
+    }
+
+    /// Send raw bytes through the full router, returning the raw response
+    async fn raw_request(
+        server: &MatrixServer,
+        method: &str,
+        uri: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> axum::http::Response<axum::body::Body> {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", content_type)
+            .header("authorization", "Bearer user_alice")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        server.create_router().await.unwrap().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_media_over_http() {
+        let server = create_test_server().await;
+        let (_, config) = request(&server, "GET", "/_matrix/client/v1/media/config", Some("user_alice"), None).await;
+        assert_eq!(config["m.upload.size"], 1024 * 1024);
+
+        let response = raw_request(&server, "POST", "/_matrix/media/v3/upload?filename=notes.txt", "text/plain", b"hello".to_vec()).await;
+        assert_eq!(response.status(), 200);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let content_uri = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["content_uri"].as_str().unwrap().to_string();
+        let (_, media_id) = media::parse_mxc_uri(&content_uri).unwrap();
+
+        let uri = format!("/_matrix/client/v1/media/download/test.local/{}", media_id);
+        let (status, _) = request(&server, "GET", &uri, None, None).await;
+        assert_eq!(status, 401);
+        let response = raw_request(&server, "GET", &uri, "text/plain", Vec::new()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.headers()["content-disposition"], "inline; filename=\"notes.txt\"");
+        assert!(response.headers()["content-security-policy"].to_str().unwrap().starts_with("sandbox"));
+        assert_eq!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap(), "hello");
+        let response = raw_request(&server, "GET", &format!("{}/renamed.txt", uri), "text/plain", Vec::new()).await;
+        assert_eq!(response.headers()["content-disposition"], "inline; filename=\"renamed.txt\"");
+
+        let response = raw_request(&server, "POST", "/_matrix/client/v1/media/upload", "application/octet-stream", vec![0; 1024 * 1024 + 1]).await;
+        assert_eq!(response.status(), 413);
+
+        let (_, created) = request(&server, "POST", "/_matrix/media/v1/create", Some("user_alice"), None).await;
+        let (_, pending_id) = media::parse_mxc_uri(created["content_uri"].as_str().unwrap()).unwrap();
+        let uri = format!("/_matrix/client/v1/media/download/test.local/{}?timeout_ms=0", pending_id);
+        let (status, error) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 504);
+        assert_eq!(error["errcode"], "M_NOT_YET_UPLOADED");
+        let upload_uri = format!("/_matrix/media/v3/upload/test.local/{}", pending_id);
+        let response = raw_request(&server, "PUT", &upload_uri, "text/plain", b"later".to_vec()).await;
+        assert_eq!(response.status(), 200);
+        let response = raw_request(&server, "PUT", &upload_uri, "text/plain", b"again".to_vec()).await;
+        assert_eq!(response.status(), 409);
+
+        // Media messages must point at content the server knows about
+        let room_id = create_room(&server, "user_alice", serde_json::json!({})).await;
+        let uri = format!("/_matrix/client/v3/rooms/{}/send/m.room.message/txn1", room_id);
+        let message = serde_json::json!({
+            "msgtype": "m.file",
+            "body": "notes.txt",
+            "url": content_uri,
+            "info": { "mimetype": "text/plain", "size": 5 },
+        });
+        let (status, _) = request(&server, "PUT", &uri, Some("user_alice"), Some(message.clone())).await;
+        assert_eq!(status, 200);
+        for url in [serde_json::json!("mxc://test.local/missing"), serde_json::json!("https://example.com/a.png"), serde_json::Value::Null] {
+            let mut message = message.clone();
+            message["url"] = url;
+            let uri = uri.replace("txn1", "txn2");
+            let (status, error) = request(&server, "PUT", &uri, Some("user_alice"), Some(message)).await;
+            assert_eq!(status, 400);
+            assert_eq!(error["errcode"], "M_INVALID_PARAM");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_media_over_federation() {
+        // The origin is a second server, reached over a local port
+        let mut origin_config = test_config();
+        origin_config.server_name = "origin.local".to_string();
+        origin_config.federation_config.server_name = "origin.local".to_string();
+        let origin = MatrixServer::new(origin_config).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = origin.create_router().await.unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = test_config();
+        config.federation_config.server_addresses.insert("origin.local".to_string(), format!("http://{}", address));
+        config.media_config.remote.origin_max_sizes.insert("origin.local".to_string(), 64 * 1024);
+        let server = MatrixServer::new(config).await.unwrap();
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(400, 200, image::Rgb([20, 120, 200])).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let png = png.into_inner();
+        let mut media_ids = Vec::new();
+        for content in [png.clone(), png.clone(), vec![0; 100 * 1024]] {
+            let uploaded = origin.media_repository
+                .upload("user_alice", Some("image/png".to_string()), Some("blue.png".to_string()), &content)
+                .await
+                .unwrap();
+            media_ids.push(media::parse_mxc_uri(&uploaded.content_uri).unwrap().1.to_string());
+        }
+
+        // Federation media endpoints only answer other servers
+        let uri = format!("/_matrix/federation/v1/media/download/{}", media_ids[0]);
+        let (status, _) = request(&origin, "GET", &uri, None, None).await;
+        assert_eq!(status, 401);
+
+        let uri = format!("/_matrix/client/v1/media/download/origin.local/{}", media_ids[0]);
+        let response = raw_request(&server, "GET", &uri, "application/json", Vec::new()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-disposition"], "inline; filename=\"blue.png\"");
+        assert_eq!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap(), png);
+        let cached = server.state_store.get_media("origin.local", &media_ids[0]).await.unwrap().unwrap();
+        assert_eq!(cached.uploader, None);
+        assert_eq!(cached.size, png.len() as u64);
+
+        // Thumbnails of uncached media come from the origin
+        let uri = format!("/_matrix/client/v1/media/thumbnail/origin.local/{}?width=50&height=50&method=crop", media_ids[1]);
+        let response = raw_request(&server, "GET", &uri, "application/json", Vec::new()).await;
+        assert_eq!(response.status(), 200);
+        let thumbnail = image::load_from_memory(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (50, 50));
+        assert!(server.state_store.get_media("origin.local", &media_ids[1]).await.unwrap().is_none());
+
+        let uri = format!("/_matrix/client/v1/media/download/origin.local/{}", media_ids[2]);
+        let (status, error) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 502);
+        assert_eq!(error["errcode"], "M_TOO_LARGE");
+        let (status, _) = request(&server, "GET", "/_matrix/client/v1/media/download/origin.local/missing", Some("user_alice"), None).await;
+        assert_eq!(status, 404);
+
+        let uri = format!("/_synapse/admin/v1/purge_media_cache?before_ts={}", presence::now_millis() + 1);
+        let (status, _) = request(&server, "POST", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 403);
+        assert_eq!(server.media_repository.purge_remote_cache(presence::now_millis() + 1).await.unwrap(), 1);
+        assert!(server.state_store.get_media("origin.local", &media_ids[0]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_url_preview_over_http() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let page_hits = Arc::new(AtomicUsize::new(0));
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(32, 16, image::Rgb([0, 0, 0])).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let png = png.into_inner();
+        let hits = page_hits.clone();
+        let site = Router::new()
+            .route("/page", get(move || async move {
+                hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                axum::response::Html(r#"<html><head><title>Fallback</title>
+                    <meta property="og:title" content="Team offsite">
+                    <meta name="description" content="Agenda &amp; travel">
+                    <meta property="og:image" content="/logo.png"></head></html>"#)
+            }))
+            .route("/logo.png", get(move || async move { ([("content-type", "image/png")], png) }))
+            .route("/loop", get(|| async { axum::response::Redirect::temporary("/loop") }))
+            .route("/internal", get(|| async { axum::response::Redirect::temporary("http://10.0.0.1/") }))
+            .route("/big", get(|| async { axum::response::Html("x".repeat(128 * 1024)) }))
+            .route("/slow", get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                axum::response::Html("late")
+            }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, site).await });
+
+        // Loopback is blacklisted by default
+        let server = create_test_server().await;
+        let uri = format!("/_matrix/client/v1/media/preview_url?url={}/page", base);
+        let (status, error) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 403);
+        assert_eq!(error["errcode"], "M_FORBIDDEN");
+
+        let mut config = test_config();
+        config.url_preview_config.ip_range_whitelist.push("127.0.0.1/32".parse().unwrap());
+        config.url_preview_config.max_spider_size = 64 * 1024;
+        config.url_preview_config.timeout = Duration::from_secs(2);
+        let server = MatrixServer::new(config).await.unwrap();
+        let (status, preview) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(preview["og:title"], "Team offsite");
+        assert_eq!(preview["og:description"], "Agenda & travel");
+        assert_eq!(preview["og:image:type"], "image/png");
+        assert_eq!(preview["og:image:width"], 32);
+        let (_, media_id) = media::parse_mxc_uri(preview["og:image"].as_str().unwrap()).unwrap();
+        let image = server.state_store.get_media("test.local", media_id).await.unwrap().unwrap();
+        assert_eq!(image.uploader.as_deref(), Some("user_alice"));
+
+        // Previews are cached per URL and time bucket
+        request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(page_hits.load(Ordering::SeqCst), 1);
+        let earlier = format!("{}&ts={}", uri, presence::now_millis() - 2 * url_preview::PREVIEW_CACHE_BUCKET.as_millis() as u64);
+        request(&server, "GET", &earlier, Some("user_alice"), None).await;
+        assert_eq!(page_hits.load(Ordering::SeqCst), 2);
+
+        for (path, expected) in [("/internal", 403), ("/loop", 502), ("/big", 502), ("/slow", 504)] {
+            let uri = format!("/_matrix/client/v1/media/preview_url?url={}{}", base, path);
+            let (status, _) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+            assert_eq!(status, expected, "{}", path);
+        }
+        let (status, _) = request(&server, "GET", "/_matrix/client/v1/media/preview_url?url=file:///etc/passwd", Some("user_alice"), None).await;
+        assert_eq!(status, 400);
+
+        // Preview images count towards the requesting user's quota
+        let mut config = test_config();
+        config.url_preview_config.ip_range_whitelist.push("127.0.0.1/32".parse().unwrap());
+        config.media_config.user_quota = Some(16);
+        let server = MatrixServer::new(config).await.unwrap();
+        let uri = format!("/_matrix/client/v1/media/preview_url?url={}/logo.png", base);
+        let (status, error) = request(&server, "GET", &uri, Some("user_alice"), None).await;
+        assert_eq!(status, 403);
+        assert_eq!(error["errcode"], "M_RESOURCE_LIMIT_EXCEEDED");
+    }
+
+    #[tokio::test]
+    async fn test_presence_over_http() {
+        let server = create_test_server().await;
+        let room_id = create_room(&server, "user_alice", serde_json::json!({ "preset": "public_chat" })).await;
+        join_room(&server, "user_bob", &room_id).await;
+
+        // Syncing brings bob online, and alice sees it in her sync
+        request(&server, "GET", "/_matrix/client/v3/sync", Some("user_bob"), None).await;
+        let (_, sync) = request(&server, "GET", "/_matrix/client/v3/sync?set_presence=offline", Some("user_alice"), None).await;
+        let presence = sync["presence"]["events"].as_array().unwrap();
+        assert_eq!(presence.len(), 1);
+        assert_eq!(presence[0]["sender"], "user_bob");
+        assert_eq!(presence[0]["content"]["presence"], "online");
+
+        let uri = "/_matrix/client/v3/presence/user_alice/status";
+        let away = serde_json::json!({ "presence": "unavailable", "status_msg": "Back at 2" });
+        let (status, _) = request(&server, "PUT", uri, Some("user_bob"), Some(away.clone())).await;
+        assert_eq!(status, 403);
+        let (status, _) = request(&server, "PUT", uri, Some("user_alice"), Some(away)).await;
+        assert_eq!(status, 200);
+
+        let (status, presence) = request(&server, "GET", uri, Some("user_bob"), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(presence["presence"], "unavailable");
+        assert_eq!(presence["status_msg"], "Back at 2");
+        assert!(presence["last_active_ago"].is_u64());
+        let (status, _) = request(&server, "GET", uri, Some("user_carol"), None).await;
+        assert_eq!(status, 403);
+
+        // Remote presence is only kept for users sharing a room with someone here
+        add_remote_member(&server, &room_id, "@dave:remote.example").await;
+        let update = |user_id: &str| serde_json::json!({ "user_id": user_id, "presence": "online" });
+        let edu = federation::Edu {
+            edu_type: presence::PRESENCE_EDU_TYPE.to_string(),
+            content: serde_json::json!({ "push": [update("@dave:remote.example"), update("@erin:remote.example")] }),
+        };
+        send_transaction(&server, "remote.example", vec![], vec![edu]).await;
+        let dave = server.presence_handler.get_presence("@dave:remote.example").await;
+        assert_eq!(dave.presence, presence::PresenceState::Online);
+        let erin = server.presence_handler.get_presence("@erin:remote.example").await;
+        assert_eq!(erin.presence, presence::PresenceState::Offline);
+    }
+}
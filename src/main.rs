@@ -6,9 +6,12 @@ use tracing::{info, error};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use matrix_chat_system::{
-    MatrixServer, ServerConfig,
+    MatrixServer, ServerConfig, MediaConfig,
+    media::{RemoteMediaConfig, DEFAULT_MAX_IMAGE_PIXELS, DEFAULT_REMOTE_MAX_SIZE},
+    url_preview::{self, UrlPreviewConfig},
     auth::OIDCConfig,
     federation::FederationConfig,
+    pushers::PusherConfig,
 };
 
 #[tokio::main]
@@ -72,6 +75,14 @@ fn load_server_config() -> Result<ServerConfig, Box<dyn std::error::Error>> {
             "urn:zitadel:iam:org:project:roles".to_string(), // Zitadel roles
         ],
         server_name: server_name.clone(),
+        user_roles: env::var("OIDC_USER_ROLES")
+            .map(|list| {
+                parse_pairs(&list)
+                    .into_iter()
+                    .map(|(user_id, roles)| (user_id, roles.split('|').map(|role| role.trim().to_string()).collect()))
+                    .collect()
+            })
+            .unwrap_or_default(),
     };
 
     let federation_config = FederationConfig {
@@ -87,17 +98,140 @@ fn load_server_config() -> Result<ServerConfig, Box<dyn std::error::Error>> {
         federation_blacklist: env::var("FEDERATION_BLACKLIST")
             .ok()
             .map(|list| list.split(',').map(|s| s.trim().to_string()).collect()),
+        server_addresses: env::var("FEDERATION_SERVER_ADDRESSES")
+        .map(|list| parse_pairs(&list))
+        .unwrap_or_default(),
+        ip_range_blacklist: env::var("FEDERATION_IP_RANGE_BLACKLIST")
+            .map(|list| parse_ip_ranges(&list))
+            .unwrap_or_else(|_| url_preview::default_ip_range_blacklist()),
+        ip_range_whitelist: env::var("FEDERATION_IP_RANGE_WHITELIST")
+            .map(|list| parse_ip_ranges(&list))
+            .unwrap_or_default(),
     };
 
+    let redaction_retention = env::var("REDACTION_RETENTION_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(std::time::Duration::from_secs);
+
+    let directory_publish_role = env::var("ROOM_DIRECTORY_PUBLISH_ROLE").ok();
+
+    let presence_enabled = env::var("PRESENCE_ENABLED")
+        .map(|v| v.parse().unwrap_or(true))
+        .unwrap_or(true);
+
+    let media_config = MediaConfig {
+        media_path: env::var("MEDIA_PATH").unwrap_or_else(|_| "./media_store".to_string()).into(),
+        max_upload_size: env::var("MAX_UPLOAD_SIZE")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(50 * 1024 * 1024),
+        user_quota: env::var("MEDIA_USER_QUOTA")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok()),
+        max_image_pixels: env::var("MAX_IMAGE_PIXELS")
+            .ok()
+            .and_then(|pixels| pixels.parse().ok())
+            .unwrap_or(DEFAULT_MAX_IMAGE_PIXELS),
+        remote: RemoteMediaConfig {
+            max_size: env::var("REMOTE_MEDIA_MAX_SIZE")
+                .ok()
+                .and_then(|bytes| bytes.parse().ok())
+                .unwrap_or(DEFAULT_REMOTE_MAX_SIZE),
+            origin_max_sizes: env::var("REMOTE_MEDIA_ORIGIN_MAX_SIZES")
+                .map(|list| {
+                    parse_pairs(&list)
+                        .into_iter()
+                        .filter_map(|(origin, bytes)| Some((origin, bytes.parse().ok()?)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            cache_max_age: env::var("REMOTE_MEDIA_CACHE_MAX_AGE_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(std::time::Duration::from_secs),
+            cache_max_size: env::var("REMOTE_MEDIA_CACHE_MAX_SIZE")
+                .ok()
+                .and_then(|bytes| bytes.parse().ok()),
+        },
+    };
+
+    let default_url_preview = UrlPreviewConfig::default();
+    let url_preview_config = UrlPreviewConfig {
+        ip_range_blacklist: env::var("URL_PREVIEW_IP_RANGE_BLACKLIST")
+            .map(|list| parse_ip_ranges(&list))
+            .unwrap_or(default_url_preview.ip_range_blacklist),
+        ip_range_whitelist: env::var("URL_PREVIEW_IP_RANGE_WHITELIST")
+            .map(|list| parse_ip_ranges(&list))
+            .unwrap_or_default(),
+        max_spider_size: env::var("URL_PREVIEW_MAX_SIZE")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(default_url_preview.max_spider_size),
+        timeout: env::var("URL_PREVIEW_TIMEOUT_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(default_url_preview.timeout),
+        max_redirects: env::var("URL_PREVIEW_MAX_REDIRECTS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(default_url_preview.max_redirects),
+        max_cached_previews: env::var("URL_PREVIEW_CACHE_SIZE")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(default_url_preview.max_cached_previews),
+    };
+
+    let pusher_config = PusherConfig {
+        ip_range_blacklist: env::var("PUSHER_IP_RANGE_BLACKLIST")
+            .map(|list| parse_ip_ranges(&list))
+            .unwrap_or_else(|_| url_preview::default_ip_range_blacklist()),
+        ip_range_whitelist: env::var("PUSHER_IP_RANGE_WHITELIST")
+            .map(|list| parse_ip_ranges(&list))
+            .unwrap_or_default(),
+    };
+
+    let admin_role = env::var("ADMIN_ROLE").ok();
+
     info!("📋 Configuration loaded:");
     info!("   Server: {}", server_name);
     info!("   OIDC: {}", oidc_config.issuer_url);
     info!("   Federation whitelist: {:?}", federation_config.federation_whitelist);
     info!("   Federation blacklist: {:?}", federation_config.federation_blacklist);
+    info!("   Redaction retention: {:?}", redaction_retention);
+    info!("   Directory publish role: {:?}", directory_publish_role);
+    info!("   Presence enabled: {}", presence_enabled);
+    info!("   Media: {:?} (max upload {} bytes)", media_config.media_path, media_config.max_upload_size);
+    info!("   Remote media cache: max age {:?}, max size {:?}", media_config.remote.cache_max_age, media_config.remote.cache_max_size);
+    info!("   URL preview blacklist: {} ranges, whitelist: {:?}", url_preview_config.ip_range_blacklist.len(), url_preview_config.ip_range_whitelist);
+    info!("   Pusher blacklist: {} ranges, whitelist: {:?}", pusher_config.ip_range_blacklist.len(), pusher_config.ip_range_whitelist);
+    info!("   Admin role: {:?}", admin_role);
 
     Ok(ServerConfig {
         server_name,
         oidc_config,
         federation_config,
+        redaction_retention,
+        directory_publish_role,
+        presence_enabled,
+        media_config,
+        url_preview_config,
+        pusher_config,
+        admin_role,
     })
 }
+
+/// Parse CIDR ranges separated by commas, skipping invalid ones
+fn parse_ip_ranges(list: &str) -> Vec<ipnet::IpNet> {
+    list.split(',').filter_map(|range| range.trim().parse().ok()).collect()
+}
+
+/// Parse `name=value` pairs separated by commas, e.g.
+/// `FEDERATION_SERVER_ADDRESSES=other.example=http://10.0.0.2:8008`
+fn parse_pairs(list: &str) -> std::collections::HashMap<String, String> {
+    list.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
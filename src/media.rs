@@ -0,0 +1,1035 @@
+// Media Repository
+// Uploads, downloads and thumbnails of content addressed by mxc:// URIs
+// Focus: Content-addressed filesystem storage, async uploads and per-user quotas
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use crate::federation::{FederationClient, FederationError};
+use crate::presence::now_millis;
+use crate::state::{MediaRecord, StateError, StateStore};
+
+pub const MXC_SCHEME: &str = "mxc://";
+
+/// How long a media ID from /create waits for its upload
+pub const UNUSED_MEDIA_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Created media IDs a user may have waiting for content at once
+pub const MAX_PENDING_UPLOADS: usize = 10;
+
+/// How long a download waits for pending content when the client gives no timeout
+pub const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Longest a client may ask a download to wait for pending content
+pub const MAX_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Largest thumbnail dimension served
+pub const MAX_THUMBNAIL_SIZE: u32 = 1600;
+
+/// Largest image, in pixels, decoded to generate thumbnails
+pub const DEFAULT_MAX_IMAGE_PIXELS: u64 = 32_000_000;
+
+/// Most bytes a decoder may allocate per pixel: four 16-bit channels
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+/// Largest remote media fetched from origins without their own limit
+pub const DEFAULT_REMOTE_MAX_SIZE: usize = 50 * 1024 * 1024;
+
+/// How often a download waiting for pending content checks for it
+const PENDING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Content types browsers may render inline rather than download
+const INLINE_CONTENT_TYPES: [&str; 11] = [
+    "text/plain", "text/csv", "image/png", "image/jpeg", "image/gif", "image/webp", "image/apng",
+    "video/mp4", "video/webm", "audio/mpeg", "audio/ogg",
+];
+
+#[derive(Debug, Clone)]
+pub struct MediaConfig {
+    /// Directory holding uploaded content and generated thumbnails
+    pub media_path: PathBuf,
+    /// Largest upload accepted, in bytes
+    pub max_upload_size: usize,
+    /// Total bytes each user may upload; unlimited when none
+    pub user_quota: Option<u64>,
+    /// Largest image, in pixels, thumbnails are generated from
+    pub max_image_pixels: u64,
+    /// Fetching and caching media from other servers
+    pub remote: RemoteMediaConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteMediaConfig {
+    /// Largest media fetched from an origin, in bytes, unless it has its own limit
+    pub max_size: usize,
+    /// Per-origin overrides of `max_size`
+    pub origin_max_sizes: HashMap<String, usize>,
+    /// Evict cached remote media this long after it was fetched
+    pub cache_max_age: Option<Duration>,
+    /// Evict the oldest cached remote media while the cache is larger than this
+    pub cache_max_size: Option<u64>,
+}
+
+impl RemoteMediaConfig {
+    fn max_size_for(&self, origin: &str) -> usize {
+        self.origin_max_sizes.get(origin).copied().unwrap_or(self.max_size)
+    }
+}
+
+impl Default for RemoteMediaConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_REMOTE_MAX_SIZE,
+            origin_max_sizes: HashMap::new(),
+            cache_max_age: None,
+            cache_max_size: None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MediaError {
+    #[error("Media not found: {0}")]
+    NotFound(String),
+
+    #[error("Upload exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+
+    #[error("Upload would exceed the media quota of {0} bytes")]
+    QuotaExceeded(u64),
+
+    #[error("Too many pending uploads")]
+    TooManyPending,
+
+    #[error("Media has not been uploaded yet: {0}")]
+    NotYetUploaded(String),
+
+    #[error("Media already uploaded: {0}")]
+    CannotOverwrite(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
+
+    #[error("Cannot generate a thumbnail: {0}")]
+    NotAnImage(String),
+
+    #[error("Image exceeds the maximum of {0} pixels")]
+    ImageTooLarge(u64),
+
+    #[error("Remote media exceeds the maximum size of {0} bytes")]
+    RemoteTooLarge(usize),
+
+    #[error("Failed to fetch remote media: {0}")]
+    Remote(String),
+
+    #[error("Storage error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+}
+
+impl MediaError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            MediaError::NotFound(_) => 404,
+            MediaError::TooLarge(_) => 413,
+            MediaError::QuotaExceeded(_) => 403,
+            MediaError::TooManyPending => 429,
+            MediaError::NotYetUploaded(_) => 504,
+            MediaError::CannotOverwrite(_) => 409,
+            MediaError::Forbidden(_) => 403,
+            MediaError::InvalidParam(_) => 400,
+            MediaError::NotAnImage(_) => 400,
+            MediaError::ImageTooLarge(_) => 400,
+            MediaError::RemoteTooLarge(_) => 502,
+            MediaError::Remote(_) => 502,
+            MediaError::Io(_) => 500,
+            MediaError::State(_) => 500,
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            MediaError::NotFound(_) => "M_NOT_FOUND",
+            MediaError::TooLarge(_) => "M_TOO_LARGE",
+            MediaError::QuotaExceeded(_) => "M_RESOURCE_LIMIT_EXCEEDED",
+            MediaError::TooManyPending => "M_LIMIT_EXCEEDED",
+            MediaError::NotYetUploaded(_) => "M_NOT_YET_UPLOADED",
+            MediaError::CannotOverwrite(_) => "M_CANNOT_OVERWRITE_MEDIA",
+            MediaError::Forbidden(_) => "M_FORBIDDEN",
+            MediaError::InvalidParam(_) => "M_INVALID_PARAM",
+            MediaError::NotAnImage(_) => "M_UNKNOWN",
+            MediaError::ImageTooLarge(_) => "M_TOO_LARGE",
+            MediaError::RemoteTooLarge(_) => "M_TOO_LARGE",
+            MediaError::Remote(_) => "M_UNKNOWN",
+            MediaError::Io(_) => "M_UNKNOWN",
+            MediaError::State(_) => "M_UNKNOWN",
+        }
+    }
+}
+
+impl From<FederationError> for MediaError {
+    fn from(err: FederationError) -> Self {
+        match err {
+            FederationError::MediaNotFound(mxc) => MediaError::NotFound(mxc),
+            FederationError::MediaTooLarge(max_size) => MediaError::RemoteTooLarge(max_size),
+            FederationError::StateError(state_err) => MediaError::State(state_err),
+            other => MediaError::Remote(other.to_string()),
+        }
+    }
+}
+
+/// Split `mxc://server/media_id` into its server name and media ID
+pub fn parse_mxc_uri(uri: &str) -> Option<(&str, &str)> {
+    let (server_name, media_id) = uri.strip_prefix(MXC_SCHEME)?.split_once('/')?;
+    let valid_id = !media_id.is_empty()
+        && media_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    (!server_name.is_empty() && valid_id).then_some((server_name, media_id))
+}
+
+pub fn format_mxc_uri(server_name: &str, media_id: &str) -> String {
+    format!("{}{}/{}", MXC_SCHEME, server_name, media_id)
+}
+
+/// Body of POST /upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadResponse {
+    pub content_uri: String,
+}
+
+/// Body of POST /create
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateMediaResponse {
+    pub content_uri: String,
+    pub unused_expires_at: u64,
+}
+
+/// Query of POST /upload and PUT /upload/{serverName}/{mediaId}
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UploadQuery {
+    pub filename: Option<String>,
+}
+
+/// Query of GET /download
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DownloadQuery {
+    pub timeout_ms: Option<u64>,
+}
+
+/// Query of POST /_synapse/admin/v1/purge_media_cache
+#[derive(Debug, Clone, Deserialize)]
+pub struct PurgeMediaCacheQuery {
+    /// Remove remote media cached before this Unix time in milliseconds
+    pub before_ts: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailMethod {
+    /// Fill the requested size exactly, cropping what does not fit
+    Crop,
+    /// Fit within the requested size, keeping the aspect ratio
+    #[default]
+    Scale,
+}
+
+impl ThumbnailMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThumbnailMethod::Crop => "crop",
+            ThumbnailMethod::Scale => "scale",
+        }
+    }
+}
+
+/// Query of GET /thumbnail
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThumbnailQuery {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub method: ThumbnailMethod,
+    pub timeout_ms: Option<u64>,
+}
+
+/// Content served for a download or thumbnail
+#[derive(Debug, Clone)]
+pub struct MediaContent {
+    pub content: Vec<u8>,
+    pub content_type: String,
+    pub filename: Option<String>,
+}
+
+impl MediaContent {
+    /// `Content-Disposition` header value: inline only for types browsers
+    /// render safely
+    pub fn content_disposition(&self) -> String {
+        let disposition = if INLINE_CONTENT_TYPES.contains(&self.content_type.as_str()) {
+            "inline"
+        } else {
+            "attachment"
+        };
+        match &self.filename {
+            Some(filename) => format!("{}; filename=\"{}\"", disposition, filename.replace(['"', '\\'], "_")),
+            None => disposition.to_string(),
+        }
+    }
+}
+
+/// Media part of a federation `multipart/mixed` media response
+#[derive(Debug, Clone)]
+pub struct MultipartMedia {
+    pub content: MediaContent,
+    /// Where to fetch the content from instead, when the part is a redirect
+    pub location: Option<String>,
+}
+
+/// Parse a federation media response: a JSON metadata part followed by the
+/// content, or by a part whose `Location` header points at the content
+pub fn parse_multipart(content_type: &str, body: &[u8]) -> Option<MultipartMedia> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/mixed") {
+        return None;
+    }
+    let boundary = params.split(';').find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        (key.eq_ignore_ascii_case("boundary")).then(|| value.trim_matches('"'))
+    })?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = [b"\r\n".as_slice(), &delimiter].concat();
+
+    let mut parts = Vec::new();
+    let mut rest = &body[find(body, &delimiter)? + delimiter.len()..];
+    while !rest.starts_with(b"--") {
+        rest = rest.strip_prefix(b"\r\n")?;
+        let end = find(rest, &next_delimiter)?;
+        parts.push(&rest[..end]);
+        rest = &rest[end + next_delimiter.len()..];
+    }
+
+    let [_metadata, media] = parts.as_slice() else {
+        return None;
+    };
+    let header_end = find(media, b"\r\n\r\n")?;
+    let mut headers = HashMap::new();
+    for line in std::str::from_utf8(&media[..header_end]).ok()?.split("\r\n") {
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    Some(MultipartMedia {
+        content: MediaContent {
+            content: media[header_end + 4..].to_vec(),
+            content_type: headers.remove("content-type").unwrap_or_else(|| "application/octet-stream".to_string()),
+            filename: headers.get("content-disposition").and_then(|value| disposition_filename(value)),
+        },
+        location: headers.remove("location"),
+    })
+}
+
+/// Serve media to another server as a federation `multipart/mixed` response
+pub fn multipart_response(content: MediaContent) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut body = format!(
+        "--{boundary}\r\nContent-Type: application/json\r\n\r\n{{}}\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Disposition: {}\r\n\r\n",
+        content.content_type,
+        content.content_disposition(),
+    ).into_bytes();
+    body.extend_from_slice(&content.content);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let content_type = format!("multipart/mixed; boundary={}", boundary);
+    ([(axum::http::header::CONTENT_TYPE, content_type)], body).into_response()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn disposition_filename(disposition: &str) -> Option<String> {
+    let (_, filename) = disposition.split_once("filename=")?;
+    Some(filename.trim_matches('"').to_string())
+}
+
+/// Async locks by key, forgotten once nobody holds or waits for them
+#[derive(Default)]
+struct KeyedLocks {
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl KeyedLocks {
+    async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// Stores media content on disk by SHA-256, so identical uploads share one
+/// file, and keeps metadata for each mxc:// URI in the state store
+///
+/// Media of other servers is fetched over federation on first use and
+/// cached the same way, subject to the remote cache's eviction policy.
+pub struct MediaRepository {
+    state_store: Arc<dyn StateStore + Send + Sync>,
+    federation_client: Option<Arc<FederationClient>>,
+    config: MediaConfig,
+    server_name: String,
+    /// Held while checking a user's pending uploads or quota until the new
+    /// media is recorded
+    user_locks: KeyedLocks,
+    /// Held while writing or removing content until the media referencing
+    /// it is recorded or confirmed gone
+    content_locks: KeyedLocks,
+}
+
+impl MediaRepository {
+    pub fn new(state_store: Arc<dyn StateStore + Send + Sync>, config: MediaConfig, server_name: impl Into<String>) -> Self {
+        Self {
+            state_store,
+            federation_client: None,
+            config,
+            server_name: server_name.into(),
+            user_locks: KeyedLocks::default(),
+            content_locks: KeyedLocks::default(),
+        }
+    }
+
+    /// Fetch media of other servers through `federation_client`; without
+    /// one, remote media is not found
+    pub fn with_federation_client(mut self, federation_client: Arc<FederationClient>) -> Self {
+        self.federation_client = Some(federation_client);
+        self
+    }
+
+    pub fn max_upload_size(&self) -> usize {
+        self.config.max_upload_size
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Reserve a media ID for content uploaded later
+    pub async fn create(&self, user_id: &str) -> Result<CreateMediaResponse, MediaError> {
+        let _user_lock = self.user_locks.lock(user_id).await;
+        let now = now_millis();
+        let pending = self.state_store
+            .get_user_media(user_id)
+            .await?
+            .iter()
+            .filter(|record| record.content_hash.is_none() && record.unused_expires_at.is_some_and(|expiry| expiry > now))
+            .count();
+        if pending >= MAX_PENDING_UPLOADS {
+            return Err(MediaError::TooManyPending);
+        }
+
+        let unused_expires_at = now + UNUSED_MEDIA_EXPIRY.as_millis() as u64;
+        let record = self.new_record(user_id, Some(unused_expires_at));
+        let content_uri = format_mxc_uri(&record.server_name, &record.media_id);
+        self.state_store.set_media(record).await?;
+        Ok(CreateMediaResponse { content_uri, unused_expires_at })
+    }
+
+    /// Store content under a new media ID
+    pub async fn upload(
+        &self,
+        user_id: &str,
+        content_type: Option<String>,
+        filename: Option<String>,
+        content: &[u8],
+    ) -> Result<UploadResponse, MediaError> {
+        let _user_lock = self.user_locks.lock(user_id).await;
+        let record = self.new_record(user_id, None);
+        let content_uri = format_mxc_uri(&record.server_name, &record.media_id);
+        self.store_content(record, content_type, filename, content).await?;
+        Ok(UploadResponse { content_uri })
+    }
+
+    /// Store content under a media ID reserved with `create`
+    pub async fn upload_to(
+        &self,
+        user_id: &str,
+        server_name: &str,
+        media_id: &str,
+        content_type: Option<String>,
+        filename: Option<String>,
+        content: &[u8],
+    ) -> Result<(), MediaError> {
+        let _user_lock = self.user_locks.lock(user_id).await;
+        let mxc = format_mxc_uri(server_name, media_id);
+        let record = self.state_store
+            .get_media(server_name, media_id)
+            .await?
+            .filter(|record| record.content_hash.is_some() || !is_expired(record))
+            .ok_or_else(|| MediaError::NotFound(mxc.clone()))?;
+        if record.uploader.as_deref() != Some(user_id) {
+            return Err(MediaError::Forbidden(format!("{} was created by another user", mxc)));
+        }
+        if record.content_hash.is_some() {
+            return Err(MediaError::CannotOverwrite(mxc));
+        }
+        self.store_content(record, content_type, filename, content).await
+    }
+
+    /// Content of media, waiting up to `timeout` for a pending local upload
+    /// and fetching remote media into the cache
+    pub async fn download(&self, server_name: &str, media_id: &str, timeout: Option<Duration>) -> Result<MediaContent, MediaError> {
+        let (record, hash) = if server_name == self.server_name {
+            self.uploaded_record(server_name, media_id, timeout).await?
+        } else {
+            match self.cached_record(server_name, media_id).await? {
+                Some(cached) => cached,
+                None => self.fetch_remote(server_name, media_id).await?,
+            }
+        };
+        Ok(MediaContent {
+            content: tokio::fs::read(self.content_path(&hash)).await?,
+            content_type: record.content_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+            filename: record.filename,
+        })
+    }
+
+    /// A thumbnail of image media, generated once per size and method
+    ///
+    /// Images already within the requested size are served as they are.
+    /// Thumbnails of remote media not in the cache are fetched from its origin.
+    pub async fn thumbnail(
+        &self,
+        server_name: &str,
+        media_id: &str,
+        query: &ThumbnailQuery,
+    ) -> Result<MediaContent, MediaError> {
+        if query.width == 0 || query.height == 0 || query.width > MAX_THUMBNAIL_SIZE || query.height > MAX_THUMBNAIL_SIZE {
+            return Err(MediaError::InvalidParam(format!("Thumbnail sizes must be 1 to {} pixels", MAX_THUMBNAIL_SIZE)));
+        }
+        let (record, hash) = if server_name == self.server_name {
+            let timeout = query.timeout_ms.map(Duration::from_millis);
+            self.uploaded_record(server_name, media_id, timeout).await?
+        } else {
+            match self.cached_record(server_name, media_id).await? {
+                Some(cached) => cached,
+                None => return self.remote_thumbnail(server_name, media_id, query).await,
+            }
+        };
+
+        let name = format!("{}x{}-{}", query.width, query.height, query.method.as_str());
+        let thumbnail_path = self.config.media_path.join("thumbnails").join(&hash).join(&name);
+        if let Ok(content) = tokio::fs::read(&thumbnail_path).await {
+            let content_type = image::guess_format(&content).map(|format| format.to_mime_type()).unwrap_or("image/png");
+            return Ok(MediaContent { content, content_type: content_type.to_string(), filename: None });
+        }
+
+        let original = tokio::fs::read(self.content_path(&hash)).await?;
+        let format = image::guess_format(&original).map_err(|e| MediaError::NotAnImage(e.to_string()))?;
+        let reader = image::ImageReader::with_format(Cursor::new(&original), format);
+        let (width, height) = reader.into_dimensions().map_err(|e| MediaError::NotAnImage(e.to_string()))?;
+        if width <= query.width && height <= query.height {
+            return Ok(MediaContent {
+                content: original,
+                content_type: record.content_type.unwrap_or_else(|| format.to_mime_type().to_string()),
+                filename: None,
+            });
+        }
+        let max_image_pixels = self.config.max_image_pixels;
+        if u64::from(width) * u64::from(height) > max_image_pixels {
+            return Err(MediaError::ImageTooLarge(max_image_pixels));
+        }
+
+        // Decoding and resizing are CPU-bound, so they stay off the async workers
+        let (width, height, method) = (query.width, query.height, query.method);
+        let (content, output) = tokio::task::spawn_blocking(move || {
+            let mut reader = image::ImageReader::with_format(Cursor::new(&original), format);
+            let mut limits = image::Limits::default();
+            limits.max_alloc = Some(max_image_pixels.saturating_mul(MAX_BYTES_PER_PIXEL));
+            reader.limits(limits);
+            let image = reader.decode().map_err(|e| MediaError::NotAnImage(e.to_string()))?;
+            render_thumbnail(&image, format, width, height, method)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        write_atomically(&thumbnail_path, &content).await?;
+        Ok(MediaContent { content, content_type: output.to_mime_type().to_string(), filename: None })
+    }
+
+    fn new_record(&self, user_id: &str, unused_expires_at: Option<u64>) -> MediaRecord {
+        MediaRecord {
+            server_name: self.server_name.clone(),
+            media_id: Uuid::new_v4().simple().to_string(),
+            uploader: Some(user_id.to_string()),
+            content_type: None,
+            filename: None,
+            size: 0,
+            content_hash: None,
+            created_ts: now_millis(),
+            unused_expires_at,
+        }
+    }
+
+    /// Write content and record it; callers hold the uploader's lock so the
+    /// quota check and the new record cannot interleave with another upload
+    async fn store_content(
+        &self,
+        mut record: MediaRecord,
+        content_type: Option<String>,
+        filename: Option<String>,
+        content: &[u8],
+    ) -> Result<(), MediaError> {
+        if content.len() > self.config.max_upload_size {
+            return Err(MediaError::TooLarge(self.config.max_upload_size));
+        }
+        if let (Some(quota), Some(uploader)) = (self.config.user_quota, record.uploader.as_deref()) {
+            let used: u64 = self.state_store.get_user_media(uploader).await?.iter().map(|media| media.size).sum();
+            if used + content.len() as u64 > quota {
+                return Err(MediaError::QuotaExceeded(quota));
+            }
+        }
+
+        let (hash, _content_lock) = self.write_content(content).await?;
+        record.content_type = content_type;
+        record.filename = filename;
+        record.size = content.len() as u64;
+        record.content_hash = Some(hash);
+        record.unused_expires_at = None;
+        self.state_store.set_media(record).await?;
+        Ok(())
+    }
+
+    /// Write content under its hash unless identical content is stored already
+    ///
+    /// The returned lock keeps the content from being removed as unused
+    /// until the caller has recorded media referencing it.
+    async fn write_content(&self, content: &[u8]) -> Result<(String, OwnedMutexGuard<()>), MediaError> {
+        let hash = hex::encode(Sha256::digest(content));
+        let content_lock = self.content_locks.lock(&hash).await;
+        let path = self.content_path(&hash);
+        if tokio::fs::metadata(&path).await.is_err() {
+            write_atomically(&path, content).await?;
+        }
+        Ok((hash, content_lock))
+    }
+
+    /// Whether cached remote media is ever evicted
+    pub fn has_cache_eviction(&self) -> bool {
+        self.config.remote.cache_max_age.is_some() || self.config.remote.cache_max_size.is_some()
+    }
+
+    /// Apply the remote cache's age and size limits, returning how many
+    /// pieces of media were evicted
+    pub async fn evict_remote_cache(&self) -> Result<usize, MediaError> {
+        let remote = &self.config.remote;
+        let cutoff = remote.cache_max_age.map(|max_age| now_millis().saturating_sub(max_age.as_millis() as u64));
+
+        let mut cached = self.state_store.get_remote_media(&self.server_name).await?;
+        cached.sort_by_key(|record| record.created_ts);
+        let mut total: u64 = cached.iter().map(|record| record.size).sum();
+        let mut evicted = 0;
+        for record in cached {
+            let too_old = cutoff.is_some_and(|cutoff| record.created_ts < cutoff);
+            let over_size = remote.cache_max_size.is_some_and(|max_size| total > max_size);
+            if !too_old && !over_size {
+                break;
+            }
+            total -= record.size;
+            self.remove_media(&record).await?;
+            evicted += 1;
+        }
+        if let Some(cutoff) = cutoff {
+            self.remove_remote_thumbnails_before(cutoff).await?;
+        }
+        Ok(evicted)
+    }
+
+    /// Remove remote media cached before `before_ts`, returning how many
+    /// pieces of media were removed
+    pub async fn purge_remote_cache(&self, before_ts: u64) -> Result<usize, MediaError> {
+        let mut purged = 0;
+        for record in self.state_store.get_remote_media(&self.server_name).await? {
+            if record.created_ts < before_ts {
+                self.remove_media(&record).await?;
+                purged += 1;
+            }
+        }
+        self.remove_remote_thumbnails_before(before_ts).await?;
+        Ok(purged)
+    }
+
+    /// Forget media, deleting its content once nothing else shares it
+    async fn remove_media(&self, record: &MediaRecord) -> Result<(), MediaError> {
+        self.state_store.delete_media(&record.server_name, &record.media_id).await?;
+        remove_if_exists(&self.remote_thumbnail_dir(&record.server_name, &record.media_id)).await?;
+        if let Some(hash) = &record.content_hash {
+            let _content_lock = self.content_locks.lock(hash).await;
+            if self.state_store.get_media_by_hash(hash).await?.is_empty() {
+                remove_if_exists(&self.content_path(hash)).await?;
+                remove_if_exists(&self.config.media_path.join("thumbnails").join(hash)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete fetched remote thumbnails not written since `before_ts`
+    async fn remove_remote_thumbnails_before(&self, before_ts: u64) -> Result<(), MediaError> {
+        let mut entries = match tokio::fs::read_dir(self.config.media_path.join("remote_thumbnails")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            let modified_ts = modified.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            if modified_ts < before_ts {
+                remove_if_exists(&entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Metadata and content hash of remote media already in the cache
+    async fn cached_record(&self, server_name: &str, media_id: &str) -> Result<Option<(MediaRecord, String)>, MediaError> {
+        let Some(record) = self.state_store.get_media(server_name, media_id).await? else {
+            return Ok(None);
+        };
+        match record.content_hash.clone() {
+            Some(hash) if tokio::fs::metadata(self.content_path(&hash)).await.is_ok() => Ok(Some((record, hash))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetch remote media from its origin into the cache
+    async fn fetch_remote(&self, server_name: &str, media_id: &str) -> Result<(MediaRecord, String), MediaError> {
+        let client = self.remote_client(server_name, media_id)?;
+        let max_size = self.config.remote.max_size_for(server_name);
+        let fetched = client.download_media(server_name, media_id, max_size).await?;
+        let (hash, _content_lock) = self.write_content(&fetched.content).await?;
+        let record = MediaRecord {
+            server_name: server_name.to_string(),
+            media_id: media_id.to_string(),
+            uploader: None,
+            content_type: Some(fetched.content_type),
+            filename: fetched.filename,
+            size: fetched.content.len() as u64,
+            content_hash: Some(hash.clone()),
+            created_ts: now_millis(),
+            unused_expires_at: None,
+        };
+        self.state_store.set_media(record.clone()).await?;
+        Ok((record, hash))
+    }
+
+    /// A thumbnail generated by the origin of remote media, cached per size
+    /// and method
+    async fn remote_thumbnail(&self, server_name: &str, media_id: &str, query: &ThumbnailQuery) -> Result<MediaContent, MediaError> {
+        let name = format!("{}x{}-{}", query.width, query.height, query.method.as_str());
+        let thumbnail_path = self.remote_thumbnail_dir(server_name, media_id).join(name);
+        if let Ok(content) = tokio::fs::read(&thumbnail_path).await {
+            let content_type = image::guess_format(&content).map(|format| format.to_mime_type()).unwrap_or("image/png");
+            return Ok(MediaContent { content, content_type: content_type.to_string(), filename: None });
+        }
+
+        let client = self.remote_client(server_name, media_id)?;
+        let max_size = self.config.remote.max_size_for(server_name);
+        let thumbnail = client.get_media_thumbnail(server_name, media_id, query, max_size).await?;
+        write_atomically(&thumbnail_path, &thumbnail.content).await?;
+        Ok(MediaContent { filename: None, ..thumbnail })
+    }
+
+    fn remote_client(&self, server_name: &str, media_id: &str) -> Result<&FederationClient, MediaError> {
+        let mxc = format_mxc_uri(server_name, media_id);
+        match &self.federation_client {
+            Some(client) if parse_mxc_uri(&mxc).is_some() => Ok(client),
+            _ => Err(MediaError::NotFound(mxc)),
+        }
+    }
+
+    /// `{media_path}/remote_thumbnails/{sha256 of the mxc URI}`, so that
+    /// remote server names and media IDs never form paths themselves
+    fn remote_thumbnail_dir(&self, server_name: &str, media_id: &str) -> PathBuf {
+        let key = hex::encode(Sha256::digest(format_mxc_uri(server_name, media_id)));
+        self.config.media_path.join("remote_thumbnails").join(key)
+    }
+
+    /// Metadata and content hash of uploaded local media
+    async fn uploaded_record(
+        &self,
+        server_name: &str,
+        media_id: &str,
+        timeout: Option<Duration>,
+    ) -> Result<(MediaRecord, String), MediaError> {
+        let mxc = format_mxc_uri(server_name, media_id);
+        let deadline = tokio::time::Instant::now() + timeout.unwrap_or(DEFAULT_DOWNLOAD_TIMEOUT).min(MAX_DOWNLOAD_TIMEOUT);
+        loop {
+            let record = self.state_store
+                .get_media(server_name, media_id)
+                .await?
+                .filter(|record| record.content_hash.is_some() || !is_expired(record))
+                .ok_or_else(|| MediaError::NotFound(mxc.clone()))?;
+            if let Some(hash) = record.content_hash.clone() {
+                return Ok((record, hash));
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(MediaError::NotYetUploaded(mxc));
+            }
+            tokio::time::sleep(PENDING_POLL_INTERVAL).await;
+        }
+    }
+
+    /// `{media_path}/content/ab/cd/abcd...`
+    fn content_path(&self, hash: &str) -> PathBuf {
+        self.config.media_path.join("content").join(&hash[..2]).join(&hash[2..4]).join(hash)
+    }
+}
+
+fn is_expired(record: &MediaRecord) -> bool {
+    record.unused_expires_at.is_some_and(|expiry| expiry <= now_millis())
+}
+
+/// Resize `image` and encode it as JPEG for JPEG sources, PNG otherwise
+fn render_thumbnail(
+    image: &DynamicImage,
+    source: ImageFormat,
+    width: u32,
+    height: u32,
+    method: ThumbnailMethod,
+) -> Result<(Vec<u8>, ImageFormat), MediaError> {
+    let thumbnail = match method {
+        ThumbnailMethod::Crop => image.resize_to_fill(width, height, FilterType::Triangle),
+        ThumbnailMethod::Scale => image.resize(width, height, FilterType::Triangle),
+    };
+    let mut content = Cursor::new(Vec::new());
+    let output = if source == ImageFormat::Jpeg {
+        DynamicImage::ImageRgb8(thumbnail.to_rgb8()).write_to(&mut content, ImageFormat::Jpeg)
+            .map(|_| ImageFormat::Jpeg)
+    } else {
+        thumbnail.write_to(&mut content, ImageFormat::Png).map(|_| ImageFormat::Png)
+    };
+    let output = output.map_err(|e| MediaError::NotAnImage(e.to_string()))?;
+    Ok((content.into_inner(), output))
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    let result = match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Write through a temporary file so readers never see partial content
+async fn write_atomically(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let temporary = path.with_extension(format!("tmp-{}", Uuid::new_v4().simple()));
+    tokio::fs::write(&temporary, content).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStateStore;
+
+    fn repository(media_path: &std::path::Path, user_quota: Option<u64>) -> (Arc<InMemoryStateStore>, MediaRepository) {
+        let store = Arc::new(InMemoryStateStore::new());
+        let config = MediaConfig {
+            media_path: media_path.to_path_buf(),
+            max_upload_size: 1024 * 1024,
+            user_quota,
+            max_image_pixels: DEFAULT_MAX_IMAGE_PIXELS,
+            remote: RemoteMediaConfig::default(),
+        };
+        (store.clone(), MediaRepository::new(store, config, "localhost"))
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(width, height, image::Rgb([200, 40, 40])));
+        let mut content = Cursor::new(Vec::new());
+        image.write_to(&mut content, ImageFormat::Png).unwrap();
+        content.into_inner()
+    }
+
+    #[test]
+    fn test_parse_mxc_uri() {
+        assert_eq!(parse_mxc_uri("mxc://localhost/abc_123"), Some(("localhost", "abc_123")));
+        assert_eq!(parse_mxc_uri("mxc://localhost/"), None);
+        assert_eq!(parse_mxc_uri("mxc://localhost/../etc"), None);
+        assert_eq!(parse_mxc_uri("https://localhost/abc"), None);
+    }
+
+    #[tokio::test]
+    async fn test_uploads_dedup_and_count_towards_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, media) = repository(dir.path(), Some(10));
+        let first = media.upload("@alice:localhost", Some("text/plain".to_string()), None, b"hello").await.unwrap();
+        let second = media.upload("@alice:localhost", None, Some("copy.txt".to_string()), b"hello").await.unwrap();
+        assert_ne!(first.content_uri, second.content_uri);
+
+        let (_, first_id) = parse_mxc_uri(&first.content_uri).unwrap();
+        let (_, second_id) = parse_mxc_uri(&second.content_uri).unwrap();
+        let first_record = store.get_media("localhost", first_id).await.unwrap().unwrap();
+        let second_record = store.get_media("localhost", second_id).await.unwrap().unwrap();
+        assert_eq!(first_record.content_hash, second_record.content_hash);
+        let hash = first_record.content_hash.unwrap();
+        assert_eq!(std::fs::read_dir(dir.path().join("content").join(&hash[..2]).join(&hash[2..4])).unwrap().count(), 1);
+
+        let downloaded = media.download("localhost", second_id, None).await.unwrap();
+        assert_eq!(downloaded.content, b"hello");
+        assert_eq!(downloaded.content_disposition(), "attachment; filename=\"copy.txt\"");
+
+        let result = media.upload("@alice:localhost", None, None, b"!").await;
+        assert!(matches!(result, Err(MediaError::QuotaExceeded(10))));
+        media.upload("@bob:localhost", None, None, b"!").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_uploads_share_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, media) = repository(dir.path(), Some(10));
+        let upload = |content: &'static [u8]| media.upload("@alice:localhost", None, None, content);
+        let results = tokio::join!(upload(b"aaaa"), upload(b"bbbb"), upload(b"cccc"), upload(b"dddd"));
+        let results = [results.0, results.1, results.2, results.3];
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        assert!(results.iter().all(|result| result.is_ok() || matches!(result, Err(MediaError::QuotaExceeded(10)))));
+    }
+
+    #[tokio::test]
+    async fn test_async_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, media) = repository(dir.path(), None);
+        let created = media.create("@alice:localhost").await.unwrap();
+        let (server_name, media_id) = parse_mxc_uri(&created.content_uri).unwrap();
+
+        let result = media.download(server_name, media_id, Some(Duration::ZERO)).await;
+        assert!(matches!(result, Err(MediaError::NotYetUploaded(_))));
+        let result = media.upload_to("@bob:localhost", server_name, media_id, None, None, b"data").await;
+        assert!(matches!(result, Err(MediaError::Forbidden(_))));
+
+        media.upload_to("@alice:localhost", server_name, media_id, None, None, b"data").await.unwrap();
+        let result = media.upload_to("@alice:localhost", server_name, media_id, None, None, b"again").await;
+        assert!(matches!(result, Err(MediaError::CannotOverwrite(_))));
+        assert_eq!(media.download(server_name, media_id, None).await.unwrap().content, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_multipart_round_trip() {
+        let content = MediaContent {
+            content: b"\r\n--not a boundary\r\n".to_vec(),
+            content_type: "text/plain".to_string(),
+            filename: Some("notes.txt".to_string()),
+        };
+        let response = multipart_response(content.clone());
+        let content_type = response.headers()["content-type"].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        let parsed = parse_multipart(&content_type, &body).unwrap();
+        assert_eq!(parsed.content.content, content.content);
+        assert_eq!(parsed.content.content_type, "text/plain");
+        assert_eq!(parsed.content.filename.as_deref(), Some("notes.txt"));
+        assert!(parsed.location.is_none());
+        assert!(parse_multipart("application/json", &body).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remote_cache_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, mut media) = repository(dir.path(), None);
+        media.config.remote.cache_max_age = Some(Duration::from_secs(60));
+        media.config.remote.cache_max_size = Some(10);
+
+        let now = now_millis();
+        let cached = [("stale", b"aaaa", now - 120_000), ("older", b"bbbb", now - 2_000), ("newer", b"cccc", now - 1_000), ("newest", b"dddd", now)];
+        for (media_id, content, created_ts) in cached {
+            let (hash, _) = media.write_content(content).await.unwrap();
+            store.set_media(MediaRecord {
+                server_name: "remote.example".to_string(),
+                media_id: media_id.to_string(),
+                uploader: None,
+                content_type: None,
+                filename: None,
+                size: content.len() as u64,
+                content_hash: Some(hash),
+                created_ts,
+                unused_expires_at: None,
+            }).await.unwrap();
+        }
+        // Local media shares content with remote media but is never evicted
+        let local = media.upload("@alice:localhost", None, None, b"bbbb").await.unwrap();
+
+        // The stale entry is too old, then the oldest go until 10 bytes remain
+        assert_eq!(media.evict_remote_cache().await.unwrap(), 2);
+        let remaining: Vec<_> = store.get_remote_media("localhost").await.unwrap().into_iter().map(|record| record.media_id).collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.contains(&"newer".to_string()) && remaining.contains(&"newest".to_string()));
+        assert!(!media.content_path(&hex::encode(Sha256::digest(b"aaaa"))).exists());
+        let (_, local_id) = parse_mxc_uri(&local.content_uri).unwrap();
+        assert_eq!(media.download("localhost", local_id, None).await.unwrap().content, b"bbbb");
+
+        assert_eq!(media.purge_remote_cache(now + 1).await.unwrap(), 2);
+        assert!(store.get_remote_media("localhost").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_thumbnails() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, media) = repository(dir.path(), None);
+        let uploaded = media.upload("@alice:localhost", Some("image/png".to_string()), None, &png(400, 200)).await.unwrap();
+        let (server_name, media_id) = parse_mxc_uri(&uploaded.content_uri).unwrap();
+
+        let query = |width, height, method| ThumbnailQuery { width, height, method, timeout_ms: None };
+        for (method, expected) in [(ThumbnailMethod::Scale, (100, 50)), (ThumbnailMethod::Crop, (100, 100))] {
+            let thumbnail = media.thumbnail(server_name, media_id, &query(100, 100, method)).await.unwrap();
+            assert_eq!(thumbnail.content_type, "image/png");
+            let image = image::load_from_memory(&thumbnail.content).unwrap();
+            assert_eq!((image.width(), image.height()), expected);
+        }
+        // Thumbnails are cached, and small images are served as they are
+        assert!(dir.path().join("thumbnails").read_dir().unwrap().next().is_some());
+        let original = media.thumbnail(server_name, media_id, &query(800, 600, ThumbnailMethod::Scale)).await.unwrap();
+        assert_eq!(original.content, png(400, 200));
+
+        let text = media.upload("@alice:localhost", Some("text/plain".to_string()), None, b"not an image").await.unwrap();
+        let (_, text_id) = parse_mxc_uri(&text.content_uri).unwrap();
+        let result = media.thumbnail(server_name, text_id, &query(32, 32, ThumbnailMethod::Scale)).await;
+        assert!(matches!(result, Err(MediaError::NotAnImage(_))));
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_pixel_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, media) = repository(dir.path(), None);
+        let uploaded = media.upload("@alice:localhost", Some("image/png".to_string()), None, &png(400, 200)).await.unwrap();
+        let (server_name, media_id) = parse_mxc_uri(&uploaded.content_uri).unwrap();
+
+        let mut config = media.config.clone();
+        config.max_image_pixels = 400 * 200 - 1;
+        let media = MediaRepository::new(store, config, "localhost");
+        let query = |width, height| ThumbnailQuery { width, height, method: ThumbnailMethod::Scale, timeout_ms: None };
+        let result = media.thumbnail(server_name, media_id, &query(100, 100)).await;
+        assert!(matches!(result, Err(MediaError::ImageTooLarge(_))));
+        // Images that already fit are served without decoding them
+        assert!(media.thumbnail(server_name, media_id, &query(400, 200)).await.is_ok());
+    }
+}
@@ -0,0 +1,82 @@
+// Notifications
+// The per-user log of events that notified them through their push rules
+// Focus: Paginating the log newest first with read state from receipts
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::events::MatrixEvent;
+use crate::push_rules::is_read;
+use crate::room::{format_stream_token, parse_stream_token, RoomError};
+use crate::state::StateStore;
+
+const DEFAULT_NOTIFICATIONS_LIMIT: usize = 20;
+const MAX_NOTIFICATIONS_LIMIT: usize = 100;
+
+/// `only` filter restricting the log to highlighted notifications
+pub const ONLY_HIGHLIGHT: &str = "highlight";
+
+/// Query of GET /notifications
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GetNotificationsRequest {
+    pub from: Option<String>,
+    pub limit: Option<usize>,
+    pub only: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub actions: Vec<Value>,
+    pub event: MatrixEvent,
+    /// Whether the user's read receipts cover the event
+    pub read: bool,
+    pub room_id: String,
+    /// When the event was sent, in milliseconds since the Unix epoch
+    pub ts: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationsResponse {
+    pub notifications: Vec<Notification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<String>,
+}
+
+/// Notifications of `user_id`, newest first, starting at the `from` token
+pub async fn get_notifications(
+    store: &dyn StateStore,
+    user_id: &str,
+    request: GetNotificationsRequest,
+) -> Result<NotificationsResponse, RoomError> {
+    let from = request.from.as_deref().map(parse_stream_token).transpose()?;
+    let limit = request.limit.unwrap_or(DEFAULT_NOTIFICATIONS_LIMIT).min(MAX_NOTIFICATIONS_LIMIT);
+    let only_highlight = match request.only.as_deref() {
+        None => false,
+        Some(ONLY_HIGHLIGHT) => true,
+        Some(only) => return Err(RoomError::InvalidParam(format!("Unknown notification filter {}", only))),
+    };
+
+    let mut notifications = Vec::new();
+    let mut next_token = None;
+    for action in store.get_push_actions(user_id, None).await?.into_iter().rev() {
+        if from.is_some_and(|from| action.stream_ordering > from) || (only_highlight && !action.highlight) {
+            continue;
+        }
+        let Some(event) = store.get_event(&action.event_id).await? else {
+            continue;
+        };
+        if notifications.len() == limit {
+            next_token = Some(format_stream_token(action.stream_ordering));
+            break;
+        }
+        notifications.push(Notification {
+            read: is_read(store, user_id, &action).await?,
+            ts: event.origin_server_ts,
+            room_id: action.room_id,
+            actions: action.actions,
+            event,
+        });
+    }
+
+    Ok(NotificationsResponse { notifications, next_token })
+}
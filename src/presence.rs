@@ -0,0 +1,420 @@
+// Presence
+// Online, unavailable and offline tracking driven by client activity
+// Focus: The in-memory presence stream, its idle timeouts and m.presence EDUs
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::events::MembershipState;
+use crate::state::{StateError, StateStore};
+
+/// EDU type of presence updates between servers
+pub const PRESENCE_EDU_TYPE: &str = "m.presence";
+
+/// Online users with no activity for this long become unavailable
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Users who have not synced for this long go offline
+pub const OFFLINE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Remote presence not refreshed for this long goes offline, and is
+/// forgotten once it has read as offline for as long again
+pub const REMOTE_PRESENCE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceState {
+    Online,
+    Unavailable,
+    #[default]
+    Offline,
+}
+
+/// Body of PUT /presence/{userId}/status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetPresenceRequest {
+    pub presence: PresenceState,
+    #[serde(default)]
+    pub status_msg: Option<String>,
+}
+
+/// A user's presence as clients see it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PresenceStatus {
+    pub presence: PresenceState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_msg: Option<String>,
+    /// Milliseconds since the user was last active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_active_ago: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currently_active: Option<bool>,
+}
+
+/// One user's presence in an `m.presence` EDU
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserPresenceUpdate {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub status: PresenceStatus,
+}
+
+/// `m.presence` EDU content
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PresenceEdu {
+    pub push: Vec<UserPresenceUpdate>,
+}
+
+struct UserPresence {
+    state: PresenceState,
+    status_msg: Option<String>,
+    /// Unix time in milliseconds of the user's last activity
+    last_active: u64,
+    /// Whether the user chose `unavailable` rather than going idle
+    explicitly_unavailable: bool,
+    /// Whether the presence is reported by the user's own server
+    remote: bool,
+    /// Unix time in milliseconds remote presence was last received or expired
+    updated: u64,
+    /// Presence stream position of the last change
+    position: u64,
+}
+
+impl UserPresence {
+    fn status(&self, now: u64) -> PresenceStatus {
+        PresenceStatus {
+            presence: self.state,
+            status_msg: self.status_msg.clone(),
+            last_active_ago: Some(now.saturating_sub(self.last_active)),
+            currently_active: Some(self.state == PresenceState::Online),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PresenceStream {
+    position: u64,
+    users: HashMap<String, UserPresence>,
+}
+
+impl PresenceStream {
+    fn next_position(&mut self) -> u64 {
+        self.position += 1;
+        self.position
+    }
+}
+
+/// Tracks each user's presence; changes advance a stream position sync reads
+///
+/// A disabled handler records nothing, so every user reads as offline and
+/// sync carries no presence.
+pub struct PresenceHandler {
+    enabled: bool,
+    stream: Arc<RwLock<PresenceStream>>,
+}
+
+impl Default for PresenceHandler {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl PresenceHandler {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            stream: Arc::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Set presence a user chose explicitly; returns whether it changed
+    pub async fn set_presence(&self, user_id: &str, request: SetPresenceRequest) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let now = now_millis();
+        let mut stream = self.stream.write().await;
+        let explicitly_unavailable = request.presence == PresenceState::Unavailable;
+        if let Some(presence) = stream.users.get_mut(user_id) {
+            presence.last_active = now;
+            presence.explicitly_unavailable = explicitly_unavailable;
+            if presence.state == request.presence && presence.status_msg == request.status_msg {
+                return false;
+            }
+        }
+
+        let position = stream.next_position();
+        stream.users.insert(user_id.to_string(), UserPresence {
+            state: request.presence,
+            status_msg: request.status_msg,
+            last_active: now,
+            explicitly_unavailable,
+            remote: false,
+            updated: now,
+            position,
+        });
+        true
+    }
+
+    /// Record a sync by `user_id`; syncing keeps them online unless they
+    /// chose otherwise with `set_presence`. Returns whether presence changed.
+    pub async fn sync_activity(&self, user_id: &str, set_presence: PresenceState) -> bool {
+        if !self.enabled || set_presence == PresenceState::Offline {
+            return false;
+        }
+        let now = now_millis();
+        let mut stream = self.stream.write().await;
+        let target = match stream.users.get(user_id) {
+            Some(presence) if presence.explicitly_unavailable => PresenceState::Unavailable,
+            _ => set_presence,
+        };
+
+        if let Some(presence) = stream.users.get_mut(user_id) {
+            presence.last_active = now;
+            if presence.state == target {
+                return false;
+            }
+        }
+        let position = stream.next_position();
+        let presence = stream.users.entry(user_id.to_string()).or_insert_with(|| UserPresence {
+            state: target,
+            status_msg: None,
+            last_active: now,
+            explicitly_unavailable: false,
+            remote: false,
+            updated: now,
+            position,
+        });
+        presence.state = target;
+        presence.position = position;
+        true
+    }
+
+    /// Presence of a user on another server, as pushed by that server
+    pub async fn receive_presence(&self, update: UserPresenceUpdate) {
+        if !self.enabled {
+            return;
+        }
+        let now = now_millis();
+        let last_active = now.saturating_sub(update.status.last_active_ago.unwrap_or(0));
+        let mut stream = self.stream.write().await;
+        let position = stream.next_position();
+        stream.users.insert(update.user_id, UserPresence {
+            state: update.status.presence,
+            status_msg: update.status.status_msg,
+            last_active,
+            explicitly_unavailable: false,
+            remote: true,
+            updated: now,
+            position,
+        });
+    }
+
+    /// Move local users to unavailable or offline once their activity
+    /// is older than the idle and offline timeouts, and expire remote
+    /// presence per `REMOTE_PRESENCE_TIMEOUT`; returns the local users who
+    /// changed
+    pub async fn apply_timeouts(&self, now: u64) -> Vec<String> {
+        let mut stream = self.stream.write().await;
+        let mut changed = Vec::new();
+        let mut expired = Vec::new();
+        let mut forgotten = Vec::new();
+        for (user_id, presence) in stream.users.iter_mut() {
+            if presence.remote {
+                if Duration::from_millis(now.saturating_sub(presence.updated)) < REMOTE_PRESENCE_TIMEOUT {
+                    continue;
+                }
+                if presence.state == PresenceState::Offline {
+                    forgotten.push(user_id.clone());
+                } else {
+                    presence.state = PresenceState::Offline;
+                    presence.updated = now;
+                    expired.push(user_id.clone());
+                }
+                continue;
+            }
+            let inactive = Duration::from_millis(now.saturating_sub(presence.last_active));
+            let target = if inactive >= OFFLINE_TIMEOUT {
+                PresenceState::Offline
+            } else if inactive >= IDLE_TIMEOUT && presence.state == PresenceState::Online {
+                PresenceState::Unavailable
+            } else {
+                continue;
+            };
+            if presence.state != target {
+                presence.state = target;
+                changed.push(user_id.clone());
+            }
+        }
+
+        for user_id in &forgotten {
+            stream.users.remove(user_id);
+        }
+        for user_id in changed.iter().chain(&expired) {
+            let position = stream.next_position();
+            if let Some(presence) = stream.users.get_mut(user_id) {
+                presence.position = position;
+            }
+        }
+        changed
+    }
+
+    /// A user's current presence; users never seen read as offline
+    pub async fn get_presence(&self, user_id: &str) -> PresenceStatus {
+        let stream = self.stream.read().await;
+        stream.users
+            .get(user_id)
+            .map(|presence| presence.status(now_millis()))
+            .unwrap_or_default()
+    }
+
+    /// Presence of `user_ids` that changed after stream position `since`
+    pub async fn changes_since(&self, user_ids: &HashSet<String>, since: u64) -> Vec<UserPresenceUpdate> {
+        let now = now_millis();
+        let stream = self.stream.read().await;
+        let mut changes: Vec<(u64, UserPresenceUpdate)> = stream.users
+            .iter()
+            .filter(|(user_id, presence)| presence.position > since && user_ids.contains(*user_id))
+            .map(|(user_id, presence)| (presence.position, UserPresenceUpdate {
+                user_id: user_id.clone(),
+                status: presence.status(now),
+            }))
+            .collect();
+        changes.sort_by_key(|(position, _)| *position);
+        changes.into_iter().map(|(_, update)| update).collect()
+    }
+
+    /// Highest presence stream position handed out so far
+    pub async fn current_position(&self) -> u64 {
+        self.stream.read().await.position
+    }
+}
+
+/// Whether `user_id` may see `other_user_id`'s presence: themselves, or
+/// anyone they share a joined room with
+pub async fn can_see_presence(store: &dyn StateStore, user_id: &str, other_user_id: &str) -> Result<bool, StateError> {
+    if user_id == other_user_id {
+        return Ok(true);
+    }
+    for room_id in store.get_rooms_for_user(user_id).await? {
+        if let Some(room_state) = store.get_room(&room_id).await? {
+            if room_state.is_member(user_id) && room_state.is_member(other_user_id) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Whether remote `user_id` shares a joined room with a user of
+/// `server_name`; this server has no use for anyone else's presence
+pub async fn shares_room_with_server(store: &dyn StateStore, user_id: &str, server_name: &str) -> Result<bool, StateError> {
+    for room_id in store.get_rooms_for_user(user_id).await? {
+        let Some(room_state) = store.get_room(&room_id).await? else {
+            continue;
+        };
+        if !room_state.is_member(user_id) {
+            continue;
+        }
+        let has_local_member = room_state.members.iter().any(|(member, membership)| {
+            *membership == MembershipState::Join
+                && member.split_once(':').is_none_or(|(_, server)| server == server_name)
+        });
+        if has_local_member {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sync_activity_and_timeouts() {
+        let presence = PresenceHandler::new(true);
+        assert!(presence.sync_activity("@alice:localhost", PresenceState::Online).await);
+        assert!(!presence.sync_activity("@alice:localhost", PresenceState::Online).await);
+        assert_eq!(presence.get_presence("@alice:localhost").await.presence, PresenceState::Online);
+
+        let now = now_millis();
+        assert!(presence.apply_timeouts(now).await.is_empty());
+        let idle = now + IDLE_TIMEOUT.as_millis() as u64;
+        assert_eq!(presence.apply_timeouts(idle).await, vec!["@alice:localhost"]);
+        assert_eq!(presence.get_presence("@alice:localhost").await.presence, PresenceState::Unavailable);
+        let gone = now + OFFLINE_TIMEOUT.as_millis() as u64;
+        assert_eq!(presence.apply_timeouts(gone).await, vec!["@alice:localhost"]);
+        assert_eq!(presence.get_presence("@alice:localhost").await.presence, PresenceState::Offline);
+
+        // Syncing again brings the user back online
+        assert!(presence.sync_activity("@alice:localhost", PresenceState::Online).await);
+        assert_eq!(presence.get_presence("@alice:localhost").await.currently_active, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_explicit_presence_survives_sync() {
+        let presence = PresenceHandler::new(true);
+        let away = SetPresenceRequest {
+            presence: PresenceState::Unavailable,
+            status_msg: Some("At lunch".to_string()),
+        };
+        assert!(presence.set_presence("@alice:localhost", away.clone()).await);
+        let position = presence.current_position().await;
+        assert!(!presence.set_presence("@alice:localhost", away).await);
+        assert_eq!(presence.current_position().await, position);
+
+        assert!(!presence.sync_activity("@alice:localhost", PresenceState::Online).await);
+        let status = presence.get_presence("@alice:localhost").await;
+        assert_eq!(status.presence, PresenceState::Unavailable);
+        assert_eq!(status.status_msg.as_deref(), Some("At lunch"));
+
+        let users = HashSet::from(["@alice:localhost".to_string()]);
+        assert_eq!(presence.changes_since(&users, 0).await.len(), 1);
+        assert!(presence.changes_since(&users, position).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remote_presence_expires() {
+        let presence = PresenceHandler::new(true);
+        presence.receive_presence(UserPresenceUpdate {
+            user_id: "@bob:remote.example".to_string(),
+            status: PresenceStatus { presence: PresenceState::Online, ..Default::default() },
+        }).await;
+        let position = presence.current_position().await;
+
+        // Remote users are never federated onwards, but their stale presence still changes
+        let now = now_millis();
+        let stale = now + REMOTE_PRESENCE_TIMEOUT.as_millis() as u64;
+        assert!(presence.apply_timeouts(stale).await.is_empty());
+        assert_eq!(presence.get_presence("@bob:remote.example").await.presence, PresenceState::Offline);
+        let users = HashSet::from(["@bob:remote.example".to_string()]);
+        assert_eq!(presence.changes_since(&users, position).await.len(), 1);
+
+        let forgotten = stale + REMOTE_PRESENCE_TIMEOUT.as_millis() as u64;
+        presence.apply_timeouts(forgotten).await;
+        assert!(presence.changes_since(&users, 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_presence_records_nothing() {
+        let presence = PresenceHandler::new(false);
+        assert!(!presence.sync_activity("@alice:localhost", PresenceState::Online).await);
+        assert_eq!(presence.get_presence("@alice:localhost").await, PresenceStatus::default());
+        assert_eq!(presence.current_position().await, 0);
+    }
+}
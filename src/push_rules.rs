@@ -0,0 +1,902 @@
+// Push Rules
+// Per-user push rules, their evaluation against new events, and unread counts
+// Focus: The spec default ruleset, rule CRUD and notification counting
+
+use std::collections::HashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::events::{glob_matches, EventContent, EventType, MatrixEvent, MembershipState, REL_TYPE_THREAD};
+use crate::receipts::{ReceiptType, MAIN_THREAD_ID};
+use crate::state::{PushAction, RoomState, StateError, StateStore};
+
+/// Global account data type holding a user's push rules
+pub const PUSH_RULES_ACCOUNT_DATA_TYPE: &str = "m.push_rules";
+
+/// The only push rule scope
+pub const GLOBAL_SCOPE: &str = "global";
+
+/// Rules superseded by intentional mentions when an event carries `m.mentions`
+const LEGACY_MENTION_RULES: [&str; 3] = [".m.rule.contains_display_name", ".m.rule.roomnotif", ".m.rule.contains_user_name"];
+
+/// Power level required for a notification key when power levels name none
+const DEFAULT_NOTIFICATION_LEVEL: i32 = 50;
+
+#[derive(Error, Debug)]
+pub enum PushRuleError {
+    #[error("Push rule not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
+
+    #[error("Bad JSON: {0}")]
+    BadJson(String),
+
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+}
+
+impl PushRuleError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            PushRuleError::NotFound(_) => 404,
+            PushRuleError::InvalidParam(_) => 400,
+            PushRuleError::BadJson(_) => 400,
+            PushRuleError::State(_) => 500,
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            PushRuleError::NotFound(_) => "M_NOT_FOUND",
+            PushRuleError::InvalidParam(_) => "M_INVALID_PARAM",
+            PushRuleError::BadJson(_) => "M_BAD_JSON",
+            PushRuleError::State(_) => "M_UNKNOWN",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    Override,
+    Content,
+    Room,
+    Sender,
+    Underride,
+}
+
+impl RuleKind {
+    /// Kinds in the order their rules are evaluated
+    pub const PRIORITY: [RuleKind; 5] = [
+        RuleKind::Override,
+        RuleKind::Content,
+        RuleKind::Room,
+        RuleKind::Sender,
+        RuleKind::Underride,
+    ];
+
+    pub fn parse(kind: &str) -> Result<Self, PushRuleError> {
+        serde_json::from_value(Value::String(kind.to_string()))
+            .map_err(|_| PushRuleError::InvalidParam(format!("Unknown push rule kind {}", kind)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PushCondition {
+    EventMatch { key: String, pattern: String },
+    EventPropertyIs { key: String, value: Value },
+    EventPropertyContains { key: String, value: Value },
+    ContainsDisplayName,
+    RoomMemberCount { is: String },
+    SenderNotificationPermission { key: String },
+    /// Conditions this server does not understand never match
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushRule {
+    pub rule_id: String,
+    /// Whether this is one of the server-default rules
+    #[serde(default)]
+    pub default: bool,
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<PushCondition>>,
+    /// Glob matched against `content.body` by content rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    pub actions: Vec<Value>,
+}
+
+impl PushRule {
+    fn server_default(rule_id: &str, conditions: Vec<PushCondition>, actions: Vec<Value>) -> Self {
+        Self {
+            rule_id: rule_id.to_string(),
+            default: true,
+            enabled: true,
+            conditions: Some(conditions),
+            pattern: None,
+            actions,
+        }
+    }
+}
+
+/// A user's rules by kind, each kind in priority order
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ruleset {
+    #[serde(rename = "override", default)]
+    pub override_rules: Vec<PushRule>,
+    #[serde(default)]
+    pub content: Vec<PushRule>,
+    #[serde(default)]
+    pub room: Vec<PushRule>,
+    #[serde(default)]
+    pub sender: Vec<PushRule>,
+    #[serde(default)]
+    pub underride: Vec<PushRule>,
+}
+
+/// Body of GET /pushrules/
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PushRulesResponse {
+    pub global: Ruleset,
+}
+
+/// Body of PUT /pushrules/global/{kind}/{ruleId}
+#[derive(Debug, Clone, Deserialize)]
+pub struct PushRuleBody {
+    pub actions: Vec<Value>,
+    #[serde(default)]
+    pub conditions: Option<Vec<PushCondition>>,
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+/// Where a new rule goes relative to the user's other rules of its kind
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PushRulePosition {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationCounts {
+    pub highlight_count: u64,
+    pub notification_count: u64,
+}
+
+impl NotificationCounts {
+    fn add(&mut self, action: &PushAction) {
+        self.notification_count += 1;
+        if action.highlight {
+            self.highlight_count += 1;
+        }
+    }
+
+    fn merge(&mut self, other: NotificationCounts) {
+        self.notification_count += other.notification_count;
+        self.highlight_count += other.highlight_count;
+    }
+}
+
+impl Ruleset {
+    /// The spec's server-default rules for `user_id`
+    pub fn server_default(user_id: &str) -> Self {
+        let event_match = |key: &str, pattern: &str| PushCondition::EventMatch {
+            key: key.to_string(),
+            pattern: pattern.to_string(),
+        };
+        let property_is = |key: &str, value: Value| PushCondition::EventPropertyIs { key: key.to_string(), value };
+        let room_permission = || PushCondition::SenderNotificationPermission { key: "room".to_string() };
+        let one_to_one = || PushCondition::RoomMemberCount { is: "2".to_string() };
+        let sound = || json!({ "set_tweak": "sound", "value": "default" });
+        let highlight = || json!({ "set_tweak": "highlight" });
+        let notify = || json!("notify");
+
+        let mut master = PushRule::server_default(".m.rule.master", vec![], vec![]);
+        master.enabled = false;
+        let localpart = user_id.trim_start_matches('@').split(':').next().unwrap_or(user_id);
+
+        Self {
+            override_rules: vec![
+                master,
+                PushRule::server_default(".m.rule.suppress_notices", vec![event_match("content.msgtype", "m.notice")], vec![]),
+                PushRule::server_default(
+                    ".m.rule.invite_for_me",
+                    vec![
+                        event_match("type", "m.room.member"),
+                        event_match("content.membership", "invite"),
+                        event_match("state_key", user_id),
+                    ],
+                    vec![notify(), sound(), json!({ "set_tweak": "highlight", "value": false })],
+                ),
+                PushRule::server_default(".m.rule.member_event", vec![event_match("type", "m.room.member")], vec![]),
+                PushRule::server_default(
+                    ".m.rule.is_user_mention",
+                    vec![PushCondition::EventPropertyContains {
+                        key: r"content.m\.mentions.user_ids".to_string(),
+                        value: json!(user_id),
+                    }],
+                    vec![notify(), sound(), highlight()],
+                ),
+                PushRule::server_default(
+                    ".m.rule.contains_display_name",
+                    vec![PushCondition::ContainsDisplayName],
+                    vec![notify(), sound(), highlight()],
+                ),
+                PushRule::server_default(
+                    ".m.rule.is_room_mention",
+                    vec![property_is(r"content.m\.mentions.room", json!(true)), room_permission()],
+                    vec![notify(), highlight()],
+                ),
+                PushRule::server_default(
+                    ".m.rule.roomnotif",
+                    vec![room_permission(), event_match("content.body", "@room")],
+                    vec![notify(), highlight()],
+                ),
+                PushRule::server_default(
+                    ".m.rule.tombstone",
+                    vec![event_match("type", "m.room.tombstone"), event_match("state_key", "")],
+                    vec![notify(), highlight()],
+                ),
+                PushRule::server_default(".m.rule.reaction", vec![event_match("type", "m.reaction")], vec![]),
+                PushRule::server_default(
+                    ".m.rule.room.server_acl",
+                    vec![event_match("type", "m.room.server_acl"), event_match("state_key", "")],
+                    vec![],
+                ),
+                PushRule::server_default(
+                    ".m.rule.suppress_edits",
+                    vec![property_is(r"content.m\.relates_to.rel_type", json!("m.replace"))],
+                    vec![],
+                ),
+            ],
+            content: vec![PushRule {
+                rule_id: ".m.rule.contains_user_name".to_string(),
+                default: true,
+                enabled: true,
+                conditions: None,
+                pattern: Some(localpart.to_string()),
+                actions: vec![notify(), sound(), highlight()],
+            }],
+            room: vec![],
+            sender: vec![],
+            underride: vec![
+                PushRule::server_default(
+                    ".m.rule.call",
+                    vec![event_match("type", "m.call.invite")],
+                    vec![notify(), json!({ "set_tweak": "sound", "value": "ring" })],
+                ),
+                PushRule::server_default(
+                    ".m.rule.encrypted_room_one_to_one",
+                    vec![one_to_one(), event_match("type", "m.room.encrypted")],
+                    vec![notify(), sound()],
+                ),
+                PushRule::server_default(
+                    ".m.rule.room_one_to_one",
+                    vec![one_to_one(), event_match("type", "m.room.message")],
+                    vec![notify(), sound()],
+                ),
+                PushRule::server_default(".m.rule.message", vec![event_match("type", "m.room.message")], vec![notify()]),
+                PushRule::server_default(".m.rule.encrypted", vec![event_match("type", "m.room.encrypted")], vec![notify()]),
+            ],
+        }
+    }
+
+    pub fn rules(&self, kind: RuleKind) -> &Vec<PushRule> {
+        match kind {
+            RuleKind::Override => &self.override_rules,
+            RuleKind::Content => &self.content,
+            RuleKind::Room => &self.room,
+            RuleKind::Sender => &self.sender,
+            RuleKind::Underride => &self.underride,
+        }
+    }
+
+    fn rules_mut(&mut self, kind: RuleKind) -> &mut Vec<PushRule> {
+        match kind {
+            RuleKind::Override => &mut self.override_rules,
+            RuleKind::Content => &mut self.content,
+            RuleKind::Room => &mut self.room,
+            RuleKind::Sender => &mut self.sender,
+            RuleKind::Underride => &mut self.underride,
+        }
+    }
+
+    /// The first enabled rule matching the event, in priority order
+    pub fn evaluate(&self, context: &PushContext<'_>) -> Option<&PushRule> {
+        let has_mentions = lookup(&context.event, r"content.m\.mentions").is_some();
+        RuleKind::PRIORITY
+            .iter()
+            .flat_map(|kind| self.rules(*kind).iter().map(move |rule| (*kind, rule)))
+            .filter(|(_, rule)| rule.enabled)
+            .filter(|(_, rule)| !(has_mentions && rule.default && LEGACY_MENTION_RULES.contains(&rule.rule_id.as_str())))
+            .find(|(kind, rule)| context.matches(*kind, rule))
+            .map(|(_, rule)| rule)
+    }
+}
+
+/// What a rule is evaluated against: the event and the user it may notify
+pub struct PushContext<'a> {
+    /// The event as clients see it
+    pub event: Value,
+    pub user_id: &'a str,
+    pub display_name: Option<&'a str>,
+    pub member_count: usize,
+    pub sender_power_level: i32,
+    /// Notification key -> power level required to trigger it
+    pub notification_levels: HashMap<String, i32>,
+}
+
+impl PushContext<'_> {
+    fn matches(&self, kind: RuleKind, rule: &PushRule) -> bool {
+        match kind {
+            RuleKind::Room => self.event["room_id"] == rule.rule_id.as_str(),
+            RuleKind::Sender => self.event["sender"] == rule.rule_id.as_str(),
+            RuleKind::Content => rule.pattern.as_deref().is_some_and(|pattern| {
+                lookup(&self.event, "content.body")
+                    .and_then(Value::as_str)
+                    .is_some_and(|body| glob_matches_words(pattern, body))
+            }),
+            RuleKind::Override | RuleKind::Underride => rule
+                .conditions
+                .iter()
+                .flatten()
+                .all(|condition| self.condition_holds(condition)),
+        }
+    }
+
+    fn condition_holds(&self, condition: &PushCondition) -> bool {
+        match condition {
+            PushCondition::EventMatch { key, pattern } => {
+                let Some(value) = lookup(&self.event, key).and_then(Value::as_str) else {
+                    return false;
+                };
+                if key == "content.body" {
+                    glob_matches_words(pattern, value)
+                } else {
+                    glob_matches(&pattern.to_lowercase(), &value.to_lowercase())
+                }
+            }
+            PushCondition::EventPropertyIs { key, value } => lookup(&self.event, key) == Some(value),
+            PushCondition::EventPropertyContains { key, value } => lookup(&self.event, key)
+                .and_then(Value::as_array)
+                .is_some_and(|values| values.contains(value)),
+            PushCondition::ContainsDisplayName => {
+                let Some(display_name) = self.display_name.filter(|name| !name.is_empty()) else {
+                    return false;
+                };
+                lookup(&self.event, "content.body")
+                    .and_then(Value::as_str)
+                    .is_some_and(|body| contains_words(body, display_name))
+            }
+            PushCondition::RoomMemberCount { is } => member_count_matches(is, self.member_count),
+            PushCondition::SenderNotificationPermission { key } => {
+                let required = self.notification_levels.get(key).copied().unwrap_or(DEFAULT_NOTIFICATION_LEVEL);
+                self.sender_power_level >= required
+            }
+            PushCondition::Unknown => false,
+        }
+    }
+}
+
+/// Value at a dotted `key` such as `content.m\.relates_to.rel_type`, where
+/// `\.` is a literal dot and `\\` a literal backslash
+fn lookup<'v>(value: &'v Value, key: &str) -> Option<&'v Value> {
+    let mut segments = vec![String::new()];
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped @ ('.' | '\\')) => segments.last_mut()?.push(escaped),
+                Some(other) => {
+                    let segment = segments.last_mut()?;
+                    segment.push('\\');
+                    segment.push(other);
+                }
+                None => segments.last_mut()?.push('\\'),
+            },
+            '.' => segments.push(String::new()),
+            other => segments.last_mut()?.push(other),
+        }
+    }
+    segments.iter().try_fold(value, |value, segment| value.get(segment))
+}
+
+/// Whether the glob `pattern` matches a run of whole words in `text`, ignoring case
+fn glob_matches_words(pattern: &str, text: &str) -> bool {
+    let mut regex = String::from(r"(?is)(^|\W)");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*?"),
+            '?' => regex.push('.'),
+            other => regex.push_str(&regex::escape(other.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push_str(r"(\W|$)");
+    Regex::new(&regex).is_ok_and(|regex| regex.is_match(text))
+}
+
+/// Whether `words` occurs in `text` between word boundaries, ignoring case
+fn contains_words(text: &str, words: &str) -> bool {
+    let text = text.to_lowercase();
+    let words = words.to_lowercase();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(&words).any(|(start, matched)| {
+        let end = start + matched.len();
+        !text[..start].chars().next_back().is_some_and(is_word) && !text[end..].chars().next().is_some_and(is_word)
+    })
+}
+
+/// `room_member_count` comparison such as `2`, `==2`, `<10` or `>=5`
+fn member_count_matches(is: &str, member_count: usize) -> bool {
+    let (operator, count) = ["==", "<=", ">=", "<", ">"]
+        .iter()
+        .find_map(|operator| is.strip_prefix(operator).map(|count| (*operator, count)))
+        .unwrap_or(("==", is));
+    let Ok(count) = count.parse::<usize>() else {
+        return false;
+    };
+    match operator {
+        "<" => member_count < count,
+        ">" => member_count > count,
+        "<=" => member_count <= count,
+        ">=" => member_count >= count,
+        _ => member_count == count,
+    }
+}
+
+/// Whether actions notify the user
+pub fn notifies(actions: &[Value]) -> bool {
+    actions.iter().any(|action| action == "notify")
+}
+
+/// Whether actions highlight the event; a `highlight` tweak without a value highlights
+pub fn highlights(actions: &[Value]) -> bool {
+    actions
+        .iter()
+        .any(|action| action["set_tweak"] == "highlight" && action.get("value").is_none_or(|value| value == true))
+}
+
+/// A user's push rules; users who never changed them get the server defaults
+pub async fn get_push_rules(store: &dyn StateStore, user_id: &str) -> Result<Ruleset, StateError> {
+    Ok(store
+        .get_account_data(user_id, None, PUSH_RULES_ACCOUNT_DATA_TYPE)
+        .await?
+        .and_then(|content| serde_json::from_value::<PushRulesResponse>(content).ok())
+        .map(|rules| rules.global)
+        .unwrap_or_else(|| Ruleset::server_default(user_id)))
+}
+
+pub async fn get_push_rule(
+    store: &dyn StateStore,
+    user_id: &str,
+    kind: RuleKind,
+    rule_id: &str,
+) -> Result<PushRule, PushRuleError> {
+    get_push_rules(store, user_id)
+        .await?
+        .rules(kind)
+        .iter()
+        .find(|rule| rule.rule_id == rule_id)
+        .cloned()
+        .ok_or_else(|| PushRuleError::NotFound(rule_id.to_string()))
+}
+
+/// Create or replace a user-defined rule
+///
+/// Without `before` or `after`, a new rule becomes the user's highest
+/// priority rule of its kind and a replaced rule keeps its place.
+pub async fn set_push_rule(
+    store: &dyn StateStore,
+    user_id: &str,
+    kind: RuleKind,
+    rule_id: &str,
+    body: PushRuleBody,
+    position: PushRulePosition,
+) -> Result<(), PushRuleError> {
+    if rule_id.starts_with('.') {
+        return Err(PushRuleError::InvalidParam("Server-default rules cannot be replaced".to_string()));
+    }
+    if rule_id.is_empty() || rule_id.contains(['/', '\\']) {
+        return Err(PushRuleError::InvalidParam(format!("Invalid rule ID {}", rule_id)));
+    }
+    validate_actions(&body.actions)?;
+
+    let rule = match kind {
+        RuleKind::Override | RuleKind::Underride => PushRule {
+            rule_id: rule_id.to_string(),
+            default: false,
+            enabled: true,
+            conditions: Some(body.conditions.unwrap_or_default()),
+            pattern: None,
+            actions: body.actions,
+        },
+        RuleKind::Content => PushRule {
+            rule_id: rule_id.to_string(),
+            default: false,
+            enabled: true,
+            conditions: None,
+            pattern: Some(body.pattern.ok_or_else(|| PushRuleError::InvalidParam("Content rules need a pattern".to_string()))?),
+            actions: body.actions,
+        },
+        RuleKind::Room | RuleKind::Sender => {
+            let sigil = if kind == RuleKind::Room { '!' } else { '@' };
+            if !rule_id.starts_with(sigil) {
+                return Err(PushRuleError::InvalidParam(format!("{:?} rule IDs must start with {}", kind, sigil)));
+            }
+            PushRule {
+                rule_id: rule_id.to_string(),
+                default: false,
+                enabled: true,
+                conditions: None,
+                pattern: None,
+                actions: body.actions,
+            }
+        }
+    };
+
+    let mut ruleset = get_push_rules(store, user_id).await?;
+    let rules = ruleset.rules_mut(kind);
+    let existing = rules.iter().position(|existing| existing.rule_id == rule_id);
+    let anchor = position.before.as_deref().map(|id| (id, 0)).or(position.after.as_deref().map(|id| (id, 1)));
+
+    match (anchor, existing) {
+        (None, Some(index)) => rules[index] = rule,
+        (None, None) => {
+            // The master rule stays ahead of everything
+            let first = rules.iter().take_while(|rule| rule.rule_id == ".m.rule.master").count();
+            rules.insert(first, rule);
+        }
+        (Some((anchor_id, offset)), _) => {
+            if anchor_id.starts_with('.') {
+                return Err(PushRuleError::InvalidParam("Rules cannot be placed relative to server-default rules".to_string()));
+            }
+            if let Some(index) = existing {
+                rules.remove(index);
+            }
+            let index = rules
+                .iter()
+                .position(|rule| rule.rule_id == anchor_id)
+                .ok_or_else(|| PushRuleError::NotFound(anchor_id.to_string()))?;
+            rules.insert(index + offset, rule);
+        }
+    }
+
+    store_push_rules(store, user_id, ruleset).await
+}
+
+/// Remove a user-defined rule
+pub async fn delete_push_rule(
+    store: &dyn StateStore,
+    user_id: &str,
+    kind: RuleKind,
+    rule_id: &str,
+) -> Result<(), PushRuleError> {
+    if rule_id.starts_with('.') {
+        return Err(PushRuleError::InvalidParam("Server-default rules cannot be deleted".to_string()));
+    }
+    let mut ruleset = get_push_rules(store, user_id).await?;
+    let rules = ruleset.rules_mut(kind);
+    let index = rules
+        .iter()
+        .position(|rule| rule.rule_id == rule_id)
+        .ok_or_else(|| PushRuleError::NotFound(rule_id.to_string()))?;
+    rules.remove(index);
+    store_push_rules(store, user_id, ruleset).await
+}
+
+/// Enable or disable any rule, server-default rules included
+pub async fn set_push_rule_enabled(
+    store: &dyn StateStore,
+    user_id: &str,
+    kind: RuleKind,
+    rule_id: &str,
+    enabled: bool,
+) -> Result<(), PushRuleError> {
+    update_rule(store, user_id, kind, rule_id, |rule| rule.enabled = enabled).await
+}
+
+/// Replace the actions of any rule, server-default rules included
+pub async fn set_push_rule_actions(
+    store: &dyn StateStore,
+    user_id: &str,
+    kind: RuleKind,
+    rule_id: &str,
+    actions: Vec<Value>,
+) -> Result<(), PushRuleError> {
+    validate_actions(&actions)?;
+    update_rule(store, user_id, kind, rule_id, |rule| rule.actions = actions).await
+}
+
+async fn update_rule(
+    store: &dyn StateStore,
+    user_id: &str,
+    kind: RuleKind,
+    rule_id: &str,
+    update: impl FnOnce(&mut PushRule),
+) -> Result<(), PushRuleError> {
+    let mut ruleset = get_push_rules(store, user_id).await?;
+    let rule = ruleset
+        .rules_mut(kind)
+        .iter_mut()
+        .find(|rule| rule.rule_id == rule_id)
+        .ok_or_else(|| PushRuleError::NotFound(rule_id.to_string()))?;
+    update(rule);
+    store_push_rules(store, user_id, ruleset).await
+}
+
+async fn store_push_rules(store: &dyn StateStore, user_id: &str, ruleset: Ruleset) -> Result<(), PushRuleError> {
+    let content = serde_json::to_value(PushRulesResponse { global: ruleset })
+        .map_err(|e| PushRuleError::BadJson(e.to_string()))?;
+    store.set_account_data(user_id, None, PUSH_RULES_ACCOUNT_DATA_TYPE, content).await?;
+    Ok(())
+}
+
+fn validate_actions(actions: &[Value]) -> Result<(), PushRuleError> {
+    for action in actions {
+        let valid = match action {
+            Value::String(action) => ["notify", "dont_notify", "coalesce"].contains(&action.as_str()),
+            Value::Object(tweak) => tweak.get("set_tweak").is_some_and(Value::is_string),
+            _ => false,
+        };
+        if !valid {
+            return Err(PushRuleError::BadJson(format!("Invalid push rule action {}", action)));
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate `event` against the push rules of each local user it may notify
+/// and record the notifications
+///
+/// Candidates are the room's joined members and the target of an invite;
+/// senders are never notified of their own events.
+pub async fn process_event(store: &dyn StateStore, server_name: &str, event: &MatrixEvent) -> Result<(), StateError> {
+    let Some(room_state) = store.get_room(&event.room_id).await? else {
+        return Ok(());
+    };
+    let Some(stream_ordering) = store.get_stream_ordering(&event.event_id).await? else {
+        return Ok(());
+    };
+
+    let joined = room_state.joined_members();
+    let mut candidates: Vec<&str> = joined.keys().map(String::as_str).collect();
+    if let (EventType::RoomMember, EventContent::RoomMember(content), Some(invitee)) =
+        (&event.event_type, &event.content, event.state_key.as_deref())
+    {
+        if content.membership == MembershipState::Invite && !candidates.contains(&invitee) {
+            candidates.push(invitee);
+        }
+    }
+    let is_local = |user_id: &str| user_id.split_once(':').is_none_or(|(_, name)| name == server_name);
+    candidates.retain(|user_id| *user_id != event.sender && is_local(user_id));
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let event_json = serde_json::to_value(event).map_err(|e| StateError::InvalidEvent(e.to_string()))?;
+    let notification_levels = notification_levels(&room_state);
+    let thread_id = event
+        .relates_to()
+        .filter(|relation| relation.rel_type == REL_TYPE_THREAD)
+        .map(|relation| relation.event_id.clone());
+
+    for user_id in candidates {
+        let ruleset = get_push_rules(store, user_id).await?;
+        let context = PushContext {
+            event: event_json.clone(),
+            user_id,
+            display_name: joined.get(user_id).and_then(|profile| profile.display_name.as_deref()),
+            member_count: joined.len(),
+            sender_power_level: room_state.get_user_power_level(&event.sender),
+            notification_levels: notification_levels.clone(),
+        };
+        let Some(rule) = ruleset.evaluate(&context) else {
+            continue;
+        };
+        if !notifies(&rule.actions) {
+            continue;
+        }
+        store.add_push_action(user_id, PushAction {
+            room_id: event.room_id.clone(),
+            event_id: event.event_id.clone(),
+            stream_ordering,
+            thread_id: thread_id.clone(),
+            actions: rule.actions.clone(),
+            highlight: highlights(&rule.actions),
+        }).await?;
+    }
+    Ok(())
+}
+
+fn notification_levels(room_state: &RoomState) -> HashMap<String, i32> {
+    match room_state.get_state_event(&EventType::RoomPowerLevels, "").map(|event| &event.content) {
+        Some(EventContent::RoomPowerLevels(content)) => content.notifications.clone().unwrap_or_default(),
+        _ => HashMap::new(),
+    }
+}
+
+/// Unread notifications of `user_id` in `room_id`: those after the user's
+/// read receipts, for the main timeline and per thread root
+pub async fn notification_counts(
+    store: &dyn StateStore,
+    user_id: &str,
+    room_id: &str,
+) -> Result<(NotificationCounts, HashMap<String, NotificationCounts>), StateError> {
+    let actions = store.get_push_actions(user_id, Some(room_id)).await?;
+    let mut main = NotificationCounts::default();
+    let mut threads: HashMap<String, NotificationCounts> = HashMap::new();
+    if actions.is_empty() {
+        return Ok((main, threads));
+    }
+
+    let unthreaded = read_up_to(store, user_id, room_id, None).await?;
+    let mut read_positions: HashMap<Option<String>, u64> = HashMap::new();
+    for action in actions {
+        let thread = action.thread_id.clone();
+        let read = match read_positions.get(&thread) {
+            Some(read) => *read,
+            None => {
+                let receipt_thread = thread.as_deref().unwrap_or(MAIN_THREAD_ID);
+                let read = unthreaded.max(read_up_to(store, user_id, room_id, Some(receipt_thread)).await?);
+                read_positions.insert(thread.clone(), read);
+                read
+            }
+        };
+        if action.stream_ordering <= read {
+            continue;
+        }
+        match thread {
+            Some(thread_id) => threads.entry(thread_id).or_default().add(&action),
+            None => main.add(&action),
+        }
+    }
+    Ok((main, threads))
+}
+
+/// Fold per-thread counts into the room's counts, for clients that do not
+/// ask for them separately
+pub fn merge_thread_counts(main: &mut NotificationCounts, threads: HashMap<String, NotificationCounts>) {
+    for counts in threads.into_values() {
+        main.merge(counts);
+    }
+}
+
+/// Whether the user's read receipts cover the event of a notification
+pub async fn is_read(store: &dyn StateStore, user_id: &str, action: &PushAction) -> Result<bool, StateError> {
+    let thread_id = action.thread_id.as_deref().unwrap_or(MAIN_THREAD_ID);
+    let read = read_up_to(store, user_id, &action.room_id, None)
+        .await?
+        .max(read_up_to(store, user_id, &action.room_id, Some(thread_id)).await?);
+    Ok(action.stream_ordering <= read)
+}
+
+/// Stream ordering of the latest event covered by the user's public or
+/// private read receipt in `thread_id`
+async fn read_up_to(store: &dyn StateStore, user_id: &str, room_id: &str, thread_id: Option<&str>) -> Result<u64, StateError> {
+    let mut read = 0;
+    for receipt_type in [ReceiptType::Read, ReceiptType::ReadPrivate] {
+        if let Some(receipt) = store.get_receipt(room_id, receipt_type.as_str(), user_id, thread_id).await? {
+            read = read.max(store.get_stream_ordering(&receipt.event_id).await?.unwrap_or(0));
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStateStore;
+
+    fn context(event: Value) -> PushContext<'static> {
+        PushContext {
+            event,
+            user_id: "@alice:localhost",
+            display_name: Some("Alice Smith"),
+            member_count: 3,
+            sender_power_level: 0,
+            notification_levels: HashMap::from([("room".to_string(), 50)]),
+        }
+    }
+
+    fn message(body: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "room_id": "!room:localhost",
+            "sender": "@bob:localhost",
+            "content": { "msgtype": "m.text", "body": body },
+        })
+    }
+
+    fn matched_rule(event: Value) -> Option<String> {
+        let ruleset = Ruleset::server_default("@alice:localhost");
+        ruleset.evaluate(&context(event)).map(|rule| rule.rule_id.clone())
+    }
+
+    #[test]
+    fn test_default_rules_classify_events() {
+        assert_eq!(matched_rule(message("lunch?")).as_deref(), Some(".m.rule.message"));
+        assert_eq!(matched_rule(message("thanks alice!")).as_deref(), Some(".m.rule.contains_user_name"));
+        assert_eq!(matched_rule(message("ask ALICE SMITH")).as_deref(), Some(".m.rule.contains_display_name"));
+        assert_eq!(matched_rule(message("malice")).as_deref(), Some(".m.rule.message"));
+
+        let mut notice = message("build passed");
+        notice["content"]["msgtype"] = json!("m.notice");
+        assert_eq!(matched_rule(notice).as_deref(), Some(".m.rule.suppress_notices"));
+
+        // @room needs the sender to hold the room notification level
+        assert_eq!(matched_rule(message("@room standup")).as_deref(), Some(".m.rule.message"));
+        let ruleset = Ruleset::server_default("@alice:localhost");
+        let mut admin = context(message("@room standup"));
+        admin.sender_power_level = 100;
+        assert_eq!(ruleset.evaluate(&admin).unwrap().rule_id, ".m.rule.roomnotif");
+
+        // Intentional mentions replace the legacy body matching
+        let mut mention = message("thanks alice!");
+        mention["content"]["m.mentions"] = json!({ "user_ids": [] });
+        assert_eq!(matched_rule(mention.clone()).as_deref(), Some(".m.rule.message"));
+        mention["content"]["m.mentions"] = json!({ "user_ids": ["@alice:localhost"] });
+        assert_eq!(matched_rule(mention).as_deref(), Some(".m.rule.is_user_mention"));
+
+        let mut one_to_one = context(message("hi"));
+        one_to_one.member_count = 2;
+        assert_eq!(ruleset.evaluate(&one_to_one).unwrap().rule_id, ".m.rule.room_one_to_one");
+    }
+
+    #[test]
+    fn test_word_matching_on_long_bodies() {
+        let body = "a ".repeat(100_000);
+        assert_eq!(matched_rule(message(&body)).as_deref(), Some(".m.rule.message"));
+        assert!(!glob_matches_words("a*b", &body));
+        assert!(glob_matches_words("a", &body));
+        assert!(glob_matches_words("al?ce", "hi, ALICE."));
+        assert!(!glob_matches_words("alice", "malice"));
+        assert!(contains_words("ping alice smith!", "Alice Smith"));
+        assert!(!contains_words("alice smithers", "Alice Smith"));
+    }
+
+    #[test]
+    fn test_condition_helpers() {
+        let event = json!({ "content": { "m.relates_to": { "rel_type": "m.replace" }, "a\\b": 1 } });
+        assert_eq!(lookup(&event, r"content.m\.relates_to.rel_type"), Some(&json!("m.replace")));
+        assert_eq!(lookup(&event, r"content.a\\b"), Some(&json!(1)));
+        assert!(member_count_matches("2", 2));
+        assert!(member_count_matches(">=5", 7));
+        assert!(!member_count_matches("<3", 3));
+        assert!(highlights(&[json!({ "set_tweak": "highlight" })]));
+        assert!(!highlights(&[json!({ "set_tweak": "highlight", "value": false })]));
+    }
+
+    #[tokio::test]
+    async fn test_user_rules_take_priority() {
+        let store = InMemoryStateStore::new();
+        let user_id = "@alice:localhost";
+        let mute = PushRuleBody { actions: vec![], conditions: None, pattern: None };
+        set_push_rule(&store, user_id, RuleKind::Room, "!room:localhost", mute, PushRulePosition::default()).await.unwrap();
+        let keyword = PushRuleBody { actions: vec![json!("notify")], conditions: None, pattern: Some("urgent".to_string()) };
+        set_push_rule(&store, user_id, RuleKind::Content, "urgent", keyword, PushRulePosition::default()).await.unwrap();
+
+        let ruleset = get_push_rules(&store, user_id).await.unwrap();
+        assert_eq!(ruleset.content[0].rule_id, "urgent");
+        assert_eq!(ruleset.evaluate(&context(message("URGENT: outage"))).unwrap().rule_id, "urgent");
+        assert_eq!(ruleset.evaluate(&context(message("hello"))).unwrap().rule_id, "!room:localhost");
+
+        let result = delete_push_rule(&store, user_id, RuleKind::Override, ".m.rule.master").await;
+        assert!(matches!(result, Err(PushRuleError::InvalidParam(_))));
+        set_push_rule_enabled(&store, user_id, RuleKind::Override, ".m.rule.master", true).await.unwrap();
+        let ruleset = get_push_rules(&store, user_id).await.unwrap();
+        assert_eq!(ruleset.evaluate(&context(message("URGENT"))).unwrap().rule_id, ".m.rule.master");
+
+        let position = PushRulePosition { before: Some("missing".to_string()), after: None };
+        let body = PushRuleBody { actions: vec![], conditions: Some(vec![]), pattern: None };
+        let result = set_push_rule(&store, user_id, RuleKind::Override, "quiet", body, position).await;
+        assert!(matches!(result, Err(PushRuleError::NotFound(_))));
+    }
+}
@@ -0,0 +1,591 @@
+// Pushers
+// Registration of push gateways and delivery of notifications to them
+// Focus: The background push sender with per-pusher backoff and rejected pushkeys
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::push_rules::{merge_thread_counts, notification_counts};
+use crate::state::{PushAction, Pusher, PusherData, StateError, StateStore};
+use crate::url_preview::{default_ip_range_blacklist, pinned_client, resolve_allowed, UrlPreviewError};
+
+/// Path every HTTP pusher's gateway URL must point at
+pub const PUSH_GATEWAY_PATH: &str = "/_matrix/push/v1/notify";
+
+/// The only pusher kind this server delivers to
+pub const HTTP_PUSHER_KIND: &str = "http";
+
+/// Gateway data format that leaves event details out of notifications
+pub const EVENT_ID_ONLY_FORMAT: &str = "event_id_only";
+
+/// Wait after a pusher's first failed delivery; doubles on every further failure
+pub const INITIAL_PUSH_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between delivery attempts to a failing pusher
+pub const MAX_PUSH_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How long a push gateway has to answer
+const PUSH_GATEWAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_PUSHKEY_LENGTH: usize = 512;
+const MAX_APP_ID_LENGTH: usize = 64;
+
+/// Where push gateways may be reached
+#[derive(Debug, Clone)]
+pub struct PusherConfig {
+    /// Addresses gateways may not be reached at
+    pub ip_range_blacklist: Vec<IpNet>,
+    /// Exceptions to the blacklist
+    pub ip_range_whitelist: Vec<IpNet>,
+}
+
+impl Default for PusherConfig {
+    fn default() -> Self {
+        Self {
+            ip_range_blacklist: default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PusherError {
+    #[error("Missing parameter: {0}")]
+    MissingParam(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
+
+    #[error("State error: {0}")]
+    State(#[from] StateError),
+}
+
+impl PusherError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            PusherError::MissingParam(_) => 400,
+            PusherError::InvalidParam(_) => 400,
+            PusherError::State(_) => 500,
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            PusherError::MissingParam(_) => "M_MISSING_PARAM",
+            PusherError::InvalidParam(_) => "M_INVALID_PARAM",
+            PusherError::State(_) => "M_UNKNOWN",
+        }
+    }
+}
+
+/// Body of POST /pushers/set; a null `kind` removes the pusher
+#[derive(Debug, Clone, Deserialize)]
+pub struct SetPusherRequest {
+    pub pushkey: String,
+    pub kind: Option<String>,
+    pub app_id: String,
+    pub app_display_name: Option<String>,
+    pub device_display_name: Option<String>,
+    #[serde(default)]
+    pub profile_tag: Option<String>,
+    pub lang: Option<String>,
+    pub data: Option<PusherData>,
+    /// Keep other users' pushers with the same app ID and pushkey
+    #[serde(default)]
+    pub append: bool,
+}
+
+/// Body of GET /pushers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushersResponse {
+    pub pushers: Vec<Pusher>,
+}
+
+/// Register, replace or remove one of `user_id`'s pushers
+///
+/// New pushers only deliver notifications raised after they are set.
+pub async fn set_pusher(
+    store: &dyn StateStore,
+    config: &PusherConfig,
+    user_id: &str,
+    request: SetPusherRequest,
+) -> Result<(), PusherError> {
+    if request.pushkey.is_empty() || request.pushkey.len() > MAX_PUSHKEY_LENGTH {
+        return Err(PusherError::InvalidParam("pushkey must be 1 to 512 bytes".to_string()));
+    }
+    if request.app_id.is_empty() || request.app_id.chars().count() > MAX_APP_ID_LENGTH {
+        return Err(PusherError::InvalidParam("app_id must be 1 to 64 characters".to_string()));
+    }
+    let Some(kind) = request.kind else {
+        store.delete_pusher(user_id, &request.app_id, &request.pushkey).await?;
+        return Ok(());
+    };
+    if kind != HTTP_PUSHER_KIND {
+        return Err(PusherError::InvalidParam(format!("Unsupported pusher kind {}", kind)));
+    }
+
+    let missing = |name: &str| PusherError::MissingParam(name.to_string());
+    let data = request.data.ok_or_else(|| missing("data"))?;
+    let url = data.url.as_deref().ok_or_else(|| missing("data.url"))?;
+    let parsed = reqwest::Url::parse(url).map_err(|e| PusherError::InvalidParam(format!("Invalid data.url: {}", e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.path() != PUSH_GATEWAY_PATH {
+        return Err(PusherError::InvalidParam(format!("data.url must be an HTTP(S) URL ending in {}", PUSH_GATEWAY_PATH)));
+    }
+    // Gateways that cannot be resolved yet are checked again on every send
+    if let Err(UrlPreviewError::Forbidden(reason)) =
+        resolve_allowed(&parsed, &config.ip_range_blacklist, &config.ip_range_whitelist).await
+    {
+        return Err(PusherError::InvalidParam(reason));
+    }
+
+    if !request.append {
+        for other in store.get_pushers(None).await? {
+            if other.user_id != user_id && other.app_id == request.app_id && other.pushkey == request.pushkey {
+                store.delete_pusher(&other.user_id, &other.app_id, &other.pushkey).await?;
+            }
+        }
+    }
+
+    let existing = store
+        .get_pushers(Some(user_id))
+        .await?
+        .into_iter()
+        .find(|pusher| pusher.app_id == request.app_id && pusher.pushkey == request.pushkey);
+    let (pushkey_ts, last_stream_ordering) = match existing {
+        Some(pusher) => (pusher.pushkey_ts, pusher.last_stream_ordering),
+        None => (crate::presence::now_millis(), store.current_stream_position().await?),
+    };
+
+    store.set_pusher(Pusher {
+        user_id: user_id.to_string(),
+        pushkey: request.pushkey,
+        kind,
+        app_id: request.app_id,
+        app_display_name: request.app_display_name.ok_or_else(|| missing("app_display_name"))?,
+        device_display_name: request.device_display_name.ok_or_else(|| missing("device_display_name"))?,
+        profile_tag: request.profile_tag,
+        lang: request.lang.ok_or_else(|| missing("lang"))?,
+        data,
+        pushkey_ts,
+        last_stream_ordering,
+    }).await?;
+    Ok(())
+}
+
+/// Outcome of handing one notification to a push gateway
+enum Delivery {
+    Delivered,
+    /// The gateway no longer accepts the pushkey
+    Rejected,
+    Failed(String),
+}
+
+/// Consecutive failures of a pusher and when it may be tried again
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// (user ID, app ID, pushkey)
+type PusherId = (String, String, String);
+
+fn pusher_id(pusher: &Pusher) -> PusherId {
+    (pusher.user_id.clone(), pusher.app_id.clone(), pusher.pushkey.clone())
+}
+
+/// Delivers recorded notifications to each pusher's gateway, in order
+///
+/// A pusher whose gateway fails is retried with exponential backoff from
+/// its first undelivered notification; one whose pushkey is rejected is
+/// removed.
+pub struct PushSender {
+    state_store: Arc<dyn StateStore + Send + Sync>,
+    config: PusherConfig,
+    initial_backoff: Duration,
+    backoff: RwLock<HashMap<PusherId, Backoff>>,
+}
+
+impl PushSender {
+    pub fn new(state_store: Arc<dyn StateStore + Send + Sync>, config: PusherConfig) -> Self {
+        Self {
+            state_store,
+            config,
+            initial_backoff: INITIAL_PUSH_BACKOFF,
+            backoff: RwLock::default(),
+        }
+    }
+
+    pub fn config(&self) -> &PusherConfig {
+        &self.config
+    }
+
+    /// Wait this long after a pusher's first failure instead of the default
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Deliver pending notifications to every pusher not backing off,
+    /// returning how many were delivered
+    pub async fn process_pending(self: &Arc<Self>) -> Result<usize, StateError> {
+        let now = Instant::now();
+        let mut deliveries = JoinSet::new();
+        for pusher in self.state_store.get_pushers(None).await? {
+            let backing_off = self.backoff
+                .read()
+                .await
+                .get(&pusher_id(&pusher))
+                .is_some_and(|backoff| backoff.retry_at > now);
+            if !backing_off {
+                let sender = Arc::clone(self);
+                deliveries.spawn(async move { sender.deliver_pending(&pusher).await });
+            }
+        }
+
+        // Let every pusher finish before reporting the first error
+        let mut delivered = 0;
+        let mut first_error = None;
+        while let Some(result) = deliveries.join_next().await {
+            match result {
+                Ok(Ok(count)) => delivered += count,
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e);
+                }
+                Err(e) => tracing::warn!("Push delivery task failed: {}", e),
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(delivered),
+        }
+    }
+
+    /// Whether `pusher` is waiting out a backoff after failed deliveries
+    pub async fn is_backing_off(&self, pusher: &Pusher) -> bool {
+        self.backoff.read().await.contains_key(&pusher_id(pusher))
+    }
+
+    async fn deliver_pending(&self, pusher: &Pusher) -> Result<usize, StateError> {
+        let store = self.state_store.as_ref();
+        let pending = store.get_push_actions_after(&pusher.user_id, pusher.last_stream_ordering).await?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let unread = self.unread_count(&pusher.user_id).await?;
+
+        let mut delivered = 0;
+        for action in pending {
+            let Some(notification) = self.notification(pusher, &action, unread).await? else {
+                store.set_pusher_position(&pusher.user_id, &pusher.app_id, &pusher.pushkey, action.stream_ordering).await?;
+                continue;
+            };
+            match self.send(pusher, &notification).await {
+                Delivery::Delivered => {
+                    store.set_pusher_position(&pusher.user_id, &pusher.app_id, &pusher.pushkey, action.stream_ordering).await?;
+                    self.backoff.write().await.remove(&pusher_id(pusher));
+                    delivered += 1;
+                }
+                Delivery::Rejected => {
+                    tracing::info!("Push gateway rejected pushkey of {}, removing pusher", pusher.user_id);
+                    store.delete_pusher(&pusher.user_id, &pusher.app_id, &pusher.pushkey).await?;
+                    self.backoff.write().await.remove(&pusher_id(pusher));
+                    break;
+                }
+                Delivery::Failed(reason) => {
+                    let delay = self.record_failure(pusher).await;
+                    tracing::warn!("Push to {} failed, retrying in {:?}: {}", pusher.user_id, delay, reason);
+                    break;
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    async fn record_failure(&self, pusher: &Pusher) -> Duration {
+        let mut backoff = self.backoff.write().await;
+        let entry = backoff.entry(pusher_id(pusher)).or_insert(Backoff {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+        let delay = self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(entry.failures))
+            .min(MAX_PUSH_BACKOFF);
+        entry.failures += 1;
+        entry.retry_at = Instant::now() + delay;
+        delay
+    }
+
+    /// The gateway request for one notification, or none once its event is gone
+    async fn notification(&self, pusher: &Pusher, action: &PushAction, unread: u64) -> Result<Option<Value>, StateError> {
+        let store = self.state_store.as_ref();
+        let Some(event) = store.get_event(&action.event_id).await? else {
+            return Ok(None);
+        };
+
+        let mut tweaks = serde_json::Map::new();
+        for action in &action.actions {
+            if let Some(tweak) = action["set_tweak"].as_str() {
+                tweaks.insert(tweak.to_string(), action.get("value").cloned().unwrap_or(json!(true)));
+            }
+        }
+        let prio = if tweaks.contains_key("sound") || action.highlight { "high" } else { "low" };
+        let mut data = pusher.data.clone();
+        data.url = None;
+
+        let mut notification = json!({
+            "event_id": event.event_id,
+            "room_id": event.room_id,
+            "counts": { "unread": unread },
+            "prio": prio,
+            "devices": [{
+                "app_id": pusher.app_id,
+                "pushkey": pusher.pushkey,
+                "pushkey_ts": pusher.pushkey_ts / 1000,
+                "data": data,
+                "tweaks": tweaks,
+            }],
+        });
+        if pusher.data.format.as_deref() != Some(EVENT_ID_ONLY_FORMAT) {
+            let room_state = store.get_room(&event.room_id).await?;
+            let event_json = serde_json::to_value(&event).map_err(|e| StateError::InvalidEvent(e.to_string()))?;
+            notification["type"] = event_json["type"].clone();
+            notification["sender"] = json!(event.sender);
+            notification["content"] = event_json["content"].clone();
+            if let Some(room_state) = room_state {
+                if let Some(name) = &room_state.name {
+                    notification["room_name"] = json!(name);
+                }
+                let display_name = room_state.member_profiles.get(&event.sender).and_then(|profile| profile.display_name.clone());
+                if let Some(display_name) = display_name {
+                    notification["sender_display_name"] = json!(display_name);
+                }
+            }
+        }
+        Ok(Some(json!({ "notification": notification })))
+    }
+
+    /// Unread notifications across all of the user's rooms
+    async fn unread_count(&self, user_id: &str) -> Result<u64, StateError> {
+        let store = self.state_store.as_ref();
+        let rooms: HashSet<String> = store
+            .get_push_actions(user_id, None)
+            .await?
+            .into_iter()
+            .map(|action| action.room_id)
+            .collect();
+        let mut unread = 0;
+        for room_id in rooms {
+            let (mut counts, threads) = notification_counts(store, user_id, &room_id).await?;
+            merge_thread_counts(&mut counts, threads);
+            unread += counts.notification_count;
+        }
+        Ok(unread)
+    }
+
+    async fn send(&self, pusher: &Pusher, notification: &Value) -> Delivery {
+        let Some(url) = pusher.data.url.as_deref() else {
+            return Delivery::Rejected;
+        };
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return Delivery::Rejected;
+        };
+        // Resolve on every send so a gateway cannot later move onto a blacklisted address
+        let address = match resolve_allowed(&url, &self.config.ip_range_blacklist, &self.config.ip_range_whitelist).await {
+            Ok(address) => address,
+            Err(e) => return Delivery::Failed(e.to_string()),
+        };
+        let client = match pinned_client(&url, address).timeout(PUSH_GATEWAY_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => return Delivery::Failed(e.to_string()),
+        };
+        let response = match client.post(url).json(notification).send().await {
+            Ok(response) => response,
+            Err(e) => return Delivery::Failed(e.to_string()),
+        };
+        if !response.status().is_success() {
+            return Delivery::Failed(format!("gateway returned {}", response.status()));
+        }
+        let body: Value = response.json().await.unwrap_or_default();
+        let rejected = body["rejected"]
+            .as_array()
+            .is_some_and(|rejected| rejected.iter().any(|pushkey| *pushkey == pusher.pushkey.as_str()));
+        if rejected {
+            Delivery::Rejected
+        } else {
+            Delivery::Delivered
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use crate::events::{EventContent, EventType, MatrixEvent};
+    use crate::state::InMemoryStateStore;
+
+    const ROOM_ID: &str = "!room:localhost";
+
+    /// Requests a stub push gateway received, and how it answers the next ones
+    #[derive(Clone, Default)]
+    struct Gateway {
+        received: Arc<Mutex<Vec<Value>>>,
+        failing: Arc<Mutex<bool>>,
+        rejected: Arc<Mutex<Vec<String>>>,
+    }
+
+    async fn notify(State(gateway): State<Gateway>, Json(body): Json<Value>) -> (StatusCode, Json<Value>) {
+        if *gateway.failing.lock().unwrap() {
+            return (StatusCode::BAD_GATEWAY, Json(json!({})));
+        }
+        gateway.received.lock().unwrap().push(body);
+        (StatusCode::OK, Json(json!({ "rejected": *gateway.rejected.lock().unwrap() })))
+    }
+
+    async fn spawn_gateway() -> (Gateway, String) {
+        let gateway = Gateway::default();
+        let app = Router::new().route(PUSH_GATEWAY_PATH, post(notify)).with_state(gateway.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), PUSH_GATEWAY_PATH);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (gateway, url)
+    }
+
+    /// Lets pushers reach a stub gateway on the loopback interface
+    fn gateway_config() -> PusherConfig {
+        let mut config = PusherConfig::default();
+        config.ip_range_whitelist.push("127.0.0.1/32".parse().unwrap());
+        config
+    }
+
+    fn pusher_request(pushkey: &str, url: Option<&str>) -> SetPusherRequest {
+        SetPusherRequest {
+            pushkey: pushkey.to_string(),
+            kind: Some(HTTP_PUSHER_KIND.to_string()),
+            app_id: "com.example.app".to_string(),
+            app_display_name: Some("Example".to_string()),
+            device_display_name: Some("Phone".to_string()),
+            profile_tag: None,
+            lang: Some("en".to_string()),
+            data: Some(PusherData {
+                url: url.map(str::to_string),
+                format: None,
+                extra: serde_json::Map::new(),
+            }),
+            append: false,
+        }
+    }
+
+    /// Store a message from alice that notifies bob
+    async fn notify_bob(store: &InMemoryStateStore, body: &str) {
+        let event = MatrixEvent::new(
+            EventType::RoomMessage,
+            EventContent::Raw(json!({ "msgtype": "m.text", "body": body })),
+            "@alice:localhost".to_string(),
+            ROOM_ID.to_string(),
+        );
+        let stream_ordering = store.append_event(event.clone()).await.unwrap();
+        store.add_push_action("@bob:localhost", PushAction {
+            room_id: ROOM_ID.to_string(),
+            event_id: event.event_id,
+            stream_ordering,
+            thread_id: None,
+            actions: vec![json!("notify"), json!({ "set_tweak": "sound", "value": "default" })],
+            highlight: false,
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_pusher_validation() {
+        let store = InMemoryStateStore::new();
+        let url = "https://push.example.com/_matrix/push/v1/notify";
+
+        let result = set_pusher(&store, &PusherConfig::default(), "@bob:localhost", pusher_request("key", None)).await;
+        assert!(matches!(result, Err(PusherError::MissingParam(_))));
+        let result = set_pusher(&store, &PusherConfig::default(), "@bob:localhost", pusher_request("key", Some("https://push.example.com/notify"))).await;
+        assert!(matches!(result, Err(PusherError::InvalidParam(_))));
+
+        set_pusher(&store, &PusherConfig::default(), "@bob:localhost", pusher_request("key", Some(url))).await.unwrap();
+        // Without append, the pushkey moves to whoever registered it last
+        set_pusher(&store, &PusherConfig::default(), "@carol:localhost", pusher_request("key", Some(url))).await.unwrap();
+        assert!(store.get_pushers(Some("@bob:localhost")).await.unwrap().is_empty());
+        let mut append = pusher_request("key", Some(url));
+        append.append = true;
+        set_pusher(&store, &PusherConfig::default(), "@bob:localhost", append).await.unwrap();
+        assert_eq!(store.get_pushers(None).await.unwrap().len(), 2);
+
+        let mut delete = pusher_request("key", None);
+        delete.kind = None;
+        set_pusher(&store, &PusherConfig::default(), "@bob:localhost", delete).await.unwrap();
+        assert_eq!(store.get_pushers(None).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pushers_cannot_reach_internal_addresses() {
+        let store = Arc::new(InMemoryStateStore::new());
+        let config = PusherConfig::default();
+        for url in ["http://127.0.0.1/_matrix/push/v1/notify", "http://169.254.169.254/_matrix/push/v1/notify", "http://[::1]:8080/_matrix/push/v1/notify"] {
+            let result = set_pusher(store.as_ref(), &config, "@bob:localhost", pusher_request("key", Some(url))).await;
+            assert!(matches!(result, Err(PusherError::InvalidParam(_))), "{} was allowed", url);
+        }
+
+        // Whatever was allowed when the pusher was set is checked again on every send
+        let (gateway, url) = spawn_gateway().await;
+        set_pusher(store.as_ref(), &gateway_config(), "@bob:localhost", pusher_request("key", Some(&url))).await.unwrap();
+        notify_bob(&store, "hello").await;
+        let sender = Arc::new(PushSender::new(store.clone(), config));
+        assert_eq!(sender.process_pending().await.unwrap(), 0);
+        assert!(gateway.received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_push_sender_backs_off_and_drops_rejected_pushkeys() {
+        let (gateway, url) = spawn_gateway().await;
+        let store = Arc::new(InMemoryStateStore::new());
+        notify_bob(&store, "before the pusher").await;
+        set_pusher(store.as_ref(), &gateway_config(), "@bob:localhost", pusher_request("key", Some(&url))).await.unwrap();
+        let sender = Arc::new(PushSender::new(store.clone(), gateway_config()).with_initial_backoff(Duration::from_millis(50)));
+
+        *gateway.failing.lock().unwrap() = true;
+        notify_bob(&store, "first").await;
+        notify_bob(&store, "second").await;
+        assert_eq!(sender.process_pending().await.unwrap(), 0);
+        let pusher = store.get_pushers(Some("@bob:localhost")).await.unwrap().remove(0);
+        assert!(sender.is_backing_off(&pusher).await);
+
+        // Nothing is retried until the backoff has passed
+        *gateway.failing.lock().unwrap() = false;
+        assert_eq!(sender.process_pending().await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(sender.process_pending().await.unwrap(), 2);
+        assert!(!sender.is_backing_off(&pusher).await);
+
+        let received = gateway.received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        let notification = &received[0]["notification"];
+        assert_eq!(notification["content"]["body"], "first");
+        assert_eq!(notification["prio"], "high");
+        assert_eq!(notification["devices"][0]["pushkey"], "key");
+        assert_eq!(notification["devices"][0]["tweaks"]["sound"], "default");
+        assert!(notification["devices"][0]["data"].get("url").is_none());
+        assert_eq!(received[0]["notification"]["counts"]["unread"], 3);
+        assert_eq!(received[1]["notification"]["counts"]["unread"], 3);
+
+        gateway.rejected.lock().unwrap().push("key".to_string());
+        notify_bob(&store, "third").await;
+        assert_eq!(sender.process_pending().await.unwrap(), 0);
+        assert!(store.get_pushers(None).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+// Receipts
+// Read receipts, private read receipts and the fully read marker
+// Focus: Moving a user's receipts forward and shaping them for sync and federation
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthenticatedUser;
+use crate::room::RoomError;
+use crate::state::{Receipt, RoomState, StateError, StateStore};
+
+/// Ephemeral event and EDU type carrying receipts
+pub const RECEIPT_EDU_TYPE: &str = "m.receipt";
+
+/// Room account data type of the fully read marker
+pub const FULLY_READ_ACCOUNT_DATA_TYPE: &str = "m.fully_read";
+
+/// Thread ID of receipts on the main timeline rather than in a thread
+pub const MAIN_THREAD_ID: &str = "main";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptType {
+    #[serde(rename = "m.read")]
+    Read,
+    /// Like `m.read`, but only ever shown to its owner and never federated
+    #[serde(rename = "m.read.private")]
+    ReadPrivate,
+    #[serde(rename = "m.fully_read")]
+    FullyRead,
+}
+
+impl ReceiptType {
+    pub fn parse(receipt_type: &str) -> Result<Self, RoomError> {
+        match receipt_type {
+            "m.read" => Ok(ReceiptType::Read),
+            "m.read.private" => Ok(ReceiptType::ReadPrivate),
+            "m.fully_read" => Ok(ReceiptType::FullyRead),
+            other => Err(RoomError::InvalidParam(format!("Unsupported receipt type {}", other))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptType::Read => "m.read",
+            ReceiptType::ReadPrivate => "m.read.private",
+            ReceiptType::FullyRead => "m.fully_read",
+        }
+    }
+}
+
+/// Body of POST /read_markers; each marker is optional
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadMarkersRequest {
+    #[serde(rename = "m.fully_read", default)]
+    pub fully_read: Option<String>,
+    #[serde(rename = "m.read", default)]
+    pub read: Option<String>,
+    #[serde(rename = "m.read.private", default)]
+    pub read_private: Option<String>,
+}
+
+/// Per-user receipt in an `m.receipt` EDU
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserReceipt {
+    pub event_ids: Vec<String>,
+    pub data: ReceiptData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptData {
+    pub ts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+}
+
+/// `m.receipt` EDU content: room ID -> receipt type -> user ID -> receipt
+pub type ReceiptEduContent = BTreeMap<String, BTreeMap<String, BTreeMap<String, UserReceipt>>>;
+
+/// Acknowledge `event_id` in `room_id` for `user`
+///
+/// Returns the receipt when it moved the user's receipt forward; receipts
+/// for events before the current one are ignored. `m.fully_read` moves the
+/// read marker instead and never returns a receipt.
+pub async fn send_receipt(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    room_id: &str,
+    receipt_type: ReceiptType,
+    event_id: &str,
+    thread_id: Option<String>,
+) -> Result<Option<Receipt>, RoomError> {
+    let room_state = joined_room(store, user, room_id).await?;
+    check_event_in_room(store, &room_state, event_id).await?;
+
+    if receipt_type == ReceiptType::FullyRead {
+        if thread_id.is_some() {
+            return Err(RoomError::InvalidParam("m.fully_read cannot be threaded".to_string()));
+        }
+        set_fully_read(store, &user.user_id, room_id, event_id).await?;
+        return Ok(None);
+    }
+
+    if let Some(thread_id) = thread_id.as_deref().filter(|thread_id| *thread_id != MAIN_THREAD_ID) {
+        check_event_in_room(store, &room_state, thread_id)
+            .await
+            .map_err(|_| RoomError::InvalidParam(format!("thread_id must be \"main\" or a thread root, not {}", thread_id)))?;
+    }
+
+    let receipt = Receipt {
+        room_id: room_id.to_string(),
+        receipt_type: receipt_type.as_str().to_string(),
+        user_id: user.user_id.clone(),
+        event_id: event_id.to_string(),
+        thread_id,
+        ts: now_millis(),
+    };
+    Ok(store_receipt(store, receipt).await?)
+}
+
+/// Move the fully read marker and read receipts in one request
+///
+/// Returns the public receipts that moved, to be sent to other servers.
+pub async fn set_read_markers(
+    store: &dyn StateStore,
+    user: &AuthenticatedUser,
+    room_id: &str,
+    request: ReadMarkersRequest,
+) -> Result<Vec<Receipt>, RoomError> {
+    let markers = [
+        (ReceiptType::FullyRead, request.fully_read),
+        (ReceiptType::Read, request.read),
+        (ReceiptType::ReadPrivate, request.read_private),
+    ];
+
+    let mut public = Vec::new();
+    for (receipt_type, event_id) in markers {
+        let Some(event_id) = event_id else {
+            continue;
+        };
+        if let Some(receipt) = send_receipt(store, user, room_id, receipt_type, &event_id, None).await? {
+            if receipt_type == ReceiptType::Read {
+                public.push(receipt);
+            }
+        }
+    }
+    Ok(public)
+}
+
+/// Store a receipt from another server, ignoring receipts of users not in
+/// the room or for events this server does not have
+pub async fn receive_receipt(store: &dyn StateStore, receipt: Receipt) -> Result<bool, StateError> {
+    let Some(room_state) = store.get_room(&receipt.room_id).await? else {
+        return Ok(false);
+    };
+    if !room_state.is_member(&receipt.user_id) {
+        return Ok(false);
+    }
+    if store.get_event(&receipt.event_id).await?.is_none_or(|event| event.room_id != receipt.room_id) {
+        return Ok(false);
+    }
+    Ok(store_receipt(store, receipt).await?.is_some())
+}
+
+/// Store `receipt` unless the user's current receipt is for a later event
+async fn store_receipt(store: &dyn StateStore, receipt: Receipt) -> Result<Option<Receipt>, StateError> {
+    let current = store
+        .get_receipt(&receipt.room_id, &receipt.receipt_type, &receipt.user_id, receipt.thread_id.as_deref())
+        .await?;
+    if let Some(current) = current {
+        let current_ordering = store.get_stream_ordering(&current.event_id).await?;
+        let new_ordering = store.get_stream_ordering(&receipt.event_id).await?;
+        if current.event_id == receipt.event_id || current_ordering > new_ordering {
+            return Ok(None);
+        }
+    }
+
+    store.set_receipt(receipt.clone()).await?;
+    Ok(Some(receipt))
+}
+
+async fn set_fully_read(store: &dyn StateStore, user_id: &str, room_id: &str, event_id: &str) -> Result<(), StateError> {
+    let content = serde_json::json!({ "event_id": event_id });
+    store.set_account_data(user_id, Some(room_id), FULLY_READ_ACCOUNT_DATA_TYPE, content).await?;
+    Ok(())
+}
+
+/// `m.receipt` ephemeral event content for the receipts `viewer` may see:
+/// event ID -> receipt type -> user ID -> `{ts, thread_id}`
+pub fn receipt_event_content(receipts: &[Receipt], viewer: &str) -> Option<serde_json::Value> {
+    let mut content: BTreeMap<&str, BTreeMap<&str, BTreeMap<&str, ReceiptData>>> = BTreeMap::new();
+    for receipt in receipts {
+        if receipt.receipt_type == ReceiptType::ReadPrivate.as_str() && receipt.user_id != viewer {
+            continue;
+        }
+        content
+            .entry(&receipt.event_id)
+            .or_default()
+            .entry(&receipt.receipt_type)
+            .or_default()
+            .insert(&receipt.user_id, ReceiptData { ts: receipt.ts, thread_id: receipt.thread_id.clone() });
+    }
+
+    if content.is_empty() {
+        return None;
+    }
+    serde_json::to_value(content).ok()
+}
+
+/// `m.receipt` EDU content announcing one public receipt to other servers
+pub fn receipt_edu_content(receipt: &Receipt) -> ReceiptEduContent {
+    let user_receipt = UserReceipt {
+        event_ids: vec![receipt.event_id.clone()],
+        data: ReceiptData { ts: receipt.ts, thread_id: receipt.thread_id.clone() },
+    };
+    BTreeMap::from([(
+        receipt.room_id.clone(),
+        BTreeMap::from([(
+            receipt.receipt_type.clone(),
+            BTreeMap::from([(receipt.user_id.clone(), user_receipt)]),
+        )]),
+    )])
+}
+
+async fn joined_room(store: &dyn StateStore, user: &AuthenticatedUser, room_id: &str) -> Result<RoomState, RoomError> {
+    let room_state = store
+        .get_room(room_id)
+        .await?
+        .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+    if !room_state.is_member(&user.user_id) {
+        return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+    }
+    Ok(room_state)
+}
+
+async fn check_event_in_room(store: &dyn StateStore, room_state: &RoomState, event_id: &str) -> Result<(), RoomError> {
+    match store.get_event(event_id).await? {
+        Some(event) if event.room_id == room_state.room_id => Ok(()),
+        _ => Err(RoomError::EventNotFound(event_id.to_string())),
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventContent, EventType, MatrixEvent, MembershipState};
+    use crate::state::InMemoryStateStore;
+    use crate::tests::test_user;
+
+    const ROOM_ID: &str = "!room:localhost";
+
+    async fn setup() -> (InMemoryStateStore, Vec<String>) {
+        let store = InMemoryStateStore::new();
+        let mut room_state = RoomState::new(ROOM_ID.to_string(), "@alice:localhost".to_string(), "10".to_string());
+        room_state.members.insert("@alice:localhost".to_string(), MembershipState::Join);
+        room_state.members.insert("@bob:localhost".to_string(), MembershipState::Join);
+        store.create_room(room_state).await.unwrap();
+
+        let mut event_ids = Vec::new();
+        for _ in 0..2 {
+            let event = MatrixEvent::new(
+                EventType::RoomMessage,
+                EventContent::Raw(serde_json::json!({ "msgtype": "m.text", "body": "hi" })),
+                "@alice:localhost".to_string(),
+                ROOM_ID.to_string(),
+            );
+            event_ids.push(event.event_id.clone());
+            store.append_event(event).await.unwrap();
+        }
+        (store, event_ids)
+    }
+
+    #[tokio::test]
+    async fn test_receipts_only_move_forward() {
+        let (store, event_ids) = setup().await;
+        let bob = test_user("@bob:localhost");
+
+        let receipt = send_receipt(&store, &bob, ROOM_ID, ReceiptType::Read, &event_ids[1], None).await.unwrap();
+        assert!(receipt.is_some());
+        let older = send_receipt(&store, &bob, ROOM_ID, ReceiptType::Read, &event_ids[0], None).await.unwrap();
+        assert!(older.is_none());
+
+        // Threads and private receipts are tracked separately
+        let threaded = send_receipt(&store, &bob, ROOM_ID, ReceiptType::Read, &event_ids[0], Some("main".to_string())).await.unwrap();
+        assert_eq!(threaded.unwrap().thread_id.as_deref(), Some("main"));
+        send_receipt(&store, &bob, ROOM_ID, ReceiptType::ReadPrivate, &event_ids[0], None).await.unwrap();
+
+        let receipts = store.get_room_receipts(ROOM_ID, 0).await.unwrap();
+        assert_eq!(receipts.len(), 3);
+        let content = receipt_event_content(&receipts, "@alice:localhost").unwrap();
+        assert!(content[&event_ids[1]]["m.read"]["@bob:localhost"]["ts"].is_u64());
+        assert!(content[&event_ids[0]].get("m.read.private").is_none());
+        let content = receipt_event_content(&receipts, "@bob:localhost").unwrap();
+        assert!(content[&event_ids[0]]["m.read.private"].get("@bob:localhost").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_receipt_validation_and_read_markers() {
+        let (store, event_ids) = setup().await;
+        let bob = test_user("@bob:localhost");
+
+        let result = send_receipt(&store, &test_user("@carol:localhost"), ROOM_ID, ReceiptType::Read, &event_ids[0], None).await;
+        assert!(matches!(result, Err(RoomError::UserNotInRoom(_))));
+        let result = send_receipt(&store, &bob, ROOM_ID, ReceiptType::Read, "$missing", None).await;
+        assert!(matches!(result, Err(RoomError::EventNotFound(_))));
+        let result = send_receipt(&store, &bob, ROOM_ID, ReceiptType::Read, &event_ids[0], Some("$missing".to_string())).await;
+        assert!(matches!(result, Err(RoomError::InvalidParam(_))));
+        assert!(ReceiptType::parse("m.unknown").is_err());
+
+        let public = set_read_markers(&store, &bob, ROOM_ID, ReadMarkersRequest {
+            fully_read: Some(event_ids[1].clone()),
+            read: Some(event_ids[1].clone()),
+            read_private: Some(event_ids[1].clone()),
+        }).await.unwrap();
+        assert_eq!(public.len(), 1);
+        assert_eq!(public[0].receipt_type, "m.read");
+        let marker = store.get_account_data("@bob:localhost", Some(ROOM_ID), FULLY_READ_ACCOUNT_DATA_TYPE).await.unwrap();
+        assert_eq!(marker, Some(serde_json::json!({ "event_id": event_ids[1] })));
+    }
+}
@@ -0,0 +1,271 @@
+// Event Relations
+// Requests and server-side aggregations for related events
+// Focus: Edits, threads, reactions and references
+
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::events::{
+    MatrixEvent, REL_TYPE_ANNOTATION, REL_TYPE_REFERENCE, REL_TYPE_REPLACE, REL_TYPE_THREAD,
+};
+use crate::state::{Direction, StateError, StateStore};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRelationsRequest {
+    pub room_id: String,
+    pub event_id: String,
+    pub rel_type: Option<String>,
+    pub event_type: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub dir: Direction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelationsResponse {
+    pub chunk: Vec<MatrixEvent>,
+    pub next_batch: Option<String>,
+    pub prev_batch: Option<String>,
+}
+
+/// Which threads /threads lists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThreadsInclude {
+    #[default]
+    #[serde(rename = "all")]
+    All,
+    #[serde(rename = "participated")]
+    Participated,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetThreadsRequest {
+    pub room_id: String,
+    #[serde(default)]
+    pub include: ThreadsInclude,
+    pub from: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadsResponse {
+    pub chunk: Vec<MatrixEvent>,
+    pub next_batch: Option<String>,
+}
+
+/// Attach `unsigned.m.relations` aggregations to events served to `user_id`
+pub async fn bundle_aggregations(
+    store: &dyn StateStore,
+    user_id: &str,
+    events: &mut [MatrixEvent],
+) -> Result<(), StateError> {
+    for event in events.iter_mut() {
+        let aggregations = aggregate_relations(store, user_id, event).await?;
+        if aggregations.is_empty() {
+            continue;
+        }
+
+        let unsigned = event.unsigned.get_or_insert_with(|| serde_json::json!({}));
+        if let Some(unsigned) = unsigned.as_object_mut() {
+            unsigned.insert("m.relations".to_string(), serde_json::Value::Object(aggregations));
+        }
+    }
+    Ok(())
+}
+
+/// Check whether `user_id` sent the thread root or any reply in its thread
+pub async fn user_participated_in_thread(
+    store: &dyn StateStore,
+    user_id: &str,
+    root: &MatrixEvent,
+) -> Result<bool, StateError> {
+    if root.sender == user_id {
+        return Ok(true);
+    }
+    let replies = store
+        .get_relations(&root.event_id, Some(REL_TYPE_THREAD), None, 0, Direction::Forward, usize::MAX)
+        .await?;
+    Ok(replies.iter().any(|(_, reply)| reply.sender == user_id))
+}
+
+async fn aggregate_relations(
+    store: &dyn StateStore,
+    user_id: &str,
+    event: &MatrixEvent,
+) -> Result<serde_json::Map<String, serde_json::Value>, StateError> {
+    let children = store
+        .get_relations(&event.event_id, None, None, 0, Direction::Forward, usize::MAX)
+        .await?;
+    let with_rel_type = |rel_type: &'static str| {
+        children
+            .iter()
+            .map(|(_, child)| child)
+            .filter(move |child| child.relates_to().is_some_and(|r| r.rel_type == rel_type))
+    };
+
+    let mut aggregations = serde_json::Map::new();
+
+    // Only edits by the original sender replace an event
+    if let Some(edit) = with_rel_type(REL_TYPE_REPLACE).rfind(|edit| edit.sender == event.sender) {
+        aggregations.insert(REL_TYPE_REPLACE.to_string(), serde_json::to_value(edit).unwrap_or_default());
+    }
+
+    let thread: Vec<&MatrixEvent> = with_rel_type(REL_TYPE_THREAD).collect();
+    if let Some(latest_event) = thread.last() {
+        let participated = event.sender == user_id || thread.iter().any(|reply| reply.sender == user_id);
+        aggregations.insert(REL_TYPE_THREAD.to_string(), serde_json::json!({
+            "latest_event": latest_event,
+            "count": thread.len(),
+            "current_user_participated": participated,
+        }));
+    }
+
+    // Annotations are grouped by event type and key, most used first
+    let mut annotations: Vec<((String, String), usize)> = Vec::new();
+    let mut positions: HashMap<(String, String), usize> = HashMap::new();
+    for annotation in with_rel_type(REL_TYPE_ANNOTATION) {
+        let Some(key) = annotation.relates_to().and_then(|r| r.key.clone()) else {
+            continue;
+        };
+        let group = (annotation.event_type.as_str().to_string(), key);
+        match positions.get(&group) {
+            Some(&position) => annotations[position].1 += 1,
+            None => {
+                positions.insert(group.clone(), annotations.len());
+                annotations.push((group, 1));
+            }
+        }
+    }
+    if !annotations.is_empty() {
+        annotations.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let chunk: Vec<serde_json::Value> = annotations
+            .into_iter()
+            .map(|((event_type, key), count)| serde_json::json!({
+                "type": event_type,
+                "key": key,
+                "count": count,
+            }))
+            .collect();
+        aggregations.insert(REL_TYPE_ANNOTATION.to_string(), serde_json::json!({ "chunk": chunk }));
+    }
+
+    let references: Vec<serde_json::Value> = with_rel_type(REL_TYPE_REFERENCE)
+        .map(|reference| serde_json::json!({ "event_id": reference.event_id }))
+        .collect();
+    if !references.is_empty() {
+        aggregations.insert(REL_TYPE_REFERENCE.to_string(), serde_json::json!({ "chunk": references }));
+    }
+
+    Ok(aggregations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventContent, EventType, MessageType, ReactionContent, RelatesTo};
+    use crate::state::InMemoryStateStore;
+
+    fn message(sender: &str, body: &str, relates_to: Option<RelatesTo>) -> MatrixEvent {
+        let mut content = EventContent::room_message(MessageType::Text, body.to_string());
+        if let EventContent::RoomMessage(ref mut message) = content {
+            message.relates_to = relates_to;
+        }
+        MatrixEvent::new(EventType::RoomMessage, content, sender.to_string(), "!room:localhost".to_string())
+    }
+
+    fn relation(rel_type: &str, event_id: &str, key: Option<&str>) -> RelatesTo {
+        RelatesTo {
+            in_reply_to: None,
+            rel_type: rel_type.to_string(),
+            event_id: event_id.to_string(),
+            key: key.map(str::to_string),
+        }
+    }
+
+    async fn store_child(store: &InMemoryStateStore, child: &MatrixEvent) {
+        store.append_event(child.clone()).await.unwrap();
+        let relates_to = child.relates_to().unwrap();
+        store.add_relation(&relates_to.event_id, &child.event_id, &relates_to.rel_type).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bundle_edit_aggregation() {
+        let store = InMemoryStateStore::new();
+        let root = message("@alice:localhost", "helo", None);
+        store.append_event(root.clone()).await.unwrap();
+
+        let edit = message("@alice:localhost", "* hello", Some(relation(REL_TYPE_REPLACE, &root.event_id, None)));
+        let foreign_edit = message("@bob:localhost", "* hijacked", Some(relation(REL_TYPE_REPLACE, &root.event_id, None)));
+        store_child(&store, &edit).await;
+        store_child(&store, &foreign_edit).await;
+
+        let mut events = vec![root];
+        bundle_aggregations(&store, "@alice:localhost", &mut events).await.unwrap();
+
+        let relations = &events[0].unsigned.as_ref().unwrap()["m.relations"];
+        assert_eq!(relations[REL_TYPE_REPLACE]["event_id"], edit.event_id);
+    }
+
+    #[tokio::test]
+    async fn test_bundle_thread_aggregation() {
+        let store = InMemoryStateStore::new();
+        let root = message("@alice:localhost", "question", None);
+        store.append_event(root.clone()).await.unwrap();
+
+        let first = message("@bob:localhost", "answer", Some(relation(REL_TYPE_THREAD, &root.event_id, None)));
+        let second = message("@carol:localhost", "thanks", Some(relation(REL_TYPE_THREAD, &root.event_id, None)));
+        store_child(&store, &first).await;
+        store_child(&store, &second).await;
+
+        let mut as_bob = vec![root.clone()];
+        bundle_aggregations(&store, "@bob:localhost", &mut as_bob).await.unwrap();
+        let thread = &as_bob[0].unsigned.as_ref().unwrap()["m.relations"][REL_TYPE_THREAD];
+        assert_eq!(thread["count"], 2);
+        assert_eq!(thread["latest_event"]["event_id"], second.event_id);
+        assert_eq!(thread["current_user_participated"], true);
+
+        let mut as_dave = vec![root];
+        bundle_aggregations(&store, "@dave:localhost", &mut as_dave).await.unwrap();
+        let thread = &as_dave[0].unsigned.as_ref().unwrap()["m.relations"][REL_TYPE_THREAD];
+        assert_eq!(thread["current_user_participated"], false);
+    }
+
+    #[tokio::test]
+    async fn test_bundle_annotation_aggregation() {
+        let store = InMemoryStateStore::new();
+        let root = message("@alice:localhost", "ship it", None);
+        store.append_event(root.clone()).await.unwrap();
+
+        for (sender, key) in [("@bob:localhost", "👍"), ("@carol:localhost", "👍"), ("@dave:localhost", "🎉")] {
+            let reaction = MatrixEvent::new(
+                EventType::Reaction,
+                EventContent::Reaction(ReactionContent {
+                    relates_to: relation(REL_TYPE_ANNOTATION, &root.event_id, Some(key)),
+                }),
+                sender.to_string(),
+                "!room:localhost".to_string(),
+            );
+            store_child(&store, &reaction).await;
+        }
+
+        let mut events = vec![root];
+        bundle_aggregations(&store, "@alice:localhost", &mut events).await.unwrap();
+
+        let chunk = &events[0].unsigned.as_ref().unwrap()["m.relations"][REL_TYPE_ANNOTATION]["chunk"];
+        assert_eq!(chunk[0], serde_json::json!({ "type": "m.reaction", "key": "👍", "count": 2 }));
+        assert_eq!(chunk[1], serde_json::json!({ "type": "m.reaction", "key": "🎉", "count": 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_no_aggregations_leaves_unsigned_untouched() {
+        let store = InMemoryStateStore::new();
+        let root = message("@alice:localhost", "lonely", None);
+        store.append_event(root.clone()).await.unwrap();
+
+        let mut events = vec![root];
+        bundle_aggregations(&store, "@alice:localhost", &mut events).await.unwrap();
+        assert!(events[0].unsigned.is_none());
+    }
+}
@@ -3,17 +3,36 @@
 // Focus: Room creation, membership, and message handling
 
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use crate::events::{
-    MatrixEvent, EventType, EventContent, RoomCreateContent, RoomMemberContent, 
-    RoomMessageContent, MessageType, MembershipState, RoomPowerLevelsContent,
-    RoomJoinRulesContent, JoinRule, RoomNameContent, RoomTopicContent
+    MatrixEvent, EventType, EventContent, RoomMemberContent,
+    RoomMessageContent, MessageType, MediaInfo, Mentions, MembershipState, RoomPowerLevelsContent,
+    RoomNameContent, RoomTopicContent, ReactionContent, RelatesTo,
+    RoomCanonicalAliasContent, RoomCreateContent, RoomJoinRulesContent, RoomHistoryVisibilityContent,
+    RoomGuestAccessContent, JoinRule, HistoryVisibility, GuestAccess, parse_room_alias,
+    REL_TYPE_ANNOTATION, REL_TYPE_REPLACE, REL_TYPE_THREAD
 };
-use crate::state::{StateStore, RoomState, StateError};
+use crate::media::parse_mxc_uri;
+use crate::push_rules;
+use crate::search;
+use crate::direct::{add_direct_room, direct_rooms_with, DirectRoomResponse};
+use crate::directory::{
+    format_directory_token, parse_directory_token, sort_public_rooms, PublicRoomsChunk, PublicRoomsRequest,
+    PublicRoomsResponse, RoomVisibility
+};
+use crate::relations::{
+    bundle_aggregations, user_participated_in_thread, GetRelationsRequest, GetThreadsRequest,
+    RelationsResponse, ThreadsInclude, ThreadsResponse
+};
+use crate::state::{StateStore, RoomState, StateError, Direction, MemberProfile};
+use crate::visibility::{self, Viewer, VisibilityFilter};
 use crate::auth::{AuthenticatedUser, AuthError};
 
 #[derive(Error, Debug)]
@@ -21,6 +40,9 @@ pub enum RoomError {
     #[error("Room not found: {0}")]
     RoomNotFound(String),
     
+    #[error("Event not found: {0}")]
+    EventNotFound(String),
+    
     #[error("User not in room: {0}")]
     UserNotInRoom(String),
     
@@ -36,6 +58,27 @@ pub enum RoomError {
     #[error("Message too large: {0} bytes")]
     MessageTooLarge(usize),
     
+    #[error("Invalid relation: {0}")]
+    InvalidRelation(String),
+
+    #[error("Invalid parameter: {0}")]
+    InvalidParam(String),
+    
+    #[error("Unable to authorise join: {0}")]
+    UnableToAuthoriseJoin(String),
+    
+    #[error("Room alias not found: {0}")]
+    AliasNotFound(String),
+    
+    #[error("Room alias already in use: {0}")]
+    AliasInUse(String),
+    
+    #[error("Invalid room alias: {0}")]
+    InvalidAlias(String),
+    
+    #[error("Bad canonical alias: {0}")]
+    BadAlias(String),
+    
     #[error("State error: {0}")]
     StateError(#[from] StateError),
     
@@ -47,11 +90,19 @@ impl RoomError {
     pub fn status_code(&self) -> u16 {
         match self {
             RoomError::RoomNotFound(_) => 404,
+            RoomError::EventNotFound(_) => 404,
             RoomError::UserNotInRoom(_) => 403,
             RoomError::InsufficientPermissions(_) => 403,
             RoomError::RoomAlreadyExists(_) => 409,
             RoomError::InvalidRoomConfig(_) => 400,
             RoomError::MessageTooLarge(_) => 413,
+            RoomError::InvalidRelation(_) => 400,
+            RoomError::InvalidParam(_) => 400,
+            RoomError::UnableToAuthoriseJoin(_) => 400,
+            RoomError::AliasNotFound(_) => 404,
+            RoomError::AliasInUse(_) => 409,
+            RoomError::InvalidAlias(_) => 400,
+            RoomError::BadAlias(_) => 400,
             RoomError::StateError(_) => 500,
             RoomError::AuthError(auth_err) => auth_err.status_code(),
         }
@@ -60,30 +111,43 @@ impl RoomError {
     pub fn error_code(&self) -> &'static str {
         match self {
             RoomError::RoomNotFound(_) => "M_NOT_FOUND",
+            RoomError::EventNotFound(_) => "M_NOT_FOUND",
             RoomError::UserNotInRoom(_) => "M_FORBIDDEN",
             RoomError::InsufficientPermissions(_) => "M_FORBIDDEN",
             RoomError::RoomAlreadyExists(_) => "M_ROOM_IN_USE",
             RoomError::InvalidRoomConfig(_) => "M_BAD_JSON",
             RoomError::MessageTooLarge(_) => "M_TOO_LARGE",
+            RoomError::InvalidRelation(_) => "M_INVALID_PARAM",
+            RoomError::InvalidParam(_) => "M_INVALID_PARAM",
+            RoomError::UnableToAuthoriseJoin(_) => "M_UNABLE_TO_AUTHORISE_JOIN",
+            RoomError::AliasNotFound(_) => "M_NOT_FOUND",
+            RoomError::AliasInUse(_) => "M_ROOM_IN_USE",
+            RoomError::InvalidAlias(_) => "M_INVALID_PARAM",
+            RoomError::BadAlias(_) => "M_BAD_ALIAS",
             RoomError::StateError(_) => "M_UNKNOWN",
             RoomError::AuthError(auth_err) => auth_err.error_code(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RoomConfig {
     pub name: Option<String>,
     pub topic: Option<String>,
     pub room_alias_name: Option<String>,
+    #[serde(default)]
     pub invite: Vec<String>,
     pub room_version: Option<String>,
     pub creation_content: Option<serde_json::Value>,
+    #[serde(default)]
     pub initial_state: Vec<StateEventConfig>,
     pub preset: Option<RoomPreset>,
     pub is_direct: Option<bool>,
     pub power_level_content_override: Option<RoomPowerLevelsContent>,
     pub federate: Option<bool>,
+    /// Whether to publish the room in the directory; also picks the default preset
+    #[serde(default)]
+    pub visibility: RoomVisibility,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -125,12 +189,36 @@ pub struct JoinRoomResponse {
     pub room_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnockRoomRequest {
+    pub room_id: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnockRoomResponse {
+    pub room_id: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeaveRoomRequest {
     pub room_id: String,
     pub reason: Option<String>,
 }
 
+/// Invite, kick, ban or unban `user_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipChangeRequest {
+    pub room_id: String,
+    pub user_id: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipChangeResponse {
+    pub event_id: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SendMessageRequest {
     pub room_id: String,
@@ -139,6 +227,22 @@ pub struct SendMessageRequest {
     pub formatted_body: Option<String>,
     pub format: Option<String>,
     pub relates_to: Option<serde_json::Value>,
+    #[serde(default)]
+    pub new_content: Option<serde_json::Value>,
+    #[serde(default)]
+    pub mentions: Option<Mentions>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub info: Option<Box<MediaInfo>>,
+    #[serde(default)]
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendReactionRequest {
+    pub room_id: String,
+    pub relates_to: RelatesTo,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -152,6 +256,8 @@ pub struct GetMessagesRequest {
     pub from: Option<String>,
     pub to: Option<String>,
     pub limit: Option<u32>,
+    #[serde(default)]
+    pub dir: Direction,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -161,14 +267,185 @@ pub struct GetMessagesResponse {
     pub end: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendStateEventRequest {
+    pub room_id: String,
+    pub event_type: String,
+    pub state_key: String,
+    pub content: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendStateEventResponse {
+    pub event_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetMembersRequest {
+    pub room_id: String,
+    /// Stream token to read membership at
+    pub at: Option<String>,
+    pub membership: Option<MembershipState>,
+    pub not_membership: Option<MembershipState>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembersResponse {
+    pub chunk: Vec<MatrixEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinedMembersResponse {
+    pub joined: HashMap<String, MemberProfile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedactEventRequest {
+    pub room_id: String,
+    pub event_id: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedactEventResponse {
+    pub event_id: String,
+}
+
+/// Subset of the client `RoomEventFilter` understood by room read paths
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomEventFilter {
+    #[serde(default)]
+    pub lazy_load_members: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetContextRequest {
+    pub room_id: String,
+    pub event_id: String,
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub filter: RoomEventFilter,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetContextResponse {
+    pub start: String,
+    pub end: String,
+    pub events_before: Vec<MatrixEvent>,
+    pub event: MatrixEvent,
+    pub events_after: Vec<MatrixEvent>,
+    pub state: Vec<MatrixEvent>,
+}
+
+/// Room ID and resident servers an alias resolves to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolveAliasResponse {
+    pub room_id: String,
+    pub servers: Vec<String>,
+}
+
+/// Server name used for room IDs and aliases unless configured otherwise
+const DEFAULT_SERVER_NAME: &str = "matrix.local";
+/// Default number of rooms returned by /publicRooms
+const DEFAULT_PUBLIC_ROOMS_LIMIT: u32 = 30;
+/// Default number of events returned by /messages
+const DEFAULT_MESSAGES_LIMIT: u32 = 10;
+/// Upper bound on events returned by a single /messages request
+const MAX_MESSAGES_LIMIT: u32 = 1000;
+
+/// How long a device's transaction IDs are remembered
+const TRANSACTION_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// (user ID, device ID, endpoint, transaction ID)
+type TransactionKey = (String, String, String, String);
+
+/// The event sent under a transaction, filled in once sending succeeds
+struct Transaction {
+    started: Instant,
+    event_id: Arc<Mutex<Option<String>>>,
+}
+
 /// Room handler - manages room operations
 pub struct RoomHandler {
     state_store: Arc<dyn StateStore + Send + Sync>,
+    server_name: String,
+    directory_publish_role: Option<String>,
+    redaction_retention: Option<Duration>,
+    transactions: Mutex<HashMap<TransactionKey, Transaction>>,
 }
 
 impl RoomHandler {
     pub fn new(state_store: Arc<dyn StateStore + Send + Sync>) -> Self {
-        Self { state_store }
+        Self {
+            state_store,
+            server_name: DEFAULT_SERVER_NAME.to_string(),
+            directory_publish_role: None,
+            redaction_retention: None,
+            transactions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Mint room IDs and local aliases under `server_name`
+    pub fn with_server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = server_name.into();
+        self
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Only let users holding the OIDC `role` publish rooms to the directory
+    pub fn with_directory_publish_role(mut self, role: impl Into<String>) -> Self {
+        self.directory_publish_role = Some(role.into());
+        self
+    }
+
+    /// Keep the original content of redacted events for `retention` before
+    /// purging it, e.g. for compliance review. Without this the original
+    /// content is discarded as soon as the event is redacted.
+    pub fn with_redaction_retention(mut self, retention: Duration) -> Self {
+        self.redaction_retention = Some(retention);
+        self
+    }
+
+    pub fn redaction_retention(&self) -> Option<Duration> {
+        self.redaction_retention
+    }
+
+    /// Run `send` once per device, `endpoint` and `txn_id`, returning the
+    /// event it sent; retries get the first attempt's event instead, and
+    /// concurrent retries wait for it
+    ///
+    /// `endpoint` names the request path without the transaction ID, e.g.
+    /// `send/!room:example.org/m.room.message`. Failed attempts are not
+    /// remembered, so they may be retried.
+    pub async fn transaction<E>(
+        &self,
+        user: &AuthenticatedUser,
+        endpoint: &str,
+        txn_id: &str,
+        send: impl Future<Output = Result<String, E>>,
+    ) -> Result<String, E> {
+        let slot = {
+            let mut transactions = self.transactions.lock().await;
+            let now = Instant::now();
+            transactions.retain(|_, transaction| now.duration_since(transaction.started) < TRANSACTION_TTL);
+            let key = (user.user_id.clone(), user.device_id.clone(), endpoint.to_string(), txn_id.to_string());
+            transactions
+                .entry(key)
+                .or_insert_with(|| Transaction { started: now, event_id: Arc::default() })
+                .event_id
+                .clone()
+        };
+
+        let mut event_id = slot.lock().await;
+        if let Some(event_id) = event_id.as_ref() {
+            return Ok(event_id.clone());
+        }
+        let sent = send.await?;
+        *event_id = Some(sent.clone());
+        Ok(sent)
     }
 
     /// Create a new room
@@ -178,67 +455,176 @@ impl RoomHandler {
         config: RoomConfig,
     ) -> Result<CreateRoomResponse, RoomError> {
         // Generate room ID
-        let room_id = self.generate_room_id(&config.room_alias_name)?;
+        let room_id = self.generate_room_id();
         
         // Check if room already exists
         if self.state_store.room_exists(&room_id).await? {
             return Err(RoomError::RoomAlreadyExists(room_id));
         }
 
+        // Claim-check the requested alias before anything is created
+        let alias = match config.room_alias_name.as_deref() {
+            Some(alias_name) => {
+                let alias = format!("#{}:{}", alias_name, self.server_name);
+                if parse_room_alias(&alias).is_none() {
+                    return Err(RoomError::InvalidAlias(alias));
+                }
+                if self.state_store.get_room_alias(&alias).await?.is_some() {
+                    return Err(RoomError::AliasInUse(alias));
+                }
+                Some(alias)
+            }
+            None => None,
+        };
+
         // Create room state
-        let room_version = config.room_version.unwrap_or_else(|| "9".to_string());
+        let room_version = config.room_version.clone().unwrap_or_else(|| "9".to_string());
         let mut room_state = RoomState::new(
             room_id.clone(),
             creator.user_id.clone(),
-            room_version,
+            room_version.clone(),
         );
 
-        // Apply initial state events
-        for state_config in &config.initial_state {
-            let event = MatrixEvent::new(
-                EventType::Custom(state_config.event_type.clone()),
-                EventContent::Raw(state_config.content.clone()),
-                creator.user_id.clone(),
-                room_id.clone(),
-            ).with_state_key(state_config.state_key.clone());
+        // The create event carries the room type and whether the room federates
+        let mut creation_content = config.creation_content.clone().unwrap_or_else(|| serde_json::json!({}));
+        let fields = creation_content
+            .as_object_mut()
+            .ok_or_else(|| RoomError::InvalidRoomConfig("creation_content must be an object".to_string()))?;
+        fields.insert("creator".to_string(), serde_json::json!(creator.user_id));
+        fields.insert("room_version".to_string(), serde_json::json!(room_version));
+        if let Some(federate) = config.federate {
+            fields.insert("m.federate".to_string(), serde_json::json!(federate));
+        }
+        let create_content: RoomCreateContent = serde_json::from_value(creation_content)
+            .map_err(|e| RoomError::InvalidRoomConfig(format!("Invalid creation_content: {}", e)))?;
+        let federates = create_content.m_federate != Some(false);
+        if !federates {
+            if let Some(remote) = config.invite.iter().find(|invitee| self.is_remote_user(invitee)) {
+                return Err(RoomError::InsufficientPermissions(format!("Cannot invite {} to a room that does not federate", remote)));
+            }
+        }
 
-            room_state.add_state_event(event)?;
+        if config.visibility == RoomVisibility::Public {
+            self.check_publish_role(creator)?;
         }
 
-        // Set room name and topic if provided
-        if let Some(name) = config.name {
-            let name_event = MatrixEvent::new(
-                EventType::RoomName,
-                EventContent::RoomName(RoomNameContent { name }),
-                creator.user_id.clone(),
-                room_id.clone(),
-            ).with_state_key("".to_string());
-            room_state.add_state_event(name_event)?;
+        let preset = config.preset.clone().unwrap_or(match config.visibility {
+            RoomVisibility::Public => RoomPreset::PublicChat,
+            RoomVisibility::Private => RoomPreset::PrivateChat,
+        });
+        let mut initial_events = vec![
+            (EventType::RoomCreate, String::new(), EventContent::RoomCreate(create_content)),
+            (EventType::RoomMember, creator.user_id.clone(), EventContent::RoomMember(member_content(MembershipState::Join, None))),
+            (
+                EventType::RoomPowerLevels,
+                String::new(),
+                EventContent::RoomPowerLevels(initial_power_levels(&creator.user_id, &preset, &config)),
+            ),
+        ];
+
+        if let Some(alias) = &alias {
+            initial_events.push((
+                EventType::RoomCanonicalAlias,
+                String::new(),
+                EventContent::RoomCanonicalAlias(RoomCanonicalAliasContent {
+                    alias: Some(alias.clone()),
+                    alt_aliases: None,
+                }),
+            ));
+        }
+
+        // Preset state; initial state may override it
+        let (join_rule, guest_access) = match preset {
+            RoomPreset::PublicChat => (JoinRule::Public, GuestAccess::Forbidden),
+            RoomPreset::PrivateChat | RoomPreset::TrustedPrivateChat => (JoinRule::Invite, GuestAccess::CanJoin),
+        };
+        initial_events.extend([
+            (
+                EventType::RoomJoinRules,
+                String::new(),
+                EventContent::RoomJoinRules(RoomJoinRulesContent { join_rule, allow: None }),
+            ),
+            (
+                EventType::RoomHistoryVisibility,
+                String::new(),
+                EventContent::RoomHistoryVisibility(RoomHistoryVisibilityContent {
+                    history_visibility: HistoryVisibility::Shared,
+                }),
+            ),
+            (
+                EventType::RoomGuestAccess,
+                String::new(),
+                EventContent::RoomGuestAccess(RoomGuestAccessContent { guest_access }),
+            ),
+        ]);
+
+        for state_config in &config.initial_state {
+            let event_type = EventType::from(state_config.event_type.as_str());
+            if matches!(event_type, EventType::RoomCreate | EventType::RoomMember) {
+                return Err(RoomError::InvalidRoomConfig(
+                    format!("{} cannot be set through initial_state", state_config.event_type)
+                ));
+            }
+            let content = EventContent::from_type(&event_type, state_config.content.clone())
+                .map_err(|e| {
+                    RoomError::InvalidRoomConfig(format!("Invalid {} content: {}", state_config.event_type, e))
+                })?;
+            if let EventContent::RoomCanonicalAlias(ref content) = content {
+                // The only alias that can point at a room being created is its own
+                for listed in content.aliases() {
+                    let (_, server_name) = parse_room_alias(listed)
+                        .ok_or_else(|| RoomError::BadAlias(listed.clone()))?;
+                    if server_name == self.server_name && alias.as_ref() != Some(listed) {
+                        return Err(RoomError::BadAlias(format!("{} does not point to this room", listed)));
+                    }
+                }
+            }
+            initial_events.push((event_type, state_config.state_key.clone(), content));
         }
 
+        if let Some(name) = config.name {
+            initial_events.push((EventType::RoomName, String::new(), EventContent::RoomName(RoomNameContent { name })));
+        }
         if let Some(topic) = config.topic {
-            let topic_event = MatrixEvent::new(
-                EventType::RoomTopic,
-                EventContent::RoomTopic(RoomTopicContent { topic }),
-                creator.user_id.clone(),
-                room_id.clone(),
-            ).with_state_key("".to_string());
-            room_state.add_state_event(topic_event)?;
+            initial_events.push((EventType::RoomTopic, String::new(), EventContent::RoomTopic(RoomTopicContent { topic })));
         }
 
-        // Set join rules based on preset
-        let join_rule = match config.preset {
-            Some(RoomPreset::PublicChat) => "public",
-            Some(RoomPreset::PrivateChat) | Some(RoomPreset::TrustedPrivateChat) => "invite",
-            None => "invite",
-        };
-        room_state.join_rules = Some(join_rule.to_string());
+        for invitee in &config.invite {
+            let content = RoomMemberContent {
+                is_direct: config.is_direct,
+                ..member_content(MembershipState::Invite, None)
+            };
+            initial_events.push((EventType::RoomMember, invitee.clone(), EventContent::RoomMember(content)));
+        }
 
-        // Set history visibility
-        room_state.history_visibility = Some("shared".to_string());
+        // Build the state first so nothing is stored for a room that fails to create
+        let initial_events: Vec<MatrixEvent> = initial_events
+            .into_iter()
+            .map(|(event_type, state_key, content)| {
+                MatrixEvent::new(event_type, content, creator.user_id.clone(), room_id.clone()).with_state_key(state_key)
+            })
+            .collect();
+        for event in &initial_events {
+            room_state.apply_state_event(event.clone())?;
+        }
 
-        // Store room in state store
+        // Store room in state store, then its initial events in order
         self.state_store.create_room(room_state).await?;
+        for event in initial_events {
+            self.persist_event(event).await?;
+        }
+        if let Some(alias) = &alias {
+            self.state_store.create_room_alias(alias, &room_id, &creator.user_id).await
+                .map_err(|_| RoomError::AliasInUse(alias.clone()))?;
+        }
+        if config.visibility == RoomVisibility::Public {
+            self.state_store.set_room_published(&room_id, true).await?;
+        }
+        if config.is_direct == Some(true) {
+            for invitee in &config.invite {
+                add_direct_room(self.state_store.as_ref(), &creator.user_id, invitee, &room_id).await?;
+            }
+        }
 
         Ok(CreateRoomResponse { room_id })
     }
@@ -257,56 +643,78 @@ impl RoomHandler {
             .await?
             .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
 
-        // Check join rules
-        let join_rule = room_state.join_rules.as_deref().unwrap_or("invite");
-        match join_rule {
-            "public" => {
-                // Anyone can join public rooms
-            }
-            "invite" => {
-                // Check if user was invited or is admin
-                if !room_state.is_admin(&user.user_id) {
-                        return Err(RoomError::InsufficientPermissions(
-                        "Room requires invitation".to_string()
-                        ));
-                }
-            }
-            _ => {
-                return Err(RoomError::InsufficientPermissions(
-                    format!("Unknown join rule: {}", join_rule)
-                ));
-            }
-        }
+        let resident_server = self.server_name.clone();
+        let authoriser = self.authorise_join(&room_state, &user.user_id, &resident_server).await?;
 
-        // Add user to room
-        let member_event = MatrixEvent::new(
-            EventType::RoomMember,
-            EventContent::RoomMember(RoomMemberContent {
-                membership: MembershipState::Join,
-                displayname: None,
-                avatar_url: None,
-                reason: request.reason,
-                is_direct: None,
-                third_party_invite: None,
-            }),
-            user.user_id.clone(),
-            room_id.clone(),
-        ).with_state_key(user.user_id.clone());
+        // Accepting a DM invite makes the room a DM with the inviter
+        let direct_inviter = room_state
+            .get_state_event(&EventType::RoomMember, &user.user_id)
+            .filter(|event| matches!(
+                &event.content,
+                EventContent::RoomMember(member) if member.membership == MembershipState::Invite && member.is_direct == Some(true)
+            ))
+            .map(|event| event.sender.clone());
 
-        room_state.process_member_event(&member_event)?;
+        // Add user to room
+        self.change_membership(&mut room_state, &user.user_id, &user.user_id, RoomMemberContent {
+            join_authorised_via_users_server: authoriser,
+            ..member_content(MembershipState::Join, request.reason)
+        })
+            .await?;
+        self.state_store.set_room_forgotten(&user.user_id, &room_id, false).await?;
 
         // Update room state
         self.state_store.update_room(room_state).await?;
+        if let Some(inviter) = direct_inviter {
+            add_direct_room(self.state_store.as_ref(), &user.user_id, &inviter, &room_id).await?;
+        }
 
         Ok(JoinRoomResponse { room_id })
     }
 
-    /// Leave a room
-    pub async fn leave_room(
+    /// The DM room `user` already shares with `other_user_id`, or a new one
+    ///
+    /// A DM is reused while `user` is joined and the other user is joined
+    /// or still invited; otherwise a trusted private chat is created and
+    /// the other user invited to it.
+    pub async fn get_or_create_direct_room(
         &self,
         user: &AuthenticatedUser,
-        request: LeaveRoomRequest,
-    ) -> Result<(), RoomError> {
+        other_user_id: &str,
+    ) -> Result<DirectRoomResponse, RoomError> {
+        if other_user_id == user.user_id {
+            return Err(RoomError::InvalidRoomConfig("Cannot start a direct chat with yourself".to_string()));
+        }
+
+        for room_id in direct_rooms_with(self.state_store.as_ref(), &user.user_id, other_user_id).await? {
+            let Some(room_state) = self.state_store.get_room(&room_id).await? else {
+                continue;
+            };
+            let other_present = matches!(
+                room_state.membership(other_user_id),
+                Some(MembershipState::Join) | Some(MembershipState::Invite)
+            );
+            if room_state.is_member(&user.user_id) && other_present {
+                return Ok(DirectRoomResponse { room_id, created: false });
+            }
+        }
+
+        let room_id = self.create_room(user, RoomConfig {
+            invite: vec![other_user_id.to_string()],
+            preset: Some(RoomPreset::TrustedPrivateChat),
+            is_direct: Some(true),
+            ..Default::default()
+        }).await?.room_id;
+
+        Ok(DirectRoomResponse { room_id, created: true })
+    }
+
+    /// Knock on a room, asking its moderators for an invite
+    pub async fn knock_room(
+        &self,
+        user: &AuthenticatedUser,
+        request: KnockRoomRequest,
+    ) -> Result<KnockRoomResponse, RoomError> {
         let room_id = request.room_id;
 
         // Get room state
@@ -315,119 +723,1571 @@ impl RoomHandler {
             .await?
             .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
 
-        // Check if user is in room
-        if !room_state.is_member(&user.user_id) {
-            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        let join_rule = room_state.join_rules.as_deref().unwrap_or("invite");
+        if !matches!(join_rule, "knock" | "knock_restricted") {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Room with join rule {} does not accept knocks", join_rule)
+            ));
+        }
+        match room_state.membership(&user.user_id) {
+            Some(MembershipState::Ban) => {
+                return Err(RoomError::InsufficientPermissions("User is banned from this room".to_string()));
+            }
+            Some(MembershipState::Join) | Some(MembershipState::Invite) => {
+                return Err(RoomError::InvalidRoomConfig("User is already invited to or in this room".to_string()));
+            }
+            _ => {}
         }
 
-        // Remove user from room
-        let member_event = MatrixEvent::new(
+        self.change_membership(&mut room_state, &user.user_id, &user.user_id, member_content(MembershipState::Knock, request.reason))
+            .await?;
+        self.state_store.update_room(room_state).await?;
+
+        Ok(KnockRoomResponse { room_id })
+    }
+
+    /// Build the join event template for a remote user, as served by federation `make_join`
+    ///
+    /// Restricted joins are vouched for by a member of `resident_server`
+    /// named in `join_authorised_via_users_server`.
+    pub async fn make_join(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        resident_server: &str,
+    ) -> Result<MatrixEvent, RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+
+        self.check_federates(&room_state, user_id)?;
+        let authoriser = self.authorise_join(&room_state, user_id, resident_server).await?;
+        Ok(MatrixEvent::new(
             EventType::RoomMember,
             EventContent::RoomMember(RoomMemberContent {
-                membership: MembershipState::Leave,
-                displayname: None,
-                avatar_url: None,
-                reason: request.reason,
-                is_direct: None,
-                third_party_invite: None,
+                join_authorised_via_users_server: authoriser,
+                ..member_content(MembershipState::Join, None)
             }),
-            user.user_id.clone(),
-            room_id.clone(),
-        ).with_state_key(user.user_id.clone());
+            user_id.to_string(),
+            room_id.to_string(),
+        ).with_state_key(user_id.to_string()))
+    }
+
+    /// Accept a remote join event, as received by federation `send_join`,
+    /// returning the room state the joining server needs
+    pub async fn send_join(
+        &self,
+        event: MatrixEvent,
+        resident_server: &str,
+    ) -> Result<Vec<MatrixEvent>, RoomError> {
+        let mut room_state = self.state_store
+            .get_room(&event.room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(event.room_id.clone()))?;
 
-        room_state.process_member_event(&member_event)?;
+        let EventContent::RoomMember(ref content) = event.content else {
+            return Err(RoomError::InvalidRoomConfig("Expected an m.room.member event".to_string()));
+        };
+        if content.membership != MembershipState::Join || event.state_key.as_deref() != Some(event.sender.as_str()) {
+            return Err(RoomError::InvalidRoomConfig("Expected a join of the sender".to_string()));
+        }
+        self.check_federates(&room_state, &event.sender)?;
 
-        // Update room state
+        // A vouched join must name a member of ours who may still vouch for it
+        let authoriser = self.authorise_join(&room_state, &event.sender, resident_server).await?;
+        match (&content.join_authorised_via_users_server, authoriser) {
+            (None, None) => {}
+            (Some(named), Some(_)) if self.can_authorise_join(&room_state, named, resident_server) => {}
+            _ => {
+                return Err(RoomError::UnableToAuthoriseJoin(
+                    "Join is not authorised by a member of this server".to_string()
+                ));
+            }
+        }
+
+        self.persist_event(event.clone()).await?;
+        room_state.apply_state_event(event)?;
+        let state = room_state.state_events.values().cloned().collect();
         self.state_store.update_room(room_state).await?;
 
-        Ok(())
+        Ok(state)
     }
 
-    /// Send a message to a room
-    pub async fn send_message(
+    /// Check whether `user_id` may join under the room's join rules,
+    /// returning the member of `resident_server` who vouches for a restricted join
+    async fn authorise_join(
+        &self,
+        room_state: &RoomState,
+        user_id: &str,
+        resident_server: &str,
+    ) -> Result<Option<String>, RoomError> {
+        let membership = room_state.membership(user_id);
+        if membership == Some(&MembershipState::Ban) {
+            return Err(RoomError::InsufficientPermissions(
+                "User is banned from this room".to_string()
+            ));
+        }
+        let invited = matches!(membership, Some(MembershipState::Invite) | Some(MembershipState::Join));
+
+        // Check join rules
+        let join_rule = room_state.join_rules.as_deref().unwrap_or("invite");
+        match join_rule {
+            "public" => {
+                // Anyone can join public rooms
+                Ok(None)
+            }
+            "invite" | "knock" => {
+                // Check if user was invited or is admin
+                if !invited && !room_state.is_admin(user_id) {
+                    return Err(RoomError::InsufficientPermissions(
+                        "Room requires invitation".to_string()
+                    ));
+                }
+                Ok(None)
+            }
+            "restricted" | "knock_restricted" => {
+                if invited {
+                    return Ok(None);
+                }
+
+                let mut allowed = false;
+                for allowed_room in room_state.join_rule_allowed_rooms() {
+                    if let Some(allowed_state) = self.state_store.get_room(&allowed_room).await? {
+                        if allowed_state.is_member(user_id) {
+                            allowed = true;
+                            break;
+                        }
+                    }
+                }
+                if !allowed {
+                    return Err(RoomError::InsufficientPermissions(
+                        "Joining requires membership of an allowed room".to_string()
+                    ));
+                }
+
+                let authoriser = room_state.members
+                    .keys()
+                    .filter(|member| self.can_authorise_join(room_state, member, resident_server))
+                    .min()
+                    .cloned()
+                    .ok_or_else(|| RoomError::UnableToAuthoriseJoin(
+                        format!("No member of {} can authorise the join", resident_server)
+                    ))?;
+                Ok(Some(authoriser))
+            }
+            _ => Err(RoomError::InsufficientPermissions(
+                format!("Unknown join rule: {}", join_rule)
+            )),
+        }
+    }
+
+    /// Joined members of `resident_server` with the invite power level may vouch for restricted joins
+    fn can_authorise_join(&self, room_state: &RoomState, user_id: &str, resident_server: &str) -> bool {
+        room_state.is_member(user_id)
+            && self.user_server(user_id) == resident_server
+            && room_state.user_has_power_level(user_id, room_state.power_levels.invite.unwrap_or(0))
+    }
+
+    /// Leave a room, or reject a pending invite
+    pub async fn leave_room(
         &self,
         user: &AuthenticatedUser,
-        request: SendMessageRequest,
-    ) -> Result<SendMessageResponse, RoomError> {
+        request: LeaveRoomRequest,
+    ) -> Result<(), RoomError> {
         let room_id = request.room_id;
 
         // Get room state
-        let room_state = self.state_store
+        let mut room_state = self.state_store
             .get_room(&room_id)
             .await?
             .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
 
         // Check if user is in room
-        if !room_state.is_member(&user.user_id) {
+        if !matches!(
+            room_state.membership(&user.user_id),
+            Some(MembershipState::Join) | Some(MembershipState::Invite) | Some(MembershipState::Knock)
+        ) {
             return Err(RoomError::UserNotInRoom(user.user_id.clone()));
         }
 
-        // Check message size
-        if request.body.len() > 65536 {
-            return Err(RoomError::MessageTooLarge(request.body.len()));
-        }
+        // Remove user from room
+        self.change_membership(&mut room_state, &user.user_id, &user.user_id, member_content(MembershipState::Leave, request.reason))
+            .await?;
 
-        // Create message event
-        let message_content = RoomMessageContent {
-            msgtype: request.msgtype,
-            body: request.body,
-            formatted_body: request.formatted_body,
-            format: request.format,
-            relates_to: None, // TODO: Convert request.relates_to to proper RelatesTo type
-        };
+        // Update room state
+        self.state_store.update_room(room_state).await?;
 
-        let event = MatrixEvent::new(
-            EventType::RoomMessage,
+        Ok(())
+    }
+
+    /// Invite a user to a room
+    pub async fn invite_user(
+        &self,
+        user: &AuthenticatedUser,
+        request: MembershipChangeRequest,
+    ) -> Result<MembershipChangeResponse, RoomError> {
+        let mut room_state = self.room_state_for_moderation(user, &request.room_id).await?;
+
+        let required = room_state.power_levels.invite.unwrap_or(0);
+        if !room_state.user_has_power_level(&user.user_id, required) {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Inviting requires power level {}", required)
+            ));
+        }
+        self.check_federates(&room_state, &request.user_id)?;
+        match room_state.membership(&request.user_id) {
+            Some(MembershipState::Join) => {
+                return Err(RoomError::InvalidRoomConfig(format!("{} is already in the room", request.user_id)));
+            }
+            Some(MembershipState::Ban) => {
+                return Err(RoomError::InsufficientPermissions(format!("{} is banned from the room", request.user_id)));
+            }
+            _ => {}
+        }
+
+        let event_id = self
+            .change_membership(&mut room_state, &user.user_id, &request.user_id, member_content(MembershipState::Invite, request.reason))
+            .await?;
+        self.state_store.update_room(room_state).await?;
+
+        Ok(MembershipChangeResponse { event_id })
+    }
+
+    /// Kick a member out of a room
+    pub async fn kick_user(
+        &self,
+        user: &AuthenticatedUser,
+        request: MembershipChangeRequest,
+    ) -> Result<MembershipChangeResponse, RoomError> {
+        let mut room_state = self.room_state_for_moderation(user, &request.room_id).await?;
+
+        if !matches!(
+            room_state.membership(&request.user_id),
+            Some(MembershipState::Join) | Some(MembershipState::Invite) | Some(MembershipState::Knock)
+        ) {
+            return Err(RoomError::UserNotInRoom(request.user_id));
+        }
+        let required = room_state.power_levels.kick.unwrap_or(50);
+        Self::check_outranks(&room_state, &user.user_id, &request.user_id, required, "kick")?;
+
+        let event_id = self
+            .change_membership(&mut room_state, &user.user_id, &request.user_id, member_content(MembershipState::Leave, request.reason))
+            .await?;
+        self.state_store.update_room(room_state).await?;
+
+        Ok(MembershipChangeResponse { event_id })
+    }
+
+    /// Ban a user from a room, whether or not they are currently a member
+    pub async fn ban_user(
+        &self,
+        user: &AuthenticatedUser,
+        request: MembershipChangeRequest,
+    ) -> Result<MembershipChangeResponse, RoomError> {
+        let mut room_state = self.room_state_for_moderation(user, &request.room_id).await?;
+
+        let required = room_state.power_levels.ban.unwrap_or(50);
+        Self::check_outranks(&room_state, &user.user_id, &request.user_id, required, "ban")?;
+
+        let event_id = self
+            .change_membership(&mut room_state, &user.user_id, &request.user_id, member_content(MembershipState::Ban, request.reason))
+            .await?;
+        self.state_store.update_room(room_state).await?;
+
+        Ok(MembershipChangeResponse { event_id })
+    }
+
+    /// Lift a ban, leaving the user free to be invited or to rejoin
+    pub async fn unban_user(
+        &self,
+        user: &AuthenticatedUser,
+        request: MembershipChangeRequest,
+    ) -> Result<MembershipChangeResponse, RoomError> {
+        let mut room_state = self.room_state_for_moderation(user, &request.room_id).await?;
+
+        if room_state.membership(&request.user_id) != Some(&MembershipState::Ban) {
+            return Err(RoomError::InvalidRoomConfig(format!("{} is not banned", request.user_id)));
+        }
+        let required = room_state.power_levels.ban.unwrap_or(50);
+        Self::check_outranks(&room_state, &user.user_id, &request.user_id, required, "unban")?;
+
+        let event_id = self
+            .change_membership(&mut room_state, &user.user_id, &request.user_id, member_content(MembershipState::Leave, request.reason))
+            .await?;
+        self.state_store.update_room(room_state).await?;
+
+        Ok(MembershipChangeResponse { event_id })
+    }
+
+    /// Forget a room the user has left, hiding it from their sync
+    pub async fn forget_room(
+        &self,
+        user: &AuthenticatedUser,
+        room_id: &str,
+    ) -> Result<(), RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+
+        if matches!(
+            room_state.membership(&user.user_id),
+            Some(MembershipState::Join) | Some(MembershipState::Invite) | Some(MembershipState::Knock)
+        ) {
+            return Err(RoomError::InvalidRoomConfig("Leave the room before forgetting it".to_string()));
+        }
+        if room_state.get_state_event(&EventType::RoomMember, &user.user_id).is_none() {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        self.state_store.set_room_forgotten(&user.user_id, room_id, true).await?;
+        Ok(())
+    }
+
+    /// Room state for a moderation action, provided the acting user is joined
+    async fn room_state_for_moderation(&self, user: &AuthenticatedUser, room_id: &str) -> Result<RoomState, RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+        Ok(room_state)
+    }
+
+    /// Check `sender` holds `required` and outranks `target`, as kicks and bans demand
+    fn check_outranks(
+        room_state: &RoomState,
+        sender: &str,
+        target: &str,
+        required: i32,
+        action: &str,
+    ) -> Result<(), RoomError> {
+        if !room_state.user_has_power_level(sender, required) {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Permission to {} requires power level {}", action, required)
+            ));
+        }
+        if room_state.get_user_power_level(target) >= room_state.get_user_power_level(sender) {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Cannot {} a user with equal or higher power level", action)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Emit an `m.room.member` event for `target` sent by `sender` and apply it to `room_state`
+    async fn change_membership(
+        &self,
+        room_state: &mut RoomState,
+        sender: &str,
+        target: &str,
+        content: RoomMemberContent,
+    ) -> Result<String, RoomError> {
+        let member_event = MatrixEvent::new(
+            EventType::RoomMember,
+            EventContent::RoomMember(content),
+            sender.to_string(),
+            room_state.room_id.clone(),
+        ).with_state_key(target.to_string());
+        let event_id = member_event.event_id.clone();
+
+        self.persist_event(member_event.clone()).await?;
+        room_state.apply_state_event(member_event)?;
+
+        Ok(event_id)
+    }
+
+    /// Send a message to a room
+    pub async fn send_message(
+        &self,
+        user: &AuthenticatedUser,
+        request: SendMessageRequest,
+    ) -> Result<SendMessageResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        // Check message size
+        if request.body.len() > 65536 {
+            return Err(RoomError::MessageTooLarge(request.body.len()));
+        }
+
+        let relates_to = request.relates_to
+            .map(serde_json::from_value::<RelatesTo>)
+            .transpose()
+            .map_err(|e| RoomError::InvalidRelation(e.to_string()))?;
+        if let Some(ref relates_to) = relates_to {
+            self.validate_relation(&room_id, &user.user_id, relates_to).await?;
+        }
+
+        if matches!(request.msgtype, MessageType::Image | MessageType::File | MessageType::Video | MessageType::Audio) {
+            self.validate_media_url(request.url.as_deref()).await?;
+        }
+
+        // Create message event
+        let message_content = RoomMessageContent {
+            msgtype: request.msgtype,
+            body: request.body,
+            formatted_body: request.formatted_body,
+            format: request.format,
+            relates_to,
+            new_content: request.new_content,
+            mentions: request.mentions,
+            url: request.url,
+            info: request.info,
+            filename: request.filename,
+        };
+
+        let event = MatrixEvent::new(
+            EventType::RoomMessage,
             EventContent::RoomMessage(message_content),
             user.user_id.clone(),
             room_id.clone(),
         );
 
-        // Generate event ID
-        let event_id = format!("${}", Uuid::new_v4().simple());
+        // Store event in timeline
+        let event_id = self.append_related_event(event).await?;
+
+        Ok(SendMessageResponse { event_id })
+    }
+
+    /// React to an event with an `m.annotation`
+    pub async fn send_reaction(
+        &self,
+        user: &AuthenticatedUser,
+        request: SendReactionRequest,
+    ) -> Result<SendMessageResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        let relates_to = request.relates_to;
+        if relates_to.rel_type != REL_TYPE_ANNOTATION {
+            return Err(RoomError::InvalidRelation("Reactions must be annotations".to_string()));
+        }
+        let Some(ref key) = relates_to.key else {
+            return Err(RoomError::InvalidRelation("Annotation is missing a key".to_string()));
+        };
+        self.validate_relation(&room_id, &user.user_id, &relates_to).await?;
+
+        // Each user may only annotate an event once per key
+        let existing = self.state_store
+            .get_relations(&relates_to.event_id, Some(REL_TYPE_ANNOTATION), Some(&EventType::Reaction), 0, Direction::Forward, usize::MAX)
+            .await?;
+        if existing.iter().any(|(_, reaction)| {
+            reaction.sender == user.user_id && reaction.relates_to().and_then(|r| r.key.as_ref()) == Some(key)
+        }) {
+            return Err(RoomError::InvalidRelation("Duplicate annotation".to_string()));
+        }
+
+        let event = MatrixEvent::new(
+            EventType::Reaction,
+            EventContent::Reaction(ReactionContent { relates_to }),
+            user.user_id.clone(),
+            room_id,
+        );
+        let event_id = self.append_related_event(event).await?;
+
+        Ok(SendMessageResponse { event_id })
+    }
+
+    /// Check that a media message points at content this server can serve:
+    /// local media must have been uploaded or reserved here
+    async fn validate_media_url(&self, url: Option<&str>) -> Result<(), RoomError> {
+        let url = url.ok_or_else(|| RoomError::InvalidParam("Media messages require a url".to_string()))?;
+        let (server_name, media_id) = parse_mxc_uri(url)
+            .ok_or_else(|| RoomError::InvalidParam(format!("Invalid mxc URI {}", url)))?;
+        if server_name == self.server_name && self.state_store.get_media(server_name, media_id).await?.is_none() {
+            return Err(RoomError::InvalidParam(format!("Unknown media {}", url)));
+        }
+        Ok(())
+    }
+
+    /// Check that a new event may relate to its parent
+    async fn validate_relation(
+        &self,
+        room_id: &str,
+        sender: &str,
+        relates_to: &RelatesTo,
+    ) -> Result<(), RoomError> {
+        let parent = self.state_store
+            .get_event(&relates_to.event_id)
+            .await?
+            .filter(|event| event.room_id == room_id)
+            .ok_or_else(|| RoomError::InvalidRelation(format!("Unknown event: {}", relates_to.event_id)))?;
+        let parent_rel_type = parent.relates_to().map(|r| r.rel_type.as_str());
+
+        match relates_to.rel_type.as_str() {
+            REL_TYPE_REPLACE if parent.sender != sender => Err(RoomError::InvalidRelation(
+                "Only the original sender can edit an event".to_string()
+            )),
+            REL_TYPE_REPLACE | REL_TYPE_THREAD if parent_rel_type == Some(REL_TYPE_REPLACE) => Err(
+                RoomError::InvalidRelation("Cannot relate to an edit".to_string())
+            ),
+            REL_TYPE_THREAD if parent_rel_type == Some(REL_TYPE_THREAD) => Err(
+                RoomError::InvalidRelation("Threads cannot be nested".to_string())
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Append an event to its room timeline, index it for search and
+    /// record who it notifies
+    async fn persist_event(&self, event: MatrixEvent) -> Result<u64, RoomError> {
+        let stream_ordering = self.state_store.append_event(event.clone()).await?;
+        search::index_event(self.state_store.as_ref(), &event).await?;
+        visibility::index_event(self.state_store.as_ref(), stream_ordering, &event).await?;
+        push_rules::process_event(self.state_store.as_ref(), &self.server_name, &event).await?;
+        Ok(stream_ordering)
+    }
+
+    /// Store an event in the timeline and index the relation it declares
+    async fn append_related_event(&self, event: MatrixEvent) -> Result<String, RoomError> {
+        let event_id = event.event_id.clone();
+        let relation = event.relates_to().map(|r| (r.event_id.clone(), r.rel_type.clone()));
+
+        self.persist_event(event).await?;
+        if let Some((parent_id, rel_type)) = relation {
+            self.state_store.add_relation(&parent_id, &event_id, &rel_type).await?;
+        }
+        Ok(event_id)
+    }
+
+    /// Paginate the events relating to an event
+    pub async fn get_relations(
+        &self,
+        user: &AuthenticatedUser,
+        request: GetRelationsRequest,
+    ) -> Result<RelationsResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        self.state_store
+            .get_event(&request.event_id)
+            .await?
+            .filter(|event| event.room_id == room_id)
+            .ok_or_else(|| RoomError::EventNotFound(request.event_id.clone()))?;
+
+        let from = match request.from.as_deref() {
+            Some(token) => parse_stream_token(token)?,
+            None if request.dir == Direction::Forward => 0,
+            None => self.state_store.current_stream_position().await?,
+        };
+        let to = request.to.as_deref().map(parse_stream_token).transpose()?;
+        let limit = request.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT).min(MAX_MESSAGES_LIMIT) as usize;
+        let event_type = request.event_type.as_deref().map(EventType::from);
+
+        // Fetch one extra child to know whether another page exists
+        let mut children = self.state_store
+            .get_relations(&request.event_id, request.rel_type.as_deref(), event_type.as_ref(), from, request.dir, limit + 1)
+            .await?;
+        children.retain(|(ordering, _)| match (to, request.dir) {
+            (Some(to), Direction::Forward) => *ordering <= to,
+            (Some(to), Direction::Backward) => *ordering > to,
+            (None, _) => true,
+        });
+        let has_more = children.len() > limit;
+        children.truncate(limit);
+
+        let next_batch = match (children.last(), has_more, request.dir) {
+            (Some((ordering, _)), true, Direction::Forward) => Some(format_stream_token(*ordering)),
+            (Some((ordering, _)), true, Direction::Backward) => Some(format_stream_token(ordering - 1)),
+            _ => None,
+        };
+
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::User(&user.user_id)).await?;
+        children.retain(|(ordering, _)| visibility.is_visible(*ordering));
+
+        let mut chunk: Vec<MatrixEvent> = children.into_iter().map(|(_, event)| event).collect();
+        bundle_aggregations(&*self.state_store, &user.user_id, &mut chunk).await?;
+
+        Ok(RelationsResponse {
+            chunk,
+            next_batch,
+            prev_batch: request.from,
+        })
+    }
+
+    /// List thread roots in a room, most recently active first
+    pub async fn get_threads(
+        &self,
+        user: &AuthenticatedUser,
+        request: GetThreadsRequest,
+    ) -> Result<ThreadsResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        let from = request.from.as_deref().map(parse_stream_token).transpose()?;
+        let limit = request.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT).min(MAX_MESSAGES_LIMIT) as usize;
+
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::User(&user.user_id)).await?;
+        let mut chunk = Vec::new();
+        let mut next_batch = None;
+        for (latest, root_id) in self.state_store.get_thread_roots(&room_id).await? {
+            if from.is_some_and(|from| latest > from) {
+                continue;
+            }
+            let root_ordering = self.state_store.get_stream_ordering(&root_id).await?;
+            if !root_ordering.is_some_and(|ordering| visibility.is_visible(ordering)) {
+                continue;
+            }
+            let Some(root) = self.state_store.get_event(&root_id).await? else {
+                continue;
+            };
+            if request.include == ThreadsInclude::Participated
+                && !user_participated_in_thread(&*self.state_store, &user.user_id, &root).await?
+            {
+                continue;
+            }
+            if chunk.len() == limit {
+                next_batch = Some(format_stream_token(latest));
+                break;
+            }
+            chunk.push(root);
+        }
+        bundle_aggregations(&*self.state_store, &user.user_id, &mut chunk).await?;
+
+        Ok(ThreadsResponse { chunk, next_batch })
+    }
+
+    /// Get messages from a room
+    pub async fn get_messages(
+        &self,
+        user: &AuthenticatedUser,
+        request: GetMessagesRequest,
+    ) -> Result<GetMessagesResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        let current = self.state_store.current_stream_position().await?;
+        let from = match request.from.as_deref() {
+            Some(token) => parse_stream_token(token)?,
+            None if request.dir == Direction::Forward => 0,
+            None => current,
+        };
+        let to = request.to.as_deref().map(parse_stream_token).transpose()?;
+        let limit = request.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT).min(MAX_MESSAGES_LIMIT) as usize;
+
+        let mut events = self.state_store
+            .get_room_events(&room_id, from, request.dir, limit)
+            .await?
+            .into_iter()
+            .take_while(|(ordering, _)| match (to, request.dir) {
+                (Some(to), Direction::Forward) => *ordering <= to,
+                (Some(to), Direction::Backward) => *ordering > to,
+                (None, _) => true,
+            })
+            .collect::<Vec<_>>();
+
+        // The end token continues pagination past the last returned event
+        let end = match (events.last(), request.dir) {
+            (Some((ordering, _)), Direction::Forward) => *ordering,
+            (Some((ordering, _)), Direction::Backward) => ordering - 1,
+            (None, _) => from,
+        };
+
+        // Hidden events still advance the end token so pagination skips past them
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::User(&user.user_id)).await?;
+        events.retain(|(ordering, _)| visibility.is_visible(*ordering));
+
+        let mut chunk: Vec<MatrixEvent> = events.into_iter().map(|(_, event)| event).collect();
+        bundle_aggregations(&*self.state_store, &user.user_id, &mut chunk).await?;
+
+        Ok(GetMessagesResponse {
+            chunk,
+            start: format_stream_token(from),
+            end: format_stream_token(end),
+        })
+    }
+
+    /// Get the events surrounding an event together with the room state at it
+    pub async fn get_context(
+        &self,
+        user: &AuthenticatedUser,
+        request: GetContextRequest,
+    ) -> Result<GetContextResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::User(&user.user_id)).await?;
+        let ordering = match self.state_store.get_stream_ordering(&request.event_id).await? {
+            Some(ordering) if visibility.is_visible(ordering) => ordering,
+            _ => return Err(RoomError::EventNotFound(request.event_id)),
+        };
+        let mut event = self.state_store
+            .get_event(&request.event_id)
+            .await?
+            .filter(|event| event.room_id == room_id)
+            .ok_or_else(|| RoomError::EventNotFound(request.event_id.clone()))?;
+
+        // The limit covers both sides, split evenly with any remainder after the event
+        let limit = request.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT).min(MAX_MESSAGES_LIMIT) as usize;
+        let before_limit = limit / 2;
+        let after_limit = limit - before_limit;
+
+        let mut before = self.state_store
+            .get_room_events(&room_id, ordering - 1, Direction::Backward, before_limit)
+            .await?;
+        let mut after = self.state_store
+            .get_room_events(&room_id, ordering, Direction::Forward, after_limit)
+            .await?;
+
+        let start = before.last().map_or(ordering - 1, |(before_ordering, _)| before_ordering - 1);
+        let end = after.last().map_or(ordering, |(after_ordering, _)| *after_ordering);
+        before.retain(|(before_ordering, _)| visibility.is_visible(*before_ordering));
+        after.retain(|(after_ordering, _)| visibility.is_visible(*after_ordering));
+
+        let mut events_before: Vec<MatrixEvent> = before.into_iter().map(|(_, event)| event).collect();
+        let mut events_after: Vec<MatrixEvent> = after.into_iter().map(|(_, event)| event).collect();
+
+        let mut state = self.state_at(&room_state, ordering).await?;
+        if request.filter.lazy_load_members {
+            // Only the members who sent one of the returned events
+            let senders: std::collections::HashSet<&str> = events_before
+                .iter()
+                .chain(std::iter::once(&event))
+                .chain(events_after.iter())
+                .map(|event| event.sender.as_str())
+                .collect();
+            state.retain(|state_event| {
+                state_event.event_type != EventType::RoomMember
+                    || state_event.state_key.as_deref().is_some_and(|member| senders.contains(member))
+            });
+        }
+
+        bundle_aggregations(&*self.state_store, &user.user_id, std::slice::from_mut(&mut event)).await?;
+        bundle_aggregations(&*self.state_store, &user.user_id, &mut events_before).await?;
+        bundle_aggregations(&*self.state_store, &user.user_id, &mut events_after).await?;
+
+        Ok(GetContextResponse {
+            start: format_stream_token(start),
+            end: format_stream_token(end),
+            events_before,
+            event,
+            events_after,
+            state,
+        })
+    }
+
+    /// Events up to and including `event_id` for a server backfilling the room, newest first
+    pub async fn backfill(
+        &self,
+        origin: &str,
+        room_id: &str,
+        event_id: &str,
+        limit: usize,
+    ) -> Result<Vec<MatrixEvent>, RoomError> {
+        let room_state = self.room_state_for_server(origin, room_id).await?;
+        let from = self.state_store
+            .get_stream_ordering(event_id)
+            .await?
+            .ok_or_else(|| RoomError::EventNotFound(event_id.to_string()))?;
+
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::Server(origin)).await?;
+        Ok(self.state_store
+            .get_room_events(room_id, from, Direction::Backward, limit.min(MAX_MESSAGES_LIMIT as usize))
+            .await?
+            .into_iter()
+            .filter(|(ordering, _)| visibility.is_visible(*ordering))
+            .map(|(_, event)| event)
+            .collect())
+    }
+
+    /// Events a server is missing between the latest events it has and `latest_events`
+    pub async fn missing_events(
+        &self,
+        origin: &str,
+        room_id: &str,
+        earliest_events: &[String],
+        latest_events: &[String],
+        limit: usize,
+    ) -> Result<Vec<MatrixEvent>, RoomError> {
+        let room_state = self.room_state_for_server(origin, room_id).await?;
+
+        let mut after = 0;
+        for event_id in earliest_events {
+            if let Some(ordering) = self.state_store.get_stream_ordering(event_id).await? {
+                after = after.max(ordering);
+            }
+        }
+        let mut before = None;
+        for event_id in latest_events {
+            if let Some(ordering) = self.state_store.get_stream_ordering(event_id).await? {
+                before = Some(before.map_or(ordering, |before: u64| before.max(ordering)));
+            }
+        }
+        let Some(before) = before else {
+            return Ok(Vec::new());
+        };
+
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::Server(origin)).await?;
+        let mut events: Vec<MatrixEvent> = self.state_store
+            .get_room_events(room_id, before - 1, Direction::Backward, limit.min(MAX_MESSAGES_LIMIT as usize))
+            .await?
+            .into_iter()
+            .take_while(|(ordering, _)| *ordering > after)
+            .filter(|(ordering, _)| visibility.is_visible(*ordering))
+            .map(|(_, event)| event)
+            .collect();
+        events.reverse();
+        Ok(events)
+    }
+
+    /// A single event for a server fetching it directly
+    ///
+    /// Events of rooms `origin` has no members in, and events its users may
+    /// not see, are reported as missing rather than forbidden.
+    pub async fn event_for_server(&self, origin: &str, event_id: &str) -> Result<MatrixEvent, RoomError> {
+        let not_found = || RoomError::EventNotFound(event_id.to_string());
+        let event = self.state_store.get_event(event_id).await?.ok_or_else(not_found)?;
+        let room_state = match self.room_state_for_server(origin, &event.room_id).await {
+            Ok(room_state) => room_state,
+            Err(RoomError::RoomNotFound(_) | RoomError::InsufficientPermissions(_)) => return Err(not_found()),
+            Err(e) => return Err(e),
+        };
+        let ordering = self.state_store.get_stream_ordering(event_id).await?.ok_or_else(not_found)?;
+
+        let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::Server(origin)).await?;
+        if !visibility.is_visible(ordering) {
+            return Err(not_found());
+        }
+        Ok(event)
+    }
+
+    /// Room state for a federation read, provided `origin` participates in the room
+    async fn room_state_for_server(&self, origin: &str, room_id: &str) -> Result<RoomState, RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+
+        if !room_state.servers().contains(origin) {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Server {} is not in room {}", origin, room_id)
+            ));
+        }
+        Ok(room_state)
+    }
+
+    /// Room state as it was once the event at `ordering` had been applied
+    ///
+    /// State that never passed through the timeline, such as state learned
+    /// from a remote server, is taken from the current room state.
+    pub async fn state_at(&self, room_state: &RoomState, ordering: u64) -> Result<Vec<MatrixEvent>, RoomError> {
+        let timeline_state: Vec<(u64, MatrixEvent)> = self.state_store
+            .get_room_events(&room_state.room_id, 0, Direction::Forward, usize::MAX)
+            .await?
+            .into_iter()
+            .filter(|(_, event)| event.is_state_event())
+            .collect();
+
+        let mut state: HashMap<(EventType, String), MatrixEvent> = room_state.state_events
+            .iter()
+            .filter(|(_, current)| !timeline_state.iter().any(|(_, event)| event.event_id == current.event_id))
+            .map(|(key, event)| (key.clone(), event.clone()))
+            .collect();
+        for (_, event) in timeline_state.into_iter().take_while(|(event_ordering, _)| *event_ordering <= ordering) {
+            let key = (event.event_type.clone(), event.state_key.clone().unwrap_or_default());
+            state.insert(key, event);
+        }
+
+        Ok(state.into_values().collect())
+    }
+
+    /// Redact an event
+    ///
+    /// Users may redact their own events; redacting anyone else's requires
+    /// the room's `redact` power level.
+    pub async fn redact_event(
+        &self,
+        user: &AuthenticatedUser,
+        request: RedactEventRequest,
+    ) -> Result<RedactEventResponse, RoomError> {
+        let room_id = request.room_id;
+
+        // Get room state
+        let mut room_state = self.state_store
+            .get_room(&room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+
+        // Check if user is in room
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        let target = self.state_store
+            .get_event(&request.event_id)
+            .await?
+            .filter(|event| event.room_id == room_id)
+            .ok_or_else(|| RoomError::EventNotFound(request.event_id.clone()))?;
+
+        let redact_level = room_state.power_levels.redact.unwrap_or(50);
+        if target.sender != user.user_id && !room_state.user_has_power_level(&user.user_id, redact_level) {
+            return Err(RoomError::InsufficientPermissions(
+                "Redacting other users' events requires the redact power level".to_string()
+            ));
+        }
+
+        let redaction = MatrixEvent::redaction(
+            &target.event_id,
+            request.reason,
+            user.user_id.clone(),
+            room_id.clone(),
+            &room_state.room_version,
+        );
+        let event_id = redaction.event_id.clone();
+
+        self.persist_event(redaction.clone()).await?;
+        self.apply_redaction(&mut room_state, target, &redaction).await?;
+
+        Ok(RedactEventResponse { event_id })
+    }
+
+    /// Apply a redaction received over federation
+    ///
+    /// The sender must be joined to the room. Remote servers may redact
+    /// events from their own users; anything else requires the sender to
+    /// hold the room's `redact` power level.
+    pub async fn receive_redaction(&self, redaction: MatrixEvent) -> Result<(), RoomError> {
+        if redaction.event_type != EventType::RoomRedaction {
+            return Err(RoomError::InvalidRoomConfig("Not a redaction event".to_string()));
+        }
+
+        let mut room_state = self.state_store
+            .get_room(&redaction.room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(redaction.room_id.clone()))?;
+        let redacts = redaction
+            .redacted_event_id(&room_state.room_version)
+            .ok_or_else(|| RoomError::InvalidRoomConfig("Redaction does not name an event".to_string()))?
+            .to_string();
+
+        if !room_state.is_member(&redaction.sender) {
+            return Err(RoomError::UserNotInRoom(redaction.sender.clone()));
+        }
+
+        let target = self.state_store
+            .get_event(&redacts)
+            .await?
+            .filter(|event| event.room_id == redaction.room_id)
+            .ok_or_else(|| RoomError::EventNotFound(redacts.clone()))?;
+
+        // A sender without a server part never matches, even another one without
+        let same_server = matches!(
+            (server_name_of(&target.sender), server_name_of(&redaction.sender)),
+            (Some(target_server), Some(sender_server)) if target_server == sender_server
+        );
+        let redact_level = room_state.power_levels.redact.unwrap_or(50);
+        if !same_server && !room_state.user_has_power_level(&redaction.sender, redact_level) {
+            return Err(RoomError::InsufficientPermissions(redaction.sender.clone()));
+        }
+
+        self.persist_event(redaction.clone()).await?;
+        self.apply_redaction(&mut room_state, target, &redaction).await
+    }
+
+    /// Replace the stored event with its redacted form
+    async fn apply_redaction(
+        &self,
+        room_state: &mut RoomState,
+        target: MatrixEvent,
+        redaction: &MatrixEvent,
+    ) -> Result<(), RoomError> {
+        // Already redacted events keep the redaction that stripped them first
+        if target.is_redacted() {
+            return Ok(());
+        }
+
+        let redacted = target.redact(redaction, &room_state.room_version);
+
+        if self.redaction_retention.is_some() {
+            self.state_store
+                .retain_redacted_original(target, redaction.origin_server_ts)
+                .await?;
+        }
+        self.state_store.replace_event(redacted.clone()).await?;
+        // Redaction strips m.relates_to, so the event no longer counts towards aggregations
+        self.state_store.remove_relation(&redacted.event_id).await?;
+        self.state_store.remove_search_terms(&redacted.event_id).await?;
+
+        if redacted.is_state_event() {
+            room_state.replace_state_event(&redacted);
+            self.state_store.update_room(room_state.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Purge retained original content of events redacted longer ago than
+    /// the configured retention, returning how many originals were dropped
+    pub async fn purge_redacted_content(&self) -> Result<usize, RoomError> {
+        let Some(retention) = self.redaction_retention else {
+            return Ok(0);
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let cutoff = now.saturating_sub(retention.as_millis() as u64);
+
+        Ok(self.state_store.purge_redacted_originals(cutoff).await?)
+    }
+
+    /// Generate an opaque room ID on this server
+    fn generate_room_id(&self) -> String {
+        format!("!{}:{}", Uuid::new_v4().simple(), self.server_name)
+    }
+
+    /// Map a local alias to an existing room; allowed for its members and for
+    /// users who may change the room's canonical alias
+    pub async fn create_alias(
+        &self,
+        user: &AuthenticatedUser,
+        alias: &str,
+        room_id: &str,
+    ) -> Result<(), RoomError> {
+        self.check_local_alias(alias)?;
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+        let can_edit_canonical = room_state.user_has_power_level(
+            &user.user_id,
+            room_state.state_event_level(&EventType::RoomCanonicalAlias),
+        );
+        if !room_state.is_member(&user.user_id) && !can_edit_canonical {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Only members of {} may create aliases for it", room_id)
+            ));
+        }
+
+        self.state_store.create_room_alias(alias, room_id, &user.user_id).await
+            .map_err(|e| match e {
+                StateError::StateConflict(_) => RoomError::AliasInUse(alias.to_string()),
+                other => RoomError::StateError(other),
+            })
+    }
+
+    /// Resolve a local alias to its room and the servers resident in it
+    pub async fn resolve_alias(&self, alias: &str) -> Result<ResolveAliasResponse, RoomError> {
+        self.check_local_alias(alias)?;
+        let mapping = self.state_store
+            .get_room_alias(alias)
+            .await?
+            .ok_or_else(|| RoomError::AliasNotFound(alias.to_string()))?;
+
+        // List ourselves first; we can always answer for our own aliases
+        let mut servers = vec![self.server_name.clone()];
+        if let Some(room_state) = self.state_store.get_room(&mapping.room_id).await? {
+            let mut resident: Vec<String> = room_state.servers()
+                .into_iter()
+                .filter(|server| *server != self.server_name)
+                .collect();
+            resident.sort();
+            servers.extend(resident);
+        }
+
+        Ok(ResolveAliasResponse { room_id: mapping.room_id, servers })
+    }
+
+    /// Remove a local alias; allowed for its creator and for users who may
+    /// change the room's canonical alias, which drops the alias from it too
+    pub async fn delete_alias(&self, user: &AuthenticatedUser, alias: &str) -> Result<(), RoomError> {
+        self.check_local_alias(alias)?;
+        let mapping = self.state_store
+            .get_room_alias(alias)
+            .await?
+            .ok_or_else(|| RoomError::AliasNotFound(alias.to_string()))?;
+        let room_state = self.state_store.get_room(&mapping.room_id).await?;
+
+        let can_edit_canonical = room_state.as_ref().is_some_and(|room_state| {
+            room_state.is_member(&user.user_id)
+                && room_state.user_has_power_level(
+                    &user.user_id,
+                    room_state.state_event_level(&EventType::RoomCanonicalAlias),
+                )
+        });
+        if mapping.creator != user.user_id && !can_edit_canonical {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Only the creator of {} or a room moderator may delete it", alias)
+            ));
+        }
+        self.state_store.delete_room_alias(alias).await?;
+
+        let Some(mut room_state) = room_state.filter(|_| can_edit_canonical) else {
+            return Ok(());
+        };
+        let Some(mut content) = room_state.canonical_alias_content() else {
+            return Ok(());
+        };
+        if !content.aliases().any(|listed| listed == alias) {
+            return Ok(());
+        }
+        if content.alias.as_deref() == Some(alias) {
+            content.alias = None;
+        }
+        if let Some(alt_aliases) = content.alt_aliases.as_mut() {
+            alt_aliases.retain(|listed| listed != alias);
+        }
+        self.append_state_event(
+            &mut room_state,
+            &user.user_id,
+            EventType::RoomCanonicalAlias,
+            String::new(),
+            EventContent::RoomCanonicalAlias(content),
+        ).await?;
+        Ok(())
+    }
+
+    /// Local aliases of a room, for members or anyone when it is world readable
+    pub async fn get_aliases(&self, user: &AuthenticatedUser, room_id: &str) -> Result<Vec<String>, RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+
+        let world_readable = room_state.history_visibility.as_deref() == Some("world_readable");
+        if !world_readable && !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        Ok(self.state_store.get_room_aliases(room_id).await?)
+    }
+
+    /// Publish a room to the directory or withdraw it; requires the power to
+    /// change the canonical alias and, to publish, the configured OIDC role
+    pub async fn set_room_visibility(
+        &self,
+        user: &AuthenticatedUser,
+        room_id: &str,
+        visibility: RoomVisibility,
+    ) -> Result<(), RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+        let required = room_state.state_event_level(&EventType::RoomCanonicalAlias);
+        if !room_state.user_has_power_level(&user.user_id, required) {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Changing directory visibility requires power level {}", required)
+            ));
+        }
+        if visibility == RoomVisibility::Public {
+            self.check_publish_role(user)?;
+        }
+
+        self.state_store
+            .set_room_published(room_id, visibility == RoomVisibility::Public)
+            .await?;
+        Ok(())
+    }
+
+    /// Publishing to the directory may be limited to users with a role
+    fn check_publish_role(&self, user: &AuthenticatedUser) -> Result<(), RoomError> {
+        match &self.directory_publish_role {
+            Some(role) if !user.roles.contains(role) => Err(RoomError::InsufficientPermissions(
+                format!("Publishing rooms requires the {} role", role)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn get_room_visibility(&self, room_id: &str) -> Result<RoomVisibility, RoomError> {
+        if !self.state_store.room_exists(room_id).await? {
+            return Err(RoomError::RoomNotFound(room_id.to_string()));
+        }
+        Ok(if self.state_store.is_room_published(room_id).await? {
+            RoomVisibility::Public
+        } else {
+            RoomVisibility::Private
+        })
+    }
+
+    /// List published rooms, busiest first; `federated_only` hides rooms
+    /// that cannot be joined over federation from remote servers
+    pub async fn get_public_rooms(
+        &self,
+        request: PublicRoomsRequest,
+        federated_only: bool,
+    ) -> Result<PublicRoomsResponse, RoomError> {
+        let mut entries = Vec::new();
+        for room_id in self.state_store.get_published_rooms().await? {
+            let Some(room_state) = self.state_store.get_room(&room_id).await? else {
+                continue;
+            };
+            if federated_only && !room_state.federates() {
+                continue;
+            }
+            let entry = PublicRoomsChunk::from_room_state(&room_state);
+            if request.filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        sort_public_rooms(&mut entries);
+
+        let total = entries.len();
+        let offset = match request.since.as_deref() {
+            Some(token) => parse_directory_token(token)
+                .ok_or_else(|| RoomError::InvalidRoomConfig(format!("Invalid pagination token: {}", token)))?,
+            None => 0,
+        };
+        let limit = request.limit.unwrap_or(DEFAULT_PUBLIC_ROOMS_LIMIT) as usize;
+        let end = offset.saturating_add(limit).min(total);
+
+        Ok(PublicRoomsResponse {
+            chunk: entries.drain(offset.min(total)..end).collect(),
+            next_batch: (end < total).then(|| format_directory_token(end)),
+            prev_batch: (offset > 0).then(|| format_directory_token(offset.saturating_sub(limit))),
+            total_room_count_estimate: total,
+        })
+    }
+
+    /// Send `m.room.canonical_alias`, whose aliases must be valid and, when
+    /// local, point at this room
+    pub async fn set_canonical_alias(
+        &self,
+        user: &AuthenticatedUser,
+        room_id: &str,
+        content: RoomCanonicalAliasContent,
+    ) -> Result<String, RoomError> {
+        let response = self.send_state_event(user, SendStateEventRequest {
+            room_id: room_id.to_string(),
+            event_type: EventType::RoomCanonicalAlias.as_str().to_string(),
+            state_key: String::new(),
+            content: serde_json::to_value(content).map_err(|e| RoomError::InvalidRoomConfig(e.to_string()))?,
+        }).await?;
+        Ok(response.event_id)
+    }
+
+    /// Set a piece of room state through the state API
+    ///
+    /// Content must match the schema of its event type, the sender needs the
+    /// level the power levels `events` map (or `state_default`) demands, and
+    /// some types carry extra rules of their own. Re-sending the sender's
+    /// current content is a no-op returning the existing event.
+    pub async fn send_state_event(
+        &self,
+        user: &AuthenticatedUser,
+        request: SendStateEventRequest,
+    ) -> Result<SendStateEventResponse, RoomError> {
+        let mut room_state = self.state_store
+            .get_room(&request.room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(request.room_id.clone()))?;
+
+        if !room_state.is_member(&user.user_id) {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        }
+
+        let event_type = EventType::from(request.event_type.as_str());
+        let content = EventContent::from_type(&event_type, request.content)
+            .map_err(|e| RoomError::InvalidRoomConfig(format!("Invalid {} content: {}", request.event_type, e)))?;
+
+        // State keys naming a user belong to that user
+        if request.state_key.starts_with('@') && request.state_key != user.user_id {
+            return Err(RoomError::InsufficientPermissions(
+                format!("State key {} is reserved for that user", request.state_key)
+            ));
+        }
+        // Member events follow membership rules rather than the events map
+        let required = room_state.state_event_level(&event_type);
+        if event_type != EventType::RoomMember && !room_state.user_has_power_level(&user.user_id, required) {
+            return Err(RoomError::InsufficientPermissions(
+                format!("Sending {} requires power level {}", request.event_type, required)
+            ));
+        }
+        self.validate_state_content(&room_state, &user.user_id, &request.state_key, &content).await?;
+
+        if let Some(current) = room_state.get_state_event(&event_type, &request.state_key) {
+            let unchanged = current.sender == user.user_id
+                && serde_json::to_value(&current.content).ok() == serde_json::to_value(&content).ok();
+            if unchanged {
+                return Ok(SendStateEventResponse { event_id: current.event_id.clone() });
+            }
+        }
+
+        let event_id = self
+            .append_state_event(&mut room_state, &user.user_id, event_type, request.state_key, content)
+            .await?;
+        Ok(SendStateEventResponse { event_id })
+    }
+
+    /// Rules specific to some state event types, on top of their schema
+    async fn validate_state_content(
+        &self,
+        room_state: &RoomState,
+        sender: &str,
+        state_key: &str,
+        content: &EventContent,
+    ) -> Result<(), RoomError> {
+        match content {
+            EventContent::RoomCreate(_) => Err(RoomError::InsufficientPermissions(
+                "m.room.create cannot be changed once the room exists".to_string()
+            )),
+            // Only profile changes go through the state API; membership has its own endpoints
+            EventContent::RoomMember(member) => {
+                if state_key == sender && member.membership == MembershipState::Join {
+                    Ok(())
+                } else {
+                    Err(RoomError::InvalidRoomConfig(
+                        "Use the membership endpoints to change membership".to_string()
+                    ))
+                }
+            }
+            EventContent::RoomPowerLevels(power_levels) => check_power_levels_change(room_state, sender, power_levels),
+            EventContent::RoomCanonicalAlias(canonical) => self.validate_canonical_alias(&room_state.room_id, canonical).await,
+            EventContent::RoomServerAcl(acl) if !acl.allows(&self.server_name) => Err(RoomError::InvalidRoomConfig(
+                format!("Server ACL would ban this server ({})", self.server_name)
+            )),
+            EventContent::SpaceChild(_) | EventContent::SpaceParent(_) if !state_key.starts_with('!') => {
+                Err(RoomError::InvalidRoomConfig(format!("State key {} is not a room ID", state_key)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Aliases in `m.room.canonical_alias` must be valid and local ones must
+    /// point at `room_id`; remote ones cannot be checked without a directory
+    /// query to their server
+    async fn validate_canonical_alias(&self, room_id: &str, content: &RoomCanonicalAliasContent) -> Result<(), RoomError> {
+        for alias in content.aliases() {
+            let (_, server_name) = parse_room_alias(alias)
+                .ok_or_else(|| RoomError::BadAlias(format!("{} is not a valid room alias", alias)))?;
+            if server_name != self.server_name {
+                continue;
+            }
+            let points_here = self.state_store
+                .get_room_alias(alias)
+                .await?
+                .is_some_and(|mapping| mapping.room_id == room_id);
+            if !points_here {
+                return Err(RoomError::BadAlias(format!("{} does not point to this room", alias)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a state event into the timeline and apply it to `room_state`
+    async fn append_state_event(
+        &self,
+        room_state: &mut RoomState,
+        sender: &str,
+        event_type: EventType,
+        state_key: String,
+        content: EventContent,
+    ) -> Result<String, RoomError> {
+        let event = MatrixEvent::new(
+            event_type,
+            content,
+            sender.to_string(),
+            room_state.room_id.clone(),
+        ).with_state_key(state_key);
+        let event_id = event.event_id.clone();
+
+        self.persist_event(event.clone()).await?;
+        room_state.apply_state_event(event)?;
+        self.state_store.update_room(room_state.clone()).await?;
+
+        Ok(event_id)
+    }
+
+    /// Room state as `user` may see it: current state for members and world
+    /// readable rooms, or the state when they left for former members
+    pub async fn get_state(&self, user: &AuthenticatedUser, room_id: &str) -> Result<Vec<MatrixEvent>, RoomError> {
+        let room_state = self.state_store
+            .get_room(room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+        self.readable_state(user, &room_state, None).await
+    }
+
+    /// State readable by `user`, optionally as of stream position `at`;
+    /// former members never see past their departure
+    async fn readable_state(
+        &self,
+        user: &AuthenticatedUser,
+        room_state: &RoomState,
+        at: Option<u64>,
+    ) -> Result<Vec<MatrixEvent>, RoomError> {
+        let world_readable = room_state.history_visibility.as_deref() == Some("world_readable");
+        if room_state.is_member(&user.user_id) || world_readable {
+            return match at {
+                Some(ordering) => self.state_at(room_state, ordering).await,
+                None => Ok(room_state.state_events.values().cloned().collect()),
+            };
+        }
+
+        let departure = room_state
+            .get_state_event(&EventType::RoomMember, &user.user_id)
+            .filter(|event| matches!(
+                &event.content,
+                EventContent::RoomMember(member) if matches!(member.membership, MembershipState::Leave | MembershipState::Ban)
+            ));
+        let Some(departure) = departure else {
+            return Err(RoomError::UserNotInRoom(user.user_id.clone()));
+        };
+        let left_at = self.state_store
+            .get_stream_ordering(&departure.event_id)
+            .await?
+            .unwrap_or(0);
+        self.state_at(room_state, at.map_or(left_at, |ordering| ordering.min(left_at))).await
+    }
 
-        // TODO: Store event in timeline
-        // For now, just return success
+    /// Member events of a room for /members, filtered by membership
+    pub async fn get_members(
+        &self,
+        user: &AuthenticatedUser,
+        request: GetMembersRequest,
+    ) -> Result<MembersResponse, RoomError> {
+        let room_state = self.state_store
+            .get_room(&request.room_id)
+            .await?
+            .ok_or_else(|| RoomError::RoomNotFound(request.room_id.clone()))?;
+        let at = request.at.as_deref().map(parse_stream_token).transpose()?;
 
-        Ok(SendMessageResponse { event_id })
+        let mut chunk: Vec<MatrixEvent> = self.readable_state(user, &room_state, at)
+            .await?
+            .into_iter()
+            .filter(|event| {
+                let EventContent::RoomMember(member) = &event.content else {
+                    return false;
+                };
+                request.membership.as_ref().is_none_or(|wanted| member.membership == *wanted)
+                    && request.not_membership.as_ref() != Some(&member.membership)
+            })
+            .collect();
+        chunk.sort_by(|a, b| a.state_key.cmp(&b.state_key));
+
+        Ok(MembersResponse { chunk })
     }
 
-    /// Get messages from a room
-    pub async fn get_messages(
+    /// Currently joined members and their profiles; only for members
+    pub async fn get_joined_members(
         &self,
         user: &AuthenticatedUser,
-        request: GetMessagesRequest,
-    ) -> Result<GetMessagesResponse, RoomError> {
-        let room_id = request.room_id;
-
-        // Get room state
+        room_id: &str,
+    ) -> Result<JoinedMembersResponse, RoomError> {
         let room_state = self.state_store
-            .get_room(&room_id)
+            .get_room(room_id)
             .await?
-            .ok_or_else(|| RoomError::RoomNotFound(room_id.clone()))?;
+            .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
 
-        // Check if user is in room
         if !room_state.is_member(&user.user_id) {
             return Err(RoomError::UserNotInRoom(user.user_id.clone()));
         }
 
-        // TODO: Implement actual message retrieval from timeline
-        // For now, return empty response
-        Ok(GetMessagesResponse {
-            chunk: vec![],
-            start: request.from.unwrap_or_else(|| "0".to_string()),
-            end: request.to.unwrap_or_else(|| "0".to_string()),
-        })
+        Ok(JoinedMembersResponse { joined: room_state.joined_members() })
     }
 
-    /// Generate a room ID
-    fn generate_room_id(&self, alias: &Option<String>) -> Result<String, RoomError> {
-        if let Some(alias_name) = alias {
-            // Use alias if provided
-            Ok(format!("#{}:matrix.local", alias_name))
-        } else {
-            // Generate random room ID
-            Ok(format!("!{}:matrix.local", Uuid::new_v4().simple()))
+    /// A single state event as `user` may see it, see `get_state`
+    pub async fn get_state_event(
+        &self,
+        user: &AuthenticatedUser,
+        room_id: &str,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<MatrixEvent, RoomError> {
+        let event_type = EventType::from(event_type);
+        self.get_state(user, room_id)
+            .await?
+            .into_iter()
+            .find(|event| event.event_type == event_type && event.state_key.as_deref() == Some(state_key))
+            .ok_or_else(|| RoomError::EventNotFound(format!("{} state with key '{}'", event_type.as_str(), state_key)))
+    }
+
+    /// The server `user_id` belongs to; IDs without a server part are ours
+    fn user_server<'a>(&'a self, user_id: &'a str) -> &'a str {
+        server_name_of(user_id).unwrap_or(&self.server_name)
+    }
+
+    /// Whether `user_id` belongs to another server
+    fn is_remote_user(&self, user_id: &str) -> bool {
+        server_name_of(user_id).is_some_and(|server_name| server_name != self.server_name)
+    }
+
+    /// Rooms created with `m.federate: false` are closed to other servers' users
+    fn check_federates(&self, room_state: &RoomState, user_id: &str) -> Result<(), RoomError> {
+        if self.is_remote_user(user_id) && !room_state.federates() {
+            return Err(RoomError::InsufficientPermissions(
+                format!("{} does not federate with {}", room_state.room_id, server_name_of(user_id).unwrap_or_default())
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_local_alias(&self, alias: &str) -> Result<(), RoomError> {
+        match parse_room_alias(alias) {
+            Some((_, server_name)) if server_name == self.server_name => Ok(()),
+            Some(_) => Err(RoomError::InvalidAlias(format!("{} is not an alias on this server", alias))),
+            None => Err(RoomError::InvalidAlias(alias.to_string())),
         }
     }
 
@@ -450,8 +2310,8 @@ impl RoomHandler {
 
         // Return room summary
         let summary = room_state.get_summary();
-        Ok(serde_json::to_value(summary)
-            .map_err(|e| RoomError::InvalidRoomConfig(e.to_string()))?)
+        serde_json::to_value(summary)
+            .map_err(|e| RoomError::InvalidRoomConfig(e.to_string()))
     }
 
     /// List user's rooms
@@ -459,12 +2319,9 @@ impl RoomHandler {
         &self,
         user: &AuthenticatedUser,
     ) -> Result<Vec<String>, RoomError> {
-        // Get all rooms
-        let all_rooms = self.state_store.list_rooms().await?;
-        
-        // Filter to rooms user is in
+        // Filter to rooms user is still in
         let mut user_rooms = Vec::new();
-        for room_id in all_rooms {
+        for room_id in self.state_store.get_rooms_for_user(&user.user_id).await? {
             if let Some(room_state) = self.state_store.get_room(&room_id).await? {
                 if room_state.is_member(&user.user_id) {
                     user_rooms.push(room_id);
@@ -476,24 +2333,158 @@ impl RoomHandler {
     }
 }
 
+/// Format a stream position as a pagination token
+pub fn format_stream_token(position: u64) -> String {
+    format!("s{}", position)
+}
+
+/// Parse a pagination token back into a stream position
+pub fn parse_stream_token(token: &str) -> Result<u64, RoomError> {
+    token
+        .strip_prefix('s')
+        .and_then(|position| position.split('_').next())
+        .and_then(|position| position.parse().ok())
+        .ok_or_else(|| RoomError::InvalidRoomConfig(format!("Invalid pagination token: {}", token)))
+}
+
+/// A power levels change may only touch levels at or below the sender's own,
+/// and may not change users at or above it other than the sender themselves
+fn check_power_levels_change(
+    room_state: &RoomState,
+    sender: &str,
+    new: &RoomPowerLevelsContent,
+) -> Result<(), RoomError> {
+    let sender_level = room_state.get_user_power_level(sender);
+    let old = &room_state.power_levels;
+    let exceeds = |level: Option<i32>| level.is_some_and(|level| level > sender_level);
+    let denied = |what: &str| RoomError::InsufficientPermissions(
+        format!("Cannot change {} beyond your own power level {}", what, sender_level)
+    );
+
+    let fields = [
+        ("users_default", old.users_default, new.users_default),
+        ("events_default", old.events_default, new.events_default),
+        ("state_default", old.state_default, new.state_default),
+        ("ban", old.ban, new.ban),
+        ("kick", old.kick, new.kick),
+        ("redact", old.redact, new.redact),
+        ("invite", old.invite, new.invite),
+    ];
+    for (field, old_level, new_level) in fields {
+        if old_level != new_level && (exceeds(old_level) || exceeds(new_level)) {
+            return Err(denied(field));
+        }
+    }
+
+    let changed_entries = |old: &Option<HashMap<String, i32>>, new: &Option<HashMap<String, i32>>| {
+        let empty = HashMap::new();
+        let (old, new) = (old.as_ref().unwrap_or(&empty), new.as_ref().unwrap_or(&empty));
+        old.keys()
+            .chain(new.keys())
+            .filter(|key| old.get(*key) != new.get(*key))
+            .map(|key| (key.clone(), old.get(key).copied(), new.get(key).copied()))
+            .collect::<Vec<_>>()
+    };
+    for (event_type, old_level, new_level) in changed_entries(&old.events, &new.events) {
+        if exceeds(old_level) || exceeds(new_level) {
+            return Err(denied(&format!("the level of {}", event_type)));
+        }
+    }
+    for (user_id, old_level, new_level) in changed_entries(&old.users, &new.users) {
+        let outranks_target = user_id == sender || old_level.is_none_or(|level| level < sender_level);
+        if !outranks_target || exceeds(new_level) {
+            return Err(denied(&format!("the level of {}", user_id)));
+        }
+    }
+    Ok(())
+}
+
+/// `m.room.power_levels` of a new room: the defaults, with invitees of a
+/// trusted private chat raised to the creator's level, and any override
+/// replacing whole top-level keys
+fn initial_power_levels(creator: &str, preset: &RoomPreset, config: &RoomConfig) -> RoomPowerLevelsContent {
+    let mut users = HashMap::from([(creator.to_string(), 100)]);
+    if *preset == RoomPreset::TrustedPrivateChat {
+        users.extend(config.invite.iter().map(|invitee| (invitee.clone(), 100)));
+    }
+    let events = HashMap::from([
+        (EventType::RoomName.as_str().to_string(), 50),
+        (EventType::RoomPowerLevels.as_str().to_string(), 100),
+        (EventType::RoomHistoryVisibility.as_str().to_string(), 100),
+        (EventType::RoomCanonicalAlias.as_str().to_string(), 50),
+        (EventType::RoomAvatar.as_str().to_string(), 50),
+        (EventType::RoomTombstone.as_str().to_string(), 100),
+        (EventType::RoomServerAcl.as_str().to_string(), 100),
+        (EventType::RoomEncryption.as_str().to_string(), 100),
+    ]);
+    let defaults = RoomPowerLevelsContent {
+        users: Some(users),
+        users_default: Some(0),
+        events: Some(events),
+        events_default: Some(0),
+        state_default: Some(50),
+        ban: Some(50),
+        kick: Some(50),
+        redact: Some(50),
+        invite: Some(if *preset == RoomPreset::PublicChat { 50 } else { 0 }),
+        notifications: Some(HashMap::from([("room".to_string(), 50)])),
+    };
+
+    let Some(overrides) = config.power_level_content_override.clone() else {
+        return defaults;
+    };
+    RoomPowerLevelsContent {
+        users: overrides.users.or(defaults.users),
+        users_default: overrides.users_default.or(defaults.users_default),
+        events: overrides.events.or(defaults.events),
+        events_default: overrides.events_default.or(defaults.events_default),
+        state_default: overrides.state_default.or(defaults.state_default),
+        ban: overrides.ban.or(defaults.ban),
+        kick: overrides.kick.or(defaults.kick),
+        redact: overrides.redact.or(defaults.redact),
+        invite: overrides.invite.or(defaults.invite),
+        notifications: overrides.notifications.or(defaults.notifications),
+    }
+}
+
+/// `m.room.member` content for a membership change with an optional reason
+fn member_content(membership: MembershipState, reason: Option<String>) -> RoomMemberContent {
+    RoomMemberContent {
+        membership,
+        displayname: None,
+        avatar_url: None,
+        reason,
+        is_direct: None,
+        third_party_invite: None,
+        join_authorised_via_users_server: None,
+    }
+}
+
+/// Server part of a Matrix user ID
+fn server_name_of(user_id: &str) -> Option<&str> {
+    user_id.split_once(':').map(|(_, server)| server)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::events::{MatrixEvent, EventType, EventContent, RoomMessageContent, MessageType};
+    use crate::events::{
+        MatrixEvent, EventType, EventContent, RoomMessageContent, MessageType, HistoryVisibility,
+        RoomHistoryVisibilityContent,
+    };
+    use crate::directory::{PublicRoomsRequest, RoomVisibility};
+    use crate::tests::{test_user, text_message};
 
     fn create_test_room_config() -> RoomConfig {
         RoomConfig {
             name: Some("Test Room".to_string()),
             topic: Some("A test room".to_string()),
             room_alias_name: Some("testroom".to_string()),
-            invite: vec![],
             room_version: Some("9".to_string()),
-            creation_content: None,
-            initial_state: vec![],
             preset: Some(RoomPreset::PublicChat),
             is_direct: Some(false),
-            power_level_content_override: None,
             federate: Some(true),
+            ..Default::default()
         }
     }
 
@@ -506,6 +2497,11 @@ mod tests {
                 relates_to: None,
                 format: None,
                 formatted_body: None,
+                new_content: None,
+                mentions: None,
+                url: None,
+                info: None,
+                filename: None,
             }),
             "@user:localhost".to_string(),
             "!testroom:localhost".to_string(),
@@ -650,4 +2646,1309 @@ mod tests {
         assert_eq!(config.state_key, deserialized.state_key);
         assert_eq!(config.content, deserialized.content);
     }
+
+    async fn create_test_room(handler: &RoomHandler, creator: &AuthenticatedUser) -> String {
+        let mut config = create_test_room_config();
+        config.room_alias_name = None;
+        handler.create_room(creator, config).await.unwrap().room_id
+    }
+
+    async fn join(handler: &RoomHandler, user: &AuthenticatedUser, room_id: &str) {
+        handler.join_room(user, JoinRoomRequest {
+            room_id: room_id.to_string(),
+            reason: None,
+        }).await.unwrap();
+    }
+
+    fn redact_request(room_id: &str, event_id: &str) -> RedactEventRequest {
+        RedactEventRequest {
+            room_id: room_id.to_string(),
+            event_id: event_id.to_string(),
+            reason: Some("spam".to_string()),
+        }
+    }
+
+    fn related_message(room_id: &str, body: &str, rel_type: &str, event_id: &str) -> SendMessageRequest {
+        let mut request = text_message(room_id, body);
+        request.relates_to = Some(serde_json::json!({ "rel_type": rel_type, "event_id": event_id }));
+        request
+    }
+
+    fn reaction(room_id: &str, event_id: &str, key: &str) -> SendReactionRequest {
+        SendReactionRequest {
+            room_id: room_id.to_string(),
+            relates_to: RelatesTo {
+                in_reply_to: None,
+                rel_type: REL_TYPE_ANNOTATION.to_string(),
+                event_id: event_id.to_string(),
+                key: Some(key.to_string()),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_message_is_stored_in_timeline() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let response = handler.send_message(&alice, text_message(&room_id, "hello")).await.unwrap();
+
+        let stored = store.get_event(&response.event_id).await.unwrap().unwrap();
+        assert_eq!(stored.room_id, room_id);
+        assert_eq!(stored.sender, "@alice:localhost");
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_paginates_backwards() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        for i in 0..5 {
+            handler.send_message(&alice, text_message(&room_id, &format!("message {}", i))).await.unwrap();
+        }
+
+        let page = |from: Option<String>| GetMessagesRequest {
+            room_id: room_id.clone(),
+            from,
+            to: None,
+            limit: Some(3),
+            dir: Direction::Backward,
+        };
+
+        let first = handler.get_messages(&alice, page(None)).await.unwrap();
+        assert_eq!(first.chunk.len(), 3);
+        assert!(matches!(&first.chunk[0].content, EventContent::RoomMessage(c) if c.body == "message 4"));
+
+        // The page reaches back into the events that created the room
+        let second = handler.get_messages(&alice, page(Some(first.end))).await.unwrap();
+        assert_eq!(second.chunk.len(), 3);
+        assert!(matches!(&second.chunk[1].content, EventContent::RoomMessage(c) if c.body == "message 0"));
+        assert!(second.chunk[2].is_state_event());
+    }
+
+    #[tokio::test]
+    async fn test_redact_own_event() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let event_id = handler.send_message(&alice, text_message(&room_id, "oops")).await.unwrap().event_id;
+
+        let response = handler.redact_event(&alice, redact_request(&room_id, &event_id)).await.unwrap();
+
+        let redacted = store.get_event(&event_id).await.unwrap().unwrap();
+        assert!(redacted.is_redacted());
+        assert!(matches!(&redacted.content, EventContent::Raw(content) if content == &serde_json::json!({})));
+        let because = &redacted.unsigned.as_ref().unwrap()["redacted_because"];
+        assert_eq!(because["event_id"], response.event_id);
+
+        // Without retention the original content is gone
+        assert!(store.get_redacted_original(&event_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_redact_others_event_requires_power_level() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let alice_event = handler.send_message(&alice, text_message(&room_id, "rules")).await.unwrap().event_id;
+        let bob_event = handler.send_message(&bob, text_message(&room_id, "spam")).await.unwrap().event_id;
+
+        let result = handler.redact_event(&bob, redact_request(&room_id, &alice_event)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        // The room creator holds the redact power level
+        handler.redact_event(&alice, redact_request(&room_id, &bob_event)).await.unwrap();
+        assert!(store.get_event(&bob_event).await.unwrap().unwrap().is_redacted());
+    }
+
+    #[tokio::test]
+    async fn test_redact_unknown_event() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let result = handler.redact_event(&alice, redact_request(&room_id, "$missing")).await;
+        assert!(matches!(result, Err(RoomError::EventNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_redaction_retention_keeps_original() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone())
+            .with_redaction_retention(Duration::from_secs(3600));
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let event_id = handler.send_message(&alice, text_message(&room_id, "secret")).await.unwrap().event_id;
+
+        handler.redact_event(&alice, redact_request(&room_id, &event_id)).await.unwrap();
+
+        let original = store.get_redacted_original(&event_id).await.unwrap().unwrap();
+        assert!(matches!(&original.content, EventContent::RoomMessage(c) if c.body == "secret"));
+        assert_eq!(handler.purge_redacted_content().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_receive_redaction_from_foreign_server_rejected() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let mut event = create_test_event();
+        event.room_id = room_id.clone();
+        store.append_event(event.clone()).await.unwrap();
+
+        let redaction = MatrixEvent::redaction(
+            &event.event_id,
+            None,
+            "@mallory:evil.example".to_string(),
+            room_id.clone(),
+            "9",
+        );
+        let result = handler.receive_redaction(redaction.clone()).await;
+        assert!(matches!(result, Err(RoomError::UserNotInRoom(_))));
+
+        join(&handler, &test_user("@mallory:evil.example"), &room_id).await;
+        let result = handler.receive_redaction(redaction).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        let moderator = test_user("@moderator:localhost");
+        join(&handler, &moderator, &room_id).await;
+        let redaction = MatrixEvent::redaction(
+            &event.event_id,
+            None,
+            moderator.user_id.clone(),
+            room_id,
+            "9",
+        );
+        handler.receive_redaction(redaction).await.unwrap();
+        assert!(store.get_event(&event.event_id).await.unwrap().unwrap().is_redacted());
+    }
+
+    #[tokio::test]
+    async fn test_receive_redaction_without_server_name_rejected() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let mallory = test_user("mallory");
+        join(&handler, &mallory, &room_id).await;
+
+        let mut event = create_test_event();
+        event.room_id = room_id.clone();
+        event.sender = "bob".to_string();
+        store.append_event(event.clone()).await.unwrap();
+
+        let redaction = MatrixEvent::redaction(
+            &event.event_id,
+            None,
+            mallory.user_id.clone(),
+            room_id,
+            "9",
+        );
+        let result = handler.receive_redaction(redaction).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+    }
+
+    #[tokio::test]
+    async fn test_edit_requires_original_sender() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+        let original = handler.send_message(&alice, text_message(&room_id, "helo")).await.unwrap().event_id;
+
+        let result = handler.send_message(&bob, related_message(&room_id, "* hijacked", REL_TYPE_REPLACE, &original)).await;
+        assert!(matches!(result, Err(RoomError::InvalidRelation(_))));
+
+        let edit = handler.send_message(&alice, related_message(&room_id, "* hello", REL_TYPE_REPLACE, &original)).await.unwrap().event_id;
+
+        // Edits are not themselves editable or threadable
+        let result = handler.send_message(&alice, related_message(&room_id, "* hello!", REL_TYPE_REPLACE, &edit)).await;
+        assert!(matches!(result, Err(RoomError::InvalidRelation(_))));
+        let result = handler.send_message(&alice, related_message(&room_id, "reply", REL_TYPE_THREAD, &edit)).await;
+        assert!(matches!(result, Err(RoomError::InvalidRelation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_relation_to_unknown_event_rejected() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let result = handler.send_message(&alice, related_message(&room_id, "reply", REL_TYPE_THREAD, "$missing")).await;
+        assert!(matches!(result, Err(RoomError::InvalidRelation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_reaction_rejected() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let target = handler.send_message(&alice, text_message(&room_id, "ship it")).await.unwrap().event_id;
+
+        handler.send_reaction(&alice, reaction(&room_id, &target, "👍")).await.unwrap();
+        let result = handler.send_reaction(&alice, reaction(&room_id, &target, "👍")).await;
+        assert!(matches!(result, Err(RoomError::InvalidRelation(_))));
+        handler.send_reaction(&alice, reaction(&room_id, &target, "🎉")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_relations_filters_and_paginates() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let root = handler.send_message(&alice, text_message(&room_id, "question")).await.unwrap().event_id;
+
+        for i in 0..3 {
+            handler.send_message(&alice, related_message(&room_id, &format!("reply {}", i), REL_TYPE_THREAD, &root)).await.unwrap();
+        }
+        handler.send_reaction(&alice, reaction(&room_id, &root, "👍")).await.unwrap();
+
+        let page = |from: Option<String>| GetRelationsRequest {
+            room_id: room_id.clone(),
+            event_id: root.clone(),
+            rel_type: Some(REL_TYPE_THREAD.to_string()),
+            event_type: None,
+            from,
+            to: None,
+            limit: Some(2),
+            dir: Direction::Backward,
+        };
+
+        let first = handler.get_relations(&alice, page(None)).await.unwrap();
+        assert_eq!(first.chunk.len(), 2);
+        assert!(matches!(&first.chunk[0].content, EventContent::RoomMessage(c) if c.body == "reply 2"));
+
+        let second = handler.get_relations(&alice, page(first.next_batch)).await.unwrap();
+        assert_eq!(second.chunk.len(), 1);
+        assert!(second.next_batch.is_none());
+
+        let all = handler.get_relations(&alice, GetRelationsRequest { rel_type: None, limit: None, ..page(None) }).await.unwrap();
+        assert_eq!(all.chunk.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_get_threads_lists_participated() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let alice_thread = handler.send_message(&alice, text_message(&room_id, "alice's topic")).await.unwrap().event_id;
+        let bob_thread = handler.send_message(&bob, text_message(&room_id, "bob's topic")).await.unwrap().event_id;
+        handler.send_message(&alice, related_message(&room_id, "reply", REL_TYPE_THREAD, &bob_thread)).await.unwrap();
+        handler.send_message(&bob, related_message(&room_id, "reply", REL_TYPE_THREAD, &alice_thread)).await.unwrap();
+
+        let request = |include| GetThreadsRequest {
+            room_id: room_id.clone(),
+            include,
+            from: None,
+            limit: None,
+        };
+
+        // Most recently active thread first
+        let all = handler.get_threads(&alice, request(ThreadsInclude::All)).await.unwrap();
+        let ids: Vec<&str> = all.chunk.iter().map(|event| event.event_id.as_str()).collect();
+        assert_eq!(ids, vec![alice_thread.as_str(), bob_thread.as_str()]);
+        assert_eq!(all.chunk[0].unsigned.as_ref().unwrap()["m.relations"][REL_TYPE_THREAD]["count"], 1);
+
+        let carol = test_user("@carol:localhost");
+        join(&handler, &carol, &room_id).await;
+        let participated = handler.get_threads(&carol, request(ThreadsInclude::Participated)).await.unwrap();
+        assert!(participated.chunk.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_redacted_relation_leaves_aggregation() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let target = handler.send_message(&alice, text_message(&room_id, "ship it")).await.unwrap().event_id;
+        let reaction_id = handler.send_reaction(&alice, reaction(&room_id, &target, "👍")).await.unwrap().event_id;
+
+        handler.redact_event(&alice, redact_request(&room_id, &reaction_id)).await.unwrap();
+
+        let mut events = vec![store.get_event(&target).await.unwrap().unwrap()];
+        bundle_aggregations(&*store, &alice.user_id, &mut events).await.unwrap();
+        assert!(events[0].unsigned.is_none());
+    }
+
+    fn context_request(room_id: &str, event_id: &str, limit: u32) -> GetContextRequest {
+        GetContextRequest {
+            room_id: room_id.to_string(),
+            event_id: event_id.to_string(),
+            limit: Some(limit),
+            filter: RoomEventFilter::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_context_surrounds_event() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let mut event_ids = Vec::new();
+        for i in 0..7 {
+            let response = handler.send_message(&alice, text_message(&room_id, &format!("message {}", i))).await.unwrap();
+            event_ids.push(response.event_id);
+        }
+
+        let context = handler.get_context(&alice, context_request(&room_id, &event_ids[3], 4)).await.unwrap();
+        assert_eq!(context.event.event_id, event_ids[3]);
+        let before: Vec<&str> = context.events_before.iter().map(|e| e.event_id.as_str()).collect();
+        let after: Vec<&str> = context.events_after.iter().map(|e| e.event_id.as_str()).collect();
+        assert_eq!(before, vec![event_ids[2].as_str(), event_ids[1].as_str()]);
+        assert_eq!(after, vec![event_ids[4].as_str(), event_ids[5].as_str()]);
+
+        // The tokens continue pagination on either side
+        let older = handler.get_messages(&alice, GetMessagesRequest {
+            room_id: room_id.clone(),
+            from: Some(context.start),
+            to: None,
+            limit: Some(10),
+            dir: Direction::Backward,
+        }).await.unwrap();
+        assert_eq!(older.chunk[0].event_id, event_ids[0]);
+
+        let newer = handler.get_messages(&alice, GetMessagesRequest {
+            room_id: room_id.clone(),
+            from: Some(context.end),
+            to: None,
+            limit: Some(10),
+            dir: Direction::Forward,
+        }).await.unwrap();
+        assert_eq!(newer.chunk.len(), 1);
+        assert_eq!(newer.chunk[0].event_id, event_ids[6]);
+    }
+
+    #[tokio::test]
+    async fn test_get_context_state_and_lazy_members() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let carol = test_user("@carol:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let target = handler.send_message(&bob, text_message(&room_id, "quoted")).await.unwrap().event_id;
+        join(&handler, &carol, &room_id).await;
+
+        // Carol joined after the event, so her membership is not part of its state
+        let context = handler.get_context(&alice, context_request(&room_id, &target, 0)).await.unwrap();
+        let member_of = |state: &[MatrixEvent], user: &str| state
+            .iter()
+            .any(|event| event.event_type == EventType::RoomMember && event.state_key.as_deref() == Some(user));
+        assert!(member_of(&context.state, "@bob:localhost"));
+        assert!(!member_of(&context.state, "@carol:localhost"));
+
+        let mut request = context_request(&room_id, &target, 0);
+        request.filter.lazy_load_members = true;
+        let lazy = handler.get_context(&carol, request).await.unwrap();
+        assert!(member_of(&lazy.state, "@bob:localhost"));
+        assert_eq!(lazy.state.iter().filter(|event| event.event_type == EventType::RoomMember).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_context_hides_events_before_join() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let joined_only = serde_json::json!({ "history_visibility": "joined" });
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.history_visibility", "", joined_only)).await.unwrap();
+
+        let earlier = handler.send_message(&alice, text_message(&room_id, "before bob")).await.unwrap().event_id;
+        join(&handler, &bob, &room_id).await;
+        let later = handler.send_message(&alice, text_message(&room_id, "after bob")).await.unwrap().event_id;
+
+        let result = handler.get_context(&bob, context_request(&room_id, &earlier, 10)).await;
+        assert!(matches!(result, Err(RoomError::EventNotFound(_))));
+
+        let context = handler.get_context(&bob, context_request(&room_id, &later, 10)).await.unwrap();
+        assert!(context.events_before.iter().all(|event| event.event_id != earlier));
+        assert_eq!(handler.get_context(&alice, context_request(&room_id, &earlier, 10)).await.unwrap().event.event_id, earlier);
+    }
+
+    async fn set_history_visibility(store: &crate::state::InMemoryStateStore, room_id: &str, visibility: HistoryVisibility) {
+        let event = MatrixEvent::new(
+            EventType::RoomHistoryVisibility,
+            EventContent::RoomHistoryVisibility(RoomHistoryVisibilityContent { history_visibility: visibility }),
+            "@alice:localhost".to_string(),
+            room_id.to_string(),
+        ).with_state_key(String::new());
+        let ordering = store.append_event(event.clone()).await.unwrap();
+        crate::visibility::index_event(store, ordering, &event).await.unwrap();
+
+        let mut room_state = store.get_room(room_id).await.unwrap().unwrap();
+        room_state.apply_state_event(event).unwrap();
+        store.update_room(room_state).await.unwrap();
+    }
+
+    async fn visible_bodies(handler: &RoomHandler, user: &AuthenticatedUser, room_id: &str) -> Vec<String> {
+        let response = handler.get_messages(user, GetMessagesRequest {
+            room_id: room_id.to_string(),
+            from: None,
+            to: None,
+            limit: Some(100),
+            dir: Direction::Forward,
+        }).await.unwrap();
+
+        response.chunk
+            .into_iter()
+            .filter_map(|event| match event.content {
+                EventContent::RoomMessage(content) => Some(content.body),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_joined_visibility_hides_history_before_join() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        set_history_visibility(&store, &room_id, HistoryVisibility::Joined).await;
+
+        handler.send_message(&alice, text_message(&room_id, "before bob")).await.unwrap();
+        join(&handler, &bob, &room_id).await;
+        handler.send_message(&alice, text_message(&room_id, "after bob")).await.unwrap();
+
+        assert_eq!(visible_bodies(&handler, &bob, &room_id).await, vec!["after bob"]);
+        assert_eq!(visible_bodies(&handler, &alice, &room_id).await, vec!["before bob", "after bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_visibility_reveals_history_before_join() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        handler.send_message(&alice, text_message(&room_id, "before bob")).await.unwrap();
+        join(&handler, &bob, &room_id).await;
+
+        assert_eq!(visible_bodies(&handler, &bob, &room_id).await, vec!["before bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_visibility_evaluated_at_each_event() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        handler.send_message(&alice, text_message(&room_id, "shared")).await.unwrap();
+        set_history_visibility(&store, &room_id, HistoryVisibility::Joined).await;
+        handler.send_message(&alice, text_message(&room_id, "members only")).await.unwrap();
+        join(&handler, &bob, &room_id).await;
+        handler.send_message(&alice, text_message(&room_id, "welcome")).await.unwrap();
+
+        assert_eq!(visible_bodies(&handler, &bob, &room_id).await, vec!["shared", "welcome"]);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_filters_by_server_visibility() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let remote = test_user("@bob:remote.example");
+        let room_id = create_test_room(&handler, &alice).await;
+        set_history_visibility(&store, &room_id, HistoryVisibility::Joined).await;
+
+        let hidden = handler.send_message(&alice, text_message(&room_id, "before remote")).await.unwrap().event_id;
+        let result = handler.backfill("remote.example", &room_id, &hidden, 10).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        join(&handler, &remote, &room_id).await;
+        let latest = handler.send_message(&alice, text_message(&room_id, "after remote")).await.unwrap().event_id;
+
+        let pdus = handler.backfill("remote.example", &room_id, &latest, 10).await.unwrap();
+        assert_eq!(pdus[0].event_id, latest);
+        assert!(pdus.iter().all(|event| event.event_id != hidden));
+
+        let missing = handler.missing_events("remote.example", &room_id, &[], std::slice::from_ref(&latest), 10).await.unwrap();
+        assert!(missing.iter().all(|event| event.event_id != hidden && event.event_id != latest));
+        assert_eq!(missing.last().unwrap().event_type, EventType::RoomMember);
+
+        let result = handler.event_for_server("remote.example", &hidden).await;
+        assert!(matches!(result, Err(RoomError::EventNotFound(_))));
+        assert_eq!(handler.event_for_server("remote.example", &latest).await.unwrap().event_id, latest);
+        let result = handler.event_for_server("elsewhere.example", &latest).await;
+        assert!(matches!(result, Err(RoomError::EventNotFound(_))));
+    }
+
+    async fn create_private_room(handler: &RoomHandler, creator: &AuthenticatedUser, invite: Vec<String>) -> String {
+        let mut config = create_test_room_config();
+        config.room_alias_name = None;
+        config.preset = Some(RoomPreset::PrivateChat);
+        config.invite = invite;
+        handler.create_room(creator, config).await.unwrap().room_id
+    }
+
+    fn membership_change(room_id: &str, user_id: &str) -> MembershipChangeRequest {
+        MembershipChangeRequest {
+            room_id: room_id.to_string(),
+            user_id: user_id.to_string(),
+            reason: Some("moderation".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invited_user_can_join_private_room() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let carol = test_user("@carol:localhost");
+        let room_id = create_private_room(&handler, &alice, vec![]).await;
+
+        let join_request = |room_id: &str| JoinRoomRequest { room_id: room_id.to_string(), reason: None };
+        let result = handler.join_room(&bob, join_request(&room_id)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        let invite = handler.invite_user(&alice, membership_change(&room_id, "@bob:localhost")).await.unwrap();
+        let event = store.get_event(&invite.event_id).await.unwrap().unwrap();
+        assert_eq!(event.sender, "@alice:localhost");
+        assert_eq!(event.state_key.as_deref(), Some("@bob:localhost"));
+
+        handler.join_room(&bob, join_request(&room_id)).await.unwrap();
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert!(room_state.is_member("@bob:localhost"));
+        assert!(!room_state.is_member("@carol:localhost"));
+
+        // Invitations need the invite power level
+        let invite_level = serde_json::json!({ "users": { "@alice:localhost": 100 }, "invite": 50 });
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.power_levels", "", invite_level)).await.unwrap();
+        let result = handler.invite_user(&bob, membership_change(&room_id, "@carol:localhost")).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+        assert!(handler.join_room(&carol, join_request(&room_id)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_create_room_invites_users() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_private_room(&handler, &alice, vec!["@bob:localhost".to_string()]).await;
+
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert_eq!(room_state.membership("@bob:localhost"), Some(&MembershipState::Invite));
+        assert_eq!(room_state.get_summary().member_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_kick_requires_power_level_and_rank() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let result = handler.kick_user(&bob, membership_change(&room_id, "@alice:localhost")).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        let kick = handler.kick_user(&alice, membership_change(&room_id, "@bob:localhost")).await.unwrap();
+        let event = store.get_event(&kick.event_id).await.unwrap().unwrap();
+        assert!(matches!(&event.content, EventContent::RoomMember(c)
+            if c.membership == MembershipState::Leave && c.reason.as_deref() == Some("moderation")));
+
+        let result = handler.send_message(&bob, text_message(&room_id, "let me back")).await;
+        assert!(matches!(result, Err(RoomError::UserNotInRoom(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ban_and_unban() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        handler.ban_user(&alice, membership_change(&room_id, "@bob:localhost")).await.unwrap();
+        let result = handler.join_room(&bob, JoinRoomRequest { room_id: room_id.clone(), reason: None }).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        // Banned users cannot be invited back until unbanned
+        let result = handler.invite_user(&alice, membership_change(&room_id, "@bob:localhost")).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        handler.unban_user(&alice, membership_change(&room_id, "@bob:localhost")).await.unwrap();
+        join(&handler, &bob, &room_id).await;
+        assert!(store.get_room(&room_id).await.unwrap().unwrap().is_member("@bob:localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_forget_requires_leaving_first() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let result = handler.forget_room(&bob, &room_id).await;
+        assert!(matches!(result, Err(RoomError::InvalidRoomConfig(_))));
+
+        handler.leave_room(&bob, LeaveRoomRequest { room_id: room_id.clone(), reason: None }).await.unwrap();
+        handler.forget_room(&bob, &room_id).await.unwrap();
+        assert!(store.is_room_forgotten("@bob:localhost", &room_id).await.unwrap());
+
+        // Rejoining brings the room back
+        join(&handler, &bob, &room_id).await;
+        assert!(!store.is_room_forgotten("@bob:localhost", &room_id).await.unwrap());
+    }
+
+    async fn create_room_with_join_rule(
+        handler: &RoomHandler,
+        creator: &AuthenticatedUser,
+        join_rule: &str,
+        allow_room: Option<&str>,
+    ) -> String {
+        let mut content = serde_json::json!({ "join_rule": join_rule });
+        if let Some(allow_room) = allow_room {
+            content["allow"] = serde_json::json!([{ "type": "m.room_membership", "room_id": allow_room }]);
+        }
+
+        let mut config = create_test_room_config();
+        config.room_alias_name = None;
+        config.preset = Some(RoomPreset::PrivateChat);
+        config.initial_state = vec![StateEventConfig {
+            event_type: "m.room.join_rules".to_string(),
+            state_key: String::new(),
+            content,
+        }];
+        handler.create_room(creator, config).await.unwrap().room_id
+    }
+
+    fn knock_request(room_id: &str) -> KnockRoomRequest {
+        KnockRoomRequest {
+            room_id: room_id.to_string(),
+            reason: Some("let me in".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_knock_approved_by_invite() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_room_with_join_rule(&handler, &alice, "knock", None).await;
+
+        handler.knock_room(&bob, knock_request(&room_id)).await.unwrap();
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert_eq!(room_state.membership("@bob:localhost"), Some(&MembershipState::Knock));
+
+        // Knocking does not let the user in by itself
+        let join_request = JoinRoomRequest { room_id: room_id.clone(), reason: None };
+        assert!(handler.join_room(&bob, join_request.clone()).await.is_err());
+
+        handler.invite_user(&alice, membership_change(&room_id, "@bob:localhost")).await.unwrap();
+        handler.join_room(&bob, join_request).await.unwrap();
+        assert!(store.get_room(&room_id).await.unwrap().unwrap().is_member("@bob:localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_knock_rejected_by_kick() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_room_with_join_rule(&handler, &alice, "knock", None).await;
+
+        handler.knock_room(&bob, knock_request(&room_id)).await.unwrap();
+        handler.kick_user(&alice, membership_change(&room_id, "@bob:localhost")).await.unwrap();
+        assert_eq!(store.get_room(&room_id).await.unwrap().unwrap().membership("@bob:localhost"), None);
+
+        // Rooms that are not knockable refuse knocks outright
+        let private = create_private_room(&handler, &alice, vec![]).await;
+        let result = handler.knock_room(&bob, knock_request(&private)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+    }
+
+    #[tokio::test]
+    async fn test_restricted_join_via_allowed_room() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_server_name("localhost");
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let carol = test_user("@carol:localhost");
+        let space_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &space_id).await;
+        let room_id = create_room_with_join_rule(&handler, &alice, "restricted", Some(&space_id)).await;
+
+        let join_request = |room_id: &str| JoinRoomRequest { room_id: room_id.to_string(), reason: None };
+        let result = handler.join_room(&carol, join_request(&room_id)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        handler.join_room(&bob, join_request(&room_id)).await.unwrap();
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        let member = room_state.get_state_event(&EventType::RoomMember, "@bob:localhost").unwrap();
+        assert!(matches!(&member.content, EventContent::RoomMember(c)
+            if c.join_authorised_via_users_server.as_deref() == Some("@alice:localhost")));
+
+        // Local joins are vouched for by our own members, whatever the joiner's user ID says
+        let dave = test_user("dave");
+        join(&handler, &dave, &space_id).await;
+        handler.join_room(&dave, join_request(&room_id)).await.unwrap();
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert!(room_state.is_member("dave"));
+    }
+
+    #[tokio::test]
+    async fn test_knock_restricted_allows_both_paths() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_server_name("localhost");
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let carol = test_user("@carol:localhost");
+        let space_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &space_id).await;
+        let room_id = create_room_with_join_rule(&handler, &alice, "knock_restricted", Some(&space_id)).await;
+
+        join(&handler, &bob, &room_id).await;
+        handler.knock_room(&carol, knock_request(&room_id)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remote_restricted_join_is_vouched() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let remote = test_user("@bob:remote.example");
+        let space_id = create_test_room(&handler, &alice).await;
+        join(&handler, &remote, &space_id).await;
+        let room_id = create_room_with_join_rule(&handler, &alice, "restricted", Some(&space_id)).await;
+
+        let template = handler.make_join(&room_id, "@bob:remote.example", "localhost").await.unwrap();
+        assert!(matches!(&template.content, EventContent::RoomMember(c)
+            if c.join_authorised_via_users_server.as_deref() == Some("@alice:localhost")));
+
+        // Vouching by someone who cannot authorise the join is refused
+        let mut forged = template.clone();
+        if let EventContent::RoomMember(ref mut content) = forged.content {
+            content.join_authorised_via_users_server = Some("@mallory:localhost".to_string());
+        }
+        let result = handler.send_join(forged, "localhost").await;
+        assert!(matches!(result, Err(RoomError::UnableToAuthoriseJoin(_))));
+
+        let state = handler.send_join(template, "localhost").await.unwrap();
+        assert!(state.iter().any(|event| event.state_key.as_deref() == Some("@bob:remote.example")));
+        assert!(store.get_room(&room_id).await.unwrap().unwrap().is_member("@bob:remote.example"));
+    }
+
+    #[tokio::test]
+    async fn test_create_room_registers_alias() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_server_name("localhost");
+        let alice = test_user("@alice:localhost");
+
+        let room_id = handler.create_room(&alice, create_test_room_config()).await.unwrap().room_id;
+        assert!(room_id.starts_with('!') && room_id.ends_with(":localhost"));
+
+        let resolved = handler.resolve_alias("#testroom:localhost").await.unwrap();
+        assert_eq!(resolved.room_id, room_id);
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert_eq!(
+            room_state.canonical_alias_content().and_then(|content| content.alias),
+            Some("#testroom:localhost".to_string())
+        );
+
+        let result = handler.create_room(&alice, create_test_room_config()).await;
+        assert!(matches!(result, Err(RoomError::AliasInUse(_))));
+    }
+
+    #[tokio::test]
+    async fn test_canonical_alias_must_point_to_room() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_server_name("localhost");
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        let other_room = create_test_room(&handler, &alice).await;
+
+        handler.create_alias(&alice, "#ours:localhost", &room_id).await.unwrap();
+        handler.create_alias(&alice, "#theirs:localhost", &other_room).await.unwrap();
+
+        let canonical = |alias: &str, alt_aliases: Vec<&str>| RoomCanonicalAliasContent {
+            alias: Some(alias.to_string()),
+            alt_aliases: Some(alt_aliases.into_iter().map(str::to_string).collect()),
+        };
+        let result = handler.set_canonical_alias(&alice, &room_id, canonical("#theirs:localhost", vec![])).await;
+        assert!(matches!(result, Err(RoomError::BadAlias(_))));
+        let result = handler.set_canonical_alias(&alice, &room_id, canonical("#ours:localhost", vec!["not-an-alias"])).await;
+        assert!(matches!(result, Err(RoomError::BadAlias(_))));
+
+        handler
+            .set_canonical_alias(&alice, &room_id, canonical("#ours:localhost", vec!["#elsewhere:remote.example"]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_scoped_and_run_once() {
+        let handler = RoomHandler::new(Arc::new(crate::state::InMemoryStateStore::new()));
+        let alice = test_user("@alice:localhost");
+        let sends = std::sync::atomic::AtomicUsize::new(0);
+        let send = |event_id: &'static str| {
+            let sends = &sends;
+            async move {
+                sends.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok::<_, RoomError>(event_id.to_string())
+            }
+        };
+
+        // Concurrent retries wait for the first attempt rather than sending again
+        let (first, retry) = tokio::join!(
+            handler.transaction(&alice, "send/!a:localhost/m.room.message", "txn1", send("$first")),
+            handler.transaction(&alice, "send/!a:localhost/m.room.message", "txn1", send("$retry")),
+        );
+        assert_eq!(first.unwrap(), "$first");
+        assert_eq!(retry.unwrap(), "$first");
+        assert_eq!(sends.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let other_room = handler.transaction(&alice, "send/!b:localhost/m.room.message", "txn1", send("$other")).await;
+        assert_eq!(other_room.unwrap(), "$other");
+        let failed = handler.transaction(&alice, "redact/!a:localhost/$first", "txn1", async {
+            Err::<String, _>(RoomError::EventNotFound("$first".to_string()))
+        }).await;
+        assert!(failed.is_err());
+        let redaction = handler.transaction(&alice, "redact/!a:localhost/$first", "txn1", send("$redaction")).await;
+        assert_eq!(redaction.unwrap(), "$redaction");
+    }
+
+    #[tokio::test]
+    async fn test_delete_alias_updates_canonical_alias() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_server_name("localhost");
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        handler.create_alias(&bob, "#bobs:localhost", &room_id).await.unwrap();
+        handler.set_canonical_alias(&alice, &room_id, RoomCanonicalAliasContent {
+            alias: Some("#bobs:localhost".to_string()),
+            alt_aliases: None,
+        }).await.unwrap();
+
+        // Bob created the alias, but only moderators also update the canonical alias
+        handler.delete_alias(&alice, "#bobs:localhost").await.unwrap();
+        assert!(matches!(handler.resolve_alias("#bobs:localhost").await, Err(RoomError::AliasNotFound(_))));
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert_eq!(room_state.canonical_alias_content().unwrap().alias, None);
+
+        let carol = test_user("@carol:localhost");
+        let result = handler.create_alias(&carol, "#carols:localhost", &room_id).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+        handler.create_alias(&bob, "#again:localhost", &room_id).await.unwrap();
+        let result = handler.delete_alias(&carol, "#again:localhost").await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+        let result = handler.create_alias(&bob, "#again:remote.example", &room_id).await;
+        assert!(matches!(result, Err(RoomError::InvalidAlias(_))));
+    }
+
+    #[tokio::test]
+    async fn test_publishing_requires_configured_role() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_directory_publish_role("community-manager");
+        let mut alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let result = handler.set_room_visibility(&alice, &room_id, RoomVisibility::Public).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+        // Withdrawing a room never needs the role
+        handler.set_room_visibility(&alice, &room_id, RoomVisibility::Private).await.unwrap();
+
+        alice.roles.push("community-manager".to_string());
+        handler.set_room_visibility(&alice, &room_id, RoomVisibility::Public).await.unwrap();
+        assert_eq!(handler.get_room_visibility(&room_id).await.unwrap(), RoomVisibility::Public);
+
+        let bob = test_user("@bob:localhost");
+        join(&handler, &bob, &room_id).await;
+        let result = handler.set_room_visibility(&bob, &room_id, RoomVisibility::Private).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+    }
+
+    #[tokio::test]
+    async fn test_public_rooms_paginate_and_hide_unfederated_rooms() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+
+        let mut room_ids = Vec::new();
+        for federate in [true, true, false] {
+            let mut config = create_test_room_config();
+            config.room_alias_name = None;
+            config.federate = Some(federate);
+            let room_id = handler.create_room(&alice, config).await.unwrap().room_id;
+            handler.set_room_visibility(&alice, &room_id, RoomVisibility::Public).await.unwrap();
+            room_ids.push(room_id);
+        }
+        // An unpublished room is never listed
+        create_test_room(&handler, &alice).await;
+
+        let page = |since: Option<String>| PublicRoomsRequest { limit: Some(2), since, ..Default::default() };
+        let first = handler.get_public_rooms(page(None), false).await.unwrap();
+        assert_eq!(first.chunk.len(), 2);
+        assert_eq!(first.total_room_count_estimate, 3);
+        assert!(first.prev_batch.is_none());
+
+        let second = handler.get_public_rooms(page(first.next_batch.clone()), false).await.unwrap();
+        assert_eq!(second.chunk.len(), 1);
+        assert!(second.next_batch.is_none());
+        assert_eq!(second.prev_batch.as_deref(), Some("p0"));
+
+        let federated = handler.get_public_rooms(PublicRoomsRequest::default(), true).await.unwrap();
+        assert_eq!(federated.total_room_count_estimate, 2);
+        assert!(federated.chunk.iter().all(|entry| entry.room_id != room_ids[2]));
+    }
+
+    #[tokio::test]
+    async fn test_redacted_room_name_leaves_public_rooms() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let mut config = create_test_room_config();
+        config.room_alias_name = None;
+        let room_id = handler.create_room(&alice, config).await.unwrap().room_id;
+        handler.set_room_visibility(&alice, &room_id, RoomVisibility::Public).await.unwrap();
+
+        let listed = handler.get_public_rooms(PublicRoomsRequest::default(), false).await.unwrap();
+        assert_eq!(listed.chunk[0].name.as_deref(), Some("Test Room"));
+
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        let name_event = room_state.get_state_event(&EventType::RoomName, "").unwrap().event_id.clone();
+        handler.redact_event(&alice, redact_request(&room_id, &name_event)).await.unwrap();
+
+        let listed = handler.get_public_rooms(PublicRoomsRequest::default(), false).await.unwrap();
+        assert_eq!(listed.chunk[0].name, None);
+        assert_eq!(store.get_room(&room_id).await.unwrap().unwrap().get_summary().name, None);
+    }
+
+    #[tokio::test]
+    async fn test_initial_state_is_parsed_by_type() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+
+        let mut config = create_test_room_config();
+        config.room_alias_name = None;
+        config.initial_state = vec![StateEventConfig {
+            event_type: "m.room.avatar".to_string(),
+            state_key: String::new(),
+            content: serde_json::json!({ "url": "mxc://localhost/avatar" }),
+        }];
+        let room_id = handler.create_room(&alice, config.clone()).await.unwrap().room_id;
+
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert_eq!(room_state.avatar_url.as_deref(), Some("mxc://localhost/avatar"));
+        assert_eq!(room_state.name.as_deref(), Some("Test Room"));
+        assert_eq!(room_state.topic.as_deref(), Some("A test room"));
+
+        config.initial_state[0] = StateEventConfig {
+            event_type: "m.room.tombstone".to_string(),
+            state_key: String::new(),
+            content: serde_json::json!({ "body": "moved" }),
+        };
+        let result = handler.create_room(&alice, config).await;
+        assert!(matches!(result, Err(RoomError::InvalidRoomConfig(_))));
+    }
+
+    fn state_request(room_id: &str, event_type: &str, state_key: &str, content: serde_json::Value) -> SendStateEventRequest {
+        SendStateEventRequest {
+            room_id: room_id.to_string(),
+            event_type: event_type.to_string(),
+            state_key: state_key.to_string(),
+            content,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_events_respect_power_level_events_map() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let topic = serde_json::json!({ "topic": "bots welcome" });
+        let result = handler.send_state_event(&bob, state_request(&room_id, "m.room.topic", "", topic.clone())).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        let power_levels = serde_json::json!({
+            "users": { "@alice:localhost": 100 },
+            "events": { "m.room.topic": 0 },
+            "state_default": 50,
+        });
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.power_levels", "", power_levels)).await.unwrap();
+
+        let sent = handler.send_state_event(&bob, state_request(&room_id, "m.room.topic", "", topic.clone())).await.unwrap();
+        let resent = handler.send_state_event(&bob, state_request(&room_id, "m.room.topic", "", topic)).await.unwrap();
+        assert_eq!(sent.event_id, resent.event_id);
+
+        let event = handler.get_state_event(&bob, &room_id, "m.room.topic", "").await.unwrap();
+        assert_eq!(event.event_id, sent.event_id);
+        assert_eq!(store.get_room(&room_id).await.unwrap().unwrap().topic.as_deref(), Some("bots welcome"));
+
+        let malformed = handler.send_state_event(&alice, state_request(&room_id, "m.room.topic", "", serde_json::json!({ "topic": 7 }))).await;
+        assert!(matches!(malformed, Err(RoomError::InvalidRoomConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_power_levels_cannot_exceed_own_level() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        let moderated = serde_json::json!({ "users": { "@alice:localhost": 100, "@bob:localhost": 50 } });
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.power_levels", "", moderated)).await.unwrap();
+
+        let promote_self = serde_json::json!({ "users": { "@alice:localhost": 100, "@bob:localhost": 100 } });
+        let result = handler.send_state_event(&bob, state_request(&room_id, "m.room.power_levels", "", promote_self)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        let demote_alice = serde_json::json!({ "users": { "@alice:localhost": 0, "@bob:localhost": 50 } });
+        let result = handler.send_state_event(&bob, state_request(&room_id, "m.room.power_levels", "", demote_alice)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+    }
+
+    #[tokio::test]
+    async fn test_state_type_specific_rules() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+
+        let cases = [
+            ("org.example.bot", "@bob:localhost", serde_json::json!({})),
+            ("m.room.create", "", serde_json::json!({ "creator": "@alice:localhost" })),
+            ("m.room.member", "@alice:localhost", serde_json::json!({ "membership": "leave" })),
+            ("m.room.server_acl", "", serde_json::json!({ "allow": ["other.example"] })),
+            ("m.space.child", "not-a-room", serde_json::json!({ "via": ["localhost"] })),
+        ];
+        for (event_type, state_key, content) in cases {
+            let result = handler.send_state_event(&alice, state_request(&room_id, event_type, state_key, content)).await;
+            assert!(result.is_err(), "{} with key '{}' should be rejected", event_type, state_key);
+        }
+
+        let own_key = serde_json::json!({ "enabled": true });
+        handler.send_state_event(&alice, state_request(&room_id, "org.example.bot", "@alice:localhost", own_key)).await.unwrap();
+        let acl = serde_json::json!({ "allow": ["*"], "deny": ["evil.example"] });
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.server_acl", "", acl)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_former_member_sees_state_when_they_left() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let carol = test_user("@carol:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.topic", "", serde_json::json!({ "topic": "before" }))).await.unwrap();
+        handler.leave_room(&bob, LeaveRoomRequest { room_id: room_id.clone(), reason: None }).await.unwrap();
+        handler.send_state_event(&alice, state_request(&room_id, "m.room.topic", "", serde_json::json!({ "topic": "after" }))).await.unwrap();
+
+        let topic = handler.get_state_event(&bob, &room_id, "m.room.topic", "").await.unwrap();
+        assert!(matches!(topic.content, EventContent::RoomTopic(ref content) if content.topic == "before"));
+
+        let result = handler.get_state(&carol, &room_id).await;
+        assert!(matches!(result, Err(RoomError::UserNotInRoom(_))));
+        let missing = handler.get_state_event(&alice, &room_id, "m.room.pinned_events", "").await;
+        assert!(matches!(missing, Err(RoomError::EventNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_members_filters_and_at_token() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+        let carol = test_user("@carol:localhost");
+        let room_id = create_test_room(&handler, &alice).await;
+        join(&handler, &bob, &room_id).await;
+        handler.invite_user(&alice, membership_change(&room_id, &carol.user_id)).await.unwrap();
+
+        let profile = serde_json::json!({ "membership": "join", "displayname": "Bob", "avatar_url": "mxc://localhost/bob" });
+        handler.send_state_event(&bob, state_request(&room_id, "m.room.member", &bob.user_id, profile)).await.unwrap();
+
+        let members_request = |at: Option<String>, membership, not_membership| GetMembersRequest {
+            room_id: room_id.clone(),
+            at,
+            membership,
+            not_membership,
+        };
+        let member_ids = |response: MembersResponse| -> Vec<String> {
+            response.chunk.into_iter().filter_map(|event| event.state_key).collect()
+        };
+
+        let joined = handler.get_members(&alice, members_request(None, Some(MembershipState::Join), None)).await.unwrap();
+        assert_eq!(member_ids(joined), vec!["@alice:localhost", "@bob:localhost"]);
+        let not_joined = handler.get_members(&alice, members_request(None, None, Some(MembershipState::Join))).await.unwrap();
+        assert_eq!(member_ids(not_joined), vec!["@carol:localhost"]);
+
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        let invite = room_state.get_state_event(&EventType::RoomMember, &carol.user_id).unwrap();
+        let invited_at = store.get_stream_ordering(&invite.event_id).await.unwrap().unwrap();
+        let before_invite = format_stream_token(invited_at - 1);
+        let earlier = handler.get_members(&alice, members_request(Some(before_invite), None, None)).await.unwrap();
+        assert_eq!(member_ids(earlier), vec!["@alice:localhost", "@bob:localhost"]);
+
+        let response = handler.get_joined_members(&alice, &room_id).await.unwrap();
+        assert_eq!(response.joined.len(), 2);
+        assert_eq!(response.joined["@bob:localhost"].display_name.as_deref(), Some("Bob"));
+        assert_eq!(response.joined["@bob:localhost"].avatar_url.as_deref(), Some("mxc://localhost/bob"));
+
+        let result = handler.get_joined_members(&carol, &room_id).await;
+        assert!(matches!(result, Err(RoomError::UserNotInRoom(_))));
+    }
+
+    #[tokio::test]
+    async fn test_create_room_emits_initial_events_in_order() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+
+        let mut config = create_test_room_config();
+        config.preset = Some(RoomPreset::TrustedPrivateChat);
+        config.invite = vec!["@bob:localhost".to_string()];
+        config.is_direct = Some(true);
+        config.initial_state = vec![StateEventConfig {
+            event_type: "m.room.history_visibility".to_string(),
+            state_key: String::new(),
+            content: serde_json::json!({ "history_visibility": "invited" }),
+        }];
+        config.power_level_content_override = Some(RoomPowerLevelsContent {
+            users: None,
+            users_default: None,
+            events: None,
+            events_default: None,
+            state_default: Some(75),
+            ban: None,
+            kick: None,
+            redact: None,
+            invite: None,
+            notifications: None,
+        });
+        let room_id = handler.create_room(&alice, config).await.unwrap().room_id;
+
+        let timeline = store.get_room_events(&room_id, 0, Direction::Forward, usize::MAX).await.unwrap();
+        let sequence: Vec<&str> = timeline.iter().map(|(_, event)| event.event_type.as_str()).collect();
+        assert_eq!(sequence, vec![
+            "m.room.create",
+            "m.room.member",
+            "m.room.power_levels",
+            "m.room.canonical_alias",
+            "m.room.join_rules",
+            "m.room.history_visibility",
+            "m.room.guest_access",
+            "m.room.history_visibility",
+            "m.room.name",
+            "m.room.topic",
+            "m.room.member",
+        ]);
+
+        let room_state = store.get_room(&room_id).await.unwrap().unwrap();
+        assert_eq!(room_state.join_rules.as_deref(), Some("invite"));
+        assert_eq!(room_state.history_visibility.as_deref(), Some("invited"));
+        assert!(room_state.guest_can_join());
+        assert_eq!(room_state.get_user_power_level("@bob:localhost"), 100);
+        assert_eq!(room_state.power_levels.state_default, Some(75));
+        assert_eq!(room_state.state_event_level(&EventType::RoomPowerLevels), 100);
+
+        let invite = room_state.get_state_event(&EventType::RoomMember, "@bob:localhost").unwrap();
+        assert!(matches!(&invite.content, EventContent::RoomMember(member) if member.is_direct == Some(true)));
+    }
+
+    #[tokio::test]
+    async fn test_non_federating_room_refuses_remote_users() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone()).with_server_name("localhost");
+        let alice = test_user("@alice:localhost");
+
+        let mut config = create_test_room_config();
+        config.room_alias_name = None;
+        config.federate = Some(false);
+        config.invite = vec!["@bob:remote.example".to_string()];
+        let result = handler.create_room(&alice, config.clone()).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+
+        config.invite = vec![];
+        let room_id = handler.create_room(&alice, config).await.unwrap().room_id;
+        let result = handler.invite_user(&alice, membership_change(&room_id, "@bob:remote.example")).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+        let result = handler.make_join(&room_id, "@carol:remote.example", "localhost").await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+        handler.invite_user(&alice, membership_change(&room_id, "@dave:localhost")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_rooms_are_tracked_and_reused() {
+        let store = Arc::new(crate::state::InMemoryStateStore::new());
+        let handler = RoomHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+
+        let first = handler.get_or_create_direct_room(&alice, &bob.user_id).await.unwrap();
+        assert!(first.created);
+        let again = handler.get_or_create_direct_room(&alice, &bob.user_id).await.unwrap();
+        assert!(!again.created);
+        assert_eq!(again.room_id, first.room_id);
+
+        let alice_dms = crate::direct::direct_rooms_with(store.as_ref(), &alice.user_id, &bob.user_id).await.unwrap();
+        assert_eq!(alice_dms, vec![first.room_id.clone()]);
+        assert!(crate::direct::direct_rooms_with(store.as_ref(), &bob.user_id, &alice.user_id).await.unwrap().is_empty());
+
+        join(&handler, &bob, &first.room_id).await;
+        let bob_dms = crate::direct::direct_rooms_with(store.as_ref(), &bob.user_id, &alice.user_id).await.unwrap();
+        assert_eq!(bob_dms, vec![first.room_id.clone()]);
+        let room_state = store.get_room(&first.room_id).await.unwrap().unwrap();
+        assert_eq!(room_state.get_user_power_level(&bob.user_id), 100);
+
+        // Once the other side has left, a fresh DM is started
+        handler.leave_room(&bob, LeaveRoomRequest { room_id: first.room_id.clone(), reason: None }).await.unwrap();
+        let fresh = handler.get_or_create_direct_room(&alice, &bob.user_id).await.unwrap();
+        assert!(fresh.created);
+        assert_ne!(fresh.room_id, first.room_id);
+
+        let result = handler.get_or_create_direct_room(&alice, &alice.user_id).await;
+        assert!(matches!(result, Err(RoomError::InvalidRoomConfig(_))));
+    }
 }
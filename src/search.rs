@@ -0,0 +1,395 @@
+// Search
+// Full-text search over room timelines
+// Focus: The inverted index of searchable keys and POST /search for room events
+
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::events::MatrixEvent;
+use crate::room::{format_stream_token, RoomError};
+use crate::state::{Direction, RoomState, StateError, StateStore};
+use crate::visibility::{Viewer, VisibilityFilter};
+
+/// Event keys the index covers
+pub const SEARCHABLE_KEYS: [&str; 3] = ["content.body", "content.name", "content.topic"];
+
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+const MAX_SEARCH_LIMIT: usize = 50;
+const MAX_CONTEXT_LIMIT: usize = 20;
+
+/// Body of POST /search
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchRequest {
+    pub search_categories: SearchCategories,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchCategories {
+    pub room_events: Option<RoomEventsCriteria>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoomEventsCriteria {
+    pub search_term: String,
+    /// Keys to search; all searchable keys when absent
+    pub keys: Option<Vec<String>>,
+    #[serde(default)]
+    pub filter: SearchFilter,
+    #[serde(default)]
+    pub order_by: SearchOrder,
+    pub event_context: Option<EventContextSpec>,
+    #[serde(default)]
+    pub include_state: bool,
+    pub groupings: Option<Groupings>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchFilter {
+    pub rooms: Option<Vec<String>>,
+    #[serde(default)]
+    pub not_rooms: Vec<String>,
+    pub senders: Option<Vec<String>>,
+    #[serde(default)]
+    pub not_senders: Vec<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchOrder {
+    #[default]
+    Rank,
+    Recent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventContextSpec {
+    #[serde(default = "default_context_limit")]
+    pub before_limit: usize,
+    #[serde(default = "default_context_limit")]
+    pub after_limit: usize,
+    #[serde(default)]
+    pub include_profile: bool,
+}
+
+fn default_context_limit() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Groupings {
+    #[serde(default)]
+    pub group_by: Vec<GroupBy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupBy {
+    pub key: GroupKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupKey {
+    RoomId,
+    Sender,
+}
+
+impl GroupKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            GroupKey::RoomId => "room_id",
+            GroupKey::Sender => "sender",
+        }
+    }
+}
+
+/// Query of POST /search
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchQuery {
+    pub next_batch: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub search_categories: SearchResultCategories,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResultCategories {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_events: Option<RoomEventsResults>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomEventsResults {
+    /// Total matching events the user may see
+    pub count: usize,
+    /// Words to highlight in the results
+    pub highlights: Vec<String>,
+    pub results: Vec<SearchResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+    /// Room ID -> current state, when `include_state` is set
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub state: HashMap<String, Vec<MatrixEvent>>,
+    /// Group key -> group value -> group
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub groups: HashMap<String, BTreeMap<String, SearchGroup>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub rank: f64,
+    pub result: MatrixEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<SearchContext>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchContext {
+    pub events_before: Vec<MatrixEvent>,
+    pub events_after: Vec<MatrixEvent>,
+    pub start: String,
+    pub end: String,
+    /// Sender -> profile in the room, when `include_profile` is set
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub profile_info: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchGroup {
+    /// Position of the group's best result among all results
+    pub order: usize,
+    pub results: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+}
+
+/// Lowercased words of `text`, split on anything but letters and digits
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Index the searchable keys of a newly stored event
+pub async fn index_event(store: &dyn StateStore, event: &MatrixEvent) -> Result<(), StateError> {
+    if event.is_redacted() {
+        return Ok(());
+    }
+    let event_json = serde_json::to_value(event).map_err(|e| StateError::InvalidEvent(e.to_string()))?;
+    for key in SEARCHABLE_KEYS {
+        let Some(text) = key.split('.').try_fold(&event_json, |value, segment| value.get(segment)).and_then(Value::as_str) else {
+            continue;
+        };
+        let terms = tokenize(text);
+        if !terms.is_empty() {
+            store.index_search_terms(&event.room_id, &event.event_id, key, terms).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A matching event with everything needed to rank and filter it
+struct Candidate {
+    rank: f64,
+    ordering: u64,
+    event: MatrixEvent,
+}
+
+/// Search the events `user_id` may see for all words of the search term
+///
+/// Searches rooms the user is or was a member of, including rooms they
+/// left; each event is checked against history visibility at that point of
+/// the timeline. `next_batch`
+/// is the number of results already returned.
+pub async fn search(
+    store: &dyn StateStore,
+    user_id: &str,
+    request: SearchRequest,
+    query: SearchQuery,
+) -> Result<SearchResponse, RoomError> {
+    let Some(criteria) = request.search_categories.room_events else {
+        return Ok(SearchResponse::default());
+    };
+    let keys = criteria.keys.unwrap_or_else(|| SEARCHABLE_KEYS.iter().map(|key| key.to_string()).collect());
+    if let Some(key) = keys.iter().find(|key| !SEARCHABLE_KEYS.contains(&key.as_str())) {
+        return Err(RoomError::InvalidParam(format!("Unsupported search key {}", key)));
+    }
+    let skip = match query.next_batch.as_deref() {
+        Some(token) => token
+            .parse::<usize>()
+            .map_err(|_| RoomError::InvalidParam(format!("Invalid next_batch: {}", token)))?,
+        None => 0,
+    };
+    let limit = criteria.filter.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT);
+
+    let mut terms = tokenize(&criteria.search_term);
+    terms.sort();
+    terms.dedup();
+    let mut results = RoomEventsResults {
+        highlights: terms.clone(),
+        ..Default::default()
+    };
+    if terms.is_empty() {
+        return Ok(wrap(results));
+    }
+
+    let filter = &criteria.filter;
+    let mut room_ids = store.get_rooms_for_user(user_id).await?;
+    room_ids.retain(|room_id| {
+        filter.rooms.as_ref().is_none_or(|rooms| rooms.contains(room_id)) && !filter.not_rooms.contains(room_id)
+    });
+
+    // Events must contain every term; rank by total occurrences
+    let mut scores: Option<HashMap<String, u32>> = None;
+    for term in &terms {
+        let matches = store.search_term(term, &keys, &room_ids).await?;
+        scores = Some(match scores {
+            None => matches,
+            Some(scores) => scores
+                .into_iter()
+                .filter_map(|(event_id, score)| matches.get(&event_id).map(|more| (event_id, score + more)))
+                .collect(),
+        });
+    }
+
+    let mut rooms: HashMap<String, Option<(RoomState, VisibilityFilter)>> = HashMap::new();
+    let mut candidates = Vec::new();
+    for (event_id, score) in scores.unwrap_or_default() {
+        let (Some(event), Some(ordering)) = (store.get_event(&event_id).await?, store.get_stream_ordering(&event_id).await?) else {
+            continue;
+        };
+        if filter.senders.as_ref().is_some_and(|senders| !senders.contains(&event.sender))
+            || filter.not_senders.contains(&event.sender)
+            || event.is_redacted()
+        {
+            continue;
+        }
+        if !rooms.contains_key(&event.room_id) {
+            let room = match store.get_room(&event.room_id).await? {
+                Some(room_state) => {
+                    let visibility = VisibilityFilter::load(store, &room_state, Viewer::User(user_id)).await?;
+                    Some((room_state, visibility))
+                }
+                None => None,
+            };
+            rooms.insert(event.room_id.clone(), room);
+        }
+        let visible = rooms[&event.room_id].as_ref().is_some_and(|(_, visibility)| visibility.is_visible(ordering));
+        if visible {
+            candidates.push(Candidate { rank: score as f64, ordering, event });
+        }
+    }
+
+    match criteria.order_by {
+        SearchOrder::Rank => candidates.sort_by(|a, b| b.rank.total_cmp(&a.rank).then(b.ordering.cmp(&a.ordering))),
+        SearchOrder::Recent => candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.ordering)),
+    }
+    results.count = candidates.len();
+    let end = skip.saturating_add(limit);
+    if end < candidates.len() {
+        results.next_batch = Some(end.to_string());
+    }
+
+    for (order, candidate) in candidates.into_iter().skip(skip).take(limit).enumerate() {
+        for group_by in criteria.groupings.iter().flat_map(|groupings| &groupings.group_by) {
+            let value = match group_by.key {
+                GroupKey::RoomId => &candidate.event.room_id,
+                GroupKey::Sender => &candidate.event.sender,
+            };
+            let group = results.groups
+                .entry(group_by.key.as_str().to_string())
+                .or_default()
+                .entry(value.clone())
+                .or_insert_with(|| SearchGroup { order: skip + order, ..Default::default() });
+            group.results.push(candidate.event.event_id.clone());
+        }
+
+        let (room_state, visibility) = rooms[&candidate.event.room_id].as_ref().expect("visible events have a room");
+        // Rooms the user left or was banned from keep their history searchable, but not their current state
+        if criteria.include_state && room_state.is_member(user_id) && !results.state.contains_key(&room_state.room_id) {
+            results.state.insert(room_state.room_id.clone(), room_state.state_events.values().cloned().collect());
+        }
+        let context = match &criteria.event_context {
+            Some(spec) => Some(event_context(store, room_state, visibility, &candidate, spec).await?),
+            None => None,
+        };
+        results.results.push(SearchResult {
+            rank: candidate.rank,
+            result: candidate.event,
+            context,
+        });
+    }
+
+    Ok(wrap(results))
+}
+
+fn wrap(room_events: RoomEventsResults) -> SearchResponse {
+    SearchResponse {
+        search_categories: SearchResultCategories { room_events: Some(room_events) },
+    }
+}
+
+/// Visible events around a result, nearest first before it and in order after it
+async fn event_context(
+    store: &dyn StateStore,
+    room_state: &RoomState,
+    visibility: &VisibilityFilter,
+    candidate: &Candidate,
+    spec: &EventContextSpec,
+) -> Result<SearchContext, StateError> {
+    let room_id = &room_state.room_id;
+    let before = store
+        .get_room_events(room_id, candidate.ordering - 1, Direction::Backward, spec.before_limit.min(MAX_CONTEXT_LIMIT))
+        .await?;
+    let after = store
+        .get_room_events(room_id, candidate.ordering, Direction::Forward, spec.after_limit.min(MAX_CONTEXT_LIMIT))
+        .await?;
+
+    let start = before.last().map_or(candidate.ordering, |(ordering, _)| *ordering) - 1;
+    let end = after.last().map_or(candidate.ordering, |(ordering, _)| *ordering);
+    let visible = |events: Vec<(u64, MatrixEvent)>| -> Vec<MatrixEvent> {
+        events
+            .into_iter()
+            .filter(|(ordering, _)| visibility.is_visible(*ordering))
+            .map(|(_, event)| event)
+            .collect()
+    };
+    let mut context = SearchContext {
+        events_before: visible(before),
+        events_after: visible(after),
+        start: format_stream_token(start),
+        end: format_stream_token(end),
+        profile_info: HashMap::new(),
+    };
+
+    if spec.include_profile {
+        let senders = context.events_before
+            .iter()
+            .chain(&context.events_after)
+            .map(|event| &event.sender)
+            .chain([&candidate.event.sender]);
+        for sender in senders {
+            let profile = room_state.member_profiles.get(sender).cloned().unwrap_or_default();
+            context.profile_info.insert(sender.clone(), serde_json::json!({
+                "displayname": profile.display_name,
+                "avatar_url": profile.avatar_url,
+            }));
+        }
+    }
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("Printer on floor-3 is JAMMED!"), vec!["printer", "on", "floor", "3", "is", "jammed"]);
+        assert!(tokenize(" ... ").is_empty());
+    }
+}
@@ -0,0 +1,461 @@
+// Spaces
+// Walking space hierarchies across local and remote rooms
+// Focus: m.space.child ordering, room accessibility and /hierarchy pagination
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use serde::{Deserialize, Serialize};
+
+use crate::directory::{format_directory_token, parse_directory_token, PublicRoomsChunk};
+use crate::events::{EventContent, EventType, JoinRule, MatrixEvent, MembershipState, SpaceChildContent};
+use crate::federation::FederationClient;
+use crate::room::RoomError;
+use crate::state::{RoomState, StateError, StateStore};
+use crate::visibility::Viewer;
+
+/// Default number of rooms returned by /hierarchy
+const DEFAULT_HIERARCHY_LIMIT: u32 = 50;
+/// Deepest level of a hierarchy explored, whatever the client asks for
+const MAX_HIERARCHY_DEPTH: u32 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HierarchyRequest {
+    pub room_id: String,
+    pub from: Option<String>,
+    pub limit: Option<u32>,
+    pub max_depth: Option<u32>,
+    #[serde(default)]
+    pub suggested_only: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpaceHierarchyRoomsChunk {
+    #[serde(flatten)]
+    pub room: PublicRoomsChunk,
+    /// Stripped `m.space.child` events of the room
+    pub children_state: Vec<serde_json::Value>,
+    /// Rooms whose members may join a restricted room; only sent over federation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_room_ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HierarchyResponse {
+    pub rooms: Vec<SpaceHierarchyRoomsChunk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+}
+
+/// Response of the federation `/hierarchy` endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederationHierarchyResponse {
+    pub room: SpaceHierarchyRoomsChunk,
+    pub children: Vec<SpaceHierarchyRoomsChunk>,
+    pub inaccessible_children: Vec<String>,
+}
+
+/// Walk the hierarchy below `request.room_id` breadth first for `user_id`,
+/// asking the servers in `via` about rooms we are not part of
+pub async fn get_hierarchy(
+    store: &dyn StateStore,
+    federation: &FederationClient,
+    user_id: &str,
+    request: HierarchyRequest,
+) -> Result<HierarchyResponse, RoomError> {
+    let max_depth = request.max_depth.unwrap_or(MAX_HIERARCHY_DEPTH).min(MAX_HIERARCHY_DEPTH);
+    let mut queue = VecDeque::from([(request.room_id.clone(), Vec::<String>::new(), 0)]);
+    let mut visited = HashSet::new();
+    // Children that remote servers already summarised alongside their parent
+    let mut remote_children: HashMap<String, SpaceHierarchyRoomsChunk> = HashMap::new();
+    let mut rooms = Vec::new();
+
+    while let Some((room_id, via, depth)) = queue.pop_front() {
+        if !visited.insert(room_id.clone()) {
+            continue;
+        }
+        let is_root = depth == 0;
+
+        let chunk = match store.get_room(&room_id).await? {
+            Some(room_state) => {
+                if !is_accessible(store, &room_state, Viewer::User(user_id)).await? {
+                    if is_root {
+                        return Err(RoomError::InsufficientPermissions(
+                            format!("{} cannot preview {}", user_id, room_id)
+                        ));
+                    }
+                    continue;
+                }
+                room_chunk(&room_state, request.suggested_only, false)
+            }
+            None => {
+                let chunk = match remote_children.remove(&room_id) {
+                    Some(chunk) => Some(chunk),
+                    None => fetch_remote(federation, &room_id, &via, request.suggested_only)
+                        .await
+                        .map(|response| {
+                            for child in response.children {
+                                remote_children.insert(child.room.room_id.clone(), child);
+                            }
+                            response.room
+                        }),
+                };
+                match chunk {
+                    Some(chunk) if remote_chunk_accessible(store, &chunk, user_id).await? => SpaceHierarchyRoomsChunk {
+                        allowed_room_ids: None,
+                        ..chunk
+                    },
+                    Some(_) if is_root => {
+                        return Err(RoomError::InsufficientPermissions(
+                            format!("{} cannot preview {}", user_id, room_id)
+                        ));
+                    }
+                    None if is_root => return Err(RoomError::RoomNotFound(room_id)),
+                    _ => continue,
+                }
+            }
+        };
+
+        if depth < max_depth {
+            for child in &chunk.children_state {
+                let Some(child_id) = child["state_key"].as_str() else {
+                    continue;
+                };
+                let via = serde_json::from_value(child["content"]["via"].clone()).unwrap_or_default();
+                queue.push_back((child_id.to_string(), via, depth + 1));
+            }
+        }
+        rooms.push(chunk);
+    }
+
+    let offset = match request.from.as_deref() {
+        Some(token) => parse_directory_token(token)
+            .ok_or_else(|| RoomError::InvalidRoomConfig(format!("Invalid pagination token: {}", token)))?,
+        None => 0,
+    };
+    let limit = request.limit.unwrap_or(DEFAULT_HIERARCHY_LIMIT).max(1) as usize;
+    let end = offset.saturating_add(limit).min(rooms.len());
+
+    Ok(HierarchyResponse {
+        next_batch: (end < rooms.len()).then(|| format_directory_token(end)),
+        rooms: rooms.drain(offset.min(end)..end).collect(),
+    })
+}
+
+/// Summarise a local space and its children for the server `origin`
+pub async fn get_federation_hierarchy(
+    store: &dyn StateStore,
+    origin: &str,
+    room_id: &str,
+    suggested_only: bool,
+) -> Result<FederationHierarchyResponse, RoomError> {
+    let room_state = store
+        .get_room(room_id)
+        .await?
+        .ok_or_else(|| RoomError::RoomNotFound(room_id.to_string()))?;
+    if !is_accessible(store, &room_state, Viewer::Server(origin)).await? {
+        return Err(RoomError::InsufficientPermissions(format!("{} cannot preview {}", origin, room_id)));
+    }
+
+    let room = room_chunk(&room_state, suggested_only, true);
+    let mut children = Vec::new();
+    let mut inaccessible_children = Vec::new();
+    for (child_id, _) in space_children(&room_state, suggested_only) {
+        // Rooms we are not part of are left for the requesting server to find elsewhere
+        let Some(child_state) = store.get_room(&child_id).await? else {
+            continue;
+        };
+        if is_accessible(store, &child_state, Viewer::Server(origin)).await? {
+            children.push(room_chunk(&child_state, suggested_only, true));
+        } else {
+            inaccessible_children.push(child_id);
+        }
+    }
+
+    Ok(FederationHierarchyResponse { room, children, inaccessible_children })
+}
+
+/// Valid `m.space.child` events of a room, ordered by `order`, then by when
+/// they were sent, then by room ID
+fn space_children(room_state: &RoomState, suggested_only: bool) -> Vec<(String, &MatrixEvent)> {
+    let mut children: Vec<(String, SpaceChildContent, &MatrixEvent)> = room_state
+        .get_state_events_by_type(&EventType::SpaceChild)
+        .into_iter()
+        .filter_map(|event| match &event.content {
+            EventContent::SpaceChild(content) => Some((event.state_key.clone()?, content.clone(), event)),
+            _ => None,
+        })
+        .filter(|(_, content, _)| content.is_valid())
+        .filter(|(_, content, _)| !suggested_only || content.suggested == Some(true))
+        .collect();
+
+    children.sort_by(|(a_id, a, a_event), (b_id, b, b_event)| {
+        // Children with a valid order come first
+        let a_order = (a.valid_order().is_none(), a.valid_order());
+        let b_order = (b.valid_order().is_none(), b.valid_order());
+        a_order
+            .cmp(&b_order)
+            .then_with(|| a_event.origin_server_ts.cmp(&b_event.origin_server_ts))
+            .then_with(|| a_id.cmp(b_id))
+    });
+    children.into_iter().map(|(child_id, _, event)| (child_id, event)).collect()
+}
+
+fn room_chunk(room_state: &RoomState, suggested_only: bool, federated: bool) -> SpaceHierarchyRoomsChunk {
+    let children_state = space_children(room_state, suggested_only)
+        .into_iter()
+        .map(|(_, event)| serde_json::json!({
+            "type": event.event_type,
+            "state_key": event.state_key,
+            "content": event.content,
+            "sender": event.sender,
+            "origin_server_ts": event.origin_server_ts,
+        }))
+        .collect();
+
+    let allowed_room_ids = match room_state.join_rules.as_deref() {
+        Some("restricted") | Some("knock_restricted") if federated => Some(room_state.join_rule_allowed_rooms()),
+        _ => None,
+    };
+
+    SpaceHierarchyRoomsChunk {
+        room: PublicRoomsChunk::from_room_state(room_state),
+        children_state,
+        allowed_room_ids,
+    }
+}
+
+/// Whether `viewer` may see a room in a hierarchy: it is previewable, they
+/// are in or invited to it, or (for users) they may join it via another room
+///
+/// Servers are shown restricted rooms along with `allowed_room_ids` so they
+/// can decide for their own users.
+async fn is_accessible(store: &dyn StateStore, room_state: &RoomState, viewer: Viewer<'_>) -> Result<bool, StateError> {
+    let join_rule = room_state.join_rules.as_deref().unwrap_or(JoinRule::Invite.as_str());
+    if matches!(join_rule, "public" | "knock" | "knock_restricted")
+        || room_state.history_visibility.as_deref() == Some("world_readable")
+    {
+        return Ok(true);
+    }
+
+    let in_room = room_state.members.iter().any(|(member, membership)| {
+        viewer.covers(member) && matches!(membership, MembershipState::Join | MembershipState::Invite)
+    });
+    if in_room {
+        return Ok(true);
+    }
+
+    match viewer {
+        Viewer::Server(_) => Ok(join_rule == "restricted"),
+        Viewer::User(user_id) if join_rule == "restricted" => {
+            member_of_any(store, user_id, &room_state.join_rule_allowed_rooms()).await
+        }
+        Viewer::User(_) => Ok(false),
+    }
+}
+
+/// Judge a remote room summary for a local user, mirroring `is_accessible`
+async fn remote_chunk_accessible(
+    store: &dyn StateStore,
+    chunk: &SpaceHierarchyRoomsChunk,
+    user_id: &str,
+) -> Result<bool, StateError> {
+    match chunk.room.join_rule.as_deref() {
+        Some("public") | Some("knock") | Some("knock_restricted") => Ok(true),
+        _ if chunk.room.world_readable => Ok(true),
+        Some("restricted") => {
+            let allowed = chunk.allowed_room_ids.clone().unwrap_or_default();
+            member_of_any(store, user_id, &allowed).await
+        }
+        _ => Ok(false),
+    }
+}
+
+async fn member_of_any(store: &dyn StateStore, user_id: &str, room_ids: &[String]) -> Result<bool, StateError> {
+    for room_id in room_ids {
+        if store.get_room(room_id).await?.is_some_and(|room_state| room_state.is_member(user_id)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn fetch_remote(
+    federation: &FederationClient,
+    room_id: &str,
+    via: &[String],
+    suggested_only: bool,
+) -> Option<FederationHierarchyResponse> {
+    for server in via {
+        match federation.get_hierarchy(server, room_id, suggested_only).await {
+            Ok(Some(response)) => return Some(response),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to fetch hierarchy of {} from {}: {}", room_id, server, e),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::auth::AuthenticatedUser;
+    use crate::federation::FederationConfig;
+    use crate::room::{JoinRoomRequest, RoomConfig, RoomHandler, RoomPreset, StateEventConfig};
+    use crate::state::InMemoryStateStore;
+    use crate::tests::test_user;
+
+    struct Fixture {
+        store: Arc<InMemoryStateStore>,
+        handler: RoomHandler,
+        federation: FederationClient,
+    }
+
+    async fn fixture() -> Fixture {
+        let store = Arc::new(InMemoryStateStore::new());
+        let federation = FederationClient::new(FederationConfig {
+            server_name: "localhost".to_string(),
+            signing_key: "test-key".to_string(),
+            verify_signatures: false,
+            federation_whitelist: None,
+            federation_blacklist: None,
+            server_addresses: std::collections::HashMap::new(),
+            ip_range_blacklist: crate::url_preview::default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
+        }).await.unwrap();
+        Fixture { handler: RoomHandler::new(store.clone()), store, federation }
+    }
+
+    fn child(room_id: &str, order: Option<&str>, suggested: bool) -> StateEventConfig {
+        StateEventConfig {
+            event_type: "m.space.child".to_string(),
+            state_key: room_id.to_string(),
+            content: serde_json::json!({ "via": ["localhost"], "order": order, "suggested": suggested }),
+        }
+    }
+
+    async fn create(
+        handler: &RoomHandler,
+        creator: &AuthenticatedUser,
+        preset: RoomPreset,
+        space: bool,
+        initial_state: Vec<StateEventConfig>,
+    ) -> String {
+        handler.create_room(creator, RoomConfig {
+            creation_content: space.then(|| serde_json::json!({ "type": "m.space" })),
+            initial_state,
+            preset: Some(preset),
+            ..Default::default()
+        }).await.unwrap().room_id
+    }
+
+    fn request(room_id: &str) -> HierarchyRequest {
+        HierarchyRequest {
+            room_id: room_id.to_string(),
+            from: None,
+            limit: None,
+            max_depth: None,
+            suggested_only: false,
+        }
+    }
+
+    fn room_ids(response: &HierarchyResponse) -> Vec<&str> {
+        response.rooms.iter().map(|chunk| chunk.room.room_id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_hierarchy_orders_children_and_hides_inaccessible_rooms() {
+        let f = fixture().await;
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+
+        let general = create(&f.handler, &alice, RoomPreset::PublicChat, false, vec![]).await;
+        let announcements = create(&f.handler, &alice, RoomPreset::PublicChat, false, vec![]).await;
+        let staff = create(&f.handler, &alice, RoomPreset::PrivateChat, false, vec![]).await;
+        let space = create(&f.handler, &alice, RoomPreset::PublicChat, true, vec![
+            child(&general, Some("b"), false),
+            child(&announcements, Some("a"), true),
+            child(&staff, None, false),
+        ]).await;
+
+        let as_alice = get_hierarchy(f.store.as_ref(), &f.federation, &alice.user_id, request(&space)).await.unwrap();
+        assert_eq!(room_ids(&as_alice), vec![space.as_str(), &announcements, &general, &staff]);
+        assert_eq!(as_alice.rooms[0].room.room_type.as_deref(), Some("m.space"));
+        assert_eq!(as_alice.rooms[0].children_state.len(), 3);
+
+        let as_bob = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, request(&space)).await.unwrap();
+        assert_eq!(room_ids(&as_bob), vec![space.as_str(), &announcements, &general]);
+
+        let suggested = HierarchyRequest { suggested_only: true, ..request(&space) };
+        let suggested = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, suggested).await.unwrap();
+        assert_eq!(room_ids(&suggested), vec![space.as_str(), &announcements]);
+
+        let result = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, request(&staff)).await;
+        assert!(matches!(result, Err(RoomError::InsufficientPermissions(_))));
+    }
+
+    #[tokio::test]
+    async fn test_hierarchy_depth_pagination_and_restricted_rooms() {
+        let f = fixture().await;
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+
+        let space = create(&f.handler, &alice, RoomPreset::PublicChat, true, vec![]).await;
+        let members_only = create(&f.handler, &alice, RoomPreset::PrivateChat, false, vec![StateEventConfig {
+            event_type: "m.room.join_rules".to_string(),
+            state_key: String::new(),
+            content: serde_json::json!({
+                "join_rule": "restricted",
+                "allow": [{ "type": "m.room_membership", "room_id": space }]
+            }),
+        }]).await;
+        let subspace = create(&f.handler, &alice, RoomPreset::PublicChat, true, vec![child(&members_only, None, false)]).await;
+        let root = create(&f.handler, &alice, RoomPreset::PublicChat, true, vec![child(&subspace, None, false)]).await;
+
+        let as_bob = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, request(&root)).await.unwrap();
+        assert_eq!(room_ids(&as_bob), vec![root.as_str(), &subspace]);
+
+        f.handler.join_room(&bob, JoinRoomRequest { room_id: space.clone(), reason: None }).await.unwrap();
+        let as_bob = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, request(&root)).await.unwrap();
+        assert_eq!(room_ids(&as_bob), vec![root.as_str(), &subspace, &members_only]);
+
+        let shallow = HierarchyRequest { max_depth: Some(1), ..request(&root) };
+        let shallow = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, shallow).await.unwrap();
+        assert_eq!(room_ids(&shallow), vec![root.as_str(), &subspace]);
+
+        let first = HierarchyRequest { limit: Some(2), ..request(&root) };
+        let first = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, first).await.unwrap();
+        assert_eq!(room_ids(&first), vec![root.as_str(), &subspace]);
+        let rest = HierarchyRequest { limit: Some(2), from: first.next_batch.clone(), ..request(&root) };
+        let rest = get_hierarchy(f.store.as_ref(), &f.federation, &bob.user_id, rest).await.unwrap();
+        assert_eq!(room_ids(&rest), vec![members_only.as_str()]);
+        assert!(rest.next_batch.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_federation_hierarchy_reports_allowed_and_inaccessible_rooms() {
+        let f = fixture().await;
+        let alice = test_user("@alice:localhost");
+
+        let staff = create(&f.handler, &alice, RoomPreset::PrivateChat, false, vec![]).await;
+        let space = create(&f.handler, &alice, RoomPreset::PublicChat, true, vec![]).await;
+        let members_only = create(&f.handler, &alice, RoomPreset::PrivateChat, false, vec![StateEventConfig {
+            event_type: "m.room.join_rules".to_string(),
+            state_key: String::new(),
+            content: serde_json::json!({
+                "join_rule": "restricted",
+                "allow": [{ "type": "m.room_membership", "room_id": space }]
+            }),
+        }]).await;
+        let root = create(&f.handler, &alice, RoomPreset::PublicChat, true, vec![
+            child(&members_only, None, false),
+            child(&staff, None, false),
+            child("!unknown:elsewhere.example", None, false),
+        ]).await;
+
+        let response = get_federation_hierarchy(f.store.as_ref(), "remote.example", &root, false).await.unwrap();
+        assert_eq!(response.room.room.room_id, root);
+        assert_eq!(response.children.len(), 1);
+        assert_eq!(response.children[0].allowed_room_ids, Some(vec![space]));
+        assert_eq!(response.inaccessible_children, vec![staff]);
+    }
+}
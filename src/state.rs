@@ -2,8 +2,11 @@
 // Simplified state store for Matrix rooms and events
 // Focus: Room state tracking and event processing
 
-use crate::events::{MatrixEvent, EventType, EventContent, MembershipState};
-use std::collections::HashMap;
+use crate::events::{
+    MatrixEvent, EventType, EventContent, GuestAccess, HistoryVisibility, MembershipState,
+    RoomCanonicalAliasContent, ALLOW_ROOM_MEMBERSHIP,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use serde::{Deserialize, Serialize};
@@ -37,6 +40,13 @@ pub struct PowerLevels {
     pub invite: Option<i32>,
 }
 
+/// Profile a member carries in their current `m.room.member` event
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemberProfile {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
 /// Room state representation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomState {
@@ -44,6 +54,8 @@ pub struct RoomState {
     pub room_version: String,
     pub state_events: HashMap<(EventType, String), MatrixEvent>, // (type, state_key) -> event
     pub members: HashMap<String, MembershipState>,
+    #[serde(default)]
+    pub member_profiles: HashMap<String, MemberProfile>,
     pub power_levels: PowerLevels,
     pub creator: String,
     pub join_rules: Option<String>,
@@ -80,6 +92,7 @@ impl RoomState {
             room_version,
             state_events: HashMap::new(),
             members,
+            member_profiles: HashMap::new(),
             power_levels,
             creator,
             join_rules: Some("invite".to_string()),
@@ -97,6 +110,7 @@ impl RoomState {
             .as_ref()
             .and_then(|users| users.get(user_id))
             .copied()
+            .or(self.power_levels.users_default)
             .unwrap_or(0)
     }
 
@@ -105,6 +119,17 @@ impl RoomState {
         self.get_user_power_level(user_id) >= required_level
     }
 
+    /// Power level required to send a state event of `event_type`
+    pub fn state_event_level(&self, event_type: &EventType) -> i32 {
+        self.power_levels
+            .events
+            .as_ref()
+            .and_then(|events| events.get(event_type.as_str()))
+            .copied()
+            .or(self.power_levels.state_default)
+            .unwrap_or(50)
+    }
+
     /// Check if user is member of the room
     pub fn is_member(&self, user_id: &str) -> bool {
         matches!(
@@ -138,6 +163,7 @@ impl RoomState {
         }
 
         // Insert the state event
+        self.update_cached_state(&event);
         self.state_events.insert(
             (event.event_type.clone(), state_key),
             event,
@@ -149,29 +175,46 @@ impl RoomState {
     /// Process member event
     pub fn process_member_event(&mut self, event: &MatrixEvent) -> Result<(), StateError> {
         if let EventContent::RoomMember(ref content) = event.content {
-            let user_id = event.sender.clone();
+            // The state key names the member; the sender may be someone else, e.g. for invites and kicks
+            let user_id = event.state_key.clone().unwrap_or_else(|| event.sender.clone());
             
             match content.membership {
-                MembershipState::Join => {
-                    self.members.insert(user_id, content.membership.clone());
-                }
                 MembershipState::Leave => {
                     self.members.remove(&user_id);
+                    self.member_profiles.remove(&user_id);
                 }
-                MembershipState::Invite => {
-                    // Handle invite logic
-                }
-                MembershipState::Ban => {
-                    self.members.remove(&user_id);
-                }
-                MembershipState::Knock => {
-                    // Handle knock logic
+                MembershipState::Join
+                | MembershipState::Invite
+                | MembershipState::Ban
+                | MembershipState::Knock => {
+                    self.member_profiles.insert(user_id.clone(), MemberProfile {
+                        display_name: content.displayname.clone(),
+                        avatar_url: content.avatar_url.clone(),
+                    });
+                    self.members.insert(user_id, content.membership.clone());
                 }
             }
         }
         Ok(())
     }
 
+    /// Current membership of a user, if they have one
+    pub fn membership(&self, user_id: &str) -> Option<&MembershipState> {
+        self.members.get(user_id)
+    }
+
+    /// Joined members with the profile from their member event
+    pub fn joined_members(&self) -> HashMap<String, MemberProfile> {
+        self.members
+            .iter()
+            .filter(|(_, membership)| **membership == MembershipState::Join)
+            .map(|(user_id, _)| {
+                let profile = self.member_profiles.get(user_id).cloned().unwrap_or_default();
+                (user_id.clone(), profile)
+            })
+            .collect()
+    }
+
     /// Get state event by type and state key
     pub fn get_state_event(&self, event_type: &EventType, state_key: &str) -> Option<&MatrixEvent> {
         self.state_events.get(&(event_type.clone(), state_key.to_string()))
@@ -186,13 +229,141 @@ impl RoomState {
             .collect()
     }
 
+    /// Apply an already authorized state event to the current state
+    pub fn apply_state_event(&mut self, event: MatrixEvent) -> Result<(), StateError> {
+        let state_key = event.state_key.clone()
+            .ok_or_else(|| StateError::InvalidEvent("State event missing state key".to_string()))?;
+
+        if event.event_type == EventType::RoomMember {
+            self.process_member_event(&event)?;
+        }
+        self.update_cached_state(&event);
+        self.state_events.insert((event.event_type.clone(), state_key), event);
+        Ok(())
+    }
+
+    /// Keep the summary fields in sync with the state events they mirror
+    fn update_cached_state(&mut self, event: &MatrixEvent) {
+        match event.content {
+            EventContent::RoomHistoryVisibility(ref content) => {
+                self.history_visibility = Some(content.history_visibility.as_str().to_string());
+            }
+            EventContent::RoomJoinRules(ref content) => {
+                self.join_rules = Some(content.join_rule.as_str().to_string());
+            }
+            EventContent::RoomName(ref content) => {
+                self.name = Some(content.name.clone());
+            }
+            EventContent::RoomTopic(ref content) => {
+                self.topic = Some(content.topic.clone());
+            }
+            EventContent::RoomAvatar(ref content) => {
+                self.avatar_url = content.url.clone();
+            }
+            EventContent::RoomPowerLevels(ref content) => {
+                self.power_levels = PowerLevels {
+                    users: content.users.clone(),
+                    users_default: content.users_default,
+                    events: content.events.clone(),
+                    events_default: content.events_default,
+                    state_default: content.state_default,
+                    ban: content.ban,
+                    kick: content.kick,
+                    redact: content.redact,
+                    invite: content.invite,
+                };
+            }
+            // Redaction strips these down to content that no longer parses
+            EventContent::Raw(_) => match event.event_type {
+                EventType::RoomName => self.name = None,
+                EventType::RoomTopic => self.topic = None,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Room type from the create event, e.g. `m.space`
+    pub fn room_type(&self) -> Option<String> {
+        match &self.get_state_event(&EventType::RoomCreate, "")?.content {
+            EventContent::RoomCreate(content) => content.room_type.clone(),
+            _ => None,
+        }
+    }
+
+    /// Whether the create event allows the room to be joined over federation
+    pub fn federates(&self) -> bool {
+        match self.get_state_event(&EventType::RoomCreate, "").map(|event| &event.content) {
+            Some(EventContent::RoomCreate(content)) => content.m_federate != Some(false),
+            _ => true,
+        }
+    }
+
+    /// Whether `m.room.guest_access` lets guests join
+    pub fn guest_can_join(&self) -> bool {
+        matches!(
+            self.get_state_event(&EventType::RoomGuestAccess, "").map(|event| &event.content),
+            Some(EventContent::RoomGuestAccess(content)) if content.guest_access == GuestAccess::CanJoin
+        )
+    }
+
+    /// Current `m.room.canonical_alias` content, if the room has one
+    pub fn canonical_alias_content(&self) -> Option<RoomCanonicalAliasContent> {
+        match &self.get_state_event(&EventType::RoomCanonicalAlias, "")?.content {
+            EventContent::RoomCanonicalAlias(content) => Some(content.clone()),
+            _ => None,
+        }
+    }
+
+    /// Rooms whose members may join under a `restricted` or `knock_restricted` join rule
+    pub fn join_rule_allowed_rooms(&self) -> Vec<String> {
+        let Some(EventContent::RoomJoinRules(content)) = self
+            .get_state_event(&EventType::RoomJoinRules, "")
+            .map(|event| &event.content)
+        else {
+            return Vec::new();
+        };
+
+        content.allow
+            .iter()
+            .flatten()
+            .filter(|condition| condition.condition_type == ALLOW_ROOM_MEMBERSHIP)
+            .filter_map(|condition| condition.room_id.clone())
+            .collect()
+    }
+
+    /// Replace the current state entry holding `event`, if it is still current
+    pub fn replace_state_event(&mut self, event: &MatrixEvent) {
+        if let Some(state_key) = &event.state_key {
+            let key = (event.event_type.clone(), state_key.clone());
+            if let Some(current) = self.state_events.get_mut(&key) {
+                if current.event_id == event.event_id {
+                    *current = event.clone();
+                    self.update_cached_state(event);
+                }
+            }
+        }
+    }
+
+    /// Servers with at least one joined member in this room
+    pub fn servers(&self) -> HashSet<String> {
+        self.members
+            .iter()
+            .filter(|(_, membership)| **membership == MembershipState::Join)
+            .filter_map(|(user_id, _)| user_id.split_once(':').map(|(_, server)| server.to_string()))
+            .collect()
+    }
+
     /// Get room summary for client
     pub fn get_summary(&self) -> RoomSummary {
         RoomSummary {
             room_id: self.room_id.clone(),
             name: self.name.clone(),
             topic: self.topic.clone(),
-            member_count: self.members.len(),
+            member_count: self.members
+                .values()
+                .filter(|membership| **membership == MembershipState::Join)
+                .count(),
             join_rules: self.join_rules.clone(),
             history_visibility: self.history_visibility.clone(),
         }
@@ -210,6 +381,16 @@ pub struct RoomSummary {
     pub history_visibility: Option<String>,
 }
 
+/// Direction for paginating a room timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "f")]
+    Forward,
+    #[default]
+    #[serde(rename = "b")]
+    Backward,
+}
+
 /// State store trait for different storage backends
 #[async_trait::async_trait]
 pub trait StateStore: Send + Sync {
@@ -219,17 +400,339 @@ pub trait StateStore: Send + Sync {
     async fn delete_room(&self, room_id: &str) -> Result<(), StateError>;
     async fn list_rooms(&self) -> Result<Vec<String>, StateError>;
     async fn room_exists(&self, room_id: &str) -> Result<bool, StateError>;
+    /// Rooms `user_id` has, or once had, a membership in, kept up to date as
+    /// member events are applied to stored room state
+    async fn get_rooms_for_user(&self, user_id: &str) -> Result<Vec<String>, StateError>;
+
+    /// Append an event to its room timeline, returning its stream ordering
+    async fn append_event(&self, event: MatrixEvent) -> Result<u64, StateError>;
+    /// Replace a stored event in place, keeping its stream ordering
+    async fn replace_event(&self, event: MatrixEvent) -> Result<(), StateError>;
+    async fn get_event(&self, event_id: &str) -> Result<Option<MatrixEvent>, StateError>;
+    async fn get_stream_ordering(&self, event_id: &str) -> Result<Option<u64>, StateError>;
+    /// Highest stream ordering handed out so far
+    async fn current_stream_position(&self) -> Result<u64, StateError>;
+    /// Paginate a room timeline from a stream position: forwards returns
+    /// events after `from` in ascending order, backwards returns events at
+    /// or before `from` in descending order
+    async fn get_room_events(
+        &self,
+        room_id: &str,
+        from: u64,
+        direction: Direction,
+        limit: usize,
+    ) -> Result<Vec<(u64, MatrixEvent)>, StateError>;
+
+    /// Index `child_id` as relating to `parent_id` with `rel_type`
+    async fn add_relation(&self, parent_id: &str, child_id: &str, rel_type: &str) -> Result<(), StateError>;
+    /// Drop the relation declared by `child_id`, e.g. once it is redacted
+    async fn remove_relation(&self, child_id: &str) -> Result<(), StateError>;
+    /// Paginate the children of an event by stream ordering, optionally
+    /// restricted to a relation type and event type
+    async fn get_relations(
+        &self,
+        parent_id: &str,
+        rel_type: Option<&str>,
+        event_type: Option<&EventType>,
+        from: u64,
+        direction: Direction,
+        limit: usize,
+    ) -> Result<Vec<(u64, MatrixEvent)>, StateError>;
+    /// Thread roots of a room with the stream ordering of their latest
+    /// reply, most recently active first
+    async fn get_thread_roots(&self, room_id: &str) -> Result<Vec<(u64, String)>, StateError>;
+
+    /// Keep the unredacted original of an event until it is purged
+    async fn retain_redacted_original(&self, original: MatrixEvent, redacted_at: u64) -> Result<(), StateError>;
+    async fn get_redacted_original(&self, event_id: &str) -> Result<Option<MatrixEvent>, StateError>;
+    /// Drop originals redacted before `redacted_before`, returning how many were purged
+    async fn purge_redacted_originals(&self, redacted_before: u64) -> Result<usize, StateError>;
+
+    /// Mark a left room as forgotten by a user, or clear the mark on rejoin
+    async fn set_room_forgotten(&self, user_id: &str, room_id: &str, forgotten: bool) -> Result<(), StateError>;
+    async fn is_room_forgotten(&self, user_id: &str, room_id: &str) -> Result<bool, StateError>;
+
+    /// Map a local room alias to a room, failing if the alias is taken
+    async fn create_room_alias(&self, alias: &str, room_id: &str, creator: &str) -> Result<(), StateError>;
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<RoomAlias>, StateError>;
+    /// Remove an alias mapping, returning whether it existed
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, StateError>;
+    /// Local aliases pointing at a room
+    async fn get_room_aliases(&self, room_id: &str) -> Result<Vec<String>, StateError>;
+
+    /// Publish a room to, or withdraw it from, the public room directory
+    async fn set_room_published(&self, room_id: &str, published: bool) -> Result<(), StateError>;
+    async fn is_room_published(&self, room_id: &str) -> Result<bool, StateError>;
+    async fn get_published_rooms(&self) -> Result<Vec<String>, StateError>;
+
+    /// Store a user's account data of `data_type`, global or for one room,
+    /// returning its position in the account data stream
+    async fn set_account_data(
+        &self,
+        user_id: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+        content: serde_json::Value,
+    ) -> Result<u64, StateError>;
+    async fn get_account_data(
+        &self,
+        user_id: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+    ) -> Result<Option<serde_json::Value>, StateError>;
+    /// A user's account data changed after account data stream position `since`, oldest first
+    async fn get_account_data_changes(&self, user_id: &str, since: u64) -> Result<Vec<AccountData>, StateError>;
+    /// Highest account data stream position handed out so far
+    async fn current_account_data_position(&self) -> Result<u64, StateError>;
+
+    /// Store a receipt, replacing the user's previous one of the same type
+    /// and thread, returning its position in the receipt stream
+    async fn set_receipt(&self, receipt: Receipt) -> Result<u64, StateError>;
+    async fn get_receipt(
+        &self,
+        room_id: &str,
+        receipt_type: &str,
+        user_id: &str,
+        thread_id: Option<&str>,
+    ) -> Result<Option<Receipt>, StateError>;
+    /// Receipts in a room changed after receipt stream position `since`, oldest first
+    async fn get_room_receipts(&self, room_id: &str, since: u64) -> Result<Vec<Receipt>, StateError>;
+    /// Highest receipt stream position handed out so far
+    async fn current_receipt_position(&self) -> Result<u64, StateError>;
+
+    /// Record that an event notifies `user_id`
+    async fn add_push_action(&self, user_id: &str, action: PushAction) -> Result<(), StateError>;
+    /// Notifications of `user_id`, in one room or all, oldest first
+    async fn get_push_actions(&self, user_id: &str, room_id: Option<&str>) -> Result<Vec<PushAction>, StateError>;
+    /// Notifications of `user_id` past stream position `after`, oldest first
+    async fn get_push_actions_after(&self, user_id: &str, after: u64) -> Result<Vec<PushAction>, StateError>;
+
+    /// Index `event_id` of `room_id` under the terms found in one of its
+    /// searchable keys, such as `content.body`
+    async fn index_search_terms(&self, room_id: &str, event_id: &str, key: &str, terms: Vec<String>) -> Result<(), StateError>;
+    /// Drop an event from the search index, e.g. once it is redacted
+    async fn remove_search_terms(&self, event_id: &str) -> Result<(), StateError>;
+    /// Events of `room_ids` indexed under `term` in any of `keys`, with how
+    /// often it occurs in each
+    async fn search_term(&self, term: &str, keys: &[String], room_ids: &[String]) -> Result<HashMap<String, u32>, StateError>;
+
+    /// Record a history visibility or membership change at a stream ordering
+    async fn add_visibility_change(&self, room_id: &str, ordering: u64, change: VisibilityChange) -> Result<(), StateError>;
+    /// History visibility and membership changes of a room, oldest first
+    async fn get_visibility_changes(&self, room_id: &str) -> Result<Vec<(u64, VisibilityChange)>, StateError>;
+
+    /// Store metadata of a piece of media, replacing any with the same server name and media ID
+    async fn set_media(&self, record: MediaRecord) -> Result<(), StateError>;
+    async fn get_media(&self, server_name: &str, media_id: &str) -> Result<Option<MediaRecord>, StateError>;
+    /// Media created or uploaded by `user_id`
+    async fn get_user_media(&self, user_id: &str) -> Result<Vec<MediaRecord>, StateError>;
+    /// Media cached from servers other than `local_server_name`
+    async fn get_remote_media(&self, local_server_name: &str) -> Result<Vec<MediaRecord>, StateError>;
+    /// Media whose content has the SHA-256 `content_hash`
+    async fn get_media_by_hash(&self, content_hash: &str) -> Result<Vec<MediaRecord>, StateError>;
+    /// Remove media metadata, returning whether it existed
+    async fn delete_media(&self, server_name: &str, media_id: &str) -> Result<bool, StateError>;
+
+    /// Store a pusher, replacing the user's pusher with the same app ID and pushkey
+    async fn set_pusher(&self, pusher: Pusher) -> Result<(), StateError>;
+    /// Remove a pusher, returning whether it existed
+    async fn delete_pusher(&self, user_id: &str, app_id: &str, pushkey: &str) -> Result<bool, StateError>;
+    /// Pushers of one user, or of every user
+    async fn get_pushers(&self, user_id: Option<&str>) -> Result<Vec<Pusher>, StateError>;
+    /// Record the last notification delivered through a pusher
+    async fn set_pusher_position(
+        &self,
+        user_id: &str,
+        app_id: &str,
+        pushkey: &str,
+        stream_ordering: u64,
+    ) -> Result<(), StateError>;
+}
+
+/// A local room alias and who created it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomAlias {
+    pub room_id: String,
+    pub creator: String,
+}
+
+/// A piece of a user's account data, global when `room_id` is none
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountData {
+    pub room_id: Option<String>,
+    pub data_type: String,
+    pub content: serde_json::Value,
+}
+
+/// (user ID, room ID or none for global account data, type)
+type AccountDataKey = (String, Option<String>, String);
+
+/// Account data and the stream position of its last change
+#[derive(Default)]
+struct AccountDataStream {
+    position: u64,
+    entries: HashMap<AccountDataKey, (u64, serde_json::Value)>,
+}
+
+/// The latest event a user has acknowledged in a room, per receipt type and thread
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub room_id: String,
+    pub receipt_type: String,
+    pub user_id: String,
+    pub event_id: String,
+    /// `main` or a thread root event ID; none for an unthreaded receipt
+    pub thread_id: Option<String>,
+    pub ts: u64,
+}
+
+/// A notification an event raised for a user through their push rules
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushAction {
+    pub room_id: String,
+    pub event_id: String,
+    pub stream_ordering: u64,
+    /// Thread root of events in a thread
+    pub thread_id: Option<String>,
+    pub actions: Vec<serde_json::Value>,
+    pub highlight: bool,
+}
+
+/// Where a user's notifications are delivered, as registered by one of their devices
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pusher {
+    #[serde(skip)]
+    pub user_id: String,
+    pub pushkey: String,
+    pub kind: String,
+    pub app_id: String,
+    pub app_display_name: String,
+    pub device_display_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_tag: Option<String>,
+    pub lang: String,
+    pub data: PusherData,
+    /// Unix time in milliseconds the pushkey was registered
+    #[serde(skip)]
+    pub pushkey_ts: u64,
+    /// Stream ordering of the last notification delivered through this pusher
+    #[serde(skip)]
+    pub last_stream_ordering: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PusherData {
+    /// Push gateway notify URL of HTTP pushers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Extra keys passed through to the push gateway
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A timeline event that changes who may read a room's history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VisibilityChange {
+    HistoryVisibility(HistoryVisibility),
+    Membership { user_id: String, membership: MembershipState },
+}
+
+/// Metadata of a piece of media in the media repository
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MediaRecord {
+    pub server_name: String,
+    pub media_id: String,
+    /// Local user who created the media ID; none for remote media
+    pub uploader: Option<String>,
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+    pub size: u64,
+    /// SHA-256 of the content, naming its file; none until content is uploaded
+    pub content_hash: Option<String>,
+    /// Unix time in milliseconds the media ID was created, or remote media
+    /// was cached
+    pub created_ts: u64,
+    /// When a created media ID expires if nothing has been uploaded to it
+    pub unused_expires_at: Option<u64>,
+}
+
+/// (event ID, key) -> occurrences of a term
+type RoomPostings = HashMap<(String, String), u32>;
+
+/// Inverted index from terms to the events whose searchable keys contain them
+#[derive(Default)]
+struct SearchIndex {
+    /// term -> room ID -> postings
+    postings: HashMap<String, HashMap<String, RoomPostings>>,
+    /// event ID -> its room and the terms it is indexed under
+    event_terms: HashMap<String, (String, HashSet<String>)>,
+}
+
+/// (user ID, app ID, pushkey)
+type PusherKey = (String, String, String);
+
+/// (room ID, receipt type, user ID, thread ID)
+type ReceiptKey = (String, String, String, Option<String>);
+
+/// Receipts and the stream position of their last change
+#[derive(Default)]
+struct ReceiptStream {
+    position: u64,
+    entries: HashMap<ReceiptKey, (u64, Receipt)>,
+}
+
+/// Stored events and their ordering across all rooms
+#[derive(Default)]
+struct Timeline {
+    stream_position: u64,
+    events: HashMap<String, (u64, MatrixEvent)>,
+    room_events: HashMap<String, BTreeMap<u64, String>>,
+    /// parent event ID -> child stream ordering -> (child event ID, rel_type)
+    relations: HashMap<String, BTreeMap<u64, (String, String)>>,
+    /// child event ID -> parent event ID
+    relation_parents: HashMap<String, String>,
+    redacted_originals: HashMap<String, (u64, MatrixEvent)>,
 }
 
 /// In-memory state store implementation
+#[derive(Default)]
 pub struct InMemoryStateStore {
     rooms: Arc<RwLock<HashMap<String, RoomState>>>,
+    /// user ID -> rooms they have, or once had, a membership in
+    user_rooms: Arc<RwLock<HashMap<String, BTreeSet<String>>>>,
+    timeline: Arc<RwLock<Timeline>>,
+    /// (user ID, room ID) pairs of forgotten rooms
+    forgotten_rooms: Arc<RwLock<HashSet<(String, String)>>>,
+    /// room alias -> mapping
+    room_aliases: Arc<RwLock<BTreeMap<String, RoomAlias>>>,
+    /// Rooms listed in the public room directory
+    published_rooms: Arc<RwLock<BTreeSet<String>>>,
+    account_data: Arc<RwLock<AccountDataStream>>,
+    receipts: Arc<RwLock<ReceiptStream>>,
+    /// user ID -> notifications in stream order
+    push_actions: Arc<RwLock<HashMap<String, Vec<PushAction>>>>,
+    pushers: Arc<RwLock<BTreeMap<PusherKey, Pusher>>>,
+    search_index: Arc<RwLock<SearchIndex>>,
+    /// room ID -> visibility changes by stream ordering
+    visibility_changes: Arc<RwLock<HashMap<String, BTreeMap<u64, VisibilityChange>>>>,
+    /// (server name, media ID) -> metadata
+    media: Arc<RwLock<HashMap<(String, String), MediaRecord>>>,
 }
 
 impl InMemoryStateStore {
     pub fn new() -> Self {
-        Self {
-            rooms: Arc::new(RwLock::new(HashMap::new())),
+        Self::default()
+    }
+
+    /// Add the room to the index of every user with a membership in it
+    async fn index_members(&self, room_state: &RoomState) {
+        let mut user_rooms = self.user_rooms.write().await;
+        for user_id in room_state.members.keys() {
+            if !user_rooms.get(user_id).is_some_and(|rooms| rooms.contains(&room_state.room_id)) {
+                user_rooms.entry(user_id.clone()).or_default().insert(room_state.room_id.clone());
+            }
         }
     }
 }
@@ -242,6 +745,7 @@ impl StateStore for InMemoryStateStore {
     }
 
     async fn create_room(&self, room_state: RoomState) -> Result<(), StateError> {
+        self.index_members(&room_state).await;
         let mut rooms = self.rooms.write().await;
         rooms.insert(room_state.room_id.clone(), room_state);
         Ok(())
@@ -255,6 +759,7 @@ impl StateStore for InMemoryStateStore {
             .ok_or_else(|| StateError::RoomNotFound(room_state.room_id.clone()))?;
         
         // Update room state
+        self.index_members(&room_state).await;
         *room = room_state;
         Ok(())
     }
@@ -263,6 +768,9 @@ impl StateStore for InMemoryStateStore {
         let mut rooms = self.rooms.write().await;
         
         if rooms.remove(room_id).is_some() {
+            for user_rooms in self.user_rooms.write().await.values_mut() {
+                user_rooms.remove(room_id);
+            }
             Ok(())
         } else {
             Err(StateError::RoomNotFound(room_id.to_string()))
@@ -278,6 +786,513 @@ impl StateStore for InMemoryStateStore {
         let rooms = self.rooms.read().await;
         Ok(rooms.contains_key(room_id))
     }
+
+    async fn get_rooms_for_user(&self, user_id: &str) -> Result<Vec<String>, StateError> {
+        let user_rooms = self.user_rooms.read().await;
+        Ok(user_rooms.get(user_id).map(|rooms| rooms.iter().cloned().collect()).unwrap_or_default())
+    }
+
+    async fn append_event(&self, event: MatrixEvent) -> Result<u64, StateError> {
+        let mut timeline = self.timeline.write().await;
+
+        if timeline.events.contains_key(&event.event_id) {
+            return Err(StateError::StateConflict(format!("Duplicate event: {}", event.event_id)));
+        }
+
+        timeline.stream_position += 1;
+        let ordering = timeline.stream_position;
+        timeline.room_events
+            .entry(event.room_id.clone())
+            .or_default()
+            .insert(ordering, event.event_id.clone());
+        timeline.events.insert(event.event_id.clone(), (ordering, event));
+        Ok(ordering)
+    }
+
+    async fn replace_event(&self, event: MatrixEvent) -> Result<(), StateError> {
+        let mut timeline = self.timeline.write().await;
+
+        let entry = timeline.events.get_mut(&event.event_id)
+            .ok_or_else(|| StateError::InvalidEvent(format!("Unknown event: {}", event.event_id)))?;
+        entry.1 = event;
+        Ok(())
+    }
+
+    async fn get_event(&self, event_id: &str) -> Result<Option<MatrixEvent>, StateError> {
+        let timeline = self.timeline.read().await;
+        Ok(timeline.events.get(event_id).map(|(_, event)| event.clone()))
+    }
+
+    async fn get_stream_ordering(&self, event_id: &str) -> Result<Option<u64>, StateError> {
+        let timeline = self.timeline.read().await;
+        Ok(timeline.events.get(event_id).map(|(ordering, _)| *ordering))
+    }
+
+    async fn current_stream_position(&self) -> Result<u64, StateError> {
+        let timeline = self.timeline.read().await;
+        Ok(timeline.stream_position)
+    }
+
+    async fn get_room_events(
+        &self,
+        room_id: &str,
+        from: u64,
+        direction: Direction,
+        limit: usize,
+    ) -> Result<Vec<(u64, MatrixEvent)>, StateError> {
+        let timeline = self.timeline.read().await;
+
+        let Some(room_events) = timeline.room_events.get(room_id) else {
+            return Ok(Vec::new());
+        };
+        let event_ids: Box<dyn Iterator<Item = (&u64, &String)>> = match direction {
+            Direction::Forward => Box::new(room_events.range(from.saturating_add(1)..)),
+            Direction::Backward => Box::new(room_events.range(..=from).rev()),
+        };
+
+        Ok(event_ids
+            .take(limit)
+            .filter_map(|(ordering, event_id)| {
+                timeline.events.get(event_id).map(|(_, event)| (*ordering, event.clone()))
+            })
+            .collect())
+    }
+
+    async fn add_relation(&self, parent_id: &str, child_id: &str, rel_type: &str) -> Result<(), StateError> {
+        let mut timeline = self.timeline.write().await;
+
+        let ordering = timeline.events.get(child_id)
+            .map(|(ordering, _)| *ordering)
+            .ok_or_else(|| StateError::InvalidEvent(format!("Unknown event: {}", child_id)))?;
+        timeline.relations
+            .entry(parent_id.to_string())
+            .or_default()
+            .insert(ordering, (child_id.to_string(), rel_type.to_string()));
+        timeline.relation_parents.insert(child_id.to_string(), parent_id.to_string());
+        Ok(())
+    }
+
+    async fn remove_relation(&self, child_id: &str) -> Result<(), StateError> {
+        let mut timeline = self.timeline.write().await;
+
+        if let Some(parent_id) = timeline.relation_parents.remove(child_id) {
+            if let Some(children) = timeline.relations.get_mut(&parent_id) {
+                children.retain(|_, (id, _)| id != child_id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_relations(
+        &self,
+        parent_id: &str,
+        rel_type: Option<&str>,
+        event_type: Option<&EventType>,
+        from: u64,
+        direction: Direction,
+        limit: usize,
+    ) -> Result<Vec<(u64, MatrixEvent)>, StateError> {
+        let timeline = self.timeline.read().await;
+
+        let Some(children) = timeline.relations.get(parent_id) else {
+            return Ok(Vec::new());
+        };
+        let children: Box<dyn Iterator<Item = (&u64, &(String, String))>> = match direction {
+            Direction::Forward => Box::new(children.range(from.saturating_add(1)..)),
+            Direction::Backward => Box::new(children.range(..=from).rev()),
+        };
+
+        Ok(children
+            .filter(|(_, (_, child_rel_type))| rel_type.is_none_or(|r| r == child_rel_type))
+            .filter_map(|(ordering, (child_id, _))| {
+                timeline.events.get(child_id).map(|(_, event)| (*ordering, event.clone()))
+            })
+            .filter(|(_, event)| event_type.is_none_or(|t| *t == event.event_type))
+            .take(limit)
+            .collect())
+    }
+
+    async fn get_thread_roots(&self, room_id: &str) -> Result<Vec<(u64, String)>, StateError> {
+        let timeline = self.timeline.read().await;
+
+        let mut roots: Vec<(u64, String)> = timeline.relations
+            .iter()
+            .filter(|(parent_id, _)| {
+                timeline.events.get(*parent_id).is_some_and(|(_, event)| event.room_id == room_id)
+            })
+            .filter_map(|(parent_id, children)| {
+                children
+                    .iter()
+                    .rev()
+                    .find(|(_, (_, rel_type))| rel_type == crate::events::REL_TYPE_THREAD)
+                    .map(|(ordering, _)| (*ordering, parent_id.clone()))
+            })
+            .collect();
+        roots.sort_by_key(|(latest, _)| std::cmp::Reverse(*latest));
+        Ok(roots)
+    }
+
+    async fn retain_redacted_original(&self, original: MatrixEvent, redacted_at: u64) -> Result<(), StateError> {
+        let mut timeline = self.timeline.write().await;
+        timeline.redacted_originals
+            .entry(original.event_id.clone())
+            .or_insert((redacted_at, original));
+        Ok(())
+    }
+
+    async fn get_redacted_original(&self, event_id: &str) -> Result<Option<MatrixEvent>, StateError> {
+        let timeline = self.timeline.read().await;
+        Ok(timeline.redacted_originals.get(event_id).map(|(_, event)| event.clone()))
+    }
+
+    async fn purge_redacted_originals(&self, redacted_before: u64) -> Result<usize, StateError> {
+        let mut timeline = self.timeline.write().await;
+        let before = timeline.redacted_originals.len();
+        timeline.redacted_originals.retain(|_, (redacted_at, _)| *redacted_at >= redacted_before);
+        Ok(before - timeline.redacted_originals.len())
+    }
+
+    async fn set_room_forgotten(&self, user_id: &str, room_id: &str, forgotten: bool) -> Result<(), StateError> {
+        let mut forgotten_rooms = self.forgotten_rooms.write().await;
+        let key = (user_id.to_string(), room_id.to_string());
+        if forgotten {
+            forgotten_rooms.insert(key);
+        } else {
+            forgotten_rooms.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn is_room_forgotten(&self, user_id: &str, room_id: &str) -> Result<bool, StateError> {
+        let forgotten_rooms = self.forgotten_rooms.read().await;
+        Ok(forgotten_rooms.contains(&(user_id.to_string(), room_id.to_string())))
+    }
+
+    async fn create_room_alias(&self, alias: &str, room_id: &str, creator: &str) -> Result<(), StateError> {
+        let mut room_aliases = self.room_aliases.write().await;
+        if room_aliases.contains_key(alias) {
+            return Err(StateError::StateConflict(format!("Alias {} already exists", alias)));
+        }
+        room_aliases.insert(alias.to_string(), RoomAlias {
+            room_id: room_id.to_string(),
+            creator: creator.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<RoomAlias>, StateError> {
+        let room_aliases = self.room_aliases.read().await;
+        Ok(room_aliases.get(alias).cloned())
+    }
+
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, StateError> {
+        let mut room_aliases = self.room_aliases.write().await;
+        Ok(room_aliases.remove(alias).is_some())
+    }
+
+    async fn get_room_aliases(&self, room_id: &str) -> Result<Vec<String>, StateError> {
+        let room_aliases = self.room_aliases.read().await;
+        Ok(room_aliases
+            .iter()
+            .filter(|(_, mapping)| mapping.room_id == room_id)
+            .map(|(alias, _)| alias.clone())
+            .collect())
+    }
+
+    async fn set_room_published(&self, room_id: &str, published: bool) -> Result<(), StateError> {
+        let mut published_rooms = self.published_rooms.write().await;
+        if published {
+            published_rooms.insert(room_id.to_string());
+        } else {
+            published_rooms.remove(room_id);
+        }
+        Ok(())
+    }
+
+    async fn is_room_published(&self, room_id: &str) -> Result<bool, StateError> {
+        let published_rooms = self.published_rooms.read().await;
+        Ok(published_rooms.contains(room_id))
+    }
+
+    async fn get_published_rooms(&self) -> Result<Vec<String>, StateError> {
+        let published_rooms = self.published_rooms.read().await;
+        Ok(published_rooms.iter().cloned().collect())
+    }
+
+    async fn set_account_data(
+        &self,
+        user_id: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+        content: serde_json::Value,
+    ) -> Result<u64, StateError> {
+        let mut account_data = self.account_data.write().await;
+        account_data.position += 1;
+        let position = account_data.position;
+        account_data.entries.insert(
+            (user_id.to_string(), room_id.map(str::to_string), data_type.to_string()),
+            (position, content),
+        );
+        Ok(position)
+    }
+
+    async fn get_account_data(
+        &self,
+        user_id: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+    ) -> Result<Option<serde_json::Value>, StateError> {
+        let account_data = self.account_data.read().await;
+        Ok(account_data.entries
+            .get(&(user_id.to_string(), room_id.map(str::to_string), data_type.to_string()))
+            .map(|(_, content)| content.clone()))
+    }
+
+    async fn get_account_data_changes(&self, user_id: &str, since: u64) -> Result<Vec<AccountData>, StateError> {
+        let account_data = self.account_data.read().await;
+        let mut changes: Vec<(u64, AccountData)> = account_data.entries
+            .iter()
+            .filter(|((owner, _, _), (position, _))| owner == user_id && *position > since)
+            .map(|((_, room_id, data_type), (position, content))| (*position, AccountData {
+                room_id: room_id.clone(),
+                data_type: data_type.clone(),
+                content: content.clone(),
+            }))
+            .collect();
+        changes.sort_by_key(|(position, _)| *position);
+        Ok(changes.into_iter().map(|(_, change)| change).collect())
+    }
+
+    async fn current_account_data_position(&self) -> Result<u64, StateError> {
+        Ok(self.account_data.read().await.position)
+    }
+
+    async fn set_receipt(&self, receipt: Receipt) -> Result<u64, StateError> {
+        let mut receipts = self.receipts.write().await;
+        receipts.position += 1;
+        let position = receipts.position;
+        receipts.entries.insert(
+            (
+                receipt.room_id.clone(),
+                receipt.receipt_type.clone(),
+                receipt.user_id.clone(),
+                receipt.thread_id.clone(),
+            ),
+            (position, receipt),
+        );
+        Ok(position)
+    }
+
+    async fn get_receipt(
+        &self,
+        room_id: &str,
+        receipt_type: &str,
+        user_id: &str,
+        thread_id: Option<&str>,
+    ) -> Result<Option<Receipt>, StateError> {
+        let receipts = self.receipts.read().await;
+        let key = (
+            room_id.to_string(),
+            receipt_type.to_string(),
+            user_id.to_string(),
+            thread_id.map(str::to_string),
+        );
+        Ok(receipts.entries.get(&key).map(|(_, receipt)| receipt.clone()))
+    }
+
+    async fn get_room_receipts(&self, room_id: &str, since: u64) -> Result<Vec<Receipt>, StateError> {
+        let receipts = self.receipts.read().await;
+        let mut changes: Vec<&(u64, Receipt)> = receipts.entries
+            .values()
+            .filter(|(position, receipt)| receipt.room_id == room_id && *position > since)
+            .collect();
+        changes.sort_by_key(|(position, _)| *position);
+        Ok(changes.into_iter().map(|(_, receipt)| receipt.clone()).collect())
+    }
+
+    async fn current_receipt_position(&self) -> Result<u64, StateError> {
+        Ok(self.receipts.read().await.position)
+    }
+
+    async fn add_push_action(&self, user_id: &str, action: PushAction) -> Result<(), StateError> {
+        let mut push_actions = self.push_actions.write().await;
+        let actions = push_actions.entry(user_id.to_string()).or_default();
+        let position = actions.partition_point(|existing| existing.stream_ordering <= action.stream_ordering);
+        actions.insert(position, action);
+        Ok(())
+    }
+
+    async fn get_push_actions(&self, user_id: &str, room_id: Option<&str>) -> Result<Vec<PushAction>, StateError> {
+        let push_actions = self.push_actions.read().await;
+        Ok(push_actions
+            .get(user_id)
+            .map(|actions| {
+                actions
+                    .iter()
+                    .filter(|action| room_id.is_none_or(|room_id| action.room_id == room_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_push_actions_after(&self, user_id: &str, after: u64) -> Result<Vec<PushAction>, StateError> {
+        let push_actions = self.push_actions.read().await;
+        Ok(push_actions
+            .get(user_id)
+            .map(|actions| {
+                let start = actions.partition_point(|action| action.stream_ordering <= after);
+                actions[start..].to_vec()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn index_search_terms(&self, room_id: &str, event_id: &str, key: &str, terms: Vec<String>) -> Result<(), StateError> {
+        let mut index = self.search_index.write().await;
+        for term in terms {
+            let occurrences = index.postings
+                .entry(term.clone())
+                .or_default()
+                .entry(room_id.to_string())
+                .or_default()
+                .entry((event_id.to_string(), key.to_string()))
+                .or_default();
+            *occurrences += 1;
+            index.event_terms
+                .entry(event_id.to_string())
+                .or_insert_with(|| (room_id.to_string(), HashSet::new()))
+                .1
+                .insert(term);
+        }
+        Ok(())
+    }
+
+    async fn remove_search_terms(&self, event_id: &str) -> Result<(), StateError> {
+        let mut index = self.search_index.write().await;
+        let Some((room_id, terms)) = index.event_terms.remove(event_id) else {
+            return Ok(());
+        };
+        for term in terms {
+            let Some(rooms) = index.postings.get_mut(&term) else {
+                continue;
+            };
+            if let Some(postings) = rooms.get_mut(&room_id) {
+                postings.retain(|(posted_id, _), _| posted_id != event_id);
+                if postings.is_empty() {
+                    rooms.remove(&room_id);
+                }
+            }
+            if rooms.is_empty() {
+                index.postings.remove(&term);
+            }
+        }
+        Ok(())
+    }
+
+    async fn search_term(&self, term: &str, keys: &[String], room_ids: &[String]) -> Result<HashMap<String, u32>, StateError> {
+        let index = self.search_index.read().await;
+        let mut matches: HashMap<String, u32> = HashMap::new();
+        let Some(rooms) = index.postings.get(term) else {
+            return Ok(matches);
+        };
+        for ((event_id, key), occurrences) in room_ids.iter().filter_map(|room_id| rooms.get(room_id)).flatten() {
+            if keys.contains(key) {
+                *matches.entry(event_id.clone()).or_default() += occurrences;
+            }
+        }
+        Ok(matches)
+    }
+
+    async fn add_visibility_change(&self, room_id: &str, ordering: u64, change: VisibilityChange) -> Result<(), StateError> {
+        let mut visibility_changes = self.visibility_changes.write().await;
+        visibility_changes.entry(room_id.to_string()).or_default().insert(ordering, change);
+        Ok(())
+    }
+
+    async fn get_visibility_changes(&self, room_id: &str) -> Result<Vec<(u64, VisibilityChange)>, StateError> {
+        let visibility_changes = self.visibility_changes.read().await;
+        Ok(visibility_changes
+            .get(room_id)
+            .map(|changes| changes.iter().map(|(ordering, change)| (*ordering, change.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_media(&self, record: MediaRecord) -> Result<(), StateError> {
+        let key = (record.server_name.clone(), record.media_id.clone());
+        self.media.write().await.insert(key, record);
+        Ok(())
+    }
+
+    async fn get_media(&self, server_name: &str, media_id: &str) -> Result<Option<MediaRecord>, StateError> {
+        let media = self.media.read().await;
+        Ok(media.get(&(server_name.to_string(), media_id.to_string())).cloned())
+    }
+
+    async fn get_user_media(&self, user_id: &str) -> Result<Vec<MediaRecord>, StateError> {
+        let media = self.media.read().await;
+        Ok(media
+            .values()
+            .filter(|record| record.uploader.as_deref() == Some(user_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_remote_media(&self, local_server_name: &str) -> Result<Vec<MediaRecord>, StateError> {
+        let media = self.media.read().await;
+        Ok(media
+            .values()
+            .filter(|record| record.server_name != local_server_name)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_media_by_hash(&self, content_hash: &str) -> Result<Vec<MediaRecord>, StateError> {
+        let media = self.media.read().await;
+        Ok(media
+            .values()
+            .filter(|record| record.content_hash.as_deref() == Some(content_hash))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_media(&self, server_name: &str, media_id: &str) -> Result<bool, StateError> {
+        let mut media = self.media.write().await;
+        Ok(media.remove(&(server_name.to_string(), media_id.to_string())).is_some())
+    }
+
+    async fn set_pusher(&self, pusher: Pusher) -> Result<(), StateError> {
+        let key = (pusher.user_id.clone(), pusher.app_id.clone(), pusher.pushkey.clone());
+        self.pushers.write().await.insert(key, pusher);
+        Ok(())
+    }
+
+    async fn delete_pusher(&self, user_id: &str, app_id: &str, pushkey: &str) -> Result<bool, StateError> {
+        let key = (user_id.to_string(), app_id.to_string(), pushkey.to_string());
+        Ok(self.pushers.write().await.remove(&key).is_some())
+    }
+
+    async fn get_pushers(&self, user_id: Option<&str>) -> Result<Vec<Pusher>, StateError> {
+        let pushers = self.pushers.read().await;
+        Ok(pushers
+            .values()
+            .filter(|pusher| user_id.is_none_or(|user_id| pusher.user_id == user_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn set_pusher_position(
+        &self,
+        user_id: &str,
+        app_id: &str,
+        pushkey: &str,
+        stream_ordering: u64,
+    ) -> Result<(), StateError> {
+        let key = (user_id.to_string(), app_id.to_string(), pushkey.to_string());
+        if let Some(pusher) = self.pushers.write().await.get_mut(&key) {
+            pusher.last_stream_ordering = pusher.last_stream_ordering.max(stream_ordering);
+        }
+        Ok(())
+    }
 }
 
 /// State conflict resolution
@@ -287,7 +1302,7 @@ impl StateResolver {
     /// Resolve state conflicts using Matrix state resolution algorithm
     pub fn resolve_state_conflicts(
         &self,
-        auth_events: &[MatrixEvent],
+        _auth_events: &[MatrixEvent],
         state_events: &[MatrixEvent],
     ) -> Result<Vec<MatrixEvent>, StateError> {
         // Simplified state resolution - in production this would implement
@@ -304,7 +1319,7 @@ impl StateResolver {
     pub fn validate_event_auth(
         &self,
         event: &MatrixEvent,
-        auth_events: &[MatrixEvent],
+        _auth_events: &[MatrixEvent],
         room_state: &RoomState,
     ) -> Result<(), StateError> {
         // Basic auth validation
@@ -327,7 +1342,7 @@ impl StateResolver {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::events::{MatrixEvent, EventType, EventContent, RoomMessageContent, MessageType};
+    use crate::events::{MatrixEvent, EventType, EventContent, MessageType};
 
     fn create_test_event() -> MatrixEvent {
         MatrixEvent::new(
@@ -459,6 +1474,22 @@ mod tests {
         assert!(!state.is_member("@nonmember:localhost"));
     }
 
+    #[test]
+    fn test_member_event_applies_to_state_key() {
+        let mut state = create_test_room_state();
+        let invite = MatrixEvent::new(
+            EventType::RoomMember,
+            EventContent::room_member(MembershipState::Invite, None),
+            "@creator:localhost".to_string(),
+            state.room_id.clone(),
+        ).with_state_key("@invitee:localhost".to_string());
+
+        state.apply_state_event(invite).unwrap();
+
+        assert_eq!(state.membership("@invitee:localhost"), Some(&MembershipState::Invite));
+        assert!(!state.is_member("@invitee:localhost"));
+    }
+
     #[test]
     fn test_room_state_serialization() {
         let state = create_test_room_state();
@@ -621,4 +1652,68 @@ mod tests {
             assert!(rooms.contains(&room_state2.room_id));
         });
     }
+
+    #[tokio::test]
+    async fn test_in_memory_state_store_rooms_for_user() {
+        let store = InMemoryStateStore::new();
+        let mut room_state = create_test_room_state();
+        store.create_room(room_state.clone()).await.unwrap();
+        assert!(store.get_rooms_for_user("@bob:localhost").await.unwrap().is_empty());
+
+        // Rooms stay indexed after leaving, so sync can still report the departure
+        for membership in [MembershipState::Join, MembershipState::Leave] {
+            room_state.members.insert("@bob:localhost".to_string(), membership);
+            store.update_room(room_state.clone()).await.unwrap();
+            assert_eq!(store.get_rooms_for_user("@bob:localhost").await.unwrap(), vec![room_state.room_id.clone()]);
+        }
+
+        store.delete_room(&room_state.room_id).await.unwrap();
+        assert!(store.get_rooms_for_user("@bob:localhost").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_state_store_timeline_pagination() {
+        let store = InMemoryStateStore::new();
+        let mut event_ids = Vec::new();
+        for _ in 0..3 {
+            let event = create_test_event();
+            event_ids.push(event.event_id.clone());
+            store.append_event(event).await.unwrap();
+        }
+
+        assert_eq!(store.current_stream_position().await.unwrap(), 3);
+        assert_eq!(store.get_stream_ordering(&event_ids[1]).await.unwrap(), Some(2));
+
+        let forward = store.get_room_events("!test:localhost", 1, Direction::Forward, 10).await.unwrap();
+        assert_eq!(forward.iter().map(|(o, _)| *o).collect::<Vec<_>>(), vec![2, 3]);
+
+        let backward = store.get_room_events("!test:localhost", 2, Direction::Backward, 10).await.unwrap();
+        assert_eq!(backward.iter().map(|(o, _)| *o).collect::<Vec<_>>(), vec![2, 1]);
+
+        let other_room = store.get_room_events("!other:localhost", 0, Direction::Forward, 10).await.unwrap();
+        assert!(other_room.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_state_store_rejects_duplicate_events() {
+        let store = InMemoryStateStore::new();
+        let event = create_test_event();
+
+        store.append_event(event.clone()).await.unwrap();
+        assert!(store.append_event(event).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_state_store_purge_redacted_originals() {
+        let store = InMemoryStateStore::new();
+        let old = create_test_event();
+        let recent = create_test_event();
+
+        store.retain_redacted_original(old.clone(), 1_000).await.unwrap();
+        store.retain_redacted_original(recent.clone(), 5_000).await.unwrap();
+
+        assert_eq!(store.purge_redacted_originals(2_000).await.unwrap(), 1);
+        assert!(store.get_redacted_original(&old.event_id).await.unwrap().is_none());
+        assert!(store.get_redacted_original(&recent.event_id).await.unwrap().is_some());
+    }
 }
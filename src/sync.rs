@@ -0,0 +1,677 @@
+// Client Sync Handler
+// Builds /sync responses from the stored room timelines
+// Focus: Incremental timeline delivery per joined room
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthenticatedUser;
+use crate::events::{EventType, MatrixEvent, MembershipState};
+use crate::presence::{PresenceHandler, PresenceState, PRESENCE_EDU_TYPE};
+use crate::push_rules::{merge_thread_counts, notification_counts, NotificationCounts};
+use crate::receipts::{receipt_event_content, RECEIPT_EDU_TYPE};
+use crate::relations::bundle_aggregations;
+use crate::room::{format_stream_token, RoomError};
+use crate::state::{Direction, RoomState, StateStore};
+use crate::typing::TypingHandler;
+use crate::visibility::{Viewer, VisibilityFilter};
+
+/// Default number of timeline events per room in a sync response
+const DEFAULT_TIMELINE_LIMIT: usize = 10;
+
+/// Position a sync has reached in each stream, as `s{events}_{account_data}_{typing}_{receipts}_{presence}`
+///
+/// Room pagination tokens only read the leading events position, so a
+/// `next_batch` can also be used as a /messages `from`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncToken {
+    pub events: u64,
+    pub account_data: u64,
+    pub typing: u64,
+    pub receipts: u64,
+    pub presence: u64,
+}
+
+impl SyncToken {
+    /// Parse a sync token; streams missing from older tokens start from zero
+    pub fn parse(token: &str) -> Result<Self, RoomError> {
+        let invalid = || RoomError::InvalidRoomConfig(format!("Invalid sync token: {}", token));
+        let mut positions = token.strip_prefix('s').ok_or_else(invalid)?.split('_');
+        let mut next_position = || -> Result<u64, RoomError> {
+            positions.next().map_or(Ok(0), |position| position.parse().map_err(|_| invalid()))
+        };
+        Ok(Self {
+            events: next_position()?,
+            account_data: next_position()?,
+            typing: next_position()?,
+            receipts: next_position()?,
+            presence: next_position()?,
+        })
+    }
+}
+
+impl std::fmt::Display for SyncToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "s{}_{}_{}_{}_{}",
+            self.events, self.account_data, self.typing, self.receipts, self.presence,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub since: Option<String>,
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub full_state: bool,
+    pub timeline_limit: Option<usize>,
+    /// Presence the sync implies; `offline` syncs without appearing online
+    #[serde(default)]
+    pub set_presence: Option<PresenceState>,
+    /// Report unread notifications per thread instead of folding them into the room's counts
+    #[serde(default)]
+    pub unread_thread_notifications: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    pub rooms: SyncRooms,
+    pub presence: EventList,
+    pub account_data: EventList,
+    pub to_device: EventList,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncRooms {
+    pub join: HashMap<String, JoinedRoom>,
+    pub invite: HashMap<String, InvitedRoom>,
+    pub knock: HashMap<String, KnockedRoom>,
+    pub leave: HashMap<String, LeftRoom>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JoinedRoom {
+    pub timeline: Timeline,
+    pub state: StateEvents,
+    pub ephemeral: EventList,
+    pub account_data: EventList,
+    pub unread_notifications: NotificationCounts,
+    /// Thread root -> unread notifications in that thread
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub unread_thread_notifications: HashMap<String, NotificationCounts>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InvitedRoom {
+    /// Stripped state describing the room to the invitee
+    pub invite_state: EventList,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KnockedRoom {
+    /// Stripped state describing the room to the knocking user
+    pub knock_state: EventList,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LeftRoom {
+    pub timeline: Timeline,
+    pub state: StateEvents,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timeline {
+    pub events: Vec<MatrixEvent>,
+    pub limited: bool,
+    pub prev_batch: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateEvents {
+    pub events: Vec<MatrixEvent>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventList {
+    pub events: Vec<serde_json::Value>,
+}
+
+/// Sync handler - assembles per-user sync responses
+pub struct SyncHandler {
+    state_store: Arc<dyn StateStore + Send + Sync>,
+    typing_handler: Arc<TypingHandler>,
+    presence_handler: Arc<PresenceHandler>,
+}
+
+impl SyncHandler {
+    pub fn new(state_store: Arc<dyn StateStore + Send + Sync>) -> Self {
+        Self {
+            state_store,
+            typing_handler: Arc::new(TypingHandler::new()),
+            presence_handler: Arc::new(PresenceHandler::default()),
+        }
+    }
+
+    /// Deliver typing notifications from a shared typing handler
+    pub fn with_typing_handler(mut self, typing_handler: Arc<TypingHandler>) -> Self {
+        self.typing_handler = typing_handler;
+        self
+    }
+
+    /// Deliver presence from a shared presence handler
+    pub fn with_presence_handler(mut self, presence_handler: Arc<PresenceHandler>) -> Self {
+        self.presence_handler = presence_handler;
+        self
+    }
+
+    /// Sync the rooms a user has joined since the given token
+    pub async fn sync(
+        &self,
+        user: &AuthenticatedUser,
+        request: SyncRequest,
+    ) -> Result<SyncResponse, RoomError> {
+        let since_token = request.since.as_deref().map(SyncToken::parse).transpose()?;
+        let since = since_token.map(|token| token.events);
+        let limit = request.timeline_limit.unwrap_or(DEFAULT_TIMELINE_LIMIT);
+        let position = self.state_store.current_stream_position().await?;
+        let next_batch = SyncToken {
+            events: position,
+            account_data: self.state_store.current_account_data_position().await?,
+            typing: self.typing_handler.current_position().await,
+            receipts: self.state_store.current_receipt_position().await?,
+            presence: self.presence_handler.current_position().await,
+        };
+
+        // Account data changed since the last sync, global and per room
+        let mut account_data = EventList::default();
+        let mut room_account_data: HashMap<String, EventList> = HashMap::new();
+        let account_data_since = since_token.map_or(0, |token| token.account_data);
+        for change in self.state_store.get_account_data_changes(&user.user_id, account_data_since).await? {
+            let event = serde_json::json!({ "type": change.data_type, "content": change.content });
+            match change.room_id {
+                Some(room_id) => room_account_data.entry(room_id).or_default().events.push(event),
+                None => account_data.events.push(event),
+            }
+        }
+
+        // Rooms whose typing users changed; an initial sync reports anyone typing
+        let typing_changed: HashSet<String> = match since_token {
+            Some(token) => self.typing_handler.rooms_changed_since(token.typing).await.into_iter().collect(),
+            None => HashSet::new(),
+        };
+
+        // Users sharing a joined room with the syncing user, whose presence they see
+        let mut presence_users = HashSet::from([user.user_id.clone()]);
+
+        let mut rooms = SyncRooms::default();
+        for room_id in self.state_store.get_rooms_for_user(&user.user_id).await? {
+            let Some(room_state) = self.state_store.get_room(&room_id).await? else {
+                continue;
+            };
+            match room_state.membership(&user.user_id) {
+                Some(MembershipState::Join) => {}
+                Some(MembershipState::Invite) => {
+                    if let Some(invite_state) = self.stripped_state(&room_state, &user.user_id, since).await? {
+                        rooms.invite.insert(room_id, InvitedRoom { invite_state });
+                    }
+                    continue;
+                }
+                Some(MembershipState::Knock) => {
+                    if let Some(knock_state) = self.stripped_state(&room_state, &user.user_id, since).await? {
+                        rooms.knock.insert(room_id, KnockedRoom { knock_state });
+                    }
+                    continue;
+                }
+                _ => {
+                    if let Some(left) = self.left_room(&room_state, &user.user_id, since, limit).await? {
+                        rooms.leave.insert(room_id, left);
+                    }
+                    continue;
+                }
+            }
+
+            presence_users.extend(room_state.joined_members().into_keys());
+
+            // Fetch one extra event to detect whether the timeline was cut short
+            let mut events = self.state_store
+                .get_room_events(&room_id, position, Direction::Backward, limit + 1)
+                .await?;
+            if let Some(since) = since {
+                events.retain(|(ordering, _)| *ordering > since);
+            }
+            let visibility = VisibilityFilter::load(&*self.state_store, &room_state, Viewer::User(&user.user_id)).await?;
+            events.retain(|(ordering, _)| visibility.is_visible(*ordering));
+            let limited = events.len() > limit;
+            events.truncate(limit);
+            events.reverse();
+
+            let room_account_data = room_account_data.remove(&room_id).unwrap_or_default();
+            let mut ephemeral = EventList::default();
+            let typing_users = self.typing_handler.typing_users(&room_id).await;
+            if typing_changed.contains(&room_id) || (since.is_none() && !typing_users.is_empty()) {
+                ephemeral.events.push(serde_json::json!({
+                    "type": "m.typing",
+                    "content": { "user_ids": typing_users },
+                }));
+            }
+            let receipts_since = since_token.map_or(0, |token| token.receipts);
+            let receipts = self.state_store.get_room_receipts(&room_id, receipts_since).await?;
+            if let Some(content) = receipt_event_content(&receipts, &user.user_id) {
+                ephemeral.events.push(serde_json::json!({ "type": RECEIPT_EDU_TYPE, "content": content }));
+            }
+            if since.is_some()
+                && events.is_empty()
+                && room_account_data.events.is_empty()
+                && ephemeral.events.is_empty()
+                && !request.full_state
+            {
+                continue;
+            }
+
+            let prev_batch = events
+                .first()
+                .map(|(ordering, _)| format_stream_token(ordering - 1));
+            let timeline_ids: HashSet<&str> = events
+                .iter()
+                .map(|(_, event)| event.event_id.as_str())
+                .collect();
+
+            // Rooms joined since the last sync are new to the client, so it needs their whole state
+            let joined_since = match (since, room_state.get_state_event(&EventType::RoomMember, &user.user_id)) {
+                (Some(since), Some(member)) => self.state_store
+                    .get_stream_ordering(&member.event_id)
+                    .await?
+                    .is_some_and(|ordering| ordering > since),
+                _ => false,
+            };
+
+            // Initial, gappy, full-state and newly joined syncs carry the state not already in the timeline
+            let state = if since.is_none() || limited || request.full_state || joined_since {
+                room_state.state_events
+                    .values()
+                    .filter(|event| !timeline_ids.contains(event.event_id.as_str()))
+                    .cloned()
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            let mut timeline_events: Vec<MatrixEvent> = events.into_iter().map(|(_, event)| event).collect();
+            bundle_aggregations(&*self.state_store, &user.user_id, &mut timeline_events).await?;
+
+            let (mut unread_notifications, mut unread_thread_notifications) =
+                notification_counts(&*self.state_store, &user.user_id, &room_id).await?;
+            if !request.unread_thread_notifications {
+                merge_thread_counts(&mut unread_notifications, std::mem::take(&mut unread_thread_notifications));
+            }
+
+            rooms.join.insert(room_id, JoinedRoom {
+                timeline: Timeline {
+                    events: timeline_events,
+                    limited,
+                    prev_batch,
+                },
+                state: StateEvents { events: state },
+                ephemeral,
+                account_data: room_account_data,
+                unread_notifications,
+                unread_thread_notifications,
+            });
+        }
+
+        let presence_since = since_token.map_or(0, |token| token.presence);
+        let presence = EventList {
+            events: self.presence_handler
+                .changes_since(&presence_users, presence_since)
+                .await
+                .into_iter()
+                .map(|update| serde_json::json!({
+                    "type": PRESENCE_EDU_TYPE,
+                    "sender": update.user_id,
+                    "content": update.status,
+                }))
+                .collect(),
+        };
+
+        Ok(SyncResponse {
+            next_batch: next_batch.to_string(),
+            rooms,
+            presence,
+            account_data,
+            ..Default::default()
+        })
+    }
+
+    /// Stripped room state for a pending invite or knock of `user_id`,
+    /// unless it was already delivered before `since`
+    async fn stripped_state(
+        &self,
+        room_state: &RoomState,
+        user_id: &str,
+        since: Option<u64>,
+    ) -> Result<Option<EventList>, RoomError> {
+        let Some(membership) = room_state.get_state_event(&EventType::RoomMember, user_id) else {
+            return Ok(None);
+        };
+        let ordering = self.state_store.get_stream_ordering(&membership.event_id).await?.unwrap_or(0);
+        if since.is_some_and(|since| ordering <= since) {
+            return Ok(None);
+        }
+
+        let stripped = [EventType::RoomCreate, EventType::RoomJoinRules, EventType::RoomName, EventType::RoomTopic]
+            .iter()
+            .filter_map(|event_type| room_state.get_state_event(event_type, ""))
+            .chain(std::iter::once(membership))
+            .map(|event| serde_json::json!({
+                "type": event.event_type,
+                "state_key": event.state_key,
+                "content": event.content,
+                "sender": event.sender,
+            }))
+            .collect();
+
+        Ok(Some(EventList { events: stripped }))
+    }
+
+    /// Room `user_id` left or was removed from since `since`, up to their departure
+    ///
+    /// Initial syncs and forgotten rooms leave it out.
+    async fn left_room(
+        &self,
+        room_state: &RoomState,
+        user_id: &str,
+        since: Option<u64>,
+        limit: usize,
+    ) -> Result<Option<LeftRoom>, RoomError> {
+        let Some(since) = since else {
+            return Ok(None);
+        };
+        let Some(departure) = room_state.get_state_event(&EventType::RoomMember, user_id) else {
+            return Ok(None);
+        };
+        let Some(left_at) = self.state_store.get_stream_ordering(&departure.event_id).await? else {
+            return Ok(None);
+        };
+        if left_at <= since || self.state_store.is_room_forgotten(user_id, &room_state.room_id).await? {
+            return Ok(None);
+        }
+
+        let visibility = VisibilityFilter::load(&*self.state_store, room_state, Viewer::User(user_id)).await?;
+        let mut events = self.state_store
+            .get_room_events(&room_state.room_id, left_at, Direction::Backward, limit + 1)
+            .await?;
+        // The departure itself is always theirs to see
+        events.retain(|(ordering, _)| *ordering > since && (*ordering == left_at || visibility.is_visible(*ordering)));
+        let limited = events.len() > limit;
+        events.truncate(limit);
+        events.reverse();
+
+        let prev_batch = events
+            .first()
+            .map(|(ordering, _)| format_stream_token(ordering - 1));
+        Ok(Some(LeftRoom {
+            timeline: Timeline {
+                events: events.into_iter().map(|(_, event)| event).collect(),
+                limited,
+                prev_batch,
+            },
+            state: StateEvents::default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventContent;
+    use crate::room::{
+        JoinRoomRequest, LeaveRoomRequest, MembershipChangeRequest, RedactEventRequest, RoomConfig, RoomHandler,
+        SendStateEventRequest,
+    };
+    use crate::state::InMemoryStateStore;
+    use crate::tests::{test_user, text_message};
+
+    #[tokio::test]
+    async fn test_incremental_sync_returns_new_events() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store);
+        let alice = test_user("@alice:localhost");
+
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+        rooms.send_message(&alice, text_message(&room_id, "first")).await.unwrap();
+
+        let initial = sync.sync(&alice, SyncRequest::default()).await.unwrap();
+        let timeline = &initial.rooms.join[&room_id].timeline.events;
+        assert!(timeline.first().is_some_and(|event| event.event_type == EventType::RoomCreate));
+        assert!(timeline.last().is_some_and(|event| matches!(&event.content, EventContent::RoomMessage(c) if c.body == "first")));
+
+        rooms.send_message(&alice, text_message(&room_id, "second")).await.unwrap();
+        let incremental = sync.sync(&alice, SyncRequest {
+            since: Some(initial.next_batch.clone()),
+            ..Default::default()
+        }).await.unwrap();
+
+        let timeline = &incremental.rooms.join[&room_id].timeline;
+        assert_eq!(timeline.events.len(), 1);
+        assert!(!timeline.limited);
+
+        let idle = sync.sync(&alice, SyncRequest {
+            since: Some(incremental.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(idle.rooms.join.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_limits_timeline() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store);
+        let alice = test_user("@alice:localhost");
+
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+        for i in 0..5 {
+            rooms.send_message(&alice, text_message(&room_id, &format!("message {}", i))).await.unwrap();
+        }
+
+        let response = sync.sync(&alice, SyncRequest {
+            timeline_limit: Some(3),
+            ..Default::default()
+        }).await.unwrap();
+
+        let timeline = &response.rooms.join[&room_id].timeline;
+        assert_eq!(timeline.events.len(), 3);
+        assert!(timeline.limited);
+        assert!(timeline.prev_batch.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_sync_delivers_redactions() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store);
+        let alice = test_user("@alice:localhost");
+
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+        let event_id = rooms.send_message(&alice, text_message(&room_id, "oops")).await.unwrap().event_id;
+        let initial = sync.sync(&alice, SyncRequest::default()).await.unwrap();
+
+        rooms.redact_event(&alice, RedactEventRequest {
+            room_id: room_id.clone(),
+            event_id: event_id.clone(),
+            reason: None,
+        }).await.unwrap();
+
+        let response = sync.sync(&alice, SyncRequest {
+            since: Some(initial.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        let timeline = &response.rooms.join[&room_id].timeline;
+        assert_eq!(timeline.events.len(), 1);
+        assert_eq!(timeline.events[0].event_type, crate::events::EventType::RoomRedaction);
+
+        let full = sync.sync(&alice, SyncRequest::default()).await.unwrap();
+        let original = full.rooms.join[&room_id].timeline.events
+            .iter()
+            .find(|event| event.event_id == event_id)
+            .unwrap();
+        assert!(original.is_redacted());
+    }
+
+    #[tokio::test]
+    async fn test_sync_reports_invites_and_kicks() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+        let invite = |user_id: &str| MembershipChangeRequest {
+            room_id: room_id.clone(),
+            user_id: user_id.to_string(),
+            reason: None,
+        };
+        rooms.invite_user(&alice, invite("@bob:localhost")).await.unwrap();
+
+        let initial = sync.sync(&bob, SyncRequest::default()).await.unwrap();
+        let invite_state = &initial.rooms.invite[&room_id].invite_state.events;
+        assert_eq!(invite_state.last().unwrap()["content"]["membership"], "invite");
+        assert!(initial.rooms.join.is_empty());
+
+        rooms.join_room(&bob, JoinRoomRequest { room_id: room_id.clone(), reason: None }).await.unwrap();
+        let joined = sync.sync(&bob, SyncRequest {
+            since: Some(initial.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        let state = &joined.rooms.join[&room_id].state.events;
+        for event_type in [EventType::RoomCreate, EventType::RoomPowerLevels, EventType::RoomJoinRules] {
+            assert!(state.iter().any(|event| event.event_type == event_type), "missing {}", event_type.as_str());
+        }
+        // Later incremental syncs only carry what changed
+        rooms.send_message(&alice, text_message(&room_id, "welcome")).await.unwrap();
+        let next = sync.sync(&bob, SyncRequest {
+            since: Some(joined.next_batch.clone()),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(next.rooms.join[&room_id].state.events.is_empty());
+
+        rooms.kick_user(&alice, invite("@bob:localhost")).await.unwrap();
+        let kicked = sync.sync(&bob, SyncRequest {
+            since: Some(joined.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        let timeline = &kicked.rooms.leave[&room_id].timeline;
+        assert_eq!(timeline.events.last().unwrap().state_key.as_deref(), Some("@bob:localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_left_room_timeline_respects_history_visibility() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store);
+        let alice = test_user("@alice:localhost");
+        let bob = test_user("@bob:localhost");
+
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+        rooms.send_state_event(&alice, SendStateEventRequest {
+            room_id: room_id.clone(),
+            event_type: "m.room.history_visibility".to_string(),
+            state_key: String::new(),
+            content: serde_json::json!({ "history_visibility": "joined" }),
+        }).await.unwrap();
+        rooms.invite_user(&alice, MembershipChangeRequest {
+            room_id: room_id.clone(),
+            user_id: bob.user_id.clone(),
+            reason: None,
+        }).await.unwrap();
+        let initial = sync.sync(&bob, SyncRequest::default()).await.unwrap();
+
+        // Bob never joins, so the message stays hidden when he rejects the invite
+        rooms.send_message(&alice, text_message(&room_id, "members only")).await.unwrap();
+        rooms.leave_room(&bob, LeaveRoomRequest { room_id: room_id.clone(), reason: None }).await.unwrap();
+        let left = sync.sync(&bob, SyncRequest {
+            since: Some(initial.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        let timeline = &left.rooms.leave[&room_id].timeline;
+        assert_eq!(timeline.events.len(), 1);
+        assert_eq!(timeline.events[0].state_key.as_deref(), Some("@bob:localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_delivers_account_data_changes() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+
+        store.set_account_data(&alice.user_id, None, "org.example.prefs", serde_json::json!({ "theme": "dark" })).await.unwrap();
+        let initial = sync.sync(&alice, SyncRequest::default()).await.unwrap();
+        assert_eq!(initial.account_data.events[0]["type"], "org.example.prefs");
+
+        let tags = serde_json::json!({ "tags": { "u.support.open": {} } });
+        store.set_account_data(&alice.user_id, Some(&room_id), "m.tag", tags.clone()).await.unwrap();
+        let incremental = sync.sync(&alice, SyncRequest {
+            since: Some(initial.next_batch.clone()),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(incremental.account_data.events.is_empty());
+        let joined = &incremental.rooms.join[&room_id];
+        assert!(joined.timeline.events.is_empty());
+        assert_eq!(joined.account_data.events, vec![serde_json::json!({ "type": "m.tag", "content": tags })]);
+
+        let idle = sync.sync(&alice, SyncRequest {
+            since: Some(incremental.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(idle.rooms.join.is_empty() && idle.account_data.events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_delivers_typing_as_ephemeral() {
+        let store: Arc<dyn StateStore + Send + Sync> = Arc::new(InMemoryStateStore::new());
+        let typing = Arc::new(TypingHandler::new());
+        let rooms = RoomHandler::new(store.clone());
+        let sync = SyncHandler::new(store.clone()).with_typing_handler(typing.clone());
+        let alice = test_user("@alice:localhost");
+        let room_id = rooms.create_room(&alice, RoomConfig::default()).await.unwrap().room_id;
+
+        let timeout = std::time::Duration::from_secs(30);
+        typing.set_typing(&room_id, "@alice:localhost", true, timeout).await;
+        let initial = sync.sync(&alice, SyncRequest::default()).await.unwrap();
+        let typing_event = serde_json::json!({ "type": "m.typing", "content": { "user_ids": ["@alice:localhost"] } });
+        assert_eq!(initial.rooms.join[&room_id].ephemeral.events, vec![typing_event]);
+
+        typing.set_typing(&room_id, "@alice:localhost", false, timeout).await;
+        let incremental = sync.sync(&alice, SyncRequest {
+            since: Some(initial.next_batch.clone()),
+            ..Default::default()
+        }).await.unwrap();
+        let joined = &incremental.rooms.join[&room_id];
+        assert!(joined.timeline.events.is_empty());
+        assert_eq!(joined.ephemeral.events[0]["content"]["user_ids"], serde_json::json!([]));
+
+        let idle = sync.sync(&alice, SyncRequest {
+            since: Some(incremental.next_batch),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(idle.rooms.join.is_empty());
+    }
+
+    #[test]
+    fn test_sync_token_round_trip() {
+        let token = SyncToken { events: 12, account_data: 3, typing: 7, receipts: 2, presence: 5 };
+        assert_eq!(token.to_string(), "s12_3_7_2_5");
+        assert_eq!(SyncToken::parse("s12_3_7_2_5").unwrap(), token);
+        assert_eq!(SyncToken::parse("s12").unwrap(), SyncToken { events: 12, ..Default::default() });
+        assert!(SyncToken::parse("t12_3").is_err());
+        assert!(SyncToken::parse("s12_x").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+// Typing Notifications
+// Who is typing in each room, expiring on server-side timers
+// Focus: The in-memory typing stream read by sync and fed by federation
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+/// EDU type of typing notifications between servers
+pub const TYPING_EDU_TYPE: &str = "m.typing";
+
+/// How long a typing notification lasts when the client gives no timeout
+pub const DEFAULT_TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest a client may ask to be shown as typing
+pub const MAX_TYPING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Content of an `m.typing` EDU: one user starting or stopping typing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingEdu {
+    pub room_id: String,
+    pub user_id: String,
+    pub typing: bool,
+}
+
+#[derive(Default)]
+struct RoomTyping {
+    /// Stream position of the last change to this room's typing users
+    position: u64,
+    /// user ID -> when their notification expires
+    users: HashMap<String, Instant>,
+}
+
+#[derive(Default)]
+struct TypingStream {
+    position: u64,
+    rooms: HashMap<String, RoomTyping>,
+}
+
+impl TypingStream {
+    fn bump(&mut self, room_id: &str) {
+        self.position += 1;
+        let position = self.position;
+        self.rooms.entry(room_id.to_string()).or_default().position = position;
+    }
+}
+
+/// Tracks typing users per room; changes advance a stream position sync reads
+#[derive(Default)]
+pub struct TypingHandler {
+    stream: Arc<RwLock<TypingStream>>,
+}
+
+impl TypingHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start or stop showing `user_id` as typing in `room_id`
+    ///
+    /// Typing lasts `timeout` unless refreshed; a timer clears it once the
+    /// latest deadline has passed.
+    pub async fn set_typing(&self, room_id: &str, user_id: &str, typing: bool, timeout: Duration) {
+        let mut stream = self.stream.write().await;
+        let users = &mut stream.rooms.entry(room_id.to_string()).or_default().users;
+        let changed = if typing {
+            users.insert(user_id.to_string(), Instant::now() + timeout).is_none()
+        } else {
+            users.remove(user_id).is_some()
+        };
+        if changed {
+            stream.bump(room_id);
+        }
+        drop(stream);
+
+        if typing {
+            let stream = self.stream.clone();
+            let room_id = room_id.to_string();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                expire(&stream, &room_id).await;
+            });
+        }
+    }
+
+    /// Users currently typing in `room_id`, sorted
+    pub async fn typing_users(&self, room_id: &str) -> Vec<String> {
+        let stream = self.stream.read().await;
+        let mut users: Vec<String> = stream.rooms
+            .get(room_id)
+            .map(|room| room.users.keys().cloned().collect())
+            .unwrap_or_default();
+        users.sort();
+        users
+    }
+
+    /// Rooms whose typing users changed after stream position `since`
+    pub async fn rooms_changed_since(&self, since: u64) -> Vec<String> {
+        let stream = self.stream.read().await;
+        stream.rooms
+            .iter()
+            .filter(|(_, room)| room.position > since)
+            .map(|(room_id, _)| room_id.clone())
+            .collect()
+    }
+
+    /// Highest typing stream position handed out so far
+    pub async fn current_position(&self) -> u64 {
+        self.stream.read().await.position
+    }
+}
+
+/// Drop users whose typing deadline has passed
+async fn expire(stream: &RwLock<TypingStream>, room_id: &str) {
+    let mut stream = stream.write().await;
+    let now = Instant::now();
+    let Some(room) = stream.rooms.get_mut(room_id) else {
+        return;
+    };
+    let before = room.users.len();
+    room.users.retain(|_, deadline| *deadline > now);
+    if room.users.len() != before {
+        stream.bump(room_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM_ID: &str = "!room:localhost";
+
+    #[tokio::test]
+    async fn test_typing_changes_advance_the_stream() {
+        let typing = TypingHandler::new();
+        typing.set_typing(ROOM_ID, "@alice:localhost", true, DEFAULT_TYPING_TIMEOUT).await;
+        let started = typing.current_position().await;
+        assert_eq!(typing.typing_users(ROOM_ID).await, vec!["@alice:localhost"]);
+
+        // Refreshing an ongoing notification is not a change
+        typing.set_typing(ROOM_ID, "@alice:localhost", true, DEFAULT_TYPING_TIMEOUT).await;
+        assert_eq!(typing.current_position().await, started);
+        assert!(typing.rooms_changed_since(started).await.is_empty());
+
+        typing.set_typing(ROOM_ID, "@alice:localhost", false, DEFAULT_TYPING_TIMEOUT).await;
+        assert!(typing.typing_users(ROOM_ID).await.is_empty());
+        assert_eq!(typing.rooms_changed_since(started).await, vec![ROOM_ID]);
+    }
+
+    #[tokio::test]
+    async fn test_typing_expires_after_timeout() {
+        let typing = TypingHandler::new();
+        typing.set_typing(ROOM_ID, "@alice:localhost", true, Duration::from_millis(20)).await;
+        typing.set_typing(ROOM_ID, "@bob:localhost", true, DEFAULT_TYPING_TIMEOUT).await;
+        let position = typing.current_position().await;
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(typing.typing_users(ROOM_ID).await, vec!["@bob:localhost"]);
+        assert!(typing.current_position().await > position);
+    }
+}
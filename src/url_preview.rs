@@ -0,0 +1,511 @@
+// URL Previews
+// OpenGraph metadata of web pages linked in messages
+// Focus: Fetching untrusted URLs without exposing internal networks
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use ipnet::IpNet;
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::media::{MediaError, MediaRepository};
+use crate::presence::now_millis;
+
+/// Address ranges previews never fetch from unless whitelisted: loopback,
+/// private, link-local, carrier-grade NAT, translation, documentation,
+/// multicast and other non-public networks
+pub const DEFAULT_IP_RANGE_BLACKLIST: [&str; 22] = [
+    "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16", "172.16.0.0/12",
+    "192.0.0.0/24", "192.0.2.0/24", "192.168.0.0/16", "198.18.0.0/15", "198.51.100.0/24",
+    "203.0.113.0/24", "224.0.0.0/4", "240.0.0.0/4", "::/128", "::1/128", "::ffff:0:0/96",
+    "64:ff9b::/96", "2001:db8::/32", "fe80::/10", "fc00::/7", "ff00::/8",
+];
+
+/// Previews of a URL are reused for timestamps in the same bucket
+pub const PREVIEW_CACHE_BUCKET: Duration = Duration::from_secs(60 * 60);
+
+/// Buckets before the current one whose previews are still kept
+const PREVIEW_CACHE_BUCKETS_KEPT: u64 = 24;
+
+pub const DEFAULT_MAX_CACHED_PREVIEWS: usize = 1000;
+
+pub const DEFAULT_MAX_SPIDER_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_PREVIEW_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+/// Longest `og:description` returned
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+const PREVIEW_USER_AGENT: &str = "matrix-chat-system URL preview";
+
+#[derive(Debug, Clone)]
+pub struct UrlPreviewConfig {
+    /// Addresses previews may not fetch from
+    pub ip_range_blacklist: Vec<IpNet>,
+    /// Exceptions to the blacklist
+    pub ip_range_whitelist: Vec<IpNet>,
+    /// Largest page or image fetched, in bytes
+    pub max_spider_size: usize,
+    /// Longest a whole preview, redirects and image included, may take
+    pub timeout: Duration,
+    pub max_redirects: usize,
+    /// Most previews kept in memory; the least recently used go first
+    pub max_cached_previews: usize,
+}
+
+impl Default for UrlPreviewConfig {
+    fn default() -> Self {
+        Self {
+            ip_range_blacklist: default_ip_range_blacklist(),
+            ip_range_whitelist: Vec::new(),
+            max_spider_size: DEFAULT_MAX_SPIDER_SIZE,
+            timeout: DEFAULT_PREVIEW_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            max_cached_previews: DEFAULT_MAX_CACHED_PREVIEWS,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UrlPreviewError {
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("URL is not allowed: {0}")]
+    Forbidden(String),
+
+    #[error("Content exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+
+    #[error("Too many redirects")]
+    TooManyRedirects,
+
+    #[error("Timed out fetching the URL")]
+    Timeout,
+
+    #[error("Failed to fetch the URL: {0}")]
+    Fetch(String),
+
+    #[error("Media error: {0}")]
+    Media(#[from] MediaError),
+}
+
+impl UrlPreviewError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            UrlPreviewError::InvalidUrl(_) => 400,
+            UrlPreviewError::Forbidden(_) => 403,
+            UrlPreviewError::TooLarge(_) => 502,
+            UrlPreviewError::TooManyRedirects => 502,
+            UrlPreviewError::Timeout => 504,
+            UrlPreviewError::Fetch(_) => 502,
+            UrlPreviewError::Media(media_err) => media_err.status_code(),
+        }
+    }
+
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            UrlPreviewError::InvalidUrl(_) => "M_INVALID_PARAM",
+            UrlPreviewError::Forbidden(_) => "M_FORBIDDEN",
+            UrlPreviewError::TooLarge(_) => "M_TOO_LARGE",
+            UrlPreviewError::TooManyRedirects => "M_UNKNOWN",
+            UrlPreviewError::Timeout => "M_UNKNOWN",
+            UrlPreviewError::Fetch(_) => "M_UNKNOWN",
+            UrlPreviewError::Media(media_err) => media_err.error_code(),
+        }
+    }
+}
+
+/// Query of GET /preview_url
+#[derive(Debug, Clone, Deserialize)]
+pub struct PreviewUrlQuery {
+    pub url: String,
+    /// Preferred time of the preview, in milliseconds since the Unix epoch
+    pub ts: Option<u64>,
+}
+
+/// Previews by (URL, time bucket), evicting the least recently used
+#[derive(Default)]
+struct PreviewCache {
+    /// (URL, time bucket) -> (preview, last use)
+    entries: HashMap<(String, u64), (Value, u64)>,
+    /// Incremented on every use, so entries order by recency
+    clock: u64,
+}
+
+impl PreviewCache {
+    fn get(&mut self, key: &(String, u64)) -> Option<Value> {
+        self.clock += 1;
+        let (preview, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.clock;
+        Some(preview.clone())
+    }
+
+    /// Insert a preview, dropping buckets before `oldest_bucket` and then
+    /// the least recently used previews beyond `max_entries`
+    fn insert(&mut self, key: (String, u64), preview: Value, oldest_bucket: u64, max_entries: usize) {
+        self.entries.retain(|(_, bucket), _| *bucket >= oldest_bucket);
+        while self.entries.len() >= max_entries.max(1) {
+            let Some(least_recent) = self.entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&least_recent);
+        }
+        self.clock += 1;
+        self.entries.insert(key, (preview, self.clock));
+    }
+}
+
+/// A response body fetched from a URL after following redirects
+struct FetchedContent {
+    url: Url,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+/// Builds OpenGraph previews of URLs, storing their images in the media
+/// repository
+///
+/// Every hop of a fetch resolves the host itself, refuses blacklisted
+/// addresses and connects to the address it checked, so DNS answers and
+/// redirects cannot point a fetch at an internal network.
+pub struct UrlPreviewer {
+    config: UrlPreviewConfig,
+    media_repository: Arc<MediaRepository>,
+    cache: Mutex<PreviewCache>,
+}
+
+impl UrlPreviewer {
+    pub fn new(config: UrlPreviewConfig, media_repository: Arc<MediaRepository>) -> Self {
+        Self {
+            config,
+            media_repository,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// OpenGraph preview of `url` as of `ts`, defaulting to now
+    ///
+    /// Images of previews generated for `user_id` count towards their
+    /// media quota.
+    pub async fn preview(&self, user_id: &str, url: &str, ts: Option<u64>) -> Result<Value, UrlPreviewError> {
+        let current_bucket = now_millis() / PREVIEW_CACHE_BUCKET.as_millis() as u64;
+        let bucket = ts.map_or(current_bucket, |ts| ts / PREVIEW_CACHE_BUCKET.as_millis() as u64);
+        let key = (url.to_string(), bucket);
+        if let Some(preview) = self.cache.lock().await.get(&key) {
+            return Ok(preview);
+        }
+
+        let preview = tokio::time::timeout(self.config.timeout, self.generate(user_id, url))
+            .await
+            .map_err(|_| UrlPreviewError::Timeout)??;
+
+        let oldest_bucket = current_bucket.saturating_sub(PREVIEW_CACHE_BUCKETS_KEPT);
+        self.cache.lock().await.insert(key, preview.clone(), oldest_bucket, self.config.max_cached_previews);
+        Ok(preview)
+    }
+
+    async fn generate(&self, user_id: &str, url: &str) -> Result<Value, UrlPreviewError> {
+        let fetched = self.fetch(url).await?;
+        if fetched.content_type.starts_with("image/") {
+            let mut og = Map::new();
+            self.add_image(user_id, &mut og, fetched).await?;
+            return Ok(Value::Object(og));
+        }
+        if !fetched.content_type.starts_with("text/html") && !fetched.content_type.starts_with("application/xhtml+xml") {
+            return Err(UrlPreviewError::Fetch(format!("Cannot preview {} content", fetched.content_type)));
+        }
+
+        let mut og = extract_open_graph(&String::from_utf8_lossy(&fetched.body));
+        // Images are best effort: a page still previews without its image
+        if let Some(image_url) = og.remove("og:image").and_then(|image| image.as_str().map(str::to_string)) {
+            if let Ok(image_url) = fetched.url.join(&image_url) {
+                match self.fetch(image_url.as_str()).await {
+                    Ok(image) if image.content_type.starts_with("image/") => self.add_image(user_id, &mut og, image).await?,
+                    Ok(_) => {}
+                    Err(e) => tracing::debug!("Failed to fetch preview image {}: {}", image_url, e),
+                }
+            }
+        }
+        Ok(Value::Object(og))
+    }
+
+    /// Store an image in the media repository as `user_id`'s upload and
+    /// describe it in `og`
+    async fn add_image(
+        &self,
+        user_id: &str,
+        og: &mut Map<String, Value>,
+        image: FetchedContent,
+    ) -> Result<(), UrlPreviewError> {
+        let filename = image.url.path_segments().and_then(|mut segments| segments.next_back()).map(str::to_string);
+        let stored = self.media_repository
+            .upload(user_id, Some(image.content_type.clone()), filename.filter(|name| !name.is_empty()), &image.body)
+            .await?;
+        og.insert("og:image".to_string(), Value::from(stored.content_uri));
+        og.insert("og:image:type".to_string(), Value::from(image.content_type));
+        og.insert("matrix:image:size".to_string(), Value::from(image.body.len()));
+        // Only the header is read, so the image is never decoded
+        let dimensions = image::ImageReader::new(std::io::Cursor::new(&image.body))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        if let Some((width, height)) = dimensions {
+            og.insert("og:image:width".to_string(), Value::from(width));
+            og.insert("og:image:height".to_string(), Value::from(height));
+        }
+        Ok(())
+    }
+
+    /// GET `url`, following redirects up to the configured limit and
+    /// checking every hop's addresses against the IP range blacklist
+    async fn fetch(&self, url: &str) -> Result<FetchedContent, UrlPreviewError> {
+        let mut url = Url::parse(url).map_err(|e| UrlPreviewError::InvalidUrl(e.to_string()))?;
+        for _ in 0..=self.config.max_redirects {
+            let address = resolve_allowed(&url, &self.config.ip_range_blacklist, &self.config.ip_range_whitelist).await?;
+            let client = pinned_client(&url, address)
+                .timeout(self.config.timeout)
+                .user_agent(PREVIEW_USER_AGENT)
+                .build()
+                .map_err(|e| UrlPreviewError::Fetch(e.to_string()))?;
+
+            let response = client.get(url.clone()).send().await.map_err(|e| UrlPreviewError::Fetch(e.to_string()))?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or_else(|| UrlPreviewError::Fetch("Redirect without a location".to_string()))?;
+                url = url.join(location).map_err(|e| UrlPreviewError::InvalidUrl(e.to_string()))?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(UrlPreviewError::Fetch(format!("{} answered {}", url, response.status())));
+            }
+
+            let content_type = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .to_ascii_lowercase();
+            let body = self.read_limited(response).await?;
+            return Ok(FetchedContent { url, content_type, body });
+        }
+        Err(UrlPreviewError::TooManyRedirects)
+    }
+
+    async fn read_limited(&self, mut response: reqwest::Response) -> Result<Vec<u8>, UrlPreviewError> {
+        let max_size = self.config.max_spider_size;
+        if response.content_length().is_some_and(|length| length > max_size as u64) {
+            return Err(UrlPreviewError::TooLarge(max_size));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| UrlPreviewError::Fetch(e.to_string()))? {
+            if body.len() + chunk.len() > max_size {
+                return Err(UrlPreviewError::TooLarge(max_size));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+/// The default `ip_range_blacklist`, parsed
+pub fn default_ip_range_blacklist() -> Vec<IpNet> {
+    DEFAULT_IP_RANGE_BLACKLIST.iter().filter_map(|range| range.parse().ok()).collect()
+}
+
+/// Whether `ip` is outside `blacklist` or covered by an exception in
+/// `whitelist`; IPv4-mapped IPv6 addresses are checked as IPv4
+pub(crate) fn is_ip_allowed(ip: IpAddr, blacklist: &[IpNet], whitelist: &[IpNet]) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    };
+    whitelist.iter().any(|range| range.contains(&ip)) || !blacklist.iter().any(|range| range.contains(&ip))
+}
+
+/// The address to connect to for `url`, refusing hosts with any
+/// blacklisted address
+pub(crate) async fn resolve_allowed(
+    url: &Url,
+    blacklist: &[IpNet],
+    whitelist: &[IpNet],
+) -> Result<SocketAddr, UrlPreviewError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(UrlPreviewError::InvalidUrl(format!("Unsupported scheme {}", url.scheme())));
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url.host_str().ok_or_else(|| UrlPreviewError::InvalidUrl(format!("{} has no host", url)))?;
+    let addresses: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| UrlPreviewError::Fetch(format!("Cannot resolve {}: {}", host, e)))?
+            .collect(),
+    };
+    if let Some(blocked) = addresses.iter().find(|address| !is_ip_allowed(address.ip(), blacklist, whitelist)) {
+        return Err(UrlPreviewError::Forbidden(format!("{} resolves to blacklisted address {}", url, blocked.ip())));
+    }
+    addresses
+        .into_iter()
+        .next()
+        .ok_or_else(|| UrlPreviewError::Fetch(format!("{} has no addresses", url)))
+}
+
+/// A client for a single hop to `url` that connects to the `address`
+/// already checked for it, never through a proxy and never following
+/// redirects on its own
+pub(crate) fn pinned_client(url: &Url, address: SocketAddr) -> reqwest::ClientBuilder {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy();
+    match url.domain() {
+        Some(domain) => client.resolve(domain, address),
+        None => client,
+    }
+}
+
+/// `og:*` meta tags of an HTML page, falling back to its `<title>` and
+/// description meta tag
+fn extract_open_graph(html: &str) -> Map<String, Value> {
+    static META: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    static TITLE: OnceLock<Regex> = OnceLock::new();
+    let meta = META.get_or_init(|| Regex::new(r"(?is)<meta\s([^>]*)>").unwrap());
+    let attribute = ATTRIBUTE.get_or_init(|| {
+        Regex::new(r#"(?is)([a-z_:.-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap()
+    });
+    let title = TITLE.get_or_init(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+
+    let mut og = Map::new();
+    let mut description = None;
+    for tag in meta.captures_iter(html) {
+        let attributes: HashMap<String, &str> = attribute
+            .captures_iter(&tag[1])
+            .filter_map(|capture| {
+                let value = capture.get(2).or(capture.get(3)).or(capture.get(4))?;
+                Some((capture[1].to_ascii_lowercase(), value.as_str()))
+            })
+            .collect();
+        let Some(content) = attributes.get("content").map(|content| decode_entities(content)) else {
+            continue;
+        };
+        let key = attributes.get("property").or(attributes.get("name")).map(|key| key.to_ascii_lowercase());
+        match key.as_deref() {
+            Some(key) if key.starts_with("og:") => {
+                og.entry(key.to_string()).or_insert(Value::from(content));
+            }
+            Some("description") => {
+                description.get_or_insert(content);
+            }
+            _ => {}
+        }
+    }
+
+    if !og.contains_key("og:title") {
+        if let Some(title) = title.captures(html).map(|capture| decode_entities(&capture[1])) {
+            og.insert("og:title".to_string(), Value::from(title));
+        }
+    }
+    if let Some(description) = description {
+        og.entry("og:description").or_insert(Value::from(description));
+    }
+    if let Some(Value::String(description)) = og.get_mut("og:description") {
+        if let Some((end, _)) = description.char_indices().nth(MAX_DESCRIPTION_LENGTH) {
+            description.truncate(end);
+            description.push('…');
+        }
+    }
+    og.retain(|_, value| value.as_str().is_some_and(|value| !value.is_empty()));
+    og
+}
+
+/// Collapse whitespace and decode the entities common in page metadata
+fn decode_entities(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_open_graph() {
+        let html = r#"<html><head>
+            <title>Fallback   title</title>
+            <meta property="og:title" content="Caf&eacute; &amp; Bar">
+            <meta name='description' content='Open &quot;daily&quot;'>
+            <META PROPERTY="og:image" CONTENT="/static/logo.png" />
+            <meta property="og:title" content="Ignored duplicate">
+        </head></html>"#;
+        let og = extract_open_graph(html);
+        assert_eq!(og["og:title"], "Caf&eacute; & Bar");
+        assert_eq!(og["og:description"], "Open \"daily\"");
+        assert_eq!(og["og:image"], "/static/logo.png");
+
+        let og = extract_open_graph("<title>Only a title</title>");
+        assert_eq!(Value::Object(og), serde_json::json!({ "og:title": "Only a title" }));
+    }
+
+    #[test]
+    fn test_preview_cache_evicts_least_recently_used() {
+        let mut cache = PreviewCache::default();
+        let key = |url: &str, bucket: u64| (url.to_string(), bucket);
+        cache.insert(key("a", 10), Value::from("a"), 0, 2);
+        cache.insert(key("b", 10), Value::from("b"), 0, 2);
+        assert_eq!(cache.get(&key("a", 10)), Some(Value::from("a")));
+
+        cache.insert(key("c", 10), Value::from("c"), 0, 2);
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get(&key("b", 10)).is_none());
+        assert!(cache.get(&key("a", 10)).is_some());
+
+        // Buckets that fell out of the kept window go regardless of recency
+        cache.insert(key("d", 20), Value::from("d"), 15, 2);
+        assert_eq!(cache.entries.keys().cloned().collect::<Vec<_>>(), vec![key("d", 20)]);
+    }
+
+    #[tokio::test]
+    async fn test_ip_range_blacklist() {
+        let mut config = UrlPreviewConfig::default();
+        let is_allowed = |config: &UrlPreviewConfig, ip: &str| {
+            is_ip_allowed(ip.parse().unwrap(), &config.ip_range_blacklist, &config.ip_range_whitelist)
+        };
+        for blocked in [
+            "127.0.0.1", "10.1.2.3", "192.168.0.10", "169.254.169.254", "::", "::1", "::ffff:127.0.0.1",
+            "::ffff:0.0.0.0", "64:ff9b::7f00:1", "2001:db8::1", "fd00::1", "ff02::1",
+        ] {
+            assert!(!is_allowed(&config, blocked), "{} should be blocked", blocked);
+        }
+        let unspecified = Url::parse("http://[::]:8080/").unwrap();
+        let result = resolve_allowed(&unspecified, &config.ip_range_blacklist, &config.ip_range_whitelist).await;
+        assert!(matches!(result, Err(UrlPreviewError::Forbidden(_))));
+        assert!(is_allowed(&config, "93.184.216.34"));
+        assert!(is_allowed(&config, "2606:2800:220:1::1"));
+
+        config.ip_range_whitelist.push("127.0.0.1/32".parse().unwrap());
+        assert!(is_allowed(&config, "127.0.0.1"));
+        assert!(!is_allowed(&config, "127.0.0.2"));
+    }
+}
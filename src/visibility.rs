@@ -0,0 +1,194 @@
+// History Visibility
+// Decides which room events a user or server may read
+// Focus: Evaluating m.room.history_visibility against the state at each event
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::events::{EventContent, EventType, HistoryVisibility, MatrixEvent, MembershipState};
+use crate::state::{RoomState, StateError, StateStore, VisibilityChange};
+
+/// Who is reading room history
+#[derive(Debug, Clone, Copy)]
+pub enum Viewer<'a> {
+    /// A local user, by user ID
+    User(&'a str),
+    /// A remote server, by server name, reading on behalf of its users
+    Server(&'a str),
+}
+
+impl Viewer<'_> {
+    /// Whether `user_id` is the viewing user or one of the viewing server's users
+    pub fn covers(&self, user_id: &str) -> bool {
+        match self {
+            Viewer::User(viewer) => *viewer == user_id,
+            Viewer::Server(server) => user_id.split_once(':').is_some_and(|(_, name)| name == *server),
+        }
+    }
+}
+
+/// History visibility and viewer membership replayed over a room's timeline
+///
+/// Changes come from the store's per-room index rather than the timeline
+/// itself. State that never passed through the timeline, such as state
+/// learned from a remote server, applies from the start of the timeline.
+pub struct VisibilityFilter {
+    visibility: BTreeMap<u64, HistoryVisibility>,
+    memberships: HashMap<String, BTreeMap<u64, MembershipState>>,
+    currently_joined: bool,
+}
+
+impl VisibilityFilter {
+    pub async fn load(
+        store: &dyn StateStore,
+        room_state: &RoomState,
+        viewer: Viewer<'_>,
+    ) -> Result<Self, StateError> {
+        let changes = store.get_visibility_changes(&room_state.room_id).await?;
+
+        let mut visibility = BTreeMap::new();
+        let mut memberships: HashMap<String, BTreeMap<u64, MembershipState>> = HashMap::new();
+        for (ordering, change) in changes {
+            match change {
+                VisibilityChange::HistoryVisibility(history_visibility) => {
+                    visibility.insert(ordering, history_visibility);
+                }
+                VisibilityChange::Membership { user_id, membership } if viewer.covers(&user_id) => {
+                    memberships.entry(user_id).or_default().insert(ordering, membership);
+                }
+                VisibilityChange::Membership { .. } => {}
+            }
+        }
+
+        if visibility.is_empty() {
+            let initial = room_state.history_visibility
+                .as_deref()
+                .and_then(|value| serde_json::from_value(serde_json::Value::String(value.to_string())).ok())
+                .unwrap_or_default();
+            visibility.insert(0, initial);
+        }
+        for (user_id, membership) in room_state.members.iter().filter(|(user_id, _)| viewer.covers(user_id)) {
+            memberships
+                .entry(user_id.clone())
+                .or_insert_with(|| BTreeMap::from([(0, membership.clone())]));
+        }
+
+        let currently_joined = room_state.members
+            .iter()
+            .any(|(user_id, membership)| viewer.covers(user_id) && *membership == MembershipState::Join);
+
+        Ok(Self { visibility, memberships, currently_joined })
+    }
+
+    /// Whether the event at stream position `ordering` is visible to the viewer
+    pub fn is_visible(&self, ordering: u64) -> bool {
+        let visibility = self.visibility
+            .range(..=ordering)
+            .next_back()
+            .map(|(_, visibility)| *visibility)
+            .unwrap_or_default();
+
+        match visibility {
+            HistoryVisibility::WorldReadable => true,
+            HistoryVisibility::Shared => self.currently_joined || self.membership_at(ordering, &[MembershipState::Join]),
+            HistoryVisibility::Invited => self.membership_at(ordering, &[MembershipState::Join, MembershipState::Invite]),
+            HistoryVisibility::Joined => self.membership_at(ordering, &[MembershipState::Join]),
+        }
+    }
+
+    fn membership_at(&self, ordering: u64, allowed: &[MembershipState]) -> bool {
+        self.memberships.values().any(|history| {
+            history
+                .range(..=ordering)
+                .next_back()
+                .is_some_and(|(_, membership)| allowed.contains(membership))
+        })
+    }
+}
+
+/// Index a newly stored event that changes who may read the room's history
+pub async fn index_event(store: &dyn StateStore, ordering: u64, event: &MatrixEvent) -> Result<(), StateError> {
+    let change = match (&event.event_type, &event.content, event.state_key.as_deref()) {
+        (EventType::RoomHistoryVisibility, EventContent::RoomHistoryVisibility(content), Some(_)) => {
+            VisibilityChange::HistoryVisibility(content.history_visibility)
+        }
+        (EventType::RoomMember, EventContent::RoomMember(content), Some(user_id)) => VisibilityChange::Membership {
+            user_id: user_id.to_string(),
+            membership: content.membership.clone(),
+        },
+        _ => return Ok(()),
+    };
+    store.add_visibility_change(&event.room_id, ordering, change).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{MatrixEvent, RoomHistoryVisibilityContent, RoomMemberContent};
+    use crate::state::InMemoryStateStore;
+
+    const ROOM_ID: &str = "!room:localhost";
+
+    fn member_event(user_id: &str, membership: MembershipState) -> MatrixEvent {
+        MatrixEvent::new(
+            EventType::RoomMember,
+            EventContent::RoomMember(RoomMemberContent {
+                membership,
+                displayname: None,
+                avatar_url: None,
+                reason: None,
+                is_direct: None,
+                third_party_invite: None,
+                join_authorised_via_users_server: None,
+            }),
+            user_id.to_string(),
+            ROOM_ID.to_string(),
+        ).with_state_key(user_id.to_string())
+    }
+
+    async fn apply(store: &InMemoryStateStore, room_state: &mut RoomState, event: MatrixEvent) -> u64 {
+        let ordering = store.append_event(event.clone()).await.unwrap();
+        index_event(store, ordering, &event).await.unwrap();
+        room_state.apply_state_event(event).unwrap();
+        ordering
+    }
+
+    #[tokio::test]
+    async fn test_invited_visibility_starts_at_invite() {
+        let store = InMemoryStateStore::new();
+        let mut room_state = RoomState::new(ROOM_ID.to_string(), "@alice:localhost".to_string(), "9".to_string());
+
+        let visibility = MatrixEvent::new(
+            EventType::RoomHistoryVisibility,
+            EventContent::RoomHistoryVisibility(RoomHistoryVisibilityContent {
+                history_visibility: HistoryVisibility::Invited,
+            }),
+            "@alice:localhost".to_string(),
+            ROOM_ID.to_string(),
+        ).with_state_key(String::new());
+        let before_invite = apply(&store, &mut room_state, visibility).await;
+        let invite = apply(&store, &mut room_state, member_event("@bob:remote.example", MembershipState::Invite)).await;
+        let join = apply(&store, &mut room_state, member_event("@bob:remote.example", MembershipState::Join)).await;
+
+        let filter = VisibilityFilter::load(&store, &room_state, Viewer::User("@bob:remote.example")).await.unwrap();
+        assert!(!filter.is_visible(before_invite));
+        assert!(filter.is_visible(invite));
+        assert!(filter.is_visible(join));
+
+        let filter = VisibilityFilter::load(&store, &room_state, Viewer::Server("remote.example")).await.unwrap();
+        assert!(filter.is_visible(invite));
+
+        let filter = VisibilityFilter::load(&store, &room_state, Viewer::User("@carol:localhost")).await.unwrap();
+        assert!(!filter.is_visible(join));
+    }
+
+    #[tokio::test]
+    async fn test_world_readable_visible_to_anyone() {
+        let store = InMemoryStateStore::new();
+        let mut room_state = RoomState::new(ROOM_ID.to_string(), "@alice:localhost".to_string(), "9".to_string());
+        room_state.history_visibility = Some("world_readable".to_string());
+        let ordering = apply(&store, &mut room_state, member_event("@alice:localhost", MembershipState::Join)).await;
+
+        let filter = VisibilityFilter::load(&store, &room_state, Viewer::Server("elsewhere.example")).await.unwrap();
+        assert!(filter.is_visible(ordering));
+    }
+}